@@ -0,0 +1,286 @@
+//! `GET /healthz` and `GET /readyz`, for the orchestrator.
+//!
+//! `/healthz` answers as long as the process can serve requests at all.
+//! `/readyz` runs every [`ReadinessCheck`] and answers 503 unless all of them
+//! pass, so traffic is only routed to instances that can actually handle it.
+
+use actix_web::{get, rt::time::timeout, web, HttpResponse, Responder};
+use async_trait::async_trait;
+use db::{db as sqlite, SqlitePool};
+use shared::{
+    data_encryption::KeyRing,
+    models::{CheckStatus, LivenessResponse, ReadinessCheckResult, ReadinessResponse},
+};
+use std::{sync::Arc, time::Duration};
+
+use crate::token::load_decoding_key;
+
+/// How long a check may take before it counts as failed. A locked SQLite
+/// file otherwise stalls for the whole busy timeout.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Something the instance needs before it can take traffic. On success the
+/// check describes what it found; on failure, what is wrong.
+#[async_trait]
+pub trait ReadinessCheck: Send + Sync {
+    fn name(&self) -> &'static str;
+    async fn check(&self) -> Result<String, String>;
+}
+
+/// The checks `GET /readyz` runs, in order.
+#[derive(Clone, Default)]
+pub struct Readiness {
+    checks: Vec<Arc<dyn ReadinessCheck>>,
+}
+
+impl Readiness {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_check(mut self, check: impl ReadinessCheck + 'static) -> Self {
+        self.checks.push(Arc::new(check));
+        self
+    }
+
+    pub async fn run(&self) -> ReadinessResponse {
+        let mut checks = Vec::with_capacity(self.checks.len());
+        for check in &self.checks {
+            let result = timeout(CHECK_TIMEOUT, check.check())
+                .await
+                .unwrap_or_else(|_| Err(format!("timed out after {CHECK_TIMEOUT:?}")));
+            let (status, detail) = match result {
+                Ok(detail) => (CheckStatus::Ok, detail),
+                Err(detail) => {
+                    tracing::warn!(check = check.name(), %detail, "readiness check failed");
+                    (CheckStatus::Failed, detail)
+                }
+            };
+            checks.push(ReadinessCheckResult {
+                name: check.name().to_string(),
+                status,
+                detail,
+            });
+        }
+
+        ReadinessResponse {
+            ready: checks.iter().all(|check| check.status == CheckStatus::Ok),
+            checks,
+        }
+    }
+}
+
+/// The SQLite database answers queries.
+pub struct DatabaseCheck(pub SqlitePool);
+
+#[async_trait]
+impl ReadinessCheck for DatabaseCheck {
+    fn name(&self) -> &'static str {
+        "database"
+    }
+
+    async fn check(&self) -> Result<String, String> {
+        sqlite::ping(&self.0)
+            .await
+            .map(|()| String::from("reachable"))
+            .map_err(|e| {
+                tracing::error!(error = %e, "database ping failed");
+                String::from("unreachable")
+            })
+    }
+}
+
+/// Every migration this build knows of has been applied.
+pub struct MigrationsCheck(pub SqlitePool);
+
+#[async_trait]
+impl ReadinessCheck for MigrationsCheck {
+    fn name(&self) -> &'static str {
+        "migrations"
+    }
+
+    async fn check(&self) -> Result<String, String> {
+        let pending = sqlite::pending_migrations(&self.0)
+            .await
+            .map_err(|e| {
+                tracing::error!(error = %e, "applied migrations could not be read");
+                String::from("could not read applied migrations")
+            })?;
+        if pending.is_empty() {
+            return Ok(String::from("all migrations applied"));
+        }
+        let versions: Vec<String> = pending.iter().map(i64::to_string).collect();
+        Err(format!("pending migrations: {}", versions.join(", ")))
+    }
+}
+
+/// The key refresh tokens are verified with is loaded.
+pub struct JwtKeyCheck;
+
+#[async_trait]
+impl ReadinessCheck for JwtKeyCheck {
+    fn name(&self) -> &'static str {
+        "jwt_decoding_key"
+    }
+
+    async fn check(&self) -> Result<String, String> {
+        // The error names the key file's path, which stays in the logs
+        load_decoding_key()
+            .map(|_| String::from("loaded"))
+            .map_err(|e| {
+                tracing::error!(error = %e, "JWT decoding key could not be loaded");
+                String::from("could not be loaded")
+            })
+    }
+}
+
+/// Keys for encrypting data at rest are configured.
+pub struct DataKeysCheck(pub Option<Arc<KeyRing>>);
+
+#[async_trait]
+impl ReadinessCheck for DataKeysCheck {
+    fn name(&self) -> &'static str {
+        "data_encryption_keys"
+    }
+
+    async fn check(&self) -> Result<String, String> {
+        match &self.0 {
+            Some(key_ring) => Ok(format!("current key id {}", key_ring.current_key_id())),
+            None => Err(String::from("neither DATA_KEYS nor DATA_KEYS_FILE is set")),
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    responses(
+        (status = 200, description = "The process is up", body = LivenessResponse),
+    )
+)]
+#[get("/healthz")]
+pub async fn healthz_handler() -> impl Responder {
+    HttpResponse::Ok().json(LivenessResponse {
+        status: String::from("ok"),
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    responses(
+        (status = 200, description = "Every check passed", body = ReadinessResponse),
+        (status = 503, description = "At least one check failed", body = ReadinessResponse),
+    )
+)]
+#[get("/readyz")]
+pub async fn readyz_handler(readiness: web::Data<Readiness>) -> impl Responder {
+    let response = readiness.run().await;
+    if response.ready {
+        HttpResponse::Ok().json(response)
+    } else {
+        HttpResponse::ServiceUnavailable().json(response)
+    }
+}
+
+/// Registers both endpoints; `/readyz` runs `readiness`.
+pub fn configure_routes(readiness: Readiness) -> impl FnOnce(&mut web::ServiceConfig) {
+    move |cfg| {
+        cfg.app_data(web::Data::new(readiness))
+            .service(healthz_handler)
+            .service(readyz_handler);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test, App};
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    struct Stalled;
+
+    #[async_trait]
+    impl ReadinessCheck for Stalled {
+        fn name(&self) -> &'static str {
+            "stalled"
+        }
+
+        async fn check(&self) -> Result<String, String> {
+            std::future::pending().await
+        }
+    }
+
+    async fn readyz(readiness: Readiness) -> (StatusCode, ReadinessResponse) {
+        let app = test::init_service(App::new().configure(configure_routes(readiness))).await;
+        let req = test::TestRequest::get().uri("/readyz").to_request();
+        let res = test::call_service(&app, req).await;
+        (res.status(), test::read_body_json(res).await)
+    }
+
+    #[actix_web::test]
+    async fn test_healthz_checks_nothing() {
+        let app = test::init_service(
+            App::new().configure(configure_routes(Readiness::new().with_check(Stalled))),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/healthz").to_request();
+        let body: LivenessResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body.status, "ok");
+    }
+
+    #[actix_web::test]
+    async fn test_readyz_reports_each_check() {
+        // One connection, since each opens its own in-memory database
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        let readiness = Readiness::new()
+            .with_check(DatabaseCheck(pool.clone()))
+            .with_check(MigrationsCheck(pool.clone()))
+            .with_check(DataKeysCheck(None));
+
+        let (status, body) = readyz(readiness.clone()).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(!body.ready);
+        let statuses: Vec<_> = body.checks.iter().map(|c| (c.name.as_str(), c.status)).collect();
+        assert_eq!(
+            statuses,
+            [
+                ("database", CheckStatus::Ok),
+                ("migrations", CheckStatus::Failed),
+                ("data_encryption_keys", CheckStatus::Failed),
+            ]
+        );
+        assert!(body.checks[1].detail.starts_with("pending migrations: "));
+
+        sqlite::MIGRATOR.run(&pool).await.unwrap();
+        let key_ring = Arc::new(KeyRing::new(1, &[7; 32]).unwrap());
+        let (status, body) = readyz(
+            Readiness::new()
+                .with_check(DatabaseCheck(pool.clone()))
+                .with_check(MigrationsCheck(pool.clone()))
+                .with_check(DataKeysCheck(Some(key_ring))),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.ready);
+
+        pool.close().await;
+        let (status, body) = readyz(readiness).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body.checks[0].status, CheckStatus::Failed);
+        assert_eq!(body.checks[0].detail, "unreachable");
+        assert_eq!(body.checks[1].detail, "could not read applied migrations");
+    }
+
+    #[actix_web::test]
+    async fn test_stalled_checks_time_out() {
+        let (status, body) = readyz(Readiness::new().with_check(Stalled)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body.checks[0].status, CheckStatus::Failed);
+        assert!(body.checks[0].detail.starts_with("timed out"));
+    }
+}
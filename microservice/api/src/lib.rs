@@ -1,3 +1,7 @@
+pub mod health;
 pub mod message;
+pub mod metrics;
+pub mod rate_limit;
+pub mod request_id;
 pub mod user;
 pub mod token;
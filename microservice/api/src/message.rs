@@ -4,13 +4,18 @@ use actix_web::{
     HttpResponse, Responder,
 };
 use base64::Engine;
-use db::{models::Message, uuid::Uuid};
+use db::{
+    models::{AnonymousMessage, Message},
+    uuid::Uuid,
+};
 use mockall::automock;
 use service::message::{repository::MessageRepository, service::MessageService};
 use shared::{
-    errors::AppError,
+    errors::{AppError, ErrorResponse},
     models::{
-        CreateMessageRequest, CreateMessageResponse as MessageCreatedResponse, CUSTOM_ENGINE,
+        CreateAnonymousMessageRequest, CreateAnonymousMessageResponse as AnonymousMessageCreatedResponse,
+        CreateMessageRequest, CreateMessageResponse as MessageCreatedResponse,
+        MessageCommitmentResponse, CUSTOM_ENGINE,
     },
 };
 use std::sync::Arc;
@@ -20,10 +25,13 @@ use serde::Deserialize;
 
 pub type CreateMessageResponse = Result<HttpResponse, AppError>;
 pub type GetMessageResponse = Result<HttpResponse, AppError>;
+pub type GetMessageCommitmentResponse = Result<HttpResponse, AppError>;
 pub type GetConversationResponse = Result<HttpResponse, AppError>;
 pub type GetThreadRepliesResponse = Result<HttpResponse, AppError>;
 pub type GetCompleteThreadResponse = Result<HttpResponse, AppError>;
 pub type GetUserThreadsResponse = Result<HttpResponse, AppError>;
+pub type CreateAnonymousMessageResponse = Result<HttpResponse, AppError>;
+pub type GetAnonymousMessagesResponse = Result<HttpResponse, AppError>;
 
 
 #[derive(Deserialize)]
@@ -44,6 +52,8 @@ pub trait MessageController: Send + Sync {
 
     async fn get_message(&self, message_id: Path<i64>) -> GetMessageResponse;
 
+    async fn get_message_commitment(&self, message_id: Path<i64>) -> GetMessageCommitmentResponse;
+
     async fn get_conversation(
         &self,
         user1_id: Path<Uuid>,
@@ -69,6 +79,17 @@ pub trait MessageController: Send + Sync {
         user_id: Path<Uuid>,
         limit: Query<Option<i64>>,
     ) -> GetUserThreadsResponse;
+
+    async fn create_anonymous_message(
+        &self,
+        request: Json<CreateAnonymousMessageRequest>,
+    ) -> CreateAnonymousMessageResponse;
+
+    async fn get_anonymous_messages(
+        &self,
+        recipient_id: Path<Uuid>,
+        limit: Query<Option<i64>>,
+    ) -> GetAnonymousMessagesResponse;
 }
 
 pub struct MessageControllerImpl<R: MessageRepository> {
@@ -95,6 +116,8 @@ impl<R: MessageRepository + 'static> MessageController for MessageControllerImpl
                 &request.encrypted_content,
                 request.signature.clone(),
                 request.parent_id,
+                request.commitment.clone(),
+                request.ephemeral_key_proof.as_ref(),
             )
             .await?
             .ok_or_else(|| AppError::InternalError(String::from("Failed to create message")))?;
@@ -112,6 +135,19 @@ impl<R: MessageRepository + 'static> MessageController for MessageControllerImpl
         Ok(HttpResponse::Ok().json(message))
     }
 
+    async fn get_message_commitment(&self, message_id: Path<i64>) -> GetMessageCommitmentResponse {
+        let commitment = self
+            .service
+            .get_message_commitment(*message_id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(String::from("Message commitment not found")))?;
+
+        Ok(HttpResponse::Ok().json(MessageCommitmentResponse {
+            message_id: *message_id,
+            commitment,
+        }))
+    }
+
     async fn get_conversation(
         &self,
         user1_id: Path<Uuid>,
@@ -165,6 +201,38 @@ impl<R: MessageRepository + 'static> MessageController for MessageControllerImpl
 
         Ok(HttpResponse::Ok().json(threads))
     }
+
+    async fn create_anonymous_message(
+        &self,
+        request: Json<CreateAnonymousMessageRequest>,
+    ) -> CreateAnonymousMessageResponse {
+        if let Err(validation_errors) = request.validate() {
+            return Err(AppError::ValidationError(validation_errors));
+        }
+        let message_id = self
+            .service
+            .create_anonymous_message(
+                request.recipient_id,
+                &request.encrypted_content,
+                &request.membership_proof,
+            )
+            .await?;
+
+        Ok(HttpResponse::Created().json(AnonymousMessageCreatedResponse { message_id }))
+    }
+
+    async fn get_anonymous_messages(
+        &self,
+        recipient_id: Path<Uuid>,
+        limit: Query<Option<i64>>,
+    ) -> GetAnonymousMessagesResponse {
+        let messages = self
+            .service
+            .get_anonymous_messages(*recipient_id, limit.into_inner())
+            .await?;
+
+        Ok(HttpResponse::Ok().json(messages))
+    }
 }
 
 // Actix-web route handlers
@@ -174,8 +242,9 @@ impl<R: MessageRepository + 'static> MessageController for MessageControllerImpl
     request_body(content = CreateMessageRequest, content_type = "application/json"),
     responses(
         (status = 201, description = "Message created successfully", body = MessageCreatedResponse),
-        (status = 400, description = "Validation error"),
-        (status = 500, description = "Internal server error"),
+        (status = 400, description = "Validation error", body = ErrorResponse),
+        (status = 429, description = "Rate limited; retry after `Retry-After` seconds", body = ErrorResponse),
+        (status = 500, description = "Internal server error", body = ErrorResponse),
     )
 )]
 #[post("")]
@@ -194,8 +263,8 @@ pub async fn create_message_handler(
     ),
     responses(
         (status = 200, description = "Message found", body = Message),
-        (status = 404, description = "Message not found"),
-        (status = 500, description = "Internal server error"),
+        (status = 404, description = "Message not found", body = ErrorResponse),
+        (status = 500, description = "Internal server error", body = ErrorResponse),
     )
 )]
 #[get("/{message_id}")]
@@ -206,6 +275,26 @@ pub async fn get_message_handler(
     controller.get_message(message_id).await
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/messages/{message_id}/commitment",
+    params(
+        ("message_id" = i64, Path, description = "Message ID")
+    ),
+    responses(
+        (status = 200, description = "Commitment to the message ciphertext", body = MessageCommitmentResponse),
+        (status = 404, description = "Message not found or sent without a commitment", body = ErrorResponse),
+        (status = 500, description = "Internal server error", body = ErrorResponse),
+    )
+)]
+#[get("/{message_id}/commitment")]
+pub async fn get_message_commitment_handler(
+    controller: Data<Arc<dyn MessageController>>,
+    message_id: Path<i64>,
+) -> impl Responder {
+    controller.get_message_commitment(message_id).await
+}
+
 #[utoipa::path(
     get,
     path = "/api/messages/conversations/{user1_id}/{user2_id}",
@@ -216,7 +305,7 @@ pub async fn get_message_handler(
     ),
     responses(
         (status = 200, description = "Conversation messages", body = Vec<Message>),
-        (status = 500, description = "Internal server error"),
+        (status = 500, description = "Internal server error", body = ErrorResponse),
     )
 )]
 #[get("/conversations/{user1_id}/{user2_id}")]
@@ -241,7 +330,7 @@ pub async fn get_conversation_handler(
     ),
     responses(
         (status = 200, description = "Thread replies", body = Vec<Message>),
-        (status = 500, description = "Internal server error"),
+        (status = 500, description = "Internal server error", body = ErrorResponse),
     )
 )]
 #[get("/threads/{parent_id}/replies")]
@@ -264,7 +353,7 @@ pub async fn get_thread_replies_handler(
     ),
     responses(
         (status = 200, description = "Complete thread", body = Vec<Message>),
-        (status = 500, description = "Internal server error"),
+        (status = 500, description = "Internal server error", body = ErrorResponse),
     )
 )]
 #[get("/threads/{thread_root_id}")]
@@ -285,7 +374,7 @@ pub async fn get_complete_thread_handler(
     ),
     responses(
         (status = 200, description = "User threads", body = Vec<Message>),
-        (status = 500, description = "Internal server error"),
+        (status = 500, description = "Internal server error", body = ErrorResponse),
     )
 )]
 #[get("/users/{user_id}/threads")]
@@ -297,10 +386,55 @@ pub async fn get_user_threads_handler(
     controller.get_user_threads(user_id, Query(query.limit)).await
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/messages/anonymous",
+    request_body(content = CreateAnonymousMessageRequest, content_type = "application/json"),
+    responses(
+        (status = 201, description = "Anonymous message created", body = AnonymousMessageCreatedResponse),
+        (status = 400, description = "Validation error or malformed proof", body = ErrorResponse),
+        (status = 403, description = "Membership proof invalid, against an unknown root or expired epoch, or not accepted; or the sender is blocked for exceeding the rate limit", body = ErrorResponse),
+        (status = 409, description = "Message already sent", body = ErrorResponse),
+        (status = 429, description = "Rate limited; retry after `Retry-After` seconds", body = ErrorResponse),
+        (status = 500, description = "Internal server error", body = ErrorResponse),
+    )
+)]
+#[post("/anonymous")]
+pub async fn create_anonymous_message_handler(
+    controller: Data<Arc<dyn MessageController>>,
+    request: Json<CreateAnonymousMessageRequest>,
+) -> impl Responder {
+    controller.create_anonymous_message(request).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/messages/anonymous/{recipient_id}",
+    params(
+        ("recipient_id" = Uuid, Path, description = "Recipient ID"),
+        ("limit" = Option<i64>, Query, description = "Maximum number of messages to return")
+    ),
+    responses(
+        (status = 200, description = "Anonymous messages, newest first", body = Vec<AnonymousMessage>),
+        (status = 500, description = "Internal server error", body = ErrorResponse),
+    )
+)]
+#[get("/anonymous/{recipient_id}")]
+pub async fn get_anonymous_messages_handler(
+    controller: Data<Arc<dyn MessageController>>,
+    recipient_id: Path<Uuid>,
+    query: Query<LimitQuery>,
+) -> impl Responder {
+    controller.get_anonymous_messages(recipient_id, Query(query.limit)).await
+}
+
 pub fn configure_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/api/messages")
             .service(create_message_handler)
+            .service(create_anonymous_message_handler)
+            .service(get_anonymous_messages_handler)
+            .service(get_message_commitment_handler)
             .service(get_message_handler)
             .service(get_conversation_handler)
             .service(get_thread_replies_handler)
@@ -355,6 +489,8 @@ mod tests {
             encrypted_content: encrypted_content.clone(),
             signature: Some(sig.clone()),
             parent_id: None,
+            ephemeral_key_proof: None,
+            commitment: None,
         };
 
         mock_repo
@@ -392,6 +528,8 @@ mod tests {
             encrypted_content: CUSTOM_ENGINE.encode(b"valid"),
             signature: Some(CUSTOM_ENGINE.encode(b"sig")),
             parent_id: None,
+            ephemeral_key_proof: None,
+            commitment: None,
         };
 
         let response = controller.create_message(Json(request)).await.unwrap();
@@ -411,6 +549,8 @@ mod tests {
             encrypted_content: "not_base64".to_string(),
             signature: Some("c2lnbmF0dXJl".to_string()),
             parent_id: None,
+            ephemeral_key_proof: None,
+            commitment: None,
         };
 
         let response = controller.create_message(Json(request)).await;
@@ -437,6 +577,8 @@ mod tests {
             encrypted_content: CUSTOM_ENGINE.encode(b"valid"),
             signature: Some(CUSTOM_ENGINE.encode(b"sig")),
             parent_id: None,
+            ephemeral_key_proof: None,
+            commitment: None,
         };
         let service = Data::new(MessageService::new(mock_repo));
         let controller = Data::new(MessageControllerImpl::new(service));
@@ -463,6 +605,8 @@ mod tests {
             encrypted_content: enc_content.clone(),
             signature: Some(long_data.clone()),
             parent_id: None,
+            ephemeral_key_proof: None,
+            commitment: None,
         };
 
         let response = controller.create_message(Json(request)).await;
@@ -511,6 +655,87 @@ mod tests {
         assert_eq!(body.encrypted_content, "test message");
     }
 
+    #[actix_web::test]
+    async fn test_get_message_commitment_success() {
+        let mut mock_repo = MockRepository::new();
+        let message_id = 42;
+
+        mock_repo
+            .expect_get_message_commitment()
+            .with(eq(message_id))
+            .times(1)
+            .returning(|_| Ok(Some("commitment".to_string())));
+
+        let service = Data::new(MessageService::new(mock_repo));
+        let controller = Data::new(MessageControllerImpl::new(service));
+        let response = controller
+            .get_message_commitment(Path::from(message_id))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: MessageCommitmentResponse = parse_response_body(response).await;
+        assert_eq!(body.message_id, message_id);
+        assert_eq!(body.commitment, "commitment");
+    }
+
+    #[actix_web::test]
+    async fn test_get_message_commitment_not_found() {
+        let mut mock_repo = MockRepository::new();
+
+        mock_repo
+            .expect_get_message_commitment()
+            .times(1)
+            .returning(|_| Ok(None));
+
+        let service = Data::new(MessageService::new(mock_repo));
+        let controller = Data::new(MessageControllerImpl::new(service));
+        let result = controller.get_message_commitment(Path::from(7)).await;
+
+        assert!(matches!(result, Err(AppError::NotFound(_))));
+    }
+
+    fn anonymous_request(encrypted_content: String) -> CreateAnonymousMessageRequest {
+        CreateAnonymousMessageRequest {
+            recipient_id: Uuid::now_v7(),
+            encrypted_content,
+            membership_proof: shared::models::MembershipProof {
+                root: CUSTOM_ENGINE.encode([0; 32]),
+                epoch: 0,
+                nullifier: CUSTOM_ENGINE.encode([0; 32]),
+                share: CUSTOM_ENGINE.encode([0; 32]),
+                proof: CUSTOM_ENGINE.encode([0; 8]),
+            },
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_create_anonymous_message_not_accepted() {
+        let (controller, _) = setup_controller().await;
+
+        let result = controller
+            .create_anonymous_message(Json(anonymous_request(CUSTOM_ENGINE.encode(b"abcd"))))
+            .await;
+
+        assert!(matches!(result, Err(AppError::Forbidden(_))));
+    }
+
+    #[actix_web::test]
+    async fn test_create_anonymous_message_invalid_encrypted_content() {
+        let (controller, _) = setup_controller().await;
+
+        let result = controller
+            .create_anonymous_message(Json(anonymous_request("not_base64".to_string())))
+            .await;
+
+        match result {
+            Err(AppError::ValidationError(errs)) => {
+                assert!(errs.field_errors().contains_key("encrypted_content"));
+            }
+            _ => panic!("Expected ValidationError for invalid base64 in encrypted_content"),
+        }
+    }
+
     #[actix_web::test]
     async fn test_get_conversation_success() {
         let mut mock_repo = MockRepository::new();
@@ -0,0 +1,90 @@
+//! `GET /metrics`, and the middleware counting and timing every request.
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    get,
+    middleware::Next,
+    web, Error, HttpResponse, Responder,
+};
+use shared::metrics::{self, HTTP_REQUESTS, HTTP_REQUEST_DURATION};
+use std::time::Instant;
+
+/// Middleware recording [`HTTP_REQUESTS`] and [`HTTP_REQUEST_DURATION`];
+/// wrap the app in it with `actix_web::middleware::from_fn(metrics::track_request)`.
+pub async fn track_request(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let method = req.method().to_string();
+    // Unmatched paths are arbitrary client input, so they share one label
+    let route = req
+        .match_pattern()
+        .unwrap_or_else(|| String::from("unmatched"));
+
+    let start = Instant::now();
+    let result = next.call(req).await;
+    let status = match &result {
+        Ok(res) => res.status(),
+        Err(err) => err.as_response_error().status_code(),
+    };
+
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[&method, &route])
+        .observe(start.elapsed().as_secs_f64());
+    HTTP_REQUESTS
+        .with_label_values(&[&method, &route, status.as_str()])
+        .inc();
+    result
+}
+
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    responses(
+        (status = 200, description = "Metrics in the Prometheus text format", body = String, content_type = "text/plain"),
+    )
+)]
+#[get("/metrics")]
+pub async fn metrics_handler() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type(metrics::CONTENT_TYPE)
+        .body(metrics::encode())
+}
+
+pub fn configure_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(metrics_handler);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{middleware::from_fn, test, App};
+
+    #[actix_web::test]
+    async fn test_requests_are_labelled_by_route() {
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(track_request))
+                .configure(configure_routes)
+                .route(
+                    "/api/users/{user_id}",
+                    web::get().to(|| async { HttpResponse::NotFound().finish() }),
+                ),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/api/users/0196a0a4-1c37-7000-8000-000000000000")
+            .to_request();
+        test::call_service(&app, req).await;
+
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let text = std::str::from_utf8(&body).unwrap();
+        assert!(text.contains(
+            "http_requests_total{method=\"GET\",route=\"/api/users/{user_id}\",status=\"404\"} 1"
+        ));
+        assert!(!text.contains("0196a0a4"));
+    }
+}
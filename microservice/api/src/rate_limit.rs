@@ -0,0 +1,781 @@
+//! Token-bucket rate limiting, with a policy per route.
+//!
+//! Each [`Policy`] gives every client, sender or recipient its own bucket of
+//! `burst` tokens, refilled one at a time every `refill_every`. A request
+//! takes a token from each bucket that applies to it, and is refused with a
+//! 429 and a `Retry-After` header if one is empty. Buckets keyed by what the
+//! body claims are only charged once the handler has accepted the request,
+//! so nobody can drain another user's bucket with requests that fail.
+//!
+//! Clients are told apart by the address their connection comes from. Behind
+//! a proxy that is the proxy's, and every client shares its buckets, unless
+//! the proxy is one of the limiter's trusted proxies; see
+//! [`RateLimiter::with_trusted_proxies`].
+//!
+//! Buckets live in a [`RateLimitStore`]. [`InMemoryRateLimitStore`] keeps
+//! them in the process, so with several instances each enforces its own
+//! limits; a store shared between instances makes them global.
+
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::Method,
+    middleware::Next,
+    web::{Bytes, Data},
+    Error,
+};
+use async_trait::async_trait;
+use db::uuid::Uuid;
+use serde::Deserialize;
+use shared::{errors::AppError, metrics::RATE_LIMITED_REQUESTS};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// A bucket's size and how fast it refills.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    /// Requests allowed at once, from a full bucket
+    pub burst: u32,
+    /// Time for one token to come back
+    pub refill_every: Duration,
+}
+
+impl Quota {
+    pub const fn new(burst: u32, refill_every: Duration) -> Self {
+        Quota { burst, refill_every }
+    }
+}
+
+/// What a policy gives each bucket to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitBy {
+    /// The address the connection comes from, or for connections from a
+    /// trusted proxy, the client's address it forwarded. Charged before the
+    /// handler runs.
+    Ip,
+    /// `sender_id` in the JSON body. Checked before the handler runs, but
+    /// only charged if it succeeds.
+    Sender,
+    /// `recipient_id` in the JSON body. Checked before the handler runs, but
+    /// only charged if it succeeds.
+    Recipient,
+}
+
+/// A limit on requests to one route.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Policy {
+    /// Labels the policy in metrics. Policies with the same name share
+    /// buckets.
+    pub name: &'static str,
+    pub method: Method,
+    /// The route pattern, as it is registered
+    pub route: &'static str,
+    pub limit_by: LimitBy,
+    pub quota: Quota,
+}
+
+/// The policies the server runs with. The per-IP quotas are sized for one
+/// client each; behind a proxy, it must be trusted for them to be.
+pub fn default_policies() -> Vec<Policy> {
+    let messages_per_recipient = Quota::new(60, Duration::from_secs(1));
+    // Backstop for requests the sender and recipient buckets don't charge
+    let messages_per_ip = Quota::new(300, Duration::from_millis(50));
+    vec![
+        Policy {
+            name: "registration_per_ip",
+            method: Method::POST,
+            route: "/api/users",
+            limit_by: LimitBy::Ip,
+            quota: Quota::new(5, Duration::from_secs(60)),
+        },
+        Policy {
+            name: "messages_per_ip",
+            method: Method::POST,
+            route: "/api/messages",
+            limit_by: LimitBy::Ip,
+            quota: messages_per_ip,
+        },
+        Policy {
+            name: "messages_per_ip",
+            method: Method::POST,
+            route: "/api/messages/anonymous",
+            limit_by: LimitBy::Ip,
+            quota: messages_per_ip,
+        },
+        Policy {
+            name: "messages_per_sender",
+            method: Method::POST,
+            route: "/api/messages",
+            limit_by: LimitBy::Sender,
+            quota: Quota::new(30, Duration::from_secs(2)),
+        },
+        Policy {
+            name: "messages_per_recipient",
+            method: Method::POST,
+            route: "/api/messages",
+            limit_by: LimitBy::Recipient,
+            quota: messages_per_recipient,
+        },
+        Policy {
+            name: "messages_per_recipient",
+            method: Method::POST,
+            route: "/api/messages/anonymous",
+            limit_by: LimitBy::Recipient,
+            quota: messages_per_recipient,
+        },
+        // Each bundle hands out one of the user's one-time prekeys
+        Policy {
+            name: "prekey_bundles_per_ip",
+            method: Method::GET,
+            route: "/api/users/{user_id}/prekey-bundle",
+            limit_by: LimitBy::Ip,
+            quota: Quota::new(20, Duration::from_secs(3)),
+        },
+        Policy {
+            name: "key_backup_challenges_per_ip",
+            method: Method::POST,
+            route: "/api/users/{user_id}/key-backup/challenge",
+            limit_by: LimitBy::Ip,
+            quota: Quota::new(10, Duration::from_secs(30)),
+        },
+        Policy {
+            name: "key_backup_recoveries_per_ip",
+            method: Method::POST,
+            route: "/api/users/{user_id}/key-backup/recover",
+            limit_by: LimitBy::Ip,
+            quota: Quota::new(10, Duration::from_secs(30)),
+        },
+        Policy {
+            name: "token_validation_per_ip",
+            method: Method::POST,
+            route: "/api/tokens/validate",
+            limit_by: LimitBy::Ip,
+            quota: Quota::new(60, Duration::from_millis(500)),
+        },
+    ]
+}
+
+/// Where buckets are kept.
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Takes a token from the bucket at `key`, which starts out full.
+    /// Returns `None` if there was one, or else how long until there is.
+    async fn take(&self, key: &str, quota: Quota) -> Result<Option<Duration>, AppError>;
+
+    /// Like [`take`](Self::take), but leaves the token in the bucket.
+    async fn check(&self, key: &str, quota: Quota) -> Result<Option<Duration>, AppError>;
+
+    /// Takes a token from the bucket at `key` even if it is empty, for a
+    /// request that was let through on [`check`](Self::check). The bucket
+    /// then owes the token, so requests let through together are still all
+    /// paid for.
+    async fn charge(&self, key: &str, quota: Quota) -> Result<(), AppError>;
+}
+
+/// What [`InMemoryRateLimitStore::update_at`] does with a bucket's token.
+#[derive(Clone, Copy)]
+enum Use {
+    /// Takes it if there is one
+    Take,
+    /// Leaves it
+    Check,
+    /// Takes it, going into debt if there isn't one
+    Charge,
+}
+
+/// Buckets in process memory. Full buckets are forgotten from time to time,
+/// since they are the same as new ones.
+#[derive(Default)]
+pub struct InMemoryRateLimitStore {
+    buckets: Mutex<Buckets>,
+}
+
+#[derive(Default)]
+struct Buckets {
+    by_key: HashMap<String, Bucket>,
+    /// How many buckets there may be before full ones are swept
+    sweep_at: usize,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+    /// When the bucket will be full again
+    full_at: Instant,
+}
+
+/// Fewest buckets worth sweeping.
+const MIN_SWEEP: usize = 10_000;
+
+impl InMemoryRateLimitStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn take_at(&self, key: &str, quota: Quota, now: Instant) -> Option<Duration> {
+        self.update_at(key, quota, now, Use::Take)
+    }
+
+    fn update_at(&self, key: &str, quota: Quota, now: Instant, token: Use) -> Option<Duration> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.by_key.len() >= buckets.sweep_at {
+            buckets.by_key.retain(|_, bucket| bucket.full_at > now);
+            buckets.sweep_at = (buckets.by_key.len() * 2).max(MIN_SWEEP);
+        }
+
+        let burst = f64::from(quota.burst);
+        let refill_every = quota.refill_every.as_secs_f64();
+        let bucket = buckets.by_key.entry(key.to_string()).or_insert(Bucket {
+            tokens: burst,
+            refilled_at: now,
+            full_at: now,
+        });
+
+        let refilled = now.saturating_duration_since(bucket.refilled_at).as_secs_f64() / refill_every;
+        bucket.tokens = (bucket.tokens + refilled).min(burst);
+        bucket.refilled_at = now;
+        let retry_after = if bucket.tokens >= 1.0 {
+            None
+        } else {
+            Some(quota.refill_every.mul_f64(1.0 - bucket.tokens))
+        };
+        match token {
+            Use::Take if retry_after.is_none() => bucket.tokens -= 1.0,
+            Use::Charge => bucket.tokens -= 1.0,
+            Use::Take | Use::Check => {}
+        }
+        bucket.full_at = now + quota.refill_every.mul_f64(burst - bucket.tokens);
+        retry_after
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for InMemoryRateLimitStore {
+    async fn take(&self, key: &str, quota: Quota) -> Result<Option<Duration>, AppError> {
+        Ok(self.take_at(key, quota, Instant::now()))
+    }
+
+    async fn check(&self, key: &str, quota: Quota) -> Result<Option<Duration>, AppError> {
+        Ok(self.update_at(key, quota, Instant::now(), Use::Check))
+    }
+
+    async fn charge(&self, key: &str, quota: Quota) -> Result<(), AppError> {
+        self.update_at(key, quota, Instant::now(), Use::Charge);
+        Ok(())
+    }
+}
+
+/// The policies to enforce and the store their buckets are in. Register one
+/// as app data, created once so that every worker shares it.
+pub struct RateLimiter {
+    store: Arc<dyn RateLimitStore>,
+    policies: Vec<Policy>,
+    trusted_proxies: Vec<IpAddr>,
+}
+
+impl RateLimiter {
+    pub fn new(store: impl RateLimitStore + 'static) -> Self {
+        RateLimiter {
+            store: Arc::new(store),
+            policies: Vec::new(),
+            trusted_proxies: Vec::new(),
+        }
+    }
+
+    pub fn with_policies(mut self, policies: impl IntoIterator<Item = Policy>) -> Self {
+        self.policies.extend(policies);
+        self
+    }
+
+    /// Proxies whose connections are limited by the client address they
+    /// forward, in `Forwarded` or `X-Forwarded-For`, rather than their own.
+    /// They must replace those headers rather than add to them, or clients
+    /// can pick their own address.
+    pub fn with_trusted_proxies(mut self, proxies: impl IntoIterator<Item = IpAddr>) -> Self {
+        self.trusted_proxies.extend(proxies);
+        self
+    }
+
+    /// The address `req` is limited by, if it has one.
+    fn client_ip(&self, req: &ServiceRequest) -> Option<String> {
+        let peer = req.peer_addr()?.ip();
+        if !self.trusted_proxies.contains(&peer) {
+            return Some(peer.to_string());
+        }
+        let forwarded = req.connection_info().realip_remote_addr()?.to_string();
+        // Forwarded addresses may come with a port
+        let client = forwarded
+            .parse::<IpAddr>()
+            .or_else(|_| forwarded.parse::<SocketAddr>().map(|addr| addr.ip()))
+            .map_or(forwarded, |ip| ip.to_string());
+        Some(client)
+    }
+}
+
+/// The fields of a message request that policies can limit by.
+#[derive(Deserialize)]
+struct MessageParties {
+    sender_id: Option<Uuid>,
+    recipient_id: Option<Uuid>,
+}
+
+/// Middleware enforcing the app's [`RateLimiter`], if it has one; wrap the app
+/// in it with `actix_web::middleware::from_fn(rate_limit::limit_requests)`.
+///
+/// Requests a policy can't key, such as a message without a readable
+/// `sender_id`, are let through for the handler to reject. So are requests
+/// the store fails on, since refusing them would make the store an outage.
+/// Sender and recipient buckets are charged once the handler has responded
+/// with a success, as the ids are only checked there.
+pub async fn limit_requests(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let Some(limiter) = req.app_data::<Data<RateLimiter>>().cloned() else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+    let route = req.match_pattern();
+    let policies: Vec<&Policy> = limiter
+        .policies
+        .iter()
+        .filter(|policy| policy.method == req.method() && route.as_deref() == Some(policy.route))
+        .collect();
+    if policies.is_empty() {
+        return Ok(next.call(req).await?.map_into_left_body());
+    }
+
+    // Reading the body consumes it, so it is put back for the handler
+    let parties = if policies.iter().any(|policy| policy.limit_by != LimitBy::Ip) {
+        let body = match req.extract::<Bytes>().await {
+            Ok(body) => body,
+            Err(err) => return Ok(req.error_response(err).map_into_right_body()),
+        };
+        req.set_payload(body.clone().into());
+        serde_json::from_slice::<MessageParties>(&body).ok()
+    } else {
+        None
+    };
+
+    let mut charges = Vec::new();
+    for policy in policies {
+        let subject = match policy.limit_by {
+            LimitBy::Ip => limiter.client_ip(&req),
+            LimitBy::Sender => parties.as_ref().and_then(|p| p.sender_id).map(|id| id.to_string()),
+            LimitBy::Recipient => parties.as_ref().and_then(|p| p.recipient_id).map(|id| id.to_string()),
+        };
+        let Some(subject) = subject else {
+            continue;
+        };
+
+        let key = format!("{}:{subject}", policy.name);
+        let available = if policy.limit_by == LimitBy::Ip {
+            limiter.store.take(&key, policy.quota).await
+        } else {
+            limiter.store.check(&key, policy.quota).await
+        };
+        match available {
+            Ok(None) => {}
+            Ok(Some(retry_after)) => {
+                RATE_LIMITED_REQUESTS.with_label_values(&[policy.name]).inc();
+                // Answered here, while the request id is still in scope
+                let err = AppError::RateLimited(retry_after);
+                return Ok(req.error_response(err).map_into_right_body());
+            }
+            Err(err) => {
+                tracing::error!(policy = policy.name, error = %err, "rate limit store failed");
+            }
+        }
+        if policy.limit_by != LimitBy::Ip {
+            charges.push((key, policy));
+        }
+    }
+
+    let res = next.call(req).await?;
+    if res.status().is_success() {
+        for (key, policy) in charges {
+            if let Err(err) = limiter.store.charge(&key, policy.quota).await {
+                tracing::error!(policy = policy.name, error = %err, "rate limit store failed");
+            }
+        }
+    }
+    Ok(res.map_into_left_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{
+        http::{header, StatusCode},
+        middleware::from_fn,
+        test, web, App, HttpResponse,
+    };
+    use crate::{
+        request_id,
+        user::{self, UserController, UserControllerImpl},
+    };
+    use db::models::OneTimePrekey;
+    use service::{
+        memory::InMemoryStore,
+        user::{UserRepository, UserService},
+    };
+    use shared::errors::{ErrorCode, ErrorResponse};
+    use std::net::SocketAddr;
+
+    const PER_SECOND: Quota = Quota::new(2, Duration::from_secs(1));
+
+    #[actix_web::test]
+    async fn test_buckets_refill_one_token_at_a_time() {
+        let store = InMemoryRateLimitStore::new();
+        let start = Instant::now();
+
+        assert_eq!(store.take_at("a", PER_SECOND, start), None);
+        assert_eq!(store.take_at("a", PER_SECOND, start), None);
+        assert_eq!(store.take_at("a", PER_SECOND, start), Some(Duration::from_secs(1)));
+        assert_eq!(store.take_at("b", PER_SECOND, start), None);
+
+        let later = start + Duration::from_millis(250);
+        assert_eq!(store.take_at("a", PER_SECOND, later), Some(Duration::from_millis(750)));
+        let later = start + Duration::from_secs(1);
+        assert_eq!(store.take_at("a", PER_SECOND, later), None);
+        assert!(store.take_at("a", PER_SECOND, later).is_some());
+
+        // Never more than a full bucket, however long it has been
+        let much_later = start + Duration::from_secs(60);
+        assert_eq!(store.take_at("a", PER_SECOND, much_later), None);
+        assert_eq!(store.take_at("a", PER_SECOND, much_later), None);
+        assert!(store.take_at("a", PER_SECOND, much_later).is_some());
+    }
+
+    #[actix_web::test]
+    async fn test_checks_leave_tokens_and_charges_go_into_debt() {
+        let store = InMemoryRateLimitStore::new();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(store.update_at("a", PER_SECOND, start, Use::Check), None);
+        }
+        for _ in 0..3 {
+            store.update_at("a", PER_SECOND, start, Use::Charge);
+        }
+        // A token is owed, so two have to come back
+        assert_eq!(
+            store.update_at("a", PER_SECOND, start, Use::Check),
+            Some(Duration::from_secs(2))
+        );
+        assert!(store.take_at("a", PER_SECOND, start + Duration::from_secs(1)).is_some());
+        assert_eq!(store.take_at("a", PER_SECOND, start + Duration::from_secs(2)), None);
+    }
+
+    #[actix_web::test]
+    async fn test_full_buckets_are_swept() {
+        let store = InMemoryRateLimitStore::new();
+        let start = Instant::now();
+        store.take_at("empty", PER_SECOND, start);
+        store.take_at("empty", PER_SECOND, start);
+        for i in 0..MIN_SWEEP - 1 {
+            store.take_at(&i.to_string(), PER_SECOND, start);
+        }
+
+        store.take_at("new", PER_SECOND, start + Duration::from_secs(1));
+        let buckets = store.buckets.lock().unwrap();
+        assert_eq!(buckets.by_key.len(), 2);
+        assert!(buckets.by_key.contains_key("empty"));
+    }
+
+    fn limiter(policy: Policy) -> Data<RateLimiter> {
+        Data::new(RateLimiter::new(InMemoryRateLimitStore::new()).with_policies([policy]))
+    }
+
+    fn from(ip: &str) -> SocketAddr {
+        format!("{ip}:40000").parse().unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_clients_are_limited_by_ip() {
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(limit_requests))
+                .wrap(from_fn(request_id::trace_request))
+                .app_data(limiter(Policy {
+                    name: "registration_per_ip",
+                    method: Method::POST,
+                    route: "/api/users",
+                    limit_by: LimitBy::Ip,
+                    quota: PER_SECOND,
+                }))
+                .route("/api/users", web::post().to(HttpResponse::Created))
+                .route("/api/users", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for _ in 0..2 {
+            let req = test::TestRequest::post().uri("/api/users").peer_addr(from("10.0.0.1"));
+            let res = test::call_service(&app, req.to_request()).await;
+            assert_eq!(res.status(), StatusCode::CREATED);
+        }
+
+        let req = test::TestRequest::post().uri("/api/users").peer_addr(from("10.0.0.1"));
+        let res = test::call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get(header::RETRY_AFTER).unwrap(), "1");
+        let body: ErrorResponse = test::read_body_json(res).await;
+        assert_eq!(body.code, ErrorCode::RateLimited);
+        assert!(body.request_id.is_some());
+
+        // Other clients, and other methods, are not affected
+        let req = test::TestRequest::post().uri("/api/users").peer_addr(from("10.0.0.2"));
+        assert_eq!(test::call_service(&app, req.to_request()).await.status(), StatusCode::CREATED);
+        let req = test::TestRequest::get().uri("/api/users").peer_addr(from("10.0.0.1"));
+        assert_eq!(test::call_service(&app, req.to_request()).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_trusted_proxies_are_limited_by_the_client_they_forward() {
+        let limiter = RateLimiter::new(InMemoryRateLimitStore::new())
+            .with_policies([Policy {
+                name: "registration_per_ip",
+                method: Method::POST,
+                route: "/api/users",
+                limit_by: LimitBy::Ip,
+                quota: PER_SECOND,
+            }])
+            .with_trusted_proxies(["10.0.0.9".parse().unwrap()]);
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(limit_requests))
+                .app_data(Data::new(limiter))
+                .route("/api/users", web::post().to(HttpResponse::Created)),
+        )
+        .await;
+        let request = |peer, client| {
+            test::TestRequest::post()
+                .uri("/api/users")
+                .peer_addr(from(peer))
+                .insert_header(("X-Forwarded-For", client))
+                .to_request()
+        };
+
+        for _ in 0..2 {
+            let res = test::call_service(&app, request("10.0.0.9", "203.0.113.1")).await;
+            assert_eq!(res.status(), StatusCode::CREATED);
+        }
+        let res = test::call_service(&app, request("10.0.0.9", "203.0.113.1")).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        let res = test::call_service(&app, request("10.0.0.9", "203.0.113.2:4000")).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        // Anyone else's forwarding headers are ignored
+        for _ in 0..2 {
+            let res = test::call_service(&app, request("10.0.0.1", "203.0.113.3")).await;
+            assert_eq!(res.status(), StatusCode::CREATED);
+        }
+        let res = test::call_service(&app, request("10.0.0.1", "203.0.113.4")).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[actix_web::test]
+    async fn test_key_backup_recovery_is_limited_by_ip() {
+        let service = UserService::new(InMemoryStore::new());
+        let controller: Arc<dyn UserController> =
+            Arc::new(UserControllerImpl::new(Data::new(service)));
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(limit_requests))
+                .app_data(Data::new(
+                    RateLimiter::new(InMemoryRateLimitStore::new()).with_policies(default_policies()),
+                ))
+                .app_data(Data::new(controller))
+                .configure(user::configure_routes),
+        )
+        .await;
+
+        let user_id = Uuid::now_v7();
+        let recover = serde_json::json!({ "challenge": "", "signature": "" });
+        for action in ["challenge", "recover"] {
+            let uri = format!("/api/users/{user_id}/key-backup/{action}");
+            let request = |ip| {
+                test::TestRequest::post()
+                    .uri(&uri)
+                    .peer_addr(from(ip))
+                    .set_json(&recover)
+                    .to_request()
+            };
+
+            // Refused by the handler, but counted all the same
+            for _ in 0..10 {
+                let res = test::call_service(&app, request("10.0.0.1")).await;
+                assert_ne!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+            }
+            let res = test::call_service(&app, request("10.0.0.1")).await;
+            assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+            let res = test::call_service(&app, request("10.0.0.2")).await;
+            assert_ne!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_prekey_bundles_are_limited_by_ip() {
+        let store = InMemoryStore::new();
+        let user_id = store.insert_user(&"A".repeat(44), "alice").await.unwrap();
+        store
+            .insert_one_time_prekeys(
+                user_id,
+                &(0..25)
+                    .map(|prekey_id| OneTimePrekey {
+                        prekey_id,
+                        prekey: format!("prekey-{prekey_id}"),
+                        signature: String::from("signature"),
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .await
+            .unwrap();
+        store
+            .upsert_signed_prekey(user_id, "identity", 1, "prekey", "signature")
+            .await
+            .unwrap();
+
+        let service = UserService::new(store.clone());
+        let controller: Arc<dyn UserController> =
+            Arc::new(UserControllerImpl::new(Data::new(service)));
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(limit_requests))
+                .app_data(Data::new(
+                    RateLimiter::new(InMemoryRateLimitStore::new()).with_policies(default_policies()),
+                ))
+                .app_data(Data::new(controller))
+                .configure(user::configure_routes),
+        )
+        .await;
+
+        let uri = format!("/api/users/{user_id}/prekey-bundle");
+        let request = |ip| test::TestRequest::get().uri(&uri).peer_addr(from(ip)).to_request();
+        for _ in 0..20 {
+            let res = test::call_service(&app, request("10.0.0.1")).await;
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        let res = test::call_service(&app, request("10.0.0.1")).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // The refused request didn't claim a prekey
+        assert_eq!(store.count_one_time_prekeys(user_id).await.unwrap(), 5);
+        let res = test::call_service(&app, request("10.0.0.2")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_messages_are_limited_by_recipient() {
+        async fn echo(body: web::Json<serde_json::Value>) -> HttpResponse {
+            HttpResponse::Created().json(body.into_inner())
+        }
+
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(limit_requests))
+                .app_data(limiter(Policy {
+                    name: "messages_per_recipient",
+                    method: Method::POST,
+                    route: "/api/messages",
+                    limit_by: LimitBy::Recipient,
+                    quota: PER_SECOND,
+                }))
+                .route("/api/messages", web::post().to(echo)),
+        )
+        .await;
+
+        let message = |recipient_id: Uuid| {
+            serde_json::json!({
+                "sender_id": Uuid::now_v7().simple().to_string(),
+                "recipient_id": recipient_id.simple().to_string(),
+                "encrypted_content": "aGVsbG8=",
+            })
+        };
+        let recipient = Uuid::now_v7();
+        for _ in 0..2 {
+            let sent = message(recipient);
+            let req = test::TestRequest::post().uri("/api/messages").set_json(&sent);
+            let res = test::call_service(&app, req.to_request()).await;
+            assert_eq!(res.status(), StatusCode::CREATED);
+            // The handler still gets the whole body
+            let received: serde_json::Value = test::read_body_json(res).await;
+            assert_eq!(received, sent);
+        }
+
+        let req = test::TestRequest::post().uri("/api/messages").set_json(message(recipient));
+        let res = test::call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let req = test::TestRequest::post().uri("/api/messages").set_json(message(Uuid::now_v7()));
+        let res = test::call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+    }
+
+    #[actix_web::test]
+    async fn test_refused_messages_are_not_charged_to_the_recipient() {
+        async fn send(body: web::Json<serde_json::Value>) -> HttpResponse {
+            if body["signature"] == "valid" {
+                HttpResponse::Created().finish()
+            } else {
+                HttpResponse::BadRequest().finish()
+            }
+        }
+
+        let limiter = RateLimiter::new(InMemoryRateLimitStore::new()).with_policies([
+            Policy {
+                name: "messages_per_ip",
+                method: Method::POST,
+                route: "/api/messages",
+                limit_by: LimitBy::Ip,
+                quota: Quota::new(5, Duration::from_secs(1)),
+            },
+            Policy {
+                name: "messages_per_recipient",
+                method: Method::POST,
+                route: "/api/messages",
+                limit_by: LimitBy::Recipient,
+                quota: PER_SECOND,
+            },
+        ]);
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(limit_requests))
+                .app_data(Data::new(limiter))
+                .route("/api/messages", web::post().to(send)),
+        )
+        .await;
+
+        let victim = Uuid::now_v7();
+        let request = |ip, signature| {
+            test::TestRequest::post()
+                .uri("/api/messages")
+                .peer_addr(from(ip))
+                .set_json(serde_json::json!({
+                    "sender_id": Uuid::now_v7(),
+                    "recipient_id": victim,
+                    "signature": signature,
+                }))
+                .to_request()
+        };
+
+        // Failed requests only count against the client sending them
+        for _ in 0..5 {
+            let res = test::call_service(&app, request("10.0.0.1", "forged")).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        }
+        let res = test::call_service(&app, request("10.0.0.1", "forged")).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        for _ in 0..2 {
+            let res = test::call_service(&app, request("10.0.0.2", "valid")).await;
+            assert_eq!(res.status(), StatusCode::CREATED);
+        }
+        let res = test::call_service(&app, request("10.0.0.3", "valid")).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+}
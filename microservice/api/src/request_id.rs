@@ -0,0 +1,135 @@
+//! Request ids, and the tracing span each request is handled in.
+//!
+//! Every request gets an id: the one in its `X-Request-Id` header if that is
+//! usable, otherwise a fresh one. The id is sent back in the response's
+//! `X-Request-Id` header and in error bodies, and tags every log line written
+//! while handling the request, down to the SQL queries.
+//!
+//! Only the method, the matched route pattern and the status are logged for
+//! the request itself. Raw paths carry user ids, and query strings, headers
+//! and bodies carry tokens, keys and ciphertexts, so none of those are.
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    middleware::Next,
+    Error,
+};
+use db::uuid::Uuid;
+use shared::errors::REQUEST_ID;
+use std::time::Instant;
+use tracing::{field, info_span, Instrument};
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest id accepted from a client.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Middleware giving each request an id and a span; wrap the app in it with
+/// `actix_web::middleware::from_fn(request_id::trace_request)`.
+pub async fn trace_request(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let request_id = req
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| is_valid(id))
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::now_v7().to_string());
+
+    let span = info_span!(
+        "http_request",
+        request_id = %request_id,
+        method = %req.method(),
+        route = req.match_pattern().as_deref().unwrap_or("unmatched"),
+        status = field::Empty,
+        latency_ms = field::Empty,
+    );
+
+    let start = Instant::now();
+    let result = REQUEST_ID
+        .scope(request_id.clone(), next.call(req))
+        .instrument(span.clone())
+        .await;
+    let status = match &result {
+        Ok(res) => res.status(),
+        Err(err) => err.as_response_error().status_code(),
+    };
+    span.record("status", status.as_u16());
+    span.record("latency_ms", start.elapsed().as_millis() as u64);
+    span.in_scope(|| tracing::info!("request completed"));
+
+    let mut res = result?;
+    // Only ids made of header-safe characters are accepted or generated
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        res.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    Ok(res)
+}
+
+/// Client ids are echoed into logs and headers, so only short ids made of
+/// unreserved characters are taken.
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{middleware::from_fn, test, web, App, HttpResponse};
+    use shared::errors::{AppError, ErrorResponse};
+
+    async fn failing() -> Result<HttpResponse, AppError> {
+        Err(AppError::NotFound(String::from("User not found")))
+    }
+
+    #[actix_web::test]
+    async fn test_request_id_is_propagated() {
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(trace_request))
+                .route("/users/{id}", web::get().to(failing)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/users/1")
+            .insert_header((REQUEST_ID_HEADER, "client-id.1"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.headers().get(&REQUEST_ID_HEADER).unwrap(), "client-id.1");
+
+        let body: ErrorResponse = test::read_body_json(res).await;
+        assert_eq!(body.request_id.as_deref(), Some("client-id.1"));
+    }
+
+    #[actix_web::test]
+    async fn test_unusable_request_ids_are_replaced() {
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(trace_request))
+                .route("/users/{id}", web::get().to(failing)),
+        )
+        .await;
+
+        for sent in [None, Some("has spaces"), Some("x".repeat(MAX_REQUEST_ID_LEN + 1).as_str())] {
+            let mut req = test::TestRequest::get().uri("/users/1");
+            if let Some(sent) = sent {
+                req = req.insert_header((REQUEST_ID_HEADER, sent));
+            }
+            let res = test::call_service(&app, req.to_request()).await;
+            let header = res.headers().get(&REQUEST_ID_HEADER).unwrap().to_str().unwrap().to_string();
+            assert!(Uuid::parse_str(&header).is_ok());
+
+            let body: ErrorResponse = test::read_body_json(res).await;
+            assert_eq!(body.request_id, Some(header));
+        }
+    }
+}
@@ -1,7 +1,10 @@
 use actix_web::{web, HttpResponse, Responder};
 use db::uuid::Uuid;
 use serde::{Deserialize, Serialize};
-use shared::errors::AppError;
+use shared::{
+    errors::{AppError, ErrorResponse},
+    metrics::TOKEN_VALIDATIONS,
+};
 use utoipa::{IntoParams, ToSchema};
 use utoipa::OpenApi;
 use service::token::repository::TokenRepository;
@@ -88,15 +91,25 @@ impl Default for JwtConfig {
 static DECODING_KEY: OnceLock<DecodingKey> = OnceLock::new();
 
 pub fn get_decoding_key() -> &'static DecodingKey {
-    DECODING_KEY.get_or_init(|| {
-        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-        path.push("../public_key.pem");
-
-        let public_key = fs::read_to_string(&path)
-            .unwrap_or_else(|_| panic!("Failed to read public key file: {:?}", path));
-        DecodingKey::from_rsa_pem(public_key.as_bytes())
-            .expect("Failed to create decoding key")
-    })
+    load_decoding_key().unwrap_or_else(|e| panic!("{e}"))
+}
+
+/// The key refresh tokens are verified with, read from `public_key.pem` the
+/// first time it is needed. Unlike [`get_decoding_key`], failing to load it is
+/// an error rather than a panic, and the next call tries again.
+pub fn load_decoding_key() -> Result<&'static DecodingKey, String> {
+    if let Some(key) = DECODING_KEY.get() {
+        return Ok(key);
+    }
+
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("../public_key.pem");
+
+    let public_key = fs::read_to_string(&path)
+        .map_err(|e| format!("Failed to read public key file {path:?}: {e}"))?;
+    let key = DecodingKey::from_rsa_pem(public_key.as_bytes())
+        .map_err(|e| format!("Failed to create decoding key: {e}"))?;
+    Ok(DECODING_KEY.get_or_init(|| key))
 }
 
 /// Verifies JWT token and extracts claims
@@ -193,34 +206,41 @@ impl<R: TokenRepository + 'static> TokenController for TokenControllerImpl<R> {
     }
 
     async fn validate_token(&self, req: ValidateTokenRequest) -> Result<bool, AppError> {
-        // First verify JWT structure and signature
-        let jwt_validation = verify_jwt(&req.jwt_token, &self.jwt_config);
-        
-        match jwt_validation {
-            // If JWT is valid, check if it's in the database and not revoked
-            Ok(token_data) => {
-                let user_id = Uuid::parse_str(&token_data.claims.sub)
-                    .map_err(|_| {
-                        let mut errors = ValidationErrors::new();
-
-                        let mut error = ValidationError::new("invalid_user_id");
-                        error.message = Some("Invalid user ID in JWT".into());
-
-                        errors.add("user_id", error);
-
-                        AppError::ValidationError(errors)
-                    })?;
-                    
-                let token_hash = self.hash_token(&req.jwt_token);
-                
-                // Check if token is in repository and not revoked
-                self.service
-                    .validate_refresh_token(user_id, &token_hash)
-                    .await
-            },
-            // If JWT is invalid, return false without checking database
-            Err(_) => Ok(false),
-        }
+        // First verify JWT structure and signature. If the JWT is invalid,
+        // return false without checking the database
+        let Ok(token_data) = verify_jwt(&req.jwt_token, &self.jwt_config) else {
+            TOKEN_VALIDATIONS.with_label_values(&["invalid_jwt"]).inc();
+            return Ok(false);
+        };
+
+        let user_id = Uuid::parse_str(&token_data.claims.sub)
+            .map_err(|_| {
+                TOKEN_VALIDATIONS.with_label_values(&["invalid_jwt"]).inc();
+
+                let mut errors = ValidationErrors::new();
+
+                let mut error = ValidationError::new("invalid_user_id");
+                error.message = Some("Invalid user ID in JWT".into());
+
+                errors.add("user_id", error);
+
+                AppError::ValidationError(errors)
+            })?;
+
+        let token_hash = self.hash_token(&req.jwt_token);
+
+        // Check if token is in repository and not revoked
+        let result = self
+            .service
+            .validate_refresh_token(user_id, &token_hash)
+            .await;
+        let outcome = match result {
+            Ok(true) => "valid",
+            Ok(false) => "unknown",
+            Err(_) => "error",
+        };
+        TOKEN_VALIDATIONS.with_label_values(&[outcome]).inc();
+        result
     }
 
     async fn revoke_token(&self, req: RevokeTokenRequest) -> Result<(), AppError> {
@@ -245,8 +265,8 @@ fn validate_request<T: Validate>(req: &T) -> Result<(), AppError> {
     request_body = StoreTokenRequest,
     responses(
         (status = 201, description = "Token stored successfully"),
-        (status = 400, description = "Invalid request"),
-        (status = 500, description = "Internal server error")
+        (status = 400, description = "Invalid request", body = ErrorResponse),
+        (status = 500, description = "Internal server error", body = ErrorResponse)
     ),
     tag = "tokens"
 )]
@@ -268,8 +288,9 @@ async fn store_token_handler<R: TokenRepository + 'static>(
     request_body = ValidateTokenRequest,
     responses(
         (status = 200, description = "Token validated", body = ValidateTokenResponse),
-        (status = 400, description = "Invalid request"),
-        (status = 500, description = "Internal server error")
+        (status = 400, description = "Invalid request", body = ErrorResponse),
+        (status = 429, description = "Rate limited; retry after `Retry-After` seconds", body = ErrorResponse),
+        (status = 500, description = "Internal server error", body = ErrorResponse)
     ),
     tag = "tokens"
 )]
@@ -291,8 +312,8 @@ async fn validate_token_handler<R: TokenRepository + 'static>(
     request_body = RevokeTokenRequest,
     responses(
         (status = 200, description = "Token revoked successfully"),
-        (status = 400, description = "Invalid request"),
-        (status = 500, description = "Internal server error")
+        (status = 400, description = "Invalid request", body = ErrorResponse),
+        (status = 500, description = "Internal server error", body = ErrorResponse)
     ),
     tag = "tokens"
 )]
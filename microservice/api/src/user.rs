@@ -4,15 +4,23 @@ use db::{
     uuid::{self, Uuid},
 };
 use mockall::automock;
-use service::user::{UserRepository, UserService};
+use service::{
+    unit_of_work::UnitOfWork,
+    user::{UserRepository, UserService},
+};
 use shared::{
-    errors::AppError,
-    models::{RegisterRequest, RegisterResponse, UpdateUserRequest},
+    errors::{AppError, ErrorResponse},
+    models::{
+        ContactVerificationResponse, KeyBackupChallengeResponse, KeyBackupResponse,
+        MembershipTreeResponse, PrekeyBundleResponse, PrekeysResponse, RecoverKeyBackupRequest,
+        RegisterRequest, RegisterResponse, UploadKeyBackupRequest, SafetyNumberResponse, UpdateUserRequest, UploadPrekeysRequest,
+        VerifyContactRequest,
+    },
 };
 use std::sync::Arc;
 use serde::{Deserialize};
 use actix_web::{
-    delete, get, patch, post,
+    delete, get, patch, post, put,
     web::{self, Data, Json, Path, Query},
     HttpResponse, Responder,
 };
@@ -24,6 +32,16 @@ pub type GetUserResponse = Result<HttpResponse, AppError>;
 pub type GetUsersResponse = Result<HttpResponse, AppError>;
 pub type UpdateUserResponse = Result<HttpResponse, AppError>;
 pub type DeleteUserResponse = Result<HttpResponse, AppError>;
+pub type GetSafetyNumberResponse = Result<HttpResponse, AppError>;
+pub type VerifyContactResponse = Result<HttpResponse, AppError>;
+pub type GetContactVerificationResponse = Result<HttpResponse, AppError>;
+pub type GetMembershipTreeResponse = Result<HttpResponse, AppError>;
+pub type UploadPrekeysResponse = Result<HttpResponse, AppError>;
+pub type GetPrekeysResponse = Result<HttpResponse, AppError>;
+pub type GetPrekeyBundleResponse = Result<HttpResponse, AppError>;
+pub type UploadKeyBackupResponse = Result<HttpResponse, AppError>;
+pub type CreateKeyBackupChallengeResponse = Result<HttpResponse, AppError>;
+pub type RecoverKeyBackupResponse = Result<HttpResponse, AppError>;
 
 #[derive(Deserialize)]
 struct GetUsersQuery {
@@ -45,6 +63,54 @@ pub trait UserController: Send + Sync {
         request: Json<UpdateUserRequest>,
     ) -> UpdateUserResponse;
 
+    async fn get_safety_number(
+        &self,
+        user_id: Path<Uuid>,
+        contact_id: Path<Uuid>,
+    ) -> GetSafetyNumberResponse;
+
+    async fn verify_contact(
+        &self,
+        user_id: Path<Uuid>,
+        contact_id: Path<Uuid>,
+        request: Json<VerifyContactRequest>,
+    ) -> VerifyContactResponse;
+
+    async fn get_contact_verification(
+        &self,
+        user_id: Path<Uuid>,
+        contact_id: Path<Uuid>,
+    ) -> GetContactVerificationResponse;
+
+    async fn get_membership_tree(&self) -> GetMembershipTreeResponse;
+
+    async fn upload_prekeys(
+        &self,
+        user_id: Path<Uuid>,
+        request: Json<UploadPrekeysRequest>,
+    ) -> UploadPrekeysResponse;
+
+    async fn get_prekeys(&self, user_id: Path<Uuid>) -> GetPrekeysResponse;
+
+    async fn get_prekey_bundle(&self, user_id: Path<Uuid>) -> GetPrekeyBundleResponse;
+
+    async fn upload_key_backup(
+        &self,
+        user_id: Path<Uuid>,
+        request: Json<UploadKeyBackupRequest>,
+    ) -> UploadKeyBackupResponse;
+
+    async fn create_key_backup_challenge(
+        &self,
+        user_id: Path<Uuid>,
+    ) -> CreateKeyBackupChallengeResponse;
+
+    async fn recover_key_backup(
+        &self,
+        user_id: Path<Uuid>,
+        request: Json<RecoverKeyBackupRequest>,
+    ) -> RecoverKeyBackupResponse;
+
     /*
     async fn delete_user(self: &Self, user_id: Path<Uuid>) -> DeleteUserResponse;
     */
@@ -61,7 +127,7 @@ impl<R: UserRepository> UserControllerImpl<R> {
 }
 
 #[async_trait::async_trait]
-impl<R: UserRepository + 'static> UserController for UserControllerImpl<R> {
+impl<R: UserRepository + UnitOfWork + 'static> UserController for UserControllerImpl<R> {
     async fn register_user(self: &Self, request: Json<RegisterRequest>) -> RegisterUserResponse {
         let response = self.service.register_user(request.into_inner()).await?;
         Ok(HttpResponse::Created().json(response))
@@ -88,6 +154,114 @@ impl<R: UserRepository + 'static> UserController for UserControllerImpl<R> {
         Ok(HttpResponse::Ok().finish())
     }
 
+    async fn get_safety_number(
+        &self,
+        user_id: Path<Uuid>,
+        contact_id: Path<Uuid>,
+    ) -> GetSafetyNumberResponse {
+        let safety_number = self
+            .service
+            .get_safety_number(*user_id, *contact_id)
+            .await?;
+
+        Ok(HttpResponse::Ok().json(SafetyNumberResponse {
+            version: safety_number.version,
+            qr_payload: safety_number.qr_payload_base64(),
+            safety_number: safety_number.digits,
+        }))
+    }
+
+    async fn verify_contact(
+        &self,
+        user_id: Path<Uuid>,
+        contact_id: Path<Uuid>,
+        request: Json<VerifyContactRequest>,
+    ) -> VerifyContactResponse {
+        request.validate()?;
+
+        self.service
+            .verify_contact(*user_id, *contact_id, request.into_inner())
+            .await?;
+        Ok(HttpResponse::Ok().finish())
+    }
+
+    async fn get_contact_verification(
+        &self,
+        user_id: Path<Uuid>,
+        contact_id: Path<Uuid>,
+    ) -> GetContactVerificationResponse {
+        let status = self
+            .service
+            .get_contact_verification(*user_id, *contact_id)
+            .await?;
+
+        Ok(HttpResponse::Ok().json(status))
+    }
+
+    async fn get_membership_tree(&self) -> GetMembershipTreeResponse {
+        let tree = self.service.get_membership_tree().await?;
+        Ok(HttpResponse::Ok().json(tree))
+    }
+
+    async fn upload_prekeys(
+        &self,
+        user_id: Path<Uuid>,
+        request: Json<UploadPrekeysRequest>,
+    ) -> UploadPrekeysResponse {
+        request.validate()?;
+
+        let prekeys = self
+            .service
+            .upload_prekeys(*user_id, request.into_inner())
+            .await?;
+        Ok(HttpResponse::Ok().json(prekeys))
+    }
+
+    async fn get_prekeys(&self, user_id: Path<Uuid>) -> GetPrekeysResponse {
+        let prekeys = self.service.get_prekeys(*user_id).await?;
+        Ok(HttpResponse::Ok().json(prekeys))
+    }
+
+    async fn get_prekey_bundle(&self, user_id: Path<Uuid>) -> GetPrekeyBundleResponse {
+        let bundle = self.service.get_prekey_bundle(*user_id).await?;
+        Ok(HttpResponse::Ok().json(bundle))
+    }
+
+    async fn upload_key_backup(
+        &self,
+        user_id: Path<Uuid>,
+        request: Json<UploadKeyBackupRequest>,
+    ) -> UploadKeyBackupResponse {
+        request.validate()?;
+
+        self.service
+            .upload_key_backup(*user_id, request.into_inner())
+            .await?;
+        Ok(HttpResponse::Ok().finish())
+    }
+
+    async fn create_key_backup_challenge(
+        &self,
+        user_id: Path<Uuid>,
+    ) -> CreateKeyBackupChallengeResponse {
+        let challenge = self.service.create_key_backup_challenge(*user_id).await?;
+        Ok(HttpResponse::Ok().json(challenge))
+    }
+
+    async fn recover_key_backup(
+        &self,
+        user_id: Path<Uuid>,
+        request: Json<RecoverKeyBackupRequest>,
+    ) -> RecoverKeyBackupResponse {
+        request.validate()?;
+
+        let backup = self
+            .service
+            .recover_key_backup(*user_id, request.into_inner())
+            .await?;
+        Ok(HttpResponse::Ok().json(backup))
+    }
+
     /*
     #[utoipa::path(
         delete,
@@ -97,8 +271,8 @@ impl<R: UserRepository + 'static> UserController for UserControllerImpl<R> {
         ),
         responses(
             (status = 204, description = "User deleted successfully"),
-            (status = 404, description = "User not found"),
-            (status = 500, description = "Internal server error"),
+            (status = 404, description = "User not found", body = ErrorResponse),
+            (status = 500, description = "Internal server error", body = ErrorResponse),
         )
     )]
     async fn delete_user(&self, user_id: Path<Uuid>) -> DeleteUserResponse {
@@ -114,9 +288,10 @@ impl<R: UserRepository + 'static> UserController for UserControllerImpl<R> {
     request_body(content = RegisterRequest, content_type = "application/json"),
     responses(
         (status = 201, description = "User registered successfully", body = RegisterResponse),
-        (status = 400, description = "Validation error"),
-        (status = 409, description = "Username or public key already exists"),
-        (status = 500, description = "Internal server error"),
+        (status = 400, description = "Validation error", body = ErrorResponse),
+        (status = 409, description = "Username or public key already exists", body = ErrorResponse),
+        (status = 429, description = "Rate limited; retry after `Retry-After` seconds", body = ErrorResponse),
+        (status = 500, description = "Internal server error", body = ErrorResponse),
     )
 )]
 #[post("")]
@@ -124,10 +299,24 @@ pub async fn register_user_handler(
     controller: Data<Arc<dyn UserController>>,
     request: Json<RegisterRequest>,
 ) -> impl Responder {
-    println!("{}", "god. \n");
     controller.register_user(request).await
 }
 
+#[utoipa::path(
+    get,
+    path = "/membership",
+    responses(
+        (status = 200, description = "Root and leaves of the anonymity set", body = MembershipTreeResponse),
+        (status = 500, description = "Internal server error", body = ErrorResponse),
+    )
+)]
+#[get("/membership")]
+pub async fn get_membership_tree_handler(
+    controller: Data<Arc<dyn UserController>>,
+) -> impl Responder {
+    controller.get_membership_tree().await
+}
+
 #[utoipa::path(
     get,
     path = "/{user_id}",
@@ -136,8 +325,8 @@ pub async fn register_user_handler(
     ),
     responses(
         (status = 200, description = "User found", body = User),
-        (status = 404, description = "User not found"),
-        (status = 500, description = "Internal server error"),
+        (status = 404, description = "User not found", body = ErrorResponse),
+        (status = 500, description = "Internal server error", body = ErrorResponse),
     )
 )]
 #[get("/{user_id}")]
@@ -156,7 +345,7 @@ pub async fn get_user_handler(
     ),
     responses(
         (status = 200, description = "List of users", body = Vec<User>),
-        (status = 500, description = "Internal server error"),
+        (status = 500, description = "Internal server error", body = ErrorResponse),
     )
 )]
 #[get("")]
@@ -175,11 +364,11 @@ pub async fn get_users_handler(
     ),
     request_body = UpdateUserRequest,
     responses(
-        (status = 200, description = "User updated successfully"),
-        (status = 400, description = "Validation error"),
-        (status = 404, description = "User not found"),
-        (status = 409, description = "New username already exists"),
-        (status = 500, description = "Internal server error"),
+        (status = 200, description = "User updated; a new public key also revokes the user's refresh tokens"),
+        (status = 400, description = "Validation error", body = ErrorResponse),
+        (status = 404, description = "User not found", body = ErrorResponse),
+        (status = 409, description = "New username already exists", body = ErrorResponse),
+        (status = 500, description = "Internal server error", body = ErrorResponse),
     )
 )]
 #[patch("/{user_id}")]
@@ -191,6 +380,219 @@ pub async fn update_user_handler(
     controller.update_user(user_id, request).await
 }
 
+#[utoipa::path(
+    get,
+    path = "/{user_id}/safety-number/{contact_id}",
+    params(
+        ("user_id" = Uuid, Path, description = "User ID"),
+        ("contact_id" = Uuid, Path, description = "Contact user ID")
+    ),
+    responses(
+        (status = 200, description = "Safety number for the pair of users", body = SafetyNumberResponse),
+        (status = 404, description = "User not found", body = ErrorResponse),
+        (status = 500, description = "Internal server error", body = ErrorResponse),
+    )
+)]
+#[get("/{user_id}/safety-number/{contact_id}")]
+pub async fn get_safety_number_handler(
+    controller: Data<Arc<dyn UserController>>,
+    path: Path<(Uuid, Uuid)>,
+) -> impl Responder {
+    let (user_id, contact_id) = path.into_inner();
+    controller
+        .get_safety_number(Path::from(user_id), Path::from(contact_id))
+        .await
+}
+
+#[utoipa::path(
+    put,
+    path = "/{user_id}/verified-contacts/{contact_id}",
+    params(
+        ("user_id" = Uuid, Path, description = "Verifying user ID"),
+        ("contact_id" = Uuid, Path, description = "Verified contact ID")
+    ),
+    request_body = VerifyContactRequest,
+    responses(
+        (status = 200, description = "Contact marked as verified"),
+        (status = 400, description = "Validation error or safety number mismatch", body = ErrorResponse),
+        (status = 404, description = "User not found", body = ErrorResponse),
+        (status = 500, description = "Internal server error", body = ErrorResponse),
+    )
+)]
+#[put("/{user_id}/verified-contacts/{contact_id}")]
+pub async fn verify_contact_handler(
+    controller: Data<Arc<dyn UserController>>,
+    path: Path<(Uuid, Uuid)>,
+    request: Json<VerifyContactRequest>,
+) -> impl Responder {
+    let (user_id, contact_id) = path.into_inner();
+    controller
+        .verify_contact(Path::from(user_id), Path::from(contact_id), request)
+        .await
+}
+
+#[utoipa::path(
+    get,
+    path = "/{user_id}/verified-contacts/{contact_id}",
+    params(
+        ("user_id" = Uuid, Path, description = "Verifying user ID"),
+        ("contact_id" = Uuid, Path, description = "Contact user ID")
+    ),
+    responses(
+        (status = 200, description = "Verification state of the contact", body = ContactVerificationResponse),
+        (status = 404, description = "User not found", body = ErrorResponse),
+        (status = 500, description = "Internal server error", body = ErrorResponse),
+    )
+)]
+#[get("/{user_id}/verified-contacts/{contact_id}")]
+pub async fn get_contact_verification_handler(
+    controller: Data<Arc<dyn UserController>>,
+    path: Path<(Uuid, Uuid)>,
+) -> impl Responder {
+    let (user_id, contact_id) = path.into_inner();
+    controller
+        .get_contact_verification(Path::from(user_id), Path::from(contact_id))
+        .await
+}
+
+#[utoipa::path(
+    post,
+    path = "/{user_id}/prekeys",
+    params(
+        ("user_id" = Uuid, Path, description = "User ID")
+    ),
+    request_body = UploadPrekeysRequest,
+    responses(
+        (status = 200, description = "Prekeys published, with how many one-time prekeys are left", body = PrekeysResponse),
+        (status = 400, description = "Validation error or malformed key", body = ErrorResponse),
+        (status = 403, description = "Signature was not made with the user's public key", body = ErrorResponse),
+        (status = 404, description = "User not found", body = ErrorResponse),
+        (status = 409, description = "A signed prekey with the same or a newer id is already published", body = ErrorResponse),
+        (status = 500, description = "Internal server error", body = ErrorResponse),
+    )
+)]
+#[post("/{user_id}/prekeys")]
+pub async fn upload_prekeys_handler(
+    controller: Data<Arc<dyn UserController>>,
+    user_id: Path<Uuid>,
+    request: Json<UploadPrekeysRequest>,
+) -> impl Responder {
+    controller.upload_prekeys(user_id, request).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/{user_id}/prekeys",
+    params(
+        ("user_id" = Uuid, Path, description = "User ID")
+    ),
+    responses(
+        (status = 200, description = "The user's published prekeys and how many one-time prekeys are left", body = PrekeysResponse),
+        (status = 404, description = "User has not published prekeys", body = ErrorResponse),
+        (status = 500, description = "Internal server error", body = ErrorResponse),
+    )
+)]
+#[get("/{user_id}/prekeys")]
+pub async fn get_prekeys_handler(
+    controller: Data<Arc<dyn UserController>>,
+    user_id: Path<Uuid>,
+) -> impl Responder {
+    controller.get_prekeys(user_id).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/{user_id}/prekey-bundle",
+    params(
+        ("user_id" = Uuid, Path, description = "User ID")
+    ),
+    responses(
+        (status = 200, description = "Prekeys for starting a session, with a one-time prekey claimed for this caller if any are left", body = PrekeyBundleResponse),
+        (status = 404, description = "User has not published prekeys", body = ErrorResponse),
+        (status = 429, description = "Rate limited; retry after `Retry-After` seconds", body = ErrorResponse),
+        (status = 500, description = "Internal server error", body = ErrorResponse),
+    )
+)]
+#[get("/{user_id}/prekey-bundle")]
+pub async fn get_prekey_bundle_handler(
+    controller: Data<Arc<dyn UserController>>,
+    user_id: Path<Uuid>,
+) -> impl Responder {
+    controller.get_prekey_bundle(user_id).await
+}
+
+#[utoipa::path(
+    put,
+    path = "/{user_id}/key-backup",
+    params(
+        ("user_id" = Uuid, Path, description = "User ID")
+    ),
+    request_body = UploadKeyBackupRequest,
+    responses(
+        (status = 200, description = "Key backup stored"),
+        (status = 400, description = "Validation error or malformed key", body = ErrorResponse),
+        (status = 403, description = "Signature was not made with the user's public key, or key backups are not accepted", body = ErrorResponse),
+        (status = 404, description = "User not found", body = ErrorResponse),
+        (status = 409, description = "A backup with the same or a newer version is already stored", body = ErrorResponse),
+        (status = 500, description = "Internal server error", body = ErrorResponse),
+    )
+)]
+#[put("/{user_id}/key-backup")]
+pub async fn upload_key_backup_handler(
+    controller: Data<Arc<dyn UserController>>,
+    user_id: Path<Uuid>,
+    request: Json<UploadKeyBackupRequest>,
+) -> impl Responder {
+    controller.upload_key_backup(user_id, request).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/{user_id}/key-backup/challenge",
+    params(
+        ("user_id" = Uuid, Path, description = "User ID")
+    ),
+    responses(
+        (status = 200, description = "A single-use recovery challenge, with the backup's KDF parameters", body = KeyBackupChallengeResponse),
+        (status = 403, description = "Key backups are not accepted", body = ErrorResponse),
+        (status = 404, description = "User has no key backup", body = ErrorResponse),
+        (status = 429, description = "Rate limited, or too many challenges pending; retry after `Retry-After` seconds", body = ErrorResponse),
+        (status = 500, description = "Internal server error", body = ErrorResponse),
+    )
+)]
+#[post("/{user_id}/key-backup/challenge")]
+pub async fn create_key_backup_challenge_handler(
+    controller: Data<Arc<dyn UserController>>,
+    user_id: Path<Uuid>,
+) -> impl Responder {
+    controller.create_key_backup_challenge(user_id).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/{user_id}/key-backup/recover",
+    params(
+        ("user_id" = Uuid, Path, description = "User ID")
+    ),
+    request_body = RecoverKeyBackupRequest,
+    responses(
+        (status = 200, description = "The key backup as the client sealed it", body = KeyBackupResponse),
+        (status = 400, description = "Validation error", body = ErrorResponse),
+        (status = 403, description = "Challenge unknown or expired, or signature invalid", body = ErrorResponse),
+        (status = 404, description = "User not found", body = ErrorResponse),
+        (status = 429, description = "Rate limited, or held back after failed attempts; retry after `Retry-After` seconds", body = ErrorResponse),
+        (status = 500, description = "Internal server error", body = ErrorResponse),
+    )
+)]
+#[post("/{user_id}/key-backup/recover")]
+pub async fn recover_key_backup_handler(
+    controller: Data<Arc<dyn UserController>>,
+    user_id: Path<Uuid>,
+    request: Json<RecoverKeyBackupRequest>,
+) -> impl Responder {
+    controller.recover_key_backup(user_id, request).await
+}
+
 /*
 #[delete("/{user_id}")]
 pub async fn delete_user_handler(
@@ -205,9 +607,19 @@ pub fn configure_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/api/users")
             .service(register_user_handler)
+            .service(get_membership_tree_handler)
             .service(get_user_handler)
             .service(get_users_handler)
-            .service(update_user_handler),
+            .service(update_user_handler)
+            .service(get_safety_number_handler)
+            .service(verify_contact_handler)
+            .service(get_contact_verification_handler)
+            .service(upload_prekeys_handler)
+            .service(get_prekeys_handler)
+            .service(get_prekey_bundle_handler)
+            .service(upload_key_backup_handler)
+            .service(create_key_backup_challenge_handler)
+            .service(recover_key_backup_handler),
         // .service(delete_user_handler),
     );
 }
@@ -232,8 +644,8 @@ mod tests {
     };
     use service::rand::Rng;
     use shared::crypto::utils::sha256_hash;
-    use sqlx::types::chrono::Utc;
-    use service::user::MockUserRepository;
+    use shared::data_encryption::KeyRing;
+    use service::memory::InMemoryStore;
 
     const CUSTOM_ENGINE: engine::GeneralPurpose =
         engine::GeneralPurpose::new(&alphabet::URL_SAFE, general_purpose::NO_PAD);
@@ -251,14 +663,13 @@ mod tests {
         (Data::new(controller_arc), mock_controller)
     }
 
-    async fn setup_controller() -> (
-        Data<UserControllerImpl<MockUserRepository>>,
-        MockUserRepository,
-    ) {
-        let mock_repo = MockUserRepository::new();
-        let service = Data::new(UserService::new(mock_repo.clone()));
-        let controller = Data::new(UserControllerImpl::new(service));
-        (controller, mock_repo)
+    fn setup_controller(store: InMemoryStore) -> Data<UserControllerImpl<InMemoryStore>> {
+        Data::new(UserControllerImpl::new(Data::new(UserService::new(store))))
+    }
+
+    async fn insert_user(store: &InMemoryStore, username: &str) -> Uuid {
+        let (public_key, _) = generate_key().await;
+        store.insert_user(public_key.as_str(), username).await.unwrap()
     }
     
     /*
@@ -287,99 +698,52 @@ mod tests {
 
     #[actix_web::test]
     async fn test_register_user_success() {
-        let mut mock_repo = MockUserRepository::new();
+        let store = InMemoryStore::new();
+        let (public_key, _) = generate_key().await;
 
-        let test_uuid = Uuid::now_v7();
-        let (public_key, public_key_hash) = generate_key().await;
-        
         let request = RegisterRequest {
             username: Some("testuser".to_string()),
             public_key: public_key.to_string(),
+            identity_commitment: None,
+            message_key: None,
         };
 
-        mock_repo
-            .expect_insert_user()
-            .withf(move |pk, username| {
-                username == "testuser".to_string() && 
-                pk.to_string() == public_key.to_string()
-            })
-            .times(1)
-            .returning(move |username, _| {
-                Ok(
-                    test_uuid
-                )
-            });
-
-        let service = Data::new(UserService::new(mock_repo));
-        let controller = Data::new(UserControllerImpl::new(service));
-       
+        let controller = setup_controller(store.clone());
+
         let response = controller.register_user(Json(request)).await.unwrap();
         assert_eq!(response.status(), StatusCode::CREATED);
-        
+
         let body: RegisterResponse = parse_response_body(response).await;
-        assert_eq!(body.user_id, test_uuid);
         assert_eq!(body.username, "testuser");
+        let user = store.get_user_by_id(body.user_id).await.unwrap();
+        assert_eq!(user.public_key, public_key);
     }
 
     #[actix_web::test]
     async fn test_register_user_without_username() {
-        let mut mock_repo = MockUserRepository::new();
-
-        let test_uuid = Uuid::now_v7();
+        let (public_key, _) = generate_key().await;
         let request = RegisterRequest {
             username: None,
-            public_key: "test_public_key".to_string(),
-        };
-        let req_json = Json(request.clone());
-
-        let expected_response = RegisterResponse {
-            user_id: test_uuid,
-            username: "testuser".to_string(),
+            public_key: public_key.to_string(),
+            identity_commitment: None,
+            message_key: None,
         };
 
-        mock_repo
-            .expect_insert_user()
-            .withf(move |pk, username| {
-                pk == "test_public_key"
-            })
-            .times(1)
-            .returning(move |username, _| {
-                Ok(
-                    test_uuid
-                )
-            });
-
-
-        let service = Data::new(UserService::new(mock_repo));
-        let controller = Data::new(UserControllerImpl::new(service));
+        let controller = setup_controller(InMemoryStore::new());
 
         let response = controller.register_user(Json(request)).await.unwrap();
         assert_eq!(response.status(), StatusCode::CREATED);
+
+        let body: RegisterResponse = parse_response_body(response).await;
+        assert!(!body.username.is_empty());
     }
 
     #[actix_web::test]
     async fn test_get_user_success() {
-        let mut mock_repo = MockUserRepository::new();
-        let (public_key, public_key_hash) = generate_key().await;
-
-        let test_uuid = Uuid::now_v7();
-        let test_user = User {
-            id: test_uuid,
-            username: "testuser".to_string(),
-            public_key,
-            public_key_hash,
-            created_at: Utc::now().naive_utc(),
-            updated_at: Utc::now().naive_utc(),
-            last_login: Some(Utc::now().naive_utc()),
-        };
-
-        mock_repo.expect_get_user_by_id()
-            .with(eq(test_uuid))
-            .times(1)
-            .returning(move |_| Ok(test_user.clone()));
+        let store = InMemoryStore::new();
+        let test_uuid = insert_user(&store, "testuser").await;
 
-        let service = Data::new(UserService::new(mock_repo));
-        let controller = Data::new(UserControllerImpl::new(service));
+        let controller = setup_controller(store);
 
         let response = controller.get_user(Path::from(test_uuid)).await.unwrap();
         assert_eq!(response.status(), StatusCode::OK);
@@ -391,93 +755,44 @@ mod tests {
 
     #[actix_web::test]
     async fn test_get_users_success() {
-        let mut mock_1 = MockUserRepository::new();
-        let mut mock_2 = MockUserRepository::new();
-        let (public_key, public_key_hash) = generate_key().await;
-        let (public_key2, public_key_hash2) = generate_key().await;
-
-        let test_users = vec![
-            User {
-                id: Uuid::now_v7(),
-                username: "user1".to_string(),
-                public_key: public_key,
-                public_key_hash: public_key_hash,
-                created_at: Utc::now().naive_utc(),
-                updated_at: Utc::now().naive_utc(),
-                last_login: Some(Utc::now().naive_utc()),
-            },
-            User {
-                id: Uuid::now_v7(),
-                username: "user2".to_string(),
-                public_key: public_key2,
-                public_key_hash: public_key_hash2,
-                created_at: Utc::now().naive_utc(),
-                updated_at: Utc::now().naive_utc(),
-                last_login: Some(Utc::now().naive_utc()),
-            },
-        ];
-
-        let test_users_1 = test_users.clone();
-        let test_users_2 = test_users.clone();
-
-        // Test with limit
-        mock_1
-            .expect_get_users()
-            .with(eq(Some(10)))
-            .times(1)
-            .returning(move |_| Ok(test_users_1.clone()));
+        let store = InMemoryStore::new();
+        insert_user(&store, "user1").await;
+        insert_user(&store, "user2").await;
 
-        let service_1 = Data::new(UserService::new(mock_1));
-        let controller_1 = Data::new(UserControllerImpl::new(service_1));
+        let controller = setup_controller(store);
 
-        let response = controller_1
-            .get_users(Query::from(actix_web::web::Query(Some(10))))
+        // Test with limit
+        let response = controller
+            .get_users(Query::from(actix_web::web::Query(Some(1))))
             .await
             .unwrap();
         assert_eq!(response.status(), StatusCode::OK);
 
         let body: Vec<User> = parse_response_body(response).await;
-        assert_eq!(body.len(), 2);
+        assert_eq!(body.len(), 1);
 
         // Test without limit
-        mock_2
-            .expect_get_users()
-            .with(eq(None))
-            .times(1)
-            .returning(move |_| Ok(test_users_2.clone()));
-
-        let service_2 = Data::new(UserService::new(mock_2));
-        let controller_2 = Data::new(UserControllerImpl::new(service_2));
-
-        let response = controller_2
+        let response = controller
             .get_users(Query::from(actix_web::web::Query(None)))
             .await
             .unwrap();
         assert_eq!(response.status(), StatusCode::OK);
+
+        let body: Vec<User> = parse_response_body(response).await;
+        assert_eq!(body.len(), 2);
     }
 
     #[actix_web::test]
     async fn test_update_user_success() {
-        let mut mock = MockUserRepository::new();
-        let test_uuid = Uuid::now_v7();
+        let store = InMemoryStore::new();
+        let test_uuid = insert_user(&store, "oldusername").await;
         let request = UpdateUserRequest {
             new_username: Some("newusername".to_string()),
             new_public_key: None,
+            new_message_key: None,
         };
-        let req_json = Json(request.clone());
-
-        mock.expect_update_user()
-            .with(
-                eq(test_uuid),
-                eq(Some("newusername".to_string())),
-                eq(None),
-                eq(None),
-            )
-            .times(1)
-            .returning(|_, _, _, _| Ok(()));
-
-        let service = Data::new(UserService::new(mock));
-        let controller = Data::new(UserControllerImpl::new(service));
+
+        let controller = setup_controller(store.clone());
 
         let response = controller
             .update_user(Path::from(test_uuid), Json(request))
@@ -485,37 +800,23 @@ mod tests {
             .unwrap();
 
         assert_eq!(response.status(), StatusCode::OK);
+        let user = store.get_user_by_id(test_uuid).await.unwrap();
+        assert_eq!(user.username, "newusername");
     }
 
     #[actix_web::test]
     async fn test_update_user_with_public_key() {
-        let mut mock = MockUserRepository::new();
+        let store = InMemoryStore::new();
+        let test_uuid = insert_user(&store, "testuser").await;
         let (public_key, public_key_hash) = generate_key().await;
-        let encoded_pkhash = CUSTOM_ENGINE.encode(public_key_hash.to_string());
-        println!("{} {} {} {}", public_key.to_string(), public_key_hash.to_string(), encoded_pkhash, CUSTOM_ENGINE.encode(public_key.to_string()));
 
-        let test_uuid = Uuid::now_v7();
-        let new_key = "new_public_key".to_string();
         let request = UpdateUserRequest {
             new_username: None,
             new_public_key: Some(public_key.to_string()),
+            new_message_key: None,
         };
 
-        let req_json = Json(request.clone());
-
-        mock.expect_update_user()
-            .with(
-                eq(test_uuid),
-                eq(None),
-                eq(Some(public_key.to_string())),
-                always(),
-            )
-            .times(1)
-            .returning(|_, _, _, _| Ok(()));
-
-
-        let service = Data::new(UserService::new(mock));
-        let controller = Data::new(UserControllerImpl::new(service));
+        let controller = setup_controller(store.clone());
 
         let response = controller
             .update_user(Path::from(test_uuid), Json(request))
@@ -523,6 +824,9 @@ mod tests {
             .unwrap();
 
         assert_eq!(response.status(), StatusCode::OK);
+        let user = store.get_user_by_id(test_uuid).await.unwrap();
+        assert_eq!(user.public_key, public_key);
+        assert_eq!(user.public_key_hash, public_key_hash);
     }
 
     #[actix_web::test]
@@ -530,6 +834,8 @@ mod tests {
         let request = RegisterRequest {
             username: Some("ab".to_string()),
             public_key: "test_public_key".to_string(),
+            identity_commitment: None,
+            message_key: None,
         };
         let validation = request.validate();
         assert!(validation.is_err());
@@ -539,6 +845,8 @@ mod tests {
         let request = RegisterRequest {
             username: Some("valid".to_string()),
             public_key: "short".to_string(),
+            identity_commitment: None,
+            message_key: None,
         };
         let validation = request.validate();
         assert!(validation.is_err());
@@ -547,71 +855,153 @@ mod tests {
     }
 
     #[actix_web::test]
-    async fn test_get_user_not_found() {
-        let mut mock = MockUserRepository::new();
-        let test_uuid = Uuid::now_v7();
+    async fn test_get_membership_tree_empty() {
+        let controller = setup_controller(InMemoryStore::new());
+
+        let response = controller.get_membership_tree().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
 
-        mock.expect_get_user_by_id()
-            .with(eq(test_uuid))
-            .times(1)
-            .returning(|_| Err(AppError::NotFound("User not found".to_string())));
+        let body = to_bytes(response.into_body()).await.unwrap();
+        let tree: MembershipTreeResponse = serde_json::from_slice(&body).unwrap();
+        assert!(tree.leaves.is_empty());
+        assert!(!tree.root.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_get_prekeys_not_published() {
+        let store = InMemoryStore::new();
+        let test_uuid = insert_user(&store, "testuser").await;
 
-        let service = Data::new(UserService::new(mock));
+        let controller = setup_controller(store);
+
+        let response = controller.get_prekeys(Path::from(test_uuid)).await;
+        assert_eq!(
+            response.unwrap_err().error_response().status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_get_prekey_bundle_without_one_time_prekeys() {
+        let store = InMemoryStore::new();
+        let test_uuid = insert_user(&store, "testuser").await;
+        store
+            .upsert_signed_prekey(test_uuid, "identity", 3, "prekey", "signature")
+            .await
+            .unwrap();
+
+        let controller = setup_controller(store);
+
+        let response = controller
+            .get_prekey_bundle(Path::from(test_uuid))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = to_bytes(response.into_body()).await.unwrap();
+        let bundle: PrekeyBundleResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!((bundle.user_id, bundle.signed_prekey_id), (test_uuid, 3));
+        assert!(bundle.one_time_prekey.is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_key_backup_challenge_without_server_key() {
+        let controller = setup_controller(InMemoryStore::new());
+
+        let response = controller
+            .create_key_backup_challenge(Path::from(Uuid::now_v7()))
+            .await;
+        assert_eq!(
+            response.unwrap_err().error_response().status(),
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_upload_key_backup_validation() {
+        let service = Data::new(UserService::new(InMemoryStore::new()).with_key_ring(Arc::new(KeyRing::new(1, &[7; 32]).unwrap())));
         let controller = Data::new(UserControllerImpl::new(service));
-        
-        let response = controller.get_user(Path::from(test_uuid)).await;
+
+        let request = UploadKeyBackupRequest {
+            version: 1,
+            encrypted_key: CUSTOM_ENGINE.encode([1u8; 64]),
+            salt: CUSTOM_ENGINE.encode([2u8; 16]),
+            kdf_iterations: 1_000,
+            recovery_key: CUSTOM_ENGINE.encode([3u8; 33]),
+            signature: CUSTOM_ENGINE.encode([4u8; 64]),
+        };
+
+        let response = controller
+            .upload_key_backup(Path::from(Uuid::now_v7()), Json(request))
+            .await;
+        assert_eq!(
+            response.unwrap_err().error_response().status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_upload_prekeys_validation() {
+        let controller = setup_controller(InMemoryStore::new());
+
+        let request = UploadPrekeysRequest {
+            identity_key: CUSTOM_ENGINE.encode([1u8; 32]),
+            signed_prekey_id: 1,
+            signed_prekey: CUSTOM_ENGINE.encode([2u8; 31]),
+            signature: CUSTOM_ENGINE.encode([3u8; 64]),
+            one_time_prekeys: vec![],
+        };
+
+        let response = controller
+            .upload_prekeys(Path::from(Uuid::now_v7()), Json(request))
+            .await;
+        assert_eq!(
+            response.unwrap_err().error_response().status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_get_user_not_found() {
+        let controller = setup_controller(InMemoryStore::new());
+
+        let response = controller.get_user(Path::from(Uuid::now_v7())).await;
         assert!(response.is_err());
 
         if let Err(err) = response {
             assert_eq!(err.error_response().status(), StatusCode::NOT_FOUND);
-            assert_eq!(err.to_string(), "Not found: User not found");
         }
     }
 
     #[actix_web::test]
     async fn test_register_user_conflict() {
-        let mut mock = MockUserRepository::new();
+        let store = InMemoryStore::new();
+        insert_user(&store, "existing_user").await;
+        let (public_key, _) = generate_key().await;
 
         let request = RegisterRequest {
             username: Some("existing_user".to_string()),
-            public_key: "existing_key".to_string(),
+            public_key: public_key.to_string(),
+            identity_commitment: None,
+            message_key: None,
         };
 
-        mock.expect_insert_user()
-            .with(
-                eq("existing_key".to_string()),
-                eq("existing_user".to_string())
-            )
-            .times(1)
-            .returning(|_, _| {
-                Err(AppError::UniqueViolation(
-                    "Username already exists".to_string(),
-                ))
-            });
-
-        let service = Data::new(UserService::new(mock));
-        let controller = Data::new(UserControllerImpl::new(service));
+        let controller = setup_controller(store);
 
         let response = controller.register_user(Json(request)).await;
         assert!(response.is_err());
 
         if let Err(err) = response {
             assert_eq!(err.error_response().status(), StatusCode::CONFLICT);
-            assert_eq!(
-                err.to_string(),
-                "Unique constraint violation: Username already exists"
-            );
         }
     }
 
     #[actix_web::test]
     async fn test_update_user_validation() {
-        let mut mock = MockUserRepository::new();
-        let test_uuid = Uuid::now_v7();
-
         let invalid_request = UpdateUserRequest {
             new_username: Some("ab".to_string()),
             new_public_key: None,
+            new_message_key: None,
         };
 
         let validation = invalid_request.validate();
@@ -620,6 +1010,7 @@ mod tests {
         let invalid_request = UpdateUserRequest {
             new_username: None,
             new_public_key: Some("short".to_string()),
+            new_message_key: None,
         };
 
         let validation = invalid_request.validate();
@@ -628,23 +1019,16 @@ mod tests {
 
     #[actix_web::test]
     async fn test_update_user_not_found() {
-        let mut mock = MockUserRepository::new();
-        let test_uuid = Uuid::now_v7();
         let request = UpdateUserRequest {
             new_username: Some("newusername".to_string()),
             new_public_key: None,
+            new_message_key: None,
         };
 
-        mock.expect_update_user()
-            .with(always(), always(), always(), always())
-            .times(1)
-            .returning(|_, _, _, _| Err(AppError::NotFound("User not found".to_string())));
+        let controller = setup_controller(InMemoryStore::new());
 
-        let service = Data::new(UserService::new(mock));
-        let controller = Data::new(UserControllerImpl::new(service));
-        
         let response = controller
-            .update_user(Path::from(test_uuid), Json(request))
+            .update_user(Path::from(Uuid::now_v7()), Json(request))
             .await;
 
         assert!(response.is_err());
@@ -1,5 +1,6 @@
 use std::sync::Arc;
 use sqlx::{Pool, Sqlite, SqlitePool};
+use db::db::SqliteDb;
 use db::models::User;
 use db::uuid::Uuid;
 use shared::models::RegisterRequest;
@@ -35,7 +36,7 @@ pub async fn create_test_connection_pool() -> Result<SqlitePool, sqlx::Error> {
 pub async fn create_test_users(pool: &SqlitePool, count: usize) -> Result<Vec<Uuid>, AppError> {
     let mut users = Vec::with_capacity(count);
 
-    let user_repo: Arc<dyn UserRepository> = Arc::new(pool.clone());
+    let user_repo: Arc<dyn UserRepository> = Arc::new(SqliteDb::new(pool.clone()));
     
     for i in 0..count {
 
@@ -5,17 +5,20 @@ use actix_web::http::header::ContentType;
 use actix_http::Request;
 use sqlx::types::chrono::Utc;
 use db::{
+    db::SqliteDb,
     models::{Message, User}, 
-    uuid::Uuid, 
-    SqlitePool
+    uuid::Uuid,
 };
+use base64::Engine;
+use circuits::{message_preparation::{self, MessageContext}, prover};
 use service::message::{
+    commitment,
     repository::MessageRepository,
     service::MessageService,
 };
 use shared::{
     errors::AppError,
-    models::{CreateMessageRequest, CreateMessageResponse},
+    models::{CreateMessageRequest, CreateMessageResponse, MessageCommitmentResponse, CUSTOM_ENGINE},
 };
 use std::sync::Arc;
 use api::message::{
@@ -27,14 +30,15 @@ use api::message::{
 mod common;
 use common::{create_test_connection_pool, create_test_users, create_test_user_with_id};
 
-async fn setup_test_app() -> (impl Service<Request, Response = ServiceResponse, Error = actix_web::Error>, SqlitePool, Uuid, Uuid) {
+async fn setup_test_app() -> (impl Service<Request, Response = ServiceResponse, Error = actix_web::Error>, SqliteDb, Uuid, Uuid) {
     let pool = create_test_connection_pool().await.unwrap();
     
     let users = create_test_users(&pool, 2).await.unwrap();
     let user1_id = users[0];
     let user2_id = users[1];
     
-    let message_service = web::Data::new(MessageService::new(pool.clone()));
+    let db = SqliteDb::new(pool);
+    let message_service = web::Data::new(MessageService::new(db.clone()));
     
     let message_controller = web::Data::new(
         Arc::new(MessageControllerImpl::new(message_service)) as Arc<dyn MessageController>
@@ -46,7 +50,7 @@ async fn setup_test_app() -> (impl Service<Request, Response = ServiceResponse,
             .configure(configure_routes)
     ).await;
     
-    (app, pool, user1_id, user2_id)
+    (app, db, user1_id, user2_id)
 }
 
 #[actix_web::test]
@@ -61,6 +65,8 @@ async fn test_create_message() {
         encrypted_content: enc_content.to_string(),
         signature: Some(sig.to_string()),
         parent_id: None,
+        ephemeral_key_proof: None,
+        commitment: None,
     };
     
     let req = test::TestRequest::post()
@@ -127,6 +133,93 @@ async fn test_get_message_not_found() {
     assert_eq!(response.status(), StatusCode::NOT_FOUND);
 }
 
+#[actix_web::test]
+async fn test_message_commitment_roundtrip() {
+    let (app, _pool, sender_id, recipient_id) = setup_test_app().await;
+
+    let context = MessageContext {
+        sender_id: *sender_id.as_bytes(),
+        recipient_id: *recipient_id.as_bytes(),
+        parent_id: None,
+    };
+    let (recipient_sk, recipient_pk) = message_preparation::generate_keypair();
+    let sealed = message_preparation::seal(&recipient_pk, b"committed", &context).unwrap();
+    let commitment = CUSTOM_ENGINE.encode(prover::to_bytes(&sealed.commitment).unwrap());
+
+    let request = CreateMessageRequest {
+        sender_id,
+        recipient_id,
+        encrypted_content: sealed.to_base64(),
+        signature: None,
+        parent_id: None,
+        ephemeral_key_proof: None,
+        commitment: Some(commitment.clone()),
+    };
+    let req = test::TestRequest::post()
+        .uri("/api/messages")
+        .set_json(&request)
+        .to_request();
+    let created: CreateMessageResponse = test::call_and_read_body_json(&app, req).await;
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/api/messages/{}/commitment", created.get_message_id()))
+        .to_request();
+    let stored: MessageCommitmentResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(stored.message_id, created.get_message_id());
+    assert_eq!(stored.commitment, commitment);
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/api/messages/{}", created.get_message_id()))
+        .to_request();
+    let delivered: Message = test::call_and_read_body_json(&app, req).await;
+
+    let opening = sealed.opening(&recipient_sk, &context).unwrap();
+    let opening = CUSTOM_ENGINE.encode(prover::to_bytes(&opening).unwrap());
+    assert!(commitment::verify_delivery(&stored.commitment, &delivered.encrypted_content, &opening).unwrap());
+}
+
+#[actix_web::test]
+async fn test_message_commitment_not_found_without_commitment() {
+    let (app, pool, sender_id, recipient_id) = setup_test_app().await;
+
+    let message_id = pool.insert_message(
+        sender_id,
+        recipient_id,
+        "MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8A",
+        None,
+        None
+    ).await.unwrap().unwrap();
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/api/messages/{}/commitment", message_id))
+        .to_request();
+    let response = test::call_service(&app, req).await;
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn test_create_message_rejects_invalid_commitment() {
+    let (app, _pool, sender_id, recipient_id) = setup_test_app().await;
+
+    let request = CreateMessageRequest {
+        sender_id,
+        recipient_id,
+        encrypted_content: "MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8A".to_string(),
+        signature: None,
+        parent_id: None,
+        ephemeral_key_proof: None,
+        commitment: Some(CUSTOM_ENGINE.encode([0xffu8; 32])),
+    };
+    let req = test::TestRequest::post()
+        .uri("/api/messages")
+        .set_json(&request)
+        .to_request();
+    let response = test::call_service(&app, req).await;
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
 #[actix_web::test]
 async fn test_get_conversation() {
     let (app, pool, user1_id, user2_id) = setup_test_app().await;
@@ -158,7 +251,7 @@ async fn test_get_conversation() {
     
     // Create a message between user1 and another user (should not be in the conversation)
     let user3_id = Uuid::now_v7();
-    let _ = create_test_user_with_id(&pool, user3_id.clone()).await.unwrap();
+    let _ = create_test_user_with_id(&pool.pool, user3_id.clone()).await.unwrap();
 
     println!("{}", 54);    
     pool.insert_message(
@@ -308,7 +401,7 @@ async fn test_user_threads() {
     
     let user3_id = Uuid::now_v7();   
     let user3 = create_test_user_with_id(
-        &pool, 
+        &pool.pool, 
         user3_id
     ).await.unwrap();
     
@@ -395,6 +488,8 @@ async fn test_create_message_validation() {
         encrypted_content: "".to_string(), 
         signature: None,
         parent_id: None,
+        ephemeral_key_proof: None,
+        commitment: None,
     };
     
     let req = test::TestRequest::post()
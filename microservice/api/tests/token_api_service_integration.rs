@@ -1,5 +1,5 @@
 use actix_web::{test, web, App};
-use db::{uuid::Uuid, SqlitePool};
+use db::{db::SqliteDb, uuid::Uuid, SqlitePool};
 use jsonwebtoken::{encode, EncodingKey, Header, Algorithm};
 use serde_json::json;
 use service::token::{
@@ -89,13 +89,14 @@ async fn get_test_app() -> (SqlitePool, impl actix_web::dev::Service<
 >) {
     let db = get_test_db().await;
     let jwt_config = get_test_jwt_config();
-    let controller = TokenControllerImpl::new_with_config(db.clone(), jwt_config);
+    let repository = SqliteDb::new(db.clone());
+    let controller = TokenControllerImpl::new_with_config(repository.clone(), jwt_config);
     
     (db.clone(), test::init_service(
         App::new()
             .app_data(web::Data::new(controller))
             .app_data(web::JsonConfig::default().limit(4096))
-            .configure(TokenControllerImpl::configure(db))
+            .configure(TokenControllerImpl::configure(repository))
             
     ).await)
 }
@@ -1,5 +1,5 @@
 use actix_web::{test, web, App};
-use db::{SqlitePool, models::User};
+use db::{SqlitePool, db::SqliteDb, models::User, token_db::TokenDb, user_db::UserDb};
 use shared::models::{RegisterRequest, UpdateUserRequest};
 use std::sync::Arc;
 use db::uuid::Uuid;
@@ -7,13 +7,14 @@ use api::{user::{configure_routes, UserController, UserControllerImpl}};
 use service::{user::UserService};
 use futures::future::join_all;
 use sqlx::migrate::Migrator;
+use sqlx::types::chrono::Utc;
 
 #[actix_web::test]
 async fn test_full_user_lifecycle() {
     let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
     sqlx::migrate!("../db/migrations").run(&pool).await;
     
-    let user_service = UserService::new(pool.clone());
+    let user_service = UserService::new(SqliteDb::new(pool.clone()));
     let controller: Arc<dyn UserController> = Arc::new(UserControllerImpl::new(web::Data::new(user_service)));
     
     let app = test::init_service(
@@ -26,6 +27,8 @@ async fn test_full_user_lifecycle() {
     let register_request = RegisterRequest {
         public_key: "VGhlIHN1biBzaGFsbCBzb29uIHNoaW5l".to_string(),
         username: Some("lifecycleuser".to_string()),
+        identity_commitment: None,
+        message_key: None,
     };
     
     let register_req = test::TestRequest::post()
@@ -58,6 +61,7 @@ async fn test_full_user_lifecycle() {
     let update_request = UpdateUserRequest {
         new_username: Some("updateduser".to_string()),
         new_public_key: None,
+        new_message_key: None,
     };
     
     let update_req = test::TestRequest::patch()
@@ -97,7 +101,7 @@ async fn test_edge_cases() {
     let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
     sqlx::migrate!("../db/migrations").run(&pool).await;
 
-    let user_service = UserService::new(pool.clone());
+    let user_service = UserService::new(SqliteDb::new(pool.clone()));
     let controller: Arc<dyn UserController> = Arc::new(UserControllerImpl::new(web::Data::new(user_service)));
 
     let app = test::init_service(
@@ -110,6 +114,8 @@ async fn test_edge_cases() {
     let invalid_request = RegisterRequest {
         public_key: "invalid-key".to_string(),
         username: Some("invalidkeyuser".to_string()),
+        identity_commitment: None,
+        message_key: None,
     };
     
     let req = test::TestRequest::post()
@@ -134,6 +140,8 @@ async fn test_edge_cases() {
     let first_request = RegisterRequest {
         public_key: "MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAzp7h".to_string(),
         username: Some("duplicate".to_string()),
+        identity_commitment: None,
+        message_key: None,
     };
     
     let req = test::TestRequest::post()
@@ -148,6 +156,8 @@ async fn test_edge_cases() {
     let second_request = RegisterRequest {
         public_key: "MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAkr6j".to_string(),
         username: Some("duplicate".to_string()),
+        identity_commitment: None,
+        message_key: None,
     };
     
     let req = test::TestRequest::post()
@@ -163,6 +173,8 @@ async fn test_edge_cases() {
     let register_request = RegisterRequest {
         public_key: "MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAtg7h".to_string(),
         username: Some("updateinvalid".to_string()),
+        identity_commitment: None,
+        message_key: None,
     };
     
     let req = test::TestRequest::post()
@@ -181,6 +193,7 @@ async fn test_edge_cases() {
     let update_request = UpdateUserRequest {
         new_username: None,
         new_public_key: Some("invalid-key".to_string()),
+        new_message_key: None,
     };
     
     let req = test::TestRequest::patch()
@@ -198,7 +211,7 @@ async fn test_concurrent_operations() {
     let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
     sqlx::migrate!("../db/migrations").run(&pool).await;
 
-    let user_service = UserService::new(pool.clone());
+    let user_service = UserService::new(SqliteDb::new(pool.clone()));
     let controller: Arc<dyn UserController> = Arc::new(UserControllerImpl::new(web::Data::new(user_service)));
 
     let app = test::init_service(
@@ -211,7 +224,7 @@ async fn test_concurrent_operations() {
     let mut futures = vec![];
 
     for i in 1..=5 {
-        let user_service = UserService::new(pool.clone());
+        let user_service = UserService::new(SqliteDb::new(pool.clone()));
         let controller: Arc<dyn UserController> = Arc::new(UserControllerImpl::new(web::Data::new(user_service)));
 
         let app = test::init_service(
@@ -224,6 +237,8 @@ async fn test_concurrent_operations() {
             let register_request = RegisterRequest {
                 public_key: format!("VGhlIHN1biBzaGFsbCBzb29uIHNoaW{}l", i),
                 username: Some(format!("concurrent{}", i)),
+                identity_commitment: None,
+                message_key: None,
             };
 
             let req = test::TestRequest::post()
@@ -261,7 +276,7 @@ async fn test_concurrent_operations() {
     let mut update_futures = Vec::new();
 
     for (i, user_id) in user_ids.iter().enumerate() {
-        let user_service = UserService::new(pool.clone());
+        let user_service = UserService::new(SqliteDb::new(pool.clone()));
         let controller: Arc<dyn UserController> = Arc::new(UserControllerImpl::new(web::Data::new(user_service)));
 
         let app = test::init_service(
@@ -275,6 +290,7 @@ async fn test_concurrent_operations() {
             let update_request = UpdateUserRequest {
                 new_username: Some(format!("updated{}", i + 1)),
                 new_public_key: None,
+                new_message_key: None,
             };
 
             let req = test::TestRequest::patch()
@@ -305,3 +321,70 @@ async fn test_concurrent_operations() {
         assert_eq!(user.username, format!("updated{}", i + 1));
     }
 }
+
+#[actix_web::test]
+async fn test_update_public_key_revokes_refresh_tokens() {
+    let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+    sqlx::migrate!("../db/migrations").run(&pool).await.unwrap();
+    let db = SqliteDb::new(pool.clone());
+
+    let user_service = UserService::new(db.clone());
+    let controller: Arc<dyn UserController> = Arc::new(UserControllerImpl::new(web::Data::new(user_service)));
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(controller))
+            .configure(configure_routes)
+    ).await;
+
+    let register_request = RegisterRequest {
+        public_key: "VGhlIHN1biBzaGFsbCBzb29uIHNoaW5l".to_string(),
+        username: Some("rotatinguser".to_string()),
+        identity_commitment: None,
+        message_key: None,
+    };
+    let req = test::TestRequest::post()
+        .uri("/api/users")
+        .set_json(&register_request)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status().as_u16(), 201);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    let user_id = Uuid::parse_str(body["user_id"].as_str().unwrap()).unwrap();
+
+    let expires_at = Utc::now().timestamp() + 3600;
+    TokenDb::store_refresh_token(&db, user_id, "laptop", expires_at, None).await.unwrap();
+
+    // A new public key ends the sessions issued under the old one
+    let update_request = UpdateUserRequest {
+        new_username: None,
+        new_public_key: Some("VGhlIG1vb24gc2hhbGwgc29vbiByaXNl".to_string()),
+        new_message_key: None,
+    };
+    let req = test::TestRequest::patch()
+        .uri(&format!("/api/users/{}", user_id))
+        .set_json(&update_request)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status().as_u16(), 200);
+
+    assert!(!TokenDb::validate_refresh_token(&db, user_id, "laptop").await.unwrap());
+    let user = UserDb::get_user_by_id(&db, user_id).await.unwrap();
+    assert_eq!(user.public_key.as_str(), "VGhlIG1vb24gc2hhbGwgc29vbiByaXNl");
+
+    // A new username alone leaves them alone
+    TokenDb::store_refresh_token(&db, user_id, "phone", expires_at, None).await.unwrap();
+    let update_request = UpdateUserRequest {
+        new_username: Some("renameduser".to_string()),
+        new_public_key: None,
+        new_message_key: None,
+    };
+    let req = test::TestRequest::patch()
+        .uri(&format!("/api/users/{}", user_id))
+        .set_json(&update_request)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status().as_u16(), 200);
+
+    assert!(TokenDb::validate_refresh_token(&db, user_id, "phone").await.unwrap());
+}
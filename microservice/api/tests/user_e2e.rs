@@ -1,7 +1,10 @@
 use actix_web::{test, App, web::{self, Data}};
-use db::{SqlitePool, models::User};
+use db::{SqlitePool, db::SqliteDb, models::User};
 use service::user::UserService;
-use shared::models::{RegisterRequest, RegisterResponse, UpdateUserRequest};
+use shared::models::{
+    ContactVerificationResponse, RegisterRequest, RegisterResponse, SafetyNumberResponse,
+    UpdateUserRequest, VerifyContactRequest,
+};
 use std::sync::Arc;
 use db::uuid::Uuid;
 
@@ -12,7 +15,7 @@ async fn test_register_user() {
     let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
     sqlx::migrate!("../db/migrations").run(&pool).await;
         
-    let service = Data::new(UserService::new(pool.clone()));
+    let service = Data::new(UserService::new(SqliteDb::new(pool.clone())));
     let controller = Arc::new(UserControllerImpl::new(service)) as Arc<dyn UserController>;
     
     let app = test::init_service(
@@ -24,6 +27,8 @@ async fn test_register_user() {
     let request = RegisterRequest {
         public_key: "MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAxpaKTGz1LlgVihe0dGlE".to_string(),
         username: Some("testuser".to_string()),
+        identity_commitment: None,
+        message_key: None,
     };
     
     let req = test::TestRequest::post()
@@ -45,7 +50,7 @@ async fn test_register_user_without_username() {
     let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
     sqlx::migrate!("../db/migrations").run(&pool).await;
     
-    let service = Data::new(UserService::new(pool.clone()));
+    let service = Data::new(UserService::new(SqliteDb::new(pool.clone())));
     let controller = Arc::new(UserControllerImpl::new(service)) as Arc<dyn UserController>;
     
     let app = test::init_service(
@@ -57,6 +62,8 @@ async fn test_register_user_without_username() {
     let request = RegisterRequest {
         public_key: "VGhlIHN1biBzaGFsbCBzb29uIHNoaW5l".to_string(),
         username: None,
+        identity_commitment: None,
+        message_key: None,
     };
     
     let req = test::TestRequest::post()
@@ -78,7 +85,7 @@ async fn test_get_user_by_id() {
     let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
     sqlx::migrate!("../db/migrations").run(&pool).await;
     
-    let service = Data::new(UserService::new(pool.clone()));
+    let service = Data::new(UserService::new(SqliteDb::new(pool.clone())));
     let controller = Arc::new(UserControllerImpl::new(service.clone())) as Arc<dyn UserController>;
     
     let app = test::init_service(
@@ -90,6 +97,8 @@ async fn test_get_user_by_id() {
     let request = RegisterRequest {
         public_key: "MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAw5VO".to_string(),
         username: Some("findme".to_string()),
+        identity_commitment: None,
+        message_key: None,
     };
     
     let response = service.register_user(request).await.unwrap();
@@ -113,7 +122,7 @@ async fn test_get_user_not_found() {
     let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
     sqlx::migrate!("../db/migrations").run(&pool).await;
     
-    let service = Data::new(UserService::new(pool.clone()));
+    let service = Data::new(UserService::new(SqliteDb::new(pool.clone())));
     let controller = Arc::new(UserControllerImpl::new(service)) as Arc<dyn UserController>;
     
     let app = test::init_service(
@@ -137,7 +146,7 @@ async fn test_get_users() {
     let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
     sqlx::migrate!("../db/migrations").run(&pool).await;
     
-    let service = Data::new(UserService::new(pool.clone()));
+    let service = Data::new(UserService::new(SqliteDb::new(pool.clone())));
     let controller = Arc::new(UserControllerImpl::new(service.clone())) as Arc<dyn UserController>;
     
     let app = test::init_service(
@@ -151,6 +160,8 @@ async fn test_get_users() {
         let request = RegisterRequest {
             public_key: format!("VGhlIHN1biBzaGFsbCBzb29uIHNoaW{}l", i),
             username: Some(format!("user{}", i)),
+            identity_commitment: None,
+            message_key: None,
         };
         service.register_user(request).await.unwrap();
     }
@@ -172,7 +183,7 @@ async fn test_get_users_with_limit() {
     let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
     sqlx::migrate!("../db/migrations").run(&pool).await;
     
-    let service = Data::new(UserService::new(pool.clone()));
+    let service = Data::new(UserService::new(SqliteDb::new(pool.clone())));
     let controller = Arc::new(UserControllerImpl::new(service.clone())) as Arc<dyn UserController>;
     
     let app = test::init_service(
@@ -185,6 +196,8 @@ async fn test_get_users_with_limit() {
         let request = RegisterRequest {
             public_key: format!("MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCQEA{}", i),
             username: Some(format!("limited{}", i)),
+            identity_commitment: None,
+            message_key: None,
         };
         service.register_user(request).await.unwrap();
     }
@@ -206,7 +219,7 @@ async fn test_update_user() {
     let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
     sqlx::migrate!("../db/migrations").run(&pool).await;
     
-    let service = Data::new(UserService::new(pool.clone()));
+    let service = Data::new(UserService::new(SqliteDb::new(pool.clone())));
     let controller = Arc::new(UserControllerImpl::new(service.clone())) as Arc<dyn UserController>;
     
     let app = test::init_service(
@@ -218,6 +231,8 @@ async fn test_update_user() {
     let request = RegisterRequest {
         public_key: "MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEA56po".to_string(),
         username: Some("beforeupdate".to_string()),
+        identity_commitment: None,
+        message_key: None,
     };
     
     let response = service.register_user(request).await.unwrap();
@@ -226,6 +241,7 @@ async fn test_update_user() {
     let update_request = UpdateUserRequest {
         new_username: Some("afterupdate".to_string()),
         new_public_key: None,
+        new_message_key: None,
     };
     
     let req = test::TestRequest::patch()
@@ -253,7 +269,7 @@ async fn test_update_user_public_key() {
     let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
     sqlx::migrate!("../db/migrations").run(&pool).await;
     
-    let service = Data::new(UserService::new(pool.clone()));
+    let service = Data::new(UserService::new(SqliteDb::new(pool.clone())));
     let controller = Arc::new(UserControllerImpl::new(service.clone())) as Arc<dyn UserController>;
     
     let app = test::init_service(
@@ -266,6 +282,8 @@ async fn test_update_user_public_key() {
     let request = RegisterRequest {
         public_key: old_public_key.clone(),
         username: Some("keyupdateuser".to_string()),
+        identity_commitment: None,
+        message_key: None,
     };
     
     let response = service.register_user(request).await.unwrap();
@@ -278,6 +296,7 @@ async fn test_update_user_public_key() {
     let update_request = UpdateUserRequest {
         new_username: None,
         new_public_key: Some(new_public_key.clone()),
+        new_message_key: None,
     };
     
     let req = test::TestRequest::patch()
@@ -304,7 +323,7 @@ async fn test_update_user_not_found() {
     let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
     sqlx::migrate!("../db/migrations").run(&pool).await;
     
-    let service = Data::new(UserService::new(pool.clone()));
+    let service = Data::new(UserService::new(SqliteDb::new(pool.clone())));
     let controller = Arc::new(UserControllerImpl::new(service)) as Arc<dyn UserController>;
     
     let app = test::init_service(
@@ -318,6 +337,7 @@ async fn test_update_user_not_found() {
     let update_request = UpdateUserRequest {
         new_username: Some("wontwork".to_string()),
         new_public_key: None,
+        new_message_key: None,
     };
     
     let req = test::TestRequest::patch()
@@ -329,3 +349,112 @@ async fn test_update_user_not_found() {
     
     assert_eq!(resp.status().as_u16(), 404);
 }
+
+#[actix_web::test]
+async fn test_safety_number_and_contact_verification() {
+    let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+    sqlx::migrate!("../db/migrations").run(&pool).await.unwrap();
+
+    let service = Data::new(UserService::new(SqliteDb::new(pool.clone())));
+    let controller = Arc::new(UserControllerImpl::new(service.clone())) as Arc<dyn UserController>;
+
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(controller.clone()))
+            .configure(configure_routes)
+    ).await;
+
+    let alice = service.register_user(RegisterRequest {
+        public_key: "MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAw5VO".to_string(),
+        username: Some("alice".to_string()),
+        identity_commitment: None,
+        message_key: None,
+    }).await.unwrap().user_id;
+    let bob = service.register_user(RegisterRequest {
+        public_key: "MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAxpaK".to_string(),
+        username: Some("bob".to_string()),
+        identity_commitment: None,
+        message_key: None,
+    }).await.unwrap().user_id;
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/api/users/{}/safety-number/{}", alice, bob))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status().as_u16(), 200);
+    let from_alice: SafetyNumberResponse = test::read_body_json(resp).await;
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/api/users/{}/safety-number/{}", bob, alice))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    let from_bob: SafetyNumberResponse = test::read_body_json(resp).await;
+
+    assert_eq!(from_alice, from_bob);
+    assert_eq!(from_alice.safety_number.len(), 60);
+
+    let req = test::TestRequest::put()
+        .uri(&format!("/api/users/{}/verified-contacts/{}", alice, bob))
+        .set_json(&VerifyContactRequest { safety_number: from_alice.safety_number.clone() })
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status().as_u16(), 200);
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/api/users/{}/verified-contacts/{}", alice, bob))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    let status: ContactVerificationResponse = test::read_body_json(resp).await;
+    assert!(status.verified);
+    assert!(!status.key_changed);
+
+    // Bob rotates his key: alice's verification must now be flagged
+    service.update_user(bob, UpdateUserRequest {
+        new_username: None,
+        new_public_key: Some("MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAv6kL".to_string()),
+        new_message_key: None,
+    }).await.unwrap();
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/api/users/{}/verified-contacts/{}", alice, bob))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    let status: ContactVerificationResponse = test::read_body_json(resp).await;
+    assert!(!status.verified);
+    assert!(status.key_changed);
+}
+
+#[actix_web::test]
+async fn test_verify_contact_with_wrong_safety_number() {
+    let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+    sqlx::migrate!("../db/migrations").run(&pool).await.unwrap();
+
+    let service = Data::new(UserService::new(SqliteDb::new(pool.clone())));
+    let controller = Arc::new(UserControllerImpl::new(service.clone())) as Arc<dyn UserController>;
+
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(controller.clone()))
+            .configure(configure_routes)
+    ).await;
+
+    let alice = service.register_user(RegisterRequest {
+        public_key: "MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAw5VO".to_string(),
+        username: Some("alice".to_string()),
+        identity_commitment: None,
+        message_key: None,
+    }).await.unwrap().user_id;
+    let bob = service.register_user(RegisterRequest {
+        public_key: "MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAxpaK".to_string(),
+        username: Some("bob".to_string()),
+        identity_commitment: None,
+        message_key: None,
+    }).await.unwrap().user_id;
+
+    let req = test::TestRequest::put()
+        .uri(&format!("/api/users/{}/verified-contacts/{}", alice, bob))
+        .set_json(&VerifyContactRequest { safety_number: "0".repeat(60) })
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status().as_u16(), 400);
+}
@@ -1,12 +1,29 @@
-use actix_web::{web, App, HttpResponse, HttpServer, Responder};
-//use api::key_generation::generate_keys;
-use db::db::create_db_pool;
+use actix_web::{middleware::from_fn, rt, web, App, HttpResponse, HttpServer, Responder};
+use db::{
+    db::{create_db_pool, SqliteDb},
+    envelope::MessageEnvelope,
+};
 use serde::{Deserialize, Serialize};
-use std::{env, sync::Arc};
-use api::{user::{configure_routes as configure_user_routes, UserController, UserControllerImpl}};
-use service::{user::UserService};
+use std::{env, io::Write, net::IpAddr, sync::Arc, time::Duration};
+use api::{
+    health::{
+        configure_routes as configure_health_routes, DataKeysCheck, DatabaseCheck, JwtKeyCheck,
+        MigrationsCheck, Readiness,
+    },
+    metrics::{self, configure_routes as configure_metrics_routes},
+    rate_limit::{self, default_policies, InMemoryRateLimitStore, RateLimiter},
+    request_id,
+    user::{configure_routes as configure_user_routes, UserController, UserControllerImpl},
+};
+use service::{jobs, membership::AnonymitySet, memory::InMemoryStore, unit_of_work::UnitOfWork, user::{UserRepository, UserService}};
+use shared::{
+    data_encryption::KeyRing,
+    metrics::{register_pool, PoolStats},
+};
 use api::token::TokenControllerImpl;
+use service::token::{repository::TokenRepository, service::TokenService};
 use service::message::{
+    proof::{EphemeralKeyVerifier, MembershipVerifier},
     repository::MessageRepository,
     service::MessageService,
 };
@@ -15,6 +32,7 @@ use api::message::{
     MessageController,
     MessageControllerImpl
 };
+use tracing_subscriber::EnvFilter;
 use utoipauto::utoipauto;
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
@@ -33,39 +51,290 @@ pub struct ApiDoc;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    env::set_var("RUST_LOG", "debug");
-    env_logger::init();
+    init_tracing();
+    let result = run().await;
+    if let Err(err) = &result {
+        tracing::error!(error = %err, "exiting after an error");
+    }
+    // Log lines are written as they happen; this only makes sure nothing is
+    // left in stdout's buffer.
+    std::io::stdout().flush()?;
+    result
+}
 
-    let pool = create_db_pool()
-        .await
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+async fn run() -> std::io::Result<()> {
+    // `STORAGE_BACKEND=memory` keeps everything in process memory, for demos
+    // and local runs without a database file.
+    let key_ring = load_key_ring()?.map(Arc::new);
+    // `/readyz` fails until every check passes; the sqlite backend adds its
+    // own.
+    let readiness = Readiness::new()
+        .with_check(JwtKeyCheck)
+        .with_check(DataKeysCheck(key_ring.clone()));
+    match env::var("STORAGE_BACKEND").as_deref() {
+        Ok("memory") => serve(InMemoryStore::new(), key_ring, readiness).await,
+        Ok("sqlite") | Err(_) => {
+            let pool = create_db_pool()
+                .await
+                .map_err(|e| std::io::Error::other(e.to_string()))?;
+            register_pool({
+                let pool = pool.clone();
+                move || PoolStats {
+                    max: pool.options().get_max_connections(),
+                    open: pool.size(),
+                    idle: pool.num_idle(),
+                }
+            });
+            let readiness = readiness
+                .with_check(DatabaseCheck(pool.clone()))
+                .with_check(MigrationsCheck(pool.clone()));
+
+            // With data encryption keys, message bodies are envelope-encrypted
+            // at rest.
+            let mut store = SqliteDb::new(pool.clone());
+            if let Some(key_ring) = &key_ring {
+                store = store.with_envelope(MessageEnvelope::new(key_ring.clone()));
+            }
+
+            // `seal-messages` encrypts the messages and anonymous messages
+            // stored before the keys were configured, then exits.
+            // `reseal-messages` wraps every message's data key again under
+            // the current key, after which the retired keys can be removed,
+            // then exits.
+            match env::args().nth(1).as_deref() {
+                Some("seal-messages") => {
+                    let sealed = store
+                        .seal_existing_messages(500)
+                        .await
+                        .map_err(|e| std::io::Error::other(e.to_string()))?;
+                    tracing::info!(sealed, "sealed existing messages");
+                    return Ok(());
+                }
+                Some("reseal-messages") => {
+                    let rewrapped = store
+                        .rewrap_sealed_messages(500)
+                        .await
+                        .map_err(|e| std::io::Error::other(e.to_string()))?;
+                    tracing::info!(rewrapped, "rewrapped sealed messages");
+                    return Ok(());
+                }
+                _ => {}
+            }
+            let result = serve(store, key_ring, readiness).await;
+            pool.close().await;
+            tracing::info!("database pool closed");
+            result
+        }
+        Ok(other) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Unknown STORAGE_BACKEND: {other}"),
+        )),
+    }
+}
+
+/// Logs as JSON lines to stdout, filtered by `RUST_LOG`. By default that is
+/// everything at info and above, plus each SQL query with its timing.
+fn init_tracing() {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new("info,sqlx::query=debug"));
+    tracing_subscriber::fmt()
+        .json()
+        .with_env_filter(filter)
+        .with_current_span(true)
+        .with_span_list(true)
+        .init();
+}
+
+/// The keys data at rest is encrypted with, read from the file at
+/// `DATA_KEYS_FILE`, or else from `DATA_KEYS`. Either holds `<key id>:<key>`
+/// entries, separated by commas or newlines, with 32-byte keys in URL-safe
+/// base64; the highest id is the current key. To rotate, add a key with a
+/// higher id and keep the old ones until everything has been re-encrypted;
+/// for messages, that is once `reseal-messages` has run.
+fn load_key_ring() -> std::io::Result<Option<KeyRing>> {
+    let config = match env::var("DATA_KEYS_FILE") {
+        Ok(path) => std::fs::read_to_string(path)?,
+        Err(_) => match env::var("DATA_KEYS") {
+            Ok(config) => config,
+            Err(_) => return Ok(None),
+        },
+    };
+    KeyRing::from_config(&config)
+        .map(Some)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))
+}
+
+/// How often expired refresh tokens are deleted.
+const TOKEN_CLEANUP_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// How long in-flight requests get to finish after SIGTERM or SIGINT; the
+/// connections still open then are dropped. Background jobs get as long again
+/// to finish their current run.
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(30);
+
+/// The port to listen on, from `PORT`, 8080 by default.
+fn port() -> std::io::Result<u16> {
+    match env::var("PORT") {
+        Ok(port) => port.parse().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid PORT: {port}"),
+            )
+        }),
+        Err(_) => Ok(8080),
+    }
+}
+
+/// The proxies whose forwarded client addresses requests are rate limited by,
+/// from `TRUSTED_PROXIES`, a comma-separated list of IP addresses. None by
+/// default, so behind a proxy every client shares the per-IP limits.
+fn trusted_proxies() -> std::io::Result<Vec<IpAddr>> {
+    let Ok(proxies) = env::var("TRUSTED_PROXIES") else {
+        return Ok(Vec::new());
+    };
+    proxies
+        .split(',')
+        .map(str::trim)
+        .filter(|proxy| !proxy.is_empty())
+        .map(|proxy| {
+            proxy.parse().map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid address in TRUSTED_PROXIES: {proxy}"),
+                )
+            })
+        })
+        .collect()
+}
 
-    let user_service = UserService::new(pool.clone());
+/// Resolves at the first SIGTERM or SIGINT, with the signal's name. Both are
+/// listened for from the call on, so neither kills the process any more.
+#[cfg(unix)]
+fn shutdown_signal() -> std::io::Result<impl std::future::Future<Output = &'static str>> {
+    use rt::signal::unix::{signal, SignalKind};
+    use std::{future::poll_fn, task::Poll};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    Ok(poll_fn(move |cx| {
+        if terminate.poll_recv(cx).is_ready() {
+            Poll::Ready("SIGTERM")
+        } else if interrupt.poll_recv(cx).is_ready() {
+            Poll::Ready("SIGINT")
+        } else {
+            Poll::Pending
+        }
+    }))
+}
+
+#[cfg(not(unix))]
+fn shutdown_signal() -> std::io::Result<impl std::future::Future<Output = &'static str>> {
+    Ok(async {
+        let _ = rt::signal::ctrl_c().await;
+        "ctrl-c"
+    })
+}
+
+async fn serve<S>(
+    store: S,
+    key_ring: Option<Arc<KeyRing>>,
+    readiness: Readiness,
+) -> std::io::Result<()>
+where
+    S: UserRepository + MessageRepository + TokenRepository + UnitOfWork + Clone + 'static,
+{
+    let token_service = TokenService::new(store.clone());
+    let token_cleanup = jobs::spawn_periodic("token_cleanup", TOKEN_CLEANUP_PERIOD, move || {
+        let token_service = token_service.clone();
+        async move { token_service.cleanup_expired_tokens().await }
+    });
+
+    let anonymity_set = Arc::new(
+        AnonymitySet::load(&store)
+            .await
+            .map_err(|e| std::io::Error::other(e.to_string()))?,
+    );
+    let mut user_service = UserService::new(store.clone()).with_anonymity_set(anonymity_set.clone());
+    // Without data encryption keys, key backups are refused.
+    if let Some(key_ring) = key_ring {
+        user_service = user_service.with_key_ring(key_ring);
+    }
     let user_controller: Arc<dyn UserController> = Arc::new(UserControllerImpl::new(web::Data::new(user_service)));
 
-    let message_service = web::Data::new(MessageService::new(pool.clone()));
+    // `EPHEMERAL_KEY_VK_PATH` points at a verifying key from the circuit
+    // setup; without it, messages carrying an ephemeral key proof are refused.
+    let mut message_service = MessageService::new(store.clone());
+    if let Ok(path) = env::var("EPHEMERAL_KEY_VK_PATH") {
+        let verifier = EphemeralKeyVerifier::from_bytes(&std::fs::read(path)?)
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        message_service = message_service.with_proof_verifier(verifier);
+    }
+    // Likewise `MEMBERSHIP_VK_PATH` for the membership circuit; without it,
+    // anonymous messages are refused.
+    if let Ok(path) = env::var("MEMBERSHIP_VK_PATH") {
+        let verifier = MembershipVerifier::from_bytes(&std::fs::read(path)?, anonymity_set)
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        message_service = message_service.with_membership_verifier(verifier);
+    }
+    let message_service = web::Data::new(message_service);
     let message_controller = Arc::new(
         MessageControllerImpl::new(message_service)
     ) as Arc<dyn MessageController>;
     
-    HttpServer::new(move || {
+    // Created once, so every worker shares the buckets
+    let rate_limiter = web::Data::new(
+        RateLimiter::new(InMemoryRateLimitStore::new())
+            .with_policies(default_policies())
+            .with_trusted_proxies(trusted_proxies()?),
+    );
+
+    // Signals are handled here rather than by actix, which would stop
+    // without draining on SIGINT.
+    let signal = shutdown_signal()?;
+    let server = HttpServer::new(move || {
         let user_controller = user_controller.clone();
         let message_controller = message_controller.clone();
-        let token_repo = pool.clone();
+        let token_repo = store.clone();
         
         App::new()
+            .wrap(from_fn(rate_limit::limit_requests))
+            .wrap(from_fn(metrics::track_request))
+            .wrap(from_fn(request_id::trace_request))
+            .app_data(rate_limiter.clone())
             .app_data(web::Data::new(user_controller))
             .app_data(web::Data::new(message_controller))
             .configure(configure_message_routes)
             .configure(configure_user_routes)
+            .configure(configure_metrics_routes)
+            .configure(configure_health_routes(readiness.clone()))
             .configure(TokenControllerImpl::configure(token_repo))
             .service(
                 SwaggerUi::new("/swagger-ui/{_:.*}")
                     .url("/api-docs/openapi.json", ApiDoc::openapi()),
             )
-//            .route("/generate-keys", web::post().to(generate_keys))
     })
-    .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+    .disable_signals()
+    .shutdown_timeout(SHUTDOWN_DEADLINE.as_secs())
+    .bind(("127.0.0.1", port()?))?
+    .run();
+
+    // Stopping closes the listeners at once, then waits for the requests in
+    // flight until the deadline.
+    let server_handle = server.handle();
+    let stop = rt::spawn(async move {
+        let signal = signal.await;
+        tracing::info!(signal, "shutting down, draining in-flight requests");
+        server_handle.stop(true).await;
+    });
+    let result = server.await;
+    stop.abort();
+    tracing::info!("server stopped");
+
+    if rt::time::timeout(SHUTDOWN_DEADLINE, token_cleanup.stop())
+        .await
+        .is_err()
+    {
+        tracing::warn!("token cleanup did not stop in time");
+    }
+    result
 }
@@ -0,0 +1,118 @@
+//! Runs the server binary and stops it with SIGTERM while a request is still
+//! being received.
+
+#![cfg(unix)]
+
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Polls `condition` every 50ms until it holds, failing after `timeout`.
+fn wait_for(what: &str, timeout: Duration, mut condition: impl FnMut() -> bool) {
+    let start = Instant::now();
+    while !condition() {
+        assert!(start.elapsed() < timeout, "timed out waiting for {what}");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn start_server(port: u16) -> Child {
+    let mut server = Command::new(env!("CARGO_BIN_EXE_binary"))
+        .env("STORAGE_BACKEND", "memory")
+        .env("PORT", port.to_string())
+        .env("RUST_LOG", "info")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    wait_for("the server to listen", Duration::from_secs(30), || {
+        assert!(server.try_wait().unwrap().is_none(), "the server exited early");
+        TcpStream::connect(("127.0.0.1", port)).is_ok()
+    });
+    server
+}
+
+fn signal(server: &Child, signal: &str) {
+    let status = Command::new("kill")
+        .args([signal, &server.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+#[test]
+fn test_sigterm_drains_in_flight_requests() {
+    let port = free_port();
+    let mut server = start_server(port);
+
+    // Too short a public key, so the request is answered with a 400 once its
+    // body has arrived
+    let body = r#"{"username":"draining","public_key":"short"}"#;
+    let (first_half, second_half) = body.split_at(body.len() / 2);
+    let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(
+        client,
+        "POST /api/users HTTP/1.1\r\n\
+         Host: localhost\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{first_half}",
+        body.len()
+    )
+    .unwrap();
+    client.flush().unwrap();
+    thread::sleep(Duration::from_millis(300));
+
+    signal(&server, "-TERM");
+    wait_for("the listener to close", Duration::from_secs(5), || {
+        TcpStream::connect(("127.0.0.1", port)).is_err()
+    });
+    assert!(server.try_wait().unwrap().is_none(), "the server did not drain");
+
+    client.write_all(second_half.as_bytes()).unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    assert!(
+        response.starts_with("HTTP/1.1 400 Bad Request"),
+        "unexpected response: {response}"
+    );
+
+    let mut status = None;
+    wait_for("the server to exit", Duration::from_secs(10), || {
+        status = server.try_wait().unwrap();
+        status.is_some()
+    });
+    assert!(status.unwrap().success());
+
+    let mut logs = String::new();
+    server.stdout.take().unwrap().read_to_string(&mut logs).unwrap();
+    let shutting_down = logs.find(r#""signal":"SIGTERM""#).expect("shutdown was not logged");
+    let stopped = logs.find("server stopped").expect("the server did not stop");
+    assert!(shutting_down < stopped);
+}
+
+#[test]
+fn test_sigint_stops_an_idle_server() {
+    let port = free_port();
+    let mut server = start_server(port);
+
+    signal(&server, "-INT");
+    let mut status = None;
+    wait_for("the server to exit", Duration::from_secs(10), || {
+        status = server.try_wait().unwrap();
+        status.is_some()
+    });
+    assert!(status.unwrap().success());
+}
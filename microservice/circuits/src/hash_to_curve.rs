@@ -0,0 +1,274 @@
+//! Hashing to edwards25519 as specified by RFC 9380, suite
+//! `edwards25519_XMD:SHA-512_ELL2_RO_`.
+//!
+//! The output is a uniformly distributed point in the prime-order subgroup
+//! whose discrete logarithm with respect to any other point is unknown, which
+//! is what independent Pedersen generators need.
+
+use ark_ec::{twisted_edwards::Affine, AffineRepr, CurveGroup};
+use ark_ed25519::{EdwardsAffine, EdwardsConfig, EdwardsProjective as Ed25519, Fq};
+use ark_ff::{AdditiveGroup, BigInteger, Field, One, PrimeField, Zero};
+use sha2::{Digest, Sha512};
+use subtle::{Choice, ConditionallySelectable, ConstantTimeEq};
+
+/// Suite identifier, to be appended to an application tag to form a DST.
+pub const SUITE_ID: &str = "edwards25519_XMD:SHA-512_ELL2_RO_";
+
+// SHA-512 output and block sizes
+const B_IN_BYTES: usize = 64;
+const S_IN_BYTES: usize = 128;
+
+// Bytes per field element: ceil((ceil(log2(p)) + k) / 8) with k = 128
+const L: usize = 48;
+
+// Montgomery coefficient of curve25519
+const J: u64 = 486662;
+
+/// `hash_to_curve(msg)` from RFC 9380 section 3, under domain separation tag
+/// `dst` (at most 255 bytes).
+pub fn hash_to_curve(msg: &[u8], dst: &[u8]) -> EdwardsAffine {
+    let [u0, u1] = hash_to_field(msg, dst);
+    let q0 = map_to_curve(u0);
+    let q1 = map_to_curve(u1);
+    let r = Ed25519::from(q0) + q1;
+    r.into_affine().mul_by_cofactor()
+}
+
+/// `expand_message_xmd` (RFC 9380 section 5.3.1) with SHA-512.
+fn expand_message_xmd(msg: &[u8], dst: &[u8], len_in_bytes: usize) -> Vec<u8> {
+    assert!(dst.len() <= 255, "DST must be at most 255 bytes");
+    let ell = len_in_bytes.div_ceil(B_IN_BYTES);
+    assert!(ell <= 255 && len_in_bytes <= 65535);
+
+    let dst_len = [dst.len() as u8];
+
+    let b_0 = Sha512::new()
+        .chain_update([0u8; S_IN_BYTES])
+        .chain_update(msg)
+        .chain_update((len_in_bytes as u16).to_be_bytes())
+        .chain_update([0u8])
+        .chain_update(dst)
+        .chain_update(dst_len)
+        .finalize();
+
+    let mut b_i = Sha512::new()
+        .chain_update(b_0)
+        .chain_update([1u8])
+        .chain_update(dst)
+        .chain_update(dst_len)
+        .finalize();
+
+    let mut uniform_bytes = b_i.to_vec();
+    for i in 2..=ell {
+        let xored: Vec<u8> = b_0.iter().zip(b_i.iter()).map(|(a, b)| a ^ b).collect();
+        b_i = Sha512::new()
+            .chain_update(xored)
+            .chain_update([i as u8])
+            .chain_update(dst)
+            .chain_update(dst_len)
+            .finalize();
+        uniform_bytes.extend_from_slice(&b_i);
+    }
+
+    uniform_bytes.truncate(len_in_bytes);
+    uniform_bytes
+}
+
+/// `hash_to_field(msg, 2)` (RFC 9380 section 5.2).
+fn hash_to_field(msg: &[u8], dst: &[u8]) -> [Fq; 2] {
+    let uniform_bytes = expand_message_xmd(msg, dst, 2 * L);
+    [
+        Fq::from_be_bytes_mod_order(&uniform_bytes[..L]),
+        Fq::from_be_bytes_mod_order(&uniform_bytes[L..]),
+    ]
+}
+
+fn sgn0(x: &Fq) -> Choice {
+    Choice::from(x.into_bigint().is_odd() as u8)
+}
+
+fn ct_eq(a: &Fq, b: &Fq) -> Choice {
+    a.into_bigint()
+        .to_bytes_le()
+        .ct_eq(&b.into_bigint().to_bytes_le())
+}
+
+fn cmov(a: &Fq, b: &Fq, choice: Choice) -> Fq {
+    // Select limb by limb so the choice does not leak through a branch
+    let a = a.into_bigint();
+    let b = b.into_bigint();
+    let mut out = a;
+    for (limb, (a, b)) in out.0.iter_mut().zip(a.0.iter().zip(b.0.iter())) {
+        *limb = u64::conditional_select(a, b, choice);
+    }
+    Fq::from_bigint(out).expect("both inputs are canonical")
+}
+
+/// `map_to_curve_elligator2_curve25519` (RFC 9380 appendix G.2.1), returning
+/// the Montgomery point as fractions `(xn, xd, yn, yd)`.
+fn map_to_curve_elligator2_curve25519(u: Fq) -> (Fq, Fq, Fq, Fq) {
+    // c1 = (q + 3) / 8, c4 = (q - 5) / 8
+    let mut c1 = Fq::MODULUS;
+    c1.add_with_carry(&3u64.into());
+    let c1 = c1 >> 3;
+    let mut c4 = Fq::MODULUS;
+    c4.sub_with_borrow(&5u64.into());
+    let c4 = c4 >> 3;
+    let c2 = Fq::from(2u64).pow(c1);
+    let c3 = sqrt_minus_one();
+    let j = Fq::from(J);
+
+    let mut tv1 = u.square();
+    tv1 = tv1.double();
+    let xd = tv1 + Fq::one();
+    let x1n = -j;
+    let mut tv2 = xd.square();
+    let gxd = tv2 * xd;
+    let mut gx1 = j * tv1;
+    gx1 *= x1n;
+    gx1 += tv2;
+    gx1 *= x1n;
+    let mut tv3 = gxd.square();
+    tv2 = tv3.square();
+    tv3 *= gxd;
+    tv3 *= gx1;
+    tv2 *= tv3;
+    let mut y11 = tv2.pow(c4);
+    y11 *= tv3;
+    let y12 = y11 * c3;
+    tv2 = y11.square();
+    tv2 *= gxd;
+    let e1 = ct_eq(&tv2, &gx1);
+    let y1 = cmov(&y12, &y11, e1);
+    let x2n = x1n * tv1;
+    let mut y21 = y11 * u;
+    y21 *= c2;
+    let y22 = y21 * c3;
+    let gx2 = gx1 * tv1;
+    tv2 = y21.square();
+    tv2 *= gxd;
+    let e2 = ct_eq(&tv2, &gx2);
+    let y2 = cmov(&y22, &y21, e2);
+    tv2 = y1.square();
+    tv2 *= gxd;
+    let e3 = ct_eq(&tv2, &gx1);
+    let xn = cmov(&x2n, &x1n, e3);
+    let y = cmov(&y2, &y1, e3);
+    let e4 = sgn0(&y);
+    let y = cmov(&y, &-y, e3 ^ e4);
+
+    (xn, xd, y, Fq::one())
+}
+
+/// `map_to_curve_elligator2_edwards25519` (RFC 9380 appendix G.2.2).
+fn map_to_curve(u: Fq) -> EdwardsAffine {
+    let (xmn, xmd, ymn, ymd) = map_to_curve_elligator2_curve25519(u);
+
+    // c1 = sqrt(-486664) with sgn0(c1) == 0
+    let c1 = {
+        let root = (-Fq::from(J + 2))
+            .sqrt()
+            .expect("-486664 is a square mod p");
+        cmov(&root, &-root, sgn0(&root))
+    };
+
+    let mut xn = xmn * ymd;
+    xn *= c1;
+    let mut xd = xmd * ymn;
+    let mut yn = xmn - xmd;
+    let mut yd = xmn + xmd;
+    let e = ct_eq(&(xd * yd), &Fq::zero());
+    xn = cmov(&xn, &Fq::zero(), e);
+    xd = cmov(&xd, &Fq::one(), e);
+    yn = cmov(&yn, &Fq::one(), e);
+    yd = cmov(&yd, &Fq::one(), e);
+
+    let x = xn * xd.inverse().expect("xd is nonzero");
+    let y = yn * yd.inverse().expect("yd is nonzero");
+    Affine::<EdwardsConfig>::new_unchecked(x, y)
+}
+
+fn sqrt_minus_one() -> Fq {
+    // 2^((p - 1) / 4) is a square root of -1 because 2 is a non-residue
+    let mut exp = Fq::MODULUS;
+    exp.sub_with_borrow(&1u64.into());
+    Fq::from(2u64).pow(exp >> 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ark_ec::PrimeGroup;
+    use ark_ed25519::Fr;
+    use hex_literal::hex;
+
+    // RFC 9380 appendix J.5.1
+    const DST: &[u8] = b"QUUX-V01-CS02-with-edwards25519_XMD:SHA-512_ELL2_RO_";
+
+    fn fq(be_bytes: [u8; 32]) -> Fq {
+        Fq::from_be_bytes_mod_order(&be_bytes)
+    }
+
+    #[test]
+    fn test_hash_to_field_vector() {
+        let [u0, u1] = hash_to_field(b"", DST);
+        assert_eq!(
+            u0,
+            fq(hex!(
+                "03fef4813c8cb5f98c6eef88fae174e6e7d5380de2b007799ac7ee712d203f3a"
+            ))
+        );
+        assert_eq!(
+            u1,
+            fq(hex!(
+                "780bdddd137290c8f589dc687795aafae35f6b674668d92bf92ae793e6a60c75"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_hash_to_curve_vector_empty_message() {
+        let expected = EdwardsAffine::new(
+            fq(hex!(
+                "3c3da6925a3c3c268448dcabb47ccde5439559d9599646a8260e47b1e4822fc6"
+            )),
+            fq(hex!(
+                "09a6c8561a0b22bef63124c588ce4c62ea83a3c899763af26d795302e115dc21"
+            )),
+        );
+        assert_eq!(hash_to_curve(b"", DST), expected);
+    }
+
+    #[test]
+    fn test_hash_to_curve_vector_abc() {
+        let expected = EdwardsAffine::new(
+            fq(hex!(
+                "608040b42285cc0d72cbb3985c6b04c935370c7361f4b7fbdb1ae7f8c1a8ecad"
+            )),
+            fq(hex!(
+                "1a8395b88338f22e435bbd301183e7f20a5f9de643f11882fb237f88268a5531"
+            )),
+        );
+        assert_eq!(hash_to_curve(b"abc", DST), expected);
+    }
+
+    #[test]
+    fn test_output_is_in_prime_order_subgroup() {
+        for msg in [&b""[..], b"abc", b"abcdef0123456789"] {
+            let p = hash_to_curve(msg, DST);
+            assert!(p.is_on_curve());
+            assert!(p.is_in_correct_subgroup_assuming_on_curve());
+            assert!(!p.is_zero());
+            assert!(Ed25519::from(p).mul_bigint(Fr::MODULUS).is_zero());
+        }
+    }
+
+    #[test]
+    fn test_domain_separation() {
+        assert_ne!(
+            hash_to_curve(b"abc", DST),
+            hash_to_curve(b"abc", b"other-DST")
+        );
+        assert_ne!(hash_to_curve(b"abc", DST), hash_to_curve(b"abd", DST));
+    }
+}
@@ -1,2 +1,6 @@
+pub mod hash_to_curve;
 pub mod message_preparation;
+pub mod membership;
 pub mod message_protocol;
+pub mod prover;
+pub mod ratchet;
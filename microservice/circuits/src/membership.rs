@@ -0,0 +1,434 @@
+//! Anonymous authorship: a Groth16 proof that the sender owns one of the
+//! leaves of the server's Merkle tree of registered users, without revealing
+//! which one.
+//!
+//! A user picks a random [`Identity`] secret and registers its commitment
+//! alongside their public key; the server appends
+//! `leaf(public_key_hash, commitment)` to its [`MembershipTree`]. To write
+//! anonymously the user proves knowledge of a secret whose leaf is under the
+//! published root, and reveals a nullifier for the current scope, typically an
+//! epoch and recipient.
+//!
+//! Nullifiers follow the rate-limiting nullifier construction: in each scope
+//! the secret is the intercept of a line with slope `H(secret, scope)`, and
+//! every message reveals one point on it, `share = secret + slope * x` at the
+//! message's `x`. The nullifier `H(slope)` is the same for every message in
+//! the scope, so the server can link them without learning who wrote; two
+//! messages with different `x` give two points, from which
+//! [`recover_secret`] recovers the sender's secret.
+//!
+//! All hashing is Poseidon over the BLS12-381 scalar field, through the
+//! `ark-crypto-primitives` CRH and its gadget.
+
+use crate::prover::{Proof, ProverError, ProvingKey, VerifyingKey};
+use ark_bls12_381::Bls12_381;
+use ark_crypto_primitives::crh::poseidon::constraints::{
+    CRHGadget, CRHParametersVar, TwoToOneCRHGadget,
+};
+use ark_crypto_primitives::crh::poseidon::{TwoToOneCRH, CRH};
+use ark_crypto_primitives::crh::{
+    CRHScheme, CRHSchemeGadget, TwoToOneCRHScheme, TwoToOneCRHSchemeGadget,
+};
+use ark_crypto_primitives::sponge::poseidon::{find_poseidon_ark_and_mds, PoseidonConfig};
+use ark_ff::{Field, PrimeField, UniformRand, Zero};
+use ark_groth16::Groth16;
+use ark_r1cs_std::{fields::fp::FpVar, prelude::*};
+use ark_relations::r1cs::{ConstraintSynthesizer, ConstraintSystemRef, SynthesisError};
+use ark_snark::SNARK;
+use ark_std::rand::{CryptoRng, Rng, RngCore};
+use blake2::{Blake2b512, Digest};
+use std::sync::OnceLock;
+
+#[cfg(test)]
+#[path = "membership.test.rs"]
+mod tests;
+
+/// The field every hash, root and nullifier in this module lives in.
+pub use ark_bls12_381::Fr;
+
+/// Depth of the membership tree, which therefore holds up to 2^20 users.
+pub const TREE_DEPTH: usize = 20;
+
+// Domain tags keeping the fixed-arity hashes apart
+const COMMITMENT_TAG: u64 = 1;
+const LEAF_TAG: u64 = 2;
+const SLOPE_TAG: u64 = 3;
+const NULLIFIER_TAG: u64 = 4;
+const SCOPE_TAG: u64 = 5;
+
+/// Poseidon with width 3, x^5 S-boxes and 8 full / 57 partial rounds, the
+/// recommended instance for a 255-bit field at the 128-bit security level.
+pub fn poseidon_config() -> &'static PoseidonConfig<Fr> {
+    static CONFIG: OnceLock<PoseidonConfig<Fr>> = OnceLock::new();
+    CONFIG.get_or_init(|| {
+        let (full_rounds, partial_rounds, rate) = (8, 57, 2);
+        let (ark, mds) = find_poseidon_ark_and_mds::<Fr>(
+            Fr::MODULUS_BIT_SIZE as u64,
+            rate,
+            full_rounds as u64,
+            partial_rounds as u64,
+            0,
+        );
+        PoseidonConfig::new(full_rounds, partial_rounds, 5, mds, ark, rate, 1)
+    })
+}
+
+fn hash(input: &[Fr]) -> Fr {
+    CRH::<Fr>::evaluate(poseidon_config(), input).expect("Poseidon accepts any input length")
+}
+
+fn hash_nodes(left: &Fr, right: &Fr) -> Fr {
+    TwoToOneCRH::<Fr>::compress(poseidon_config(), left, right)
+        .expect("Poseidon accepts any input length")
+}
+
+/// Maps the raw bytes of a registered `public_key_hash` into the field.
+pub fn public_key_hash_to_field(public_key_hash: &[u8]) -> Fr {
+    Fr::from_le_bytes_mod_order(public_key_hash)
+}
+
+/// The tree leaf for a registered user.
+pub fn leaf(public_key_hash: Fr, identity_commitment: Fr) -> Fr {
+    hash(&[Fr::from(LEAF_TAG), public_key_hash, identity_commitment])
+}
+
+/// The scope nullifiers are computed in for messages to `recipient_id`
+/// during `epoch`, so each member gets one message per recipient per epoch.
+pub fn epoch_scope(epoch: u64, recipient_id: &[u8; 16]) -> Fr {
+    hash(&[
+        Fr::from(SCOPE_TAG),
+        Fr::from(epoch),
+        Fr::from_le_bytes_mod_order(recipient_id),
+    ])
+}
+
+/// The `x` coordinate a message's share is evaluated at. Both sides derive it
+/// from the message itself, so a sender can't reuse one `x` for two messages.
+pub fn message_to_field(message: &[u8]) -> Fr {
+    Fr::from_le_bytes_mod_order(&Blake2b512::digest(message))
+}
+
+/// Recovers the secret from two shares revealed in the same scope, or `None`
+/// if they were taken at the same `x` and so are the same point.
+pub fn recover_secret(first: (Fr, Fr), second: (Fr, Fr)) -> Option<Fr> {
+    let ((x1, y1), (x2, y2)) = (first, second);
+    let slope = (y2 - y1) * (x2 - x1).inverse()?;
+    Some(y1 - slope * x1)
+}
+
+/// The secret behind a user's anonymous credential. Only its commitment is
+/// ever sent to the server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    secret: Fr,
+}
+
+impl Identity {
+    pub fn generate<R: Rng>(rng: &mut R) -> Self {
+        Self::from_secret(Fr::rand(rng))
+    }
+
+    pub fn from_secret(secret: Fr) -> Self {
+        Self { secret }
+    }
+
+    pub fn secret(&self) -> Fr {
+        self.secret
+    }
+
+    /// The value registered with the server.
+    pub fn commitment(&self) -> Fr {
+        hash(&[Fr::from(COMMITMENT_TAG), self.secret])
+    }
+
+    /// The tag revealed with every anonymous message in `scope`.
+    pub fn nullifier(&self, scope: Fr) -> Fr {
+        hash(&[Fr::from(NULLIFIER_TAG), self.slope(scope)])
+    }
+
+    /// The point on this scope's line revealed with a message at `x`.
+    pub fn share(&self, scope: Fr, x: Fr) -> Fr {
+        self.secret + self.slope(scope) * x
+    }
+
+    fn slope(&self, scope: Fr) -> Fr {
+        hash(&[Fr::from(SLOPE_TAG), self.secret, scope])
+    }
+}
+
+/// The siblings from a leaf up to the root. Bit `i` of `index` is set when
+/// the node at level `i` is a right child.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MerklePath {
+    pub index: u64,
+    pub siblings: Vec<Fr>,
+}
+
+impl MerklePath {
+    /// The root reached by hashing `leaf` up along this path.
+    pub fn root(&self, leaf: Fr) -> Fr {
+        self.siblings
+            .iter()
+            .enumerate()
+            .fold(leaf, |node, (level, sibling)| {
+                if self.index >> level & 1 == 1 {
+                    hash_nodes(sibling, &node)
+                } else {
+                    hash_nodes(&node, sibling)
+                }
+            })
+    }
+}
+
+/// An append-only Merkle tree of fixed depth [`TREE_DEPTH`] whose empty
+/// leaves are zero. Only the filled part of each level is stored.
+#[derive(Clone, Debug)]
+pub struct MembershipTree {
+    levels: Vec<Vec<Fr>>,
+}
+
+impl Default for MembershipTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MembershipTree {
+    pub fn new() -> Self {
+        Self {
+            levels: vec![Vec::new(); TREE_DEPTH + 1],
+        }
+    }
+
+    pub fn from_leaves(leaves: impl IntoIterator<Item = Fr>) -> Self {
+        let mut tree = Self::new();
+        for leaf in leaves {
+            tree.insert(leaf);
+        }
+        tree
+    }
+
+    pub fn len(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn leaves(&self) -> &[Fr] {
+        &self.levels[0]
+    }
+
+    /// Appends `leaf` and returns its index.
+    ///
+    /// # Panics
+    /// If the tree already holds 2^[`TREE_DEPTH`] leaves.
+    pub fn insert(&mut self, leaf: Fr) -> usize {
+        let index = self.len();
+        assert!(index < 1 << TREE_DEPTH, "Membership tree is full");
+
+        self.levels[0].push(leaf);
+        let mut position = index;
+        for level in 0..TREE_DEPTH {
+            let parent = position / 2;
+            let left = self.node(level, parent * 2);
+            let right = self.node(level, parent * 2 + 1);
+            let hashed = hash_nodes(&left, &right);
+
+            let above = &mut self.levels[level + 1];
+            if parent < above.len() {
+                above[parent] = hashed;
+            } else {
+                above.push(hashed);
+            }
+            position = parent;
+        }
+
+        index
+    }
+
+    pub fn root(&self) -> Fr {
+        self.node(TREE_DEPTH, 0)
+    }
+
+    pub fn path(&self, index: usize) -> Option<MerklePath> {
+        if index >= self.len() {
+            return None;
+        }
+
+        let siblings = (0..TREE_DEPTH)
+            .map(|level| self.node(level, (index >> level) ^ 1))
+            .collect();
+
+        Some(MerklePath {
+            index: index as u64,
+            siblings,
+        })
+    }
+
+    fn node(&self, level: usize, position: usize) -> Fr {
+        self.levels[level]
+            .get(position)
+            .copied()
+            .unwrap_or_else(|| empty_subtree_root(level))
+    }
+}
+
+/// Root of a subtree of height `level` with only empty leaves.
+fn empty_subtree_root(level: usize) -> Fr {
+    static ROOTS: OnceLock<Vec<Fr>> = OnceLock::new();
+    ROOTS.get_or_init(|| {
+        let mut roots = vec![Fr::zero()];
+        for level in 0..TREE_DEPTH {
+            roots.push(hash_nodes(&roots[level], &roots[level]));
+        }
+        roots
+    })[level]
+}
+
+/// Proves that the holder of `secret` registered `public_key_hash` under
+/// `root`, and that `nullifier` and the share `y` at `x` were derived from the
+/// same secret in `scope`.
+pub struct MembershipCircuit {
+    // Public inputs
+    pub root: Option<Fr>,
+    pub scope: Option<Fr>,
+    pub nullifier: Option<Fr>,
+    pub x: Option<Fr>,
+    pub y: Option<Fr>,
+    // Witnesses
+    pub secret: Option<Fr>,
+    pub public_key_hash: Option<Fr>,
+    pub path: Option<MerklePath>,
+}
+
+impl MembershipCircuit {
+    /// An instance with no assignments, used for key generation.
+    pub fn blank() -> Self {
+        Self {
+            root: None,
+            scope: None,
+            nullifier: None,
+            x: None,
+            y: None,
+            secret: None,
+            public_key_hash: None,
+            path: None,
+        }
+    }
+
+    /// The public inputs the verifier supplies, in allocation order.
+    pub fn public_inputs(root: Fr, scope: Fr, nullifier: Fr, x: Fr, y: Fr) -> Vec<Fr> {
+        vec![root, scope, nullifier, x, y]
+    }
+}
+
+impl ConstraintSynthesizer<Fr> for MembershipCircuit {
+    fn generate_constraints(self, cs: ConstraintSystemRef<Fr>) -> Result<(), SynthesisError> {
+        let params = CRHParametersVar::new_constant(cs.clone(), poseidon_config())?;
+        let missing = || SynthesisError::AssignmentMissing;
+
+        let root = FpVar::new_input(cs.clone(), || self.root.ok_or_else(missing))?;
+        let scope = FpVar::new_input(cs.clone(), || self.scope.ok_or_else(missing))?;
+        let nullifier = FpVar::new_input(cs.clone(), || self.nullifier.ok_or_else(missing))?;
+        let x = FpVar::new_input(cs.clone(), || self.x.ok_or_else(missing))?;
+        let y = FpVar::new_input(cs.clone(), || self.y.ok_or_else(missing))?;
+
+        let secret = FpVar::new_witness(cs.clone(), || self.secret.ok_or_else(missing))?;
+        let public_key_hash =
+            FpVar::new_witness(cs.clone(), || self.public_key_hash.ok_or_else(missing))?;
+        let path = self.path.as_ref();
+        let index_bits = (0..TREE_DEPTH)
+            .map(|level| {
+                Boolean::new_witness(cs.clone(), || {
+                    path.map(|path| path.index >> level & 1 == 1)
+                        .ok_or_else(missing)
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let siblings = (0..TREE_DEPTH)
+            .map(|level| {
+                FpVar::new_witness(cs.clone(), || {
+                    path.and_then(|path| path.siblings.get(level).copied())
+                        .ok_or_else(missing)
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let tag = |value: u64| FpVar::constant(Fr::from(value));
+
+        // leaf = H(LEAF_TAG, public_key_hash, H(COMMITMENT_TAG, secret))
+        let commitment = CRHGadget::evaluate(&params, &[tag(COMMITMENT_TAG), secret.clone()])?;
+        let leaf = CRHGadget::evaluate(&params, &[tag(LEAF_TAG), public_key_hash, commitment])?;
+
+        // The leaf hashes up to the public root
+        let mut node = leaf;
+        for (bit, sibling) in index_bits.iter().zip(&siblings) {
+            let left = bit.select(sibling, &node)?;
+            let right = bit.select(&node, sibling)?;
+            node = TwoToOneCRHGadget::compress(&params, &left, &right)?;
+        }
+        node.enforce_equal(&root)?;
+
+        // slope = H(SLOPE_TAG, secret, scope), nullifier = H(NULLIFIER_TAG, slope)
+        let slope = CRHGadget::evaluate(&params, &[tag(SLOPE_TAG), secret.clone(), scope])?;
+        CRHGadget::evaluate(&params, &[tag(NULLIFIER_TAG), slope.clone()])?
+            .enforce_equal(&nullifier)?;
+
+        // y = secret + slope * x
+        (secret + slope * x).enforce_equal(&y)?;
+
+        Ok(())
+    }
+}
+
+/// Runs the circuit-specific setup and returns the proving and verifying keys.
+pub fn setup<R: RngCore + CryptoRng>(
+    rng: &mut R,
+) -> Result<(ProvingKey, VerifyingKey), ProverError> {
+    Ok(Groth16::<Bls12_381>::circuit_specific_setup(
+        MembershipCircuit::blank(),
+        rng,
+    )?)
+}
+
+/// Proves that `identity` registered `public_key_hash` at the leaf `path`
+/// leads from, revealing the nullifier for `scope` and the share at `x`.
+pub fn prove<R: RngCore + CryptoRng>(
+    pk: &ProvingKey,
+    identity: &Identity,
+    public_key_hash: Fr,
+    path: &MerklePath,
+    scope: Fr,
+    x: Fr,
+    rng: &mut R,
+) -> Result<Proof, ProverError> {
+    let leaf = leaf(public_key_hash, identity.commitment());
+    let circuit = MembershipCircuit {
+        root: Some(path.root(leaf)),
+        scope: Some(scope),
+        nullifier: Some(identity.nullifier(scope)),
+        x: Some(x),
+        y: Some(identity.share(scope, x)),
+        secret: Some(identity.secret()),
+        public_key_hash: Some(public_key_hash),
+        path: Some(path.clone()),
+    };
+
+    Ok(Groth16::<Bls12_381>::prove(pk, circuit, rng)?)
+}
+
+/// Checks `proof` against the public inputs, where `(x, y)` is the share.
+pub fn verify(
+    vk: &VerifyingKey,
+    root: Fr,
+    scope: Fr,
+    nullifier: Fr,
+    x: Fr,
+    y: Fr,
+    proof: &Proof,
+) -> Result<bool, ProverError> {
+    let inputs = MembershipCircuit::public_inputs(root, scope, nullifier, x, y);
+    Ok(Groth16::<Bls12_381>::verify(vk, &inputs, proof)?)
+}
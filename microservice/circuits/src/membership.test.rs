@@ -0,0 +1,217 @@
+use super::*;
+use crate::prover::{from_bytes, to_bytes};
+use ark_relations::r1cs::ConstraintSystem;
+use ark_std::rand::{rngs::StdRng, SeedableRng};
+use std::sync::OnceLock;
+
+// Groth16 requires a `CryptoRng`, which `ark_std::test_rng` is not
+fn test_rng() -> StdRng {
+    StdRng::seed_from_u64(0x5eed)
+}
+
+// Setup dominates the test time, so the proving tests share one key pair
+fn keys() -> &'static (ProvingKey, VerifyingKey) {
+    static KEYS: OnceLock<(ProvingKey, VerifyingKey)> = OnceLock::new();
+    KEYS.get_or_init(|| setup(&mut test_rng()).unwrap())
+}
+
+struct Member {
+    identity: Identity,
+    public_key_hash: Fr,
+    index: usize,
+}
+
+/// A tree of `size` registered users, returning the one at `member`.
+fn registered(size: usize, member: usize) -> (MembershipTree, Member) {
+    let mut rng = test_rng();
+    let mut tree = MembershipTree::new();
+    let mut chosen = None;
+
+    for index in 0..size {
+        let identity = Identity::generate(&mut rng);
+        let public_key_hash = Fr::rand(&mut rng);
+        tree.insert(leaf(public_key_hash, identity.commitment()));
+        if index == member {
+            chosen = Some(Member {
+                identity,
+                public_key_hash,
+                index,
+            });
+        }
+    }
+
+    (tree, chosen.unwrap())
+}
+
+fn circuit(tree: &MembershipTree, member: &Member, scope: Fr) -> MembershipCircuit {
+    let x = message_to_field(b"message");
+    MembershipCircuit {
+        root: Some(tree.root()),
+        scope: Some(scope),
+        nullifier: Some(member.identity.nullifier(scope)),
+        x: Some(x),
+        y: Some(member.identity.share(scope, x)),
+        secret: Some(member.identity.secret()),
+        public_key_hash: Some(member.public_key_hash),
+        path: tree.path(member.index),
+    }
+}
+
+fn is_satisfied(circuit: MembershipCircuit) -> bool {
+    let cs = ConstraintSystem::new_ref();
+    circuit.generate_constraints(cs.clone()).unwrap();
+    cs.is_satisfied().unwrap()
+}
+
+#[test]
+fn test_empty_tree_root_is_all_zero_leaves() {
+    let tree = MembershipTree::new();
+    let path = MerklePath {
+        index: 0,
+        siblings: (0..TREE_DEPTH).map(empty_subtree_root).collect(),
+    };
+
+    assert!(tree.is_empty());
+    assert_eq!(tree.root(), path.root(Fr::zero()));
+}
+
+#[test]
+fn test_paths_lead_to_root() {
+    let (tree, _) = registered(5, 0);
+
+    for index in 0..tree.len() {
+        let path = tree.path(index).unwrap();
+        assert_eq!(path.root(tree.leaves()[index]), tree.root());
+    }
+    assert!(tree.path(tree.len()).is_none());
+}
+
+#[test]
+fn test_insert_changes_root_and_rebuild_matches() {
+    let (mut tree, _) = registered(3, 0);
+    let before = tree.root();
+
+    assert_eq!(tree.insert(Fr::from(7u64)), 3);
+    assert_ne!(tree.root(), before);
+
+    let rebuilt = MembershipTree::from_leaves(tree.leaves().to_vec());
+    assert_eq!(rebuilt.root(), tree.root());
+}
+
+#[test]
+fn test_nullifier_is_per_scope() {
+    let identity = Identity::generate(&mut test_rng());
+    let other = Identity::generate(&mut StdRng::seed_from_u64(1));
+    let (a, b) = (Fr::from(1u64), Fr::from(2u64));
+
+    assert_eq!(identity.nullifier(a), identity.nullifier(a));
+    assert_ne!(identity.nullifier(a), identity.nullifier(b));
+    assert_ne!(identity.nullifier(a), other.nullifier(a));
+    assert_ne!(identity.nullifier(Fr::zero()), identity.commitment());
+}
+
+#[test]
+fn test_two_shares_recover_secret() {
+    let identity = Identity::generate(&mut test_rng());
+    let scope = epoch_scope(7, &[1; 16]);
+    let (x1, x2) = (message_to_field(b"first"), message_to_field(b"second"));
+    let first = (x1, identity.share(scope, x1));
+    let second = (x2, identity.share(scope, x2));
+
+    assert_eq!(recover_secret(first, second), Some(identity.secret()));
+    assert_eq!(recover_secret(first, first), None);
+
+    // Shares from different scopes lie on different lines
+    let other = (x2, identity.share(epoch_scope(8, &[1; 16]), x2));
+    assert_ne!(recover_secret(first, other), Some(identity.secret()));
+}
+
+#[test]
+fn test_epoch_scope_differs_per_epoch_and_recipient() {
+    let scope = epoch_scope(1, &[1; 16]);
+    assert_ne!(scope, epoch_scope(2, &[1; 16]));
+    assert_ne!(scope, epoch_scope(1, &[2; 16]));
+}
+
+#[test]
+fn test_message_to_field_differs_per_message() {
+    assert_eq!(message_to_field(b"a"), message_to_field(b"a"));
+    assert_ne!(message_to_field(b"a"), message_to_field(b"b"));
+}
+
+#[test]
+fn test_member_satisfies_circuit() {
+    let (tree, member) = registered(4, 2);
+    assert!(is_satisfied(circuit(&tree, &member, Fr::from(9u64))));
+}
+
+#[test]
+fn test_non_member_fails() {
+    let (tree, mut member) = registered(4, 2);
+    member.identity = Identity::generate(&mut StdRng::seed_from_u64(1));
+    assert!(!is_satisfied(circuit(&tree, &member, Fr::from(9u64))));
+}
+
+#[test]
+fn test_wrong_public_key_hash_fails() {
+    let (tree, mut member) = registered(4, 2);
+    member.public_key_hash += Fr::from(1u64);
+    assert!(!is_satisfied(circuit(&tree, &member, Fr::from(9u64))));
+}
+
+#[test]
+fn test_wrong_nullifier_fails() {
+    let (tree, member) = registered(4, 2);
+    let mut circuit = circuit(&tree, &member, Fr::from(9u64));
+    circuit.nullifier = Some(member.identity.nullifier(Fr::from(10u64)));
+    assert!(!is_satisfied(circuit));
+}
+
+#[test]
+fn test_wrong_share_fails() {
+    let (tree, member) = registered(4, 2);
+    let mut circuit = circuit(&tree, &member, Fr::from(9u64));
+    circuit.y = circuit.y.map(|y| y + Fr::from(1u64));
+    assert!(!is_satisfied(circuit));
+}
+
+#[test]
+fn test_wrong_root_fails() {
+    let (tree, member) = registered(4, 2);
+    let mut circuit = circuit(&tree, &member, Fr::from(9u64));
+    circuit.root = Some(MembershipTree::new().root());
+    assert!(!is_satisfied(circuit));
+}
+
+#[test]
+fn test_prove_and_verify() {
+    let (pk, vk) = keys();
+    let (tree, member) = registered(3, 1);
+    let scope = epoch_scope(4, &[4; 16]);
+    let x = message_to_field(b"message");
+    let path = tree.path(member.index).unwrap();
+
+    let proof = prove(
+        pk,
+        &member.identity,
+        member.public_key_hash,
+        &path,
+        scope,
+        x,
+        &mut test_rng(),
+    )
+    .unwrap();
+    let nullifier = member.identity.nullifier(scope);
+    let y = member.identity.share(scope, x);
+    let one = Fr::from(1u64);
+
+    assert!(verify(vk, tree.root(), scope, nullifier, x, y, &proof).unwrap());
+    // The proof is bound to the scope, the nullifier and the share
+    assert!(!verify(vk, tree.root(), epoch_scope(5, &[4; 16]), nullifier, x, y, &proof).unwrap());
+    assert!(!verify(vk, tree.root(), scope, nullifier + one, x, y, &proof).unwrap());
+    assert!(!verify(vk, tree.root(), scope, nullifier, x + one, y, &proof).unwrap());
+    assert!(!verify(vk, tree.root(), scope, nullifier, x, y + one, &proof).unwrap());
+
+    let proof: Proof = from_bytes(&to_bytes(&proof).unwrap()).unwrap();
+    assert!(verify(vk, tree.root(), scope, nullifier, x, y, &proof).unwrap());
+}
@@ -1,18 +1,22 @@
+use crate::hash_to_curve::hash_to_curve;
 use aes_gcm::{
-    aead::{Aead, KeyInit},
+    aead::{Aead, KeyInit, Payload},
     Aes256Gcm, Nonce,
 };
 use ark_ec::twisted_edwards::TECurveConfig;
 use ark_ec::{twisted_edwards::Projective as TEProjective, AffineRepr, CurveGroup, PrimeGroup};
 use ark_ed25519::{EdwardsAffine, EdwardsConfig, EdwardsProjective as Ed25519, Fr};
+use ark_ed_on_bls12_381::{EdwardsProjective as Jubjub, Fr as JubjubFr};
 use ark_ff::{Field, PrimeField, UniformRand};
 use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
-use ark_std::rand::thread_rng;
-use ark_std::Zero;
+use ark_std::{rand::thread_rng, Zero};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use blake2::Blake2b512;
 use hex_literal::hex;
+use hkdf::Hkdf;
 use sha2::{Digest, Sha256};
 use std::ops::Mul;
+use std::sync::OnceLock;
 use subtle::{Choice, ConstantTimeEq};
 
 #[cfg(test)]
@@ -20,13 +24,13 @@ use subtle::{Choice, ConstantTimeEq};
 mod tests;
 
 struct UserB {
-    esk: Fr,
-    epub: Ed25519,
+    esk: JubjubFr,
+    epub: Jubjub,
 }
 
 #[derive(Debug)]
 struct DerivedKeys {
-    randomness: [u8; 32], // r for commitment
+    randomness: [u8; 64], // r for commitment, reduced mod the group order
     enc_key: [u8; 32],    // K for encryption
 }
 
@@ -36,7 +40,51 @@ struct Ciphertext {
     encrypted: Vec<u8>, // Encrypted message with authentication tag
 }
 
-#[derive(Clone, Debug)]
+/// Where a message belongs. It is bound into both the key derivation and the
+/// AES-GCM associated data, so a ciphertext only opens in the conversation and
+/// thread it was sealed for.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MessageContext {
+    /// Sender's user id as raw UUID bytes
+    pub sender_id: [u8; 16],
+    /// Recipient's user id as raw UUID bytes
+    pub recipient_id: [u8; 16],
+    /// Message being replied to, if any
+    pub parent_id: Option<i64>,
+}
+
+impl MessageContext {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(16 + 16 + 9);
+        bytes.extend_from_slice(&self.sender_id);
+        bytes.extend_from_slice(&self.recipient_id);
+        match self.parent_id {
+            Some(parent_id) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&parent_id.to_le_bytes());
+            }
+            None => bytes.push(0),
+        }
+        bytes
+    }
+
+    /// AES-GCM associated data: the protocol version followed by the context.
+    fn associated_data(&self) -> Vec<u8> {
+        let mut aad = AAD_LABEL.to_vec();
+        aad.extend_from_slice(&self.to_bytes());
+        aad
+    }
+}
+
+// HKDF info strings and AAD label; bump the version when the derivation changes
+const RANDOMNESS_INFO: &[u8] = b"anon-messaging/v1/commitment-randomness";
+const ENC_KEY_INFO: &[u8] = b"anon-messaging/v1/aes-256-gcm-key";
+const AAD_LABEL: &[u8] = b"anon-messaging/v1/aad";
+
+/// Domain separation tag for deriving the Pedersen generator H.
+pub const PEDERSEN_H_DST: &[u8] = b"anon-messaging-v1-pedersen-h_edwards25519_XMD:SHA-512_ELL2_RO_";
+
+#[derive(Clone, Debug, PartialEq, Eq, CanonicalSerialize, CanonicalDeserialize)]
 pub struct PedersenParams {
     /// Base generator G
     pub g: EdwardsAffine,
@@ -65,183 +113,191 @@ pub enum CommitmentError {
     InvalidParameters,
 }
 
-impl PedersenParams {
-    /// Initialize Pedersen parameters using standardized generators
-    pub fn new() -> Self {
-        let mut rng = thread_rng();
-        // Base generator G is the standard Ed25519 generator
-        let g = EdwardsAffine::generator();
+/// Errors from [`seal`], [`open`] and envelope decoding.
+#[derive(Debug, thiserror::Error)]
+pub enum SealError {
+    #[error("Malformed sealed message envelope")]
+    MalformedEnvelope,
 
-        let h = EdwardsAffine::rand(&mut rng);
+    #[error("Unsupported sealed message version {0}")]
+    UnsupportedVersion(u8),
 
-        Self { g, h }
-    }
+    #[error("Encryption failed")]
+    EncryptionFailed,
 
-    /// Check if point has large order (not in small subgroup)
-    fn has_large_order(point: &EdwardsAffine) -> bool {
-        // Ed25519 cofactor is 8
-        let eight = Fr::from(8u64);
-        let eight_times_point = Ed25519::from(*point).mul(eight);
-        !eight_times_point.is_zero()
-    }
+    #[error("Decryption failed")]
+    DecryptionFailed,
 
-    /// Hash to curve implementation following RFC 9380
-    fn hash_to_curve(hash: &[u8]) -> Option<EdwardsAffine> {
-        // In production, use a proper hash-to-curve implementation
-        // This is a placeholder showing the concept
-        let mut field_elem = Fr::zero();
-        if Fr::deserialize_compressed(hash).is_ok() {
-            let point = Ed25519::generator().mul(field_elem);
-            Some(point.into_affine())
-        } else {
-            None
-        }
-    }
+    #[error("Commitment does not match the ciphertext")]
+    CommitmentMismatch,
 
-    /// Generate the secondary generator H using nothing-up-my-sleeve process
-    fn generate_h(g: &EdwardsAffine) -> EdwardsAffine {
-        let mut g_bytes = Vec::new();
-        g.serialize_uncompressed(&mut g_bytes)
-            .expect("Serialization of base point failed");
+    #[error("Malformed commitment or opening")]
+    MalformedCommitment,
 
-        let mut counter = 0u64;
-        let max_attempts = 1000; // Maximum number of attempts to find a valid H
+    #[error("Malformed public key")]
+    MalformedKey,
 
-        loop {
-            if counter >= max_attempts {
-                panic!("Failed to generate H after {} attempts", max_attempts);
-            }
+    #[error(transparent)]
+    Commitment(#[from] CommitmentError),
+}
 
-            let mut hasher = Blake2b512::new();
-            hasher.update(b"ed25519_pedersen_h");
-            hasher.update(&g_bytes);
-            hasher.update(&counter.to_le_bytes());
-            let hash = hasher.finalize();
-
-            // Try to map hash to curve point
-            if let Some(h) = Self::hash_to_curve(&hash) {
-                // Multiply by cofactor to ensure the point is in the prime-order subgroup
-                let h_prime = h.mul_by_cofactor();
-
-                // Verify h_prime is not the identity
-                if !h_prime.is_zero() {
-                    return h_prime;
-                }
-            }
+/// An encrypted message as it travels from sender to recipient: the sender's
+/// ephemeral public key, the AES-GCM nonce and ciphertext, and a Pedersen
+/// commitment to the ciphertext.
+///
+/// The key agreement runs on Jubjub, the curve [`crate::prover`] proves
+/// statements about, so a sender can prove they know the secret behind
+/// `epub`. The commitment stays on edwards25519.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SealedMessage {
+    pub epub: Jubjub,
+    pub nonce: [u8; 12],
+    pub ciphertext: Vec<u8>,
+    pub commitment: Ed25519,
+}
 
-            counter += 1;
-        }
+impl PedersenParams {
+    /// Same as [`PedersenParams::standard`]; every process must commit with
+    /// the same generators for commitments to verify elsewhere.
+    pub fn new() -> Self {
+        Self::standard()
+    }
+
+    /// The fixed parameters: G is the standard Ed25519 base point and H is
+    /// hashed to the curve from [`PEDERSEN_H_DST`], so nobody knows its
+    /// discrete logarithm with respect to G.
+    pub fn standard() -> Self {
+        static STANDARD: OnceLock<PedersenParams> = OnceLock::new();
+        STANDARD
+            .get_or_init(|| Self {
+                g: EdwardsAffine::generator(),
+                h: hash_to_curve(b"H", PEDERSEN_H_DST),
+            })
+            .clone()
     }
 
-    /// Derive the generator H using a hash-to-curve method
-    /* fn derive_h(g: &EdwardsAffine) -> EdwardsAffine {
-        // Domain separator for hash-to-curve
-        let domain_separator = b"PedersenCommitmentGeneratorH";
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.compressed_size());
+        self.serialize_compressed(&mut bytes)
+            .expect("Serialization of parameters failed");
+        bytes
+    }
 
-        // Hash the domain separator using SHA-256
-        let mut hasher = Sha256::new();
-        hasher.update(domain_separator);
-        let hash_result = hasher.finalize();
+    /// Decodes parameters written by [`PedersenParams::to_bytes`], rejecting
+    /// points outside the prime-order subgroup and degenerate generators.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CommitmentError> {
+        let params =
+            Self::deserialize_compressed(bytes).map_err(|_| CommitmentError::InvalidParameters)?;
+        if params.g.is_zero() || params.h.is_zero() || params.g == params.h {
+            return Err(CommitmentError::InvalidParameters);
+        }
+        Ok(params)
+    }
+}
 
-        // Convert the hash output to a field element
-        let mut hash_bytes = [0u8; 32];
-        hash_bytes.copy_from_slice(&hash_result);
-        let field_element = Fr::from_le_bytes_mod_order(&hash_bytes);
+impl Default for PedersenParams {
+    fn default() -> Self {
+        Self::standard()
+    }
+}
 
-        // Map the field element to a curve point
-        let h_projective = g.mul(field_element.into());
-        let h_affine = h_projective.into_affine();
+impl DerivedKeys {
+    /// HKDF-SHA256 over the shared secret, salted with the transcript
+    /// `epub || pk_recipient || context`. Both sides derive the same keys: the
+    /// sender from `esk * pk_recipient`, the recipient from `sk * epub`.
+    fn derive(
+        shared_secret: &Jubjub,
+        epub: &Jubjub,
+        pk_recipient: &Jubjub,
+        context: &MessageContext,
+    ) -> Self {
+        let mut ss_bytes = Vec::new();
+        shared_secret
+            .serialize_compressed(&mut ss_bytes)
+            .expect("Serialization failed");
 
-        h_affine
-    } */
+        let mut transcript = Vec::new();
+        epub.serialize_compressed(&mut transcript)
+            .expect("Serialization failed");
+        pk_recipient
+            .serialize_compressed(&mut transcript)
+            .expect("Serialization failed");
+        transcript.extend_from_slice(&context.to_bytes());
 
-    fn generate_alternate_generator(g: &EdwardsAffine) -> EdwardsAffine {
-        let mut hasher = Blake2b512::new();
-        hasher.update(b"ed25519_pedersen_h_v1");
+        let hkdf = Hkdf::<Sha256>::new(Some(&transcript), &ss_bytes);
 
-        // Add generator coordinates to the hash
-        let mut g_bytes = Vec::new();
-        g.serialize_uncompressed(&mut g_bytes)
-            .expect("Serialization of base point failed");
-        hasher.update(&g_bytes);
+        let mut randomness = [0u8; 64];
+        let mut enc_key = [0u8; 32];
+        hkdf.expand(RANDOMNESS_INFO, &mut randomness)
+            .expect("64 bytes is a valid HKDF-SHA256 output length");
+        hkdf.expand(ENC_KEY_INFO, &mut enc_key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
 
-        // Use the hash output to create a scalar
-        let hash = hasher.finalize();
-        let mut scalar = Fr::deserialize_compressed(&hash[..32]).expect("Hash to scalar failed");
+        DerivedKeys {
+            randomness,
+            enc_key,
+        }
+    }
+}
 
-        // Multiply generator by scalar and clear cofactor
-        // This ensures the point has the right order
-        let h_projective = Ed25519::generator().mul(scalar);
-        let h = h_projective.into_affine();
+impl Ciphertext {
+    fn decrypt(
+        &self,
+        key: &[u8; 32],
+        context: &MessageContext,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let cipher = Aes256Gcm::new(key.as_slice().into());
+        let nonce = Nonce::from_slice(&self.nonce);
+        let payload = Payload {
+            msg: &self.encrypted,
+            aad: &context.associated_data(),
+        };
 
-        // Verify point is valid (non-zero and correct order)
-        assert!(!h.is_zero(), "Generated point must not be identity");
+        let decrypted = cipher
+            .decrypt(nonce, payload)
+            .map_err(|e| format!("Decryption failed: {}", e))?;
 
-        h
+        Ok(decrypted)
     }
 }
 
 impl UserB {
+    // Senders go through `seal_with_ephemeral_key`; the tests draw key pairs
+    #[cfg(test)]
     fn generate_ephemeral_keypair() -> Self {
-        // Initialize random number generator
-        let mut rng = thread_rng();
-
         // Generate ephemeral secret key (esk)
-        let esk = Fr::rand(&mut rng);
+        Self::from_secret(JubjubFr::rand(&mut thread_rng()))
+    }
 
+    fn from_secret(esk: JubjubFr) -> Self {
         // Compute ephemeral public key (epub)
         // Get the generator point G and multiply by esk
-        let generator = Ed25519::generator();
+        let generator = Jubjub::generator();
         let epub = generator.mul(esk);
 
         Self { esk, epub }
     }
 
     // Compute shared secret using User A's public key
-    fn compute_shared_secret(&self, pk_a: &Ed25519) -> Ed25519 {
+    fn compute_shared_secret(&self, pk_a: &Jubjub) -> Jubjub {
         // Multiply User A's public key by our ephemeral secret key
         pk_a.mul(self.esk)
     }
 
-    fn derive_keys(&self, shared_secret: &Ed25519) -> DerivedKeys {
-        // Serialize the shared secret point to bytes
-        let mut ss_bytes = Vec::new();
-        shared_secret
-            .serialize_uncompressed(&mut ss_bytes)
-            .expect("Serialization failed");
-
-        // Create two separate hasher instances for domain separation
-        let mut hasher_r = Sha256::new();
-        let mut hasher_k = Sha256::new();
-
-        // Add domain separation tags before the shared secret
-        hasher_r.update(b"randomness");
-        hasher_r.update(&ss_bytes);
-
-        hasher_k.update(b"encryption_key");
-        hasher_k.update(&ss_bytes);
-
-        // Finalize both hashes
-        let randomness = hasher_r.finalize();
-        let enc_key = hasher_k.finalize();
-
-        // Convert the generic arrays to fixed-size arrays
-        let mut r_bytes = [0u8; 32];
-        let mut k_bytes = [0u8; 32];
-
-        r_bytes.copy_from_slice(&randomness[..32]);
-        k_bytes.copy_from_slice(&enc_key[..32]);
-
-        DerivedKeys {
-            randomness: r_bytes,
-            enc_key: k_bytes,
-        }
+    fn derive_keys(
+        &self,
+        pk_a: &Jubjub,
+        shared_secret: &Jubjub,
+        context: &MessageContext,
+    ) -> DerivedKeys {
+        DerivedKeys::derive(shared_secret, &self.epub, pk_a, context)
     }
 
     fn encrypt_message(
         &self,
         message: &[u8],
         key: &[u8; 32],
+        context: &MessageContext,
     ) -> Result<Ciphertext, Box<dyn std::error::Error>> {
         // Create a new cipher instance
         let cipher = Aes256Gcm::new(key.as_slice().into());
@@ -254,9 +310,13 @@ impl UserB {
         }
         let nonce = Nonce::from_slice(&nonce);
 
-        // Encrypt the message
+        // Encrypt the message, authenticating the context alongside it
+        let payload = Payload {
+            msg: message,
+            aad: &context.associated_data(),
+        };
         let encrypted = cipher
-            .encrypt(nonce, message)
+            .encrypt(nonce, payload)
             .map_err(|e| format!("Encryption failed: {}", e))?;
 
         Ok(Ciphertext {
@@ -265,23 +325,20 @@ impl UserB {
         })
     }
 
-    // SHOULD NOT BE HERE!!!!!!!!
+    // Recipients go through `open`; the tests exercise encryption alone
+    #[cfg(test)]
     fn decrypt_message(
         &self,
         ciphertext: &Ciphertext,
         key: &[u8; 32],
+        context: &MessageContext,
     ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let cipher = Aes256Gcm::new(key.as_slice().into());
-        let nonce = Nonce::from_slice(&ciphertext.nonce);
-
-        let decrypted = cipher
-            .decrypt(nonce, ciphertext.encrypted.as_ref())
-            .map_err(|e| format!("Decryption failed: {}", e))?;
-
-        Ok(decrypted)
+        ciphertext.decrypt(key, context)
     }
 
-    /// Compute the commitment in constant time
+    /// Compute the commitment. Not constant time: `mul_bigint` branches on
+    /// the scalars' bits, so timing can leak the blinding factor to anyone
+    /// who can measure it while the sender commits.
     fn compute_commitment(m: &Fr, r: &Fr, params: &PedersenParams) -> Ed25519 {
         let g_m = Ed25519::from(params.g).mul_bigint(m.into_bigint());
         let h_r = Ed25519::from(params.h).mul_bigint(r.into_bigint());
@@ -301,21 +358,17 @@ impl UserB {
         hasher.update(&(ciphertext.encrypted.len() as u64).to_le_bytes());
         hasher.update(&ciphertext.encrypted);
 
-        let hash = hasher.finalize();
-
-        let mut scalar =
-            Fr::deserialize_compressed(&hash[..32]).map_err(|_| CommitmentError::HashingError)?;
+        let hash: [u8; 64] = hasher.finalize().into();
 
-        Ok(scalar)
+        Ok(wide_bytes_to_scalar(&hash))
     }
 
     /// Convert randomness bytes to scalar in constant time
-    fn randomness_to_scalar(randomness: &[u8; 32]) -> Result<Fr, CommitmentError> {
-        let mut scalar = Fr::deserialize_compressed(&randomness[..])
-            .map_err(|_| CommitmentError::InvalidRandomness)?;
+    fn randomness_to_scalar(randomness: &[u8; 64]) -> Result<Fr, CommitmentError> {
+        let scalar = wide_bytes_to_scalar(randomness);
 
-        // Ensure scalar is in proper range
-        if scalar.is_zero() {
+        // A zero blinding factor would make the commitment non-hiding
+        if bool::from(scalar_is_zero(&scalar)) {
             return Err(CommitmentError::InvalidRandomness);
         }
 
@@ -334,7 +387,7 @@ impl UserB {
     pub fn create_commitment(
         &self,
         ciphertext: &Ciphertext,
-        randomness: &[u8; 32],
+        randomness: &[u8; 64],
         params: &PedersenParams,
     ) -> Result<PedersenCommitment, CommitmentError> {
         // Convert randomness to scalar in constant time
@@ -352,7 +405,10 @@ impl UserB {
         })
     }
 
-    /// Verify a commitment opening
+    /// Verify a commitment opening. Only the tests hold a
+    /// [`PedersenCommitment`]; delivered messages are checked with
+    /// [`verify_delivery`].
+    #[cfg(test)]
     pub fn verify_commitment(
         &self,
         commitment: &PedersenCommitment,
@@ -367,3 +423,205 @@ impl UserB {
             .eq(&commitment.commitment.into_affine()))
     }
 }
+
+/// Reduces 64 uniformly random bytes modulo the group order. Reducing twice
+/// the scalar width keeps the bias below 2^-250, where 32 bytes would make
+/// small scalars noticeably more likely.
+fn wide_bytes_to_scalar(bytes: &[u8; 64]) -> Fr {
+    Fr::from_le_bytes_mod_order(bytes)
+}
+
+fn scalar_is_zero(scalar: &Fr) -> Choice {
+    let mut bytes = [0u8; 32];
+    scalar
+        .serialize_compressed(&mut bytes[..])
+        .expect("A scalar fits in 32 bytes");
+    bytes.ct_eq(&[0u8; 32])
+}
+
+impl SealedMessage {
+    /// Current envelope format:
+    /// `version (1) || epub (32) || nonce (12) || commitment (32) || ciphertext`,
+    /// with points in compressed form. Version 1 carried an edwards25519 epub.
+    pub const VERSION: u8 = 2;
+
+    const POINT_LEN: usize = 32;
+    const HEADER_LEN: usize = 1 + Self::POINT_LEN + 12 + Self::POINT_LEN;
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::HEADER_LEN + self.ciphertext.len());
+        bytes.push(Self::VERSION);
+        self.epub
+            .serialize_compressed(&mut bytes)
+            .expect("Serialization of epub failed");
+        bytes.extend_from_slice(&self.nonce);
+        self.commitment
+            .serialize_compressed(&mut bytes)
+            .expect("Serialization of commitment failed");
+        bytes.extend_from_slice(&self.ciphertext);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SealError> {
+        let (&version, rest) = bytes.split_first().ok_or(SealError::MalformedEnvelope)?;
+        if version != Self::VERSION {
+            return Err(SealError::UnsupportedVersion(version));
+        }
+        if bytes.len() < Self::HEADER_LEN {
+            return Err(SealError::MalformedEnvelope);
+        }
+
+        let (epub, rest) = rest.split_at(Self::POINT_LEN);
+        let (nonce, rest) = rest.split_at(12);
+        let (commitment, ciphertext) = rest.split_at(Self::POINT_LEN);
+
+        Ok(Self {
+            epub: Jubjub::deserialize_compressed(epub)
+                .map_err(|_| SealError::MalformedEnvelope)?,
+            nonce: nonce.try_into().map_err(|_| SealError::MalformedEnvelope)?,
+            ciphertext: ciphertext.to_vec(),
+            commitment: Ed25519::deserialize_compressed(commitment)
+                .map_err(|_| SealError::MalformedEnvelope)?,
+        })
+    }
+
+    /// Encodes the envelope for `CreateMessageRequest.encrypted_content`.
+    pub fn to_base64(&self) -> String {
+        URL_SAFE_NO_PAD.encode(self.to_bytes())
+    }
+
+    pub fn from_base64(encoded: &str) -> Result<Self, SealError> {
+        let bytes = URL_SAFE_NO_PAD
+            .decode(encoded)
+            .map_err(|_| SealError::MalformedEnvelope)?;
+        Self::from_bytes(&bytes)
+    }
+
+    /// The randomness the commitment was made with. Only the recipient can
+    /// derive it; handing it to an auditor lets them run [`verify_delivery`].
+    pub fn opening(
+        &self,
+        recipient_sk: &JubjubFr,
+        context: &MessageContext,
+    ) -> Result<Fr, SealError> {
+        let shared_secret = self.epub.mul(*recipient_sk);
+        let recipient_pk = Jubjub::generator().mul(*recipient_sk);
+        let keys = DerivedKeys::derive(&shared_secret, &self.epub, &recipient_pk, context);
+        Ok(UserB::randomness_to_scalar(&keys.randomness)?)
+    }
+}
+
+/// A fresh recipient key pair `(sk, sk * G)` on Jubjub for [`seal`] and
+/// [`open`].
+pub fn generate_keypair() -> (JubjubFr, Jubjub) {
+    let sk = JubjubFr::rand(&mut thread_rng());
+    (sk, Jubjub::generator().mul(sk))
+}
+
+/// Encrypts `plaintext` to the holder of the secret key behind `recipient_pk`
+/// under a fresh ephemeral key, and commits to the resulting ciphertext. The
+/// message can only be opened with the same `context`.
+pub fn seal(
+    recipient_pk: &Jubjub,
+    plaintext: &[u8],
+    context: &MessageContext,
+) -> Result<SealedMessage, SealError> {
+    let esk = JubjubFr::rand(&mut thread_rng());
+    seal_with_ephemeral_key(esk, recipient_pk, plaintext, context)
+}
+
+/// Like [`seal`], under the ephemeral secret `esk` the caller drew, so that
+/// they can also prove knowledge of it with [`crate::prover::prove`]. `esk`
+/// must be fresh for every message.
+pub fn seal_with_ephemeral_key(
+    esk: JubjubFr,
+    recipient_pk: &Jubjub,
+    plaintext: &[u8],
+    context: &MessageContext,
+) -> Result<SealedMessage, SealError> {
+    let sender = UserB::from_secret(esk);
+    let shared_secret = sender.compute_shared_secret(recipient_pk);
+    let keys = sender.derive_keys(recipient_pk, &shared_secret, context);
+
+    let ciphertext = sender
+        .encrypt_message(plaintext, &keys.enc_key, context)
+        .map_err(|_| SealError::EncryptionFailed)?;
+    let commitment =
+        sender.create_commitment(&ciphertext, &keys.randomness, &PedersenParams::standard())?;
+
+    Ok(SealedMessage {
+        epub: sender.epub,
+        nonce: ciphertext.nonce,
+        ciphertext: ciphertext.encrypted,
+        commitment: commitment.commitment,
+    })
+}
+
+/// Recovers the plaintext of a message sealed to `recipient_sk`'s public key,
+/// checking the commitment before decrypting.
+pub fn open(
+    recipient_sk: &JubjubFr,
+    sealed: &SealedMessage,
+    context: &MessageContext,
+) -> Result<Vec<u8>, SealError> {
+    let shared_secret = sealed.epub.mul(*recipient_sk);
+    let recipient_pk = Jubjub::generator().mul(*recipient_sk);
+    let keys = DerivedKeys::derive(&shared_secret, &sealed.epub, &recipient_pk, context);
+
+    let ciphertext = Ciphertext {
+        nonce: sealed.nonce,
+        encrypted: sealed.ciphertext.clone(),
+    };
+
+    let r = UserB::randomness_to_scalar(&keys.randomness)?;
+    if !verify_delivery(&sealed.commitment, sealed, &r)? {
+        return Err(SealError::CommitmentMismatch);
+    }
+
+    ciphertext
+        .decrypt(&keys.enc_key, context)
+        .map_err(|_| SealError::DecryptionFailed)
+}
+
+/// Checks that `commitment` opens to the ciphertext carried by `sealed` under
+/// `opening`, i.e. that `sealed` is exactly what the sender committed to.
+pub fn verify_delivery(
+    commitment: &Ed25519,
+    sealed: &SealedMessage,
+    opening: &Fr,
+) -> Result<bool, SealError> {
+    let ciphertext = Ciphertext {
+        nonce: sealed.nonce,
+        encrypted: sealed.ciphertext.clone(),
+    };
+    let m = UserB::hash_ciphertext_to_scalar(&ciphertext)?;
+    let expected = UserB::compute_commitment(&m, opening, &PedersenParams::standard());
+    Ok(expected == *commitment)
+}
+
+/// Decodes a compressed commitment point, rejecting points outside the
+/// prime-order subgroup.
+pub fn decode_commitment(bytes: &[u8]) -> Result<Ed25519, SealError> {
+    Ed25519::deserialize_compressed(bytes).map_err(|_| SealError::MalformedCommitment)
+}
+
+/// Decodes a compressed recipient key for [`seal`], rejecting the identity and
+/// points outside the prime-order subgroup.
+pub fn decode_public_key(bytes: &[u8]) -> Result<Jubjub, SealError> {
+    let key = Jubjub::deserialize_compressed(bytes).map_err(|_| SealError::MalformedKey)?;
+    if key.is_zero() {
+        return Err(SealError::MalformedKey);
+    }
+    Ok(key)
+}
+
+/// Like [`verify_delivery`], with the commitment and opening in compressed
+/// form.
+pub fn verify_delivery_encoded(
+    commitment: &[u8],
+    sealed: &SealedMessage,
+    opening: &[u8],
+) -> Result<bool, SealError> {
+    let opening = Fr::deserialize_compressed(opening).map_err(|_| SealError::MalformedCommitment)?;
+    verify_delivery(&decode_commitment(commitment)?, sealed, &opening)
+}
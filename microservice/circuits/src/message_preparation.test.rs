@@ -1,8 +1,12 @@
 use super::*;
-use ark_std::rand::thread_rng;
+use ark_std::rand::{thread_rng, Rng, RngCore};
+use ark_ff::{BigInteger, One};
 use ark_std::Zero;
 use std::collections::HashSet;
 
+// Compressed encoding of `PedersenParams::standard().h`
+const PINNED_H: [u8; 32] = hex!("076ecb92c212f3520835c72b6281faa97326b12f96a6479ca30b3de36aa3e464");
+
 #[test]
 fn test_generate_ephemeral_keypair() {
     let UserB { esk, epub } = UserB::generate_ephemeral_keypair();
@@ -11,7 +15,7 @@ fn test_generate_ephemeral_keypair() {
     assert!(!esk.is_zero(), "Secret key should not be zero");
 
     // Ensure the public key is correctly derived
-    let generator = Ed25519::generator();
+    let generator = Jubjub::generator();
     let expected_pub = generator.mul(esk);
     assert_eq!(epub, expected_pub, "Public key must be esk * G");
 }
@@ -32,7 +36,7 @@ fn test_generate_unique_keys() {
 fn test_public_key_on_curve() {
     let UserB { epub, .. } = UserB::generate_ephemeral_keypair();
 
-    // Check that epub is a valid Ed25519 point
+    // Check that epub is a valid Jubjub point
     let affine_epub = epub.into_affine();
 
     assert!(affine_epub.is_on_curve());
@@ -42,15 +46,15 @@ fn test_public_key_on_curve() {
 #[test]
 fn test_compute_shared_secret() {
     // Generate a random ephemeral secret key
-    let esk = Fr::rand(&mut rand::thread_rng());
+    let esk = JubjubFr::rand(&mut rand::thread_rng());
 
     // Generate a random public key for User A
-    let pk_a = Ed25519::generator().mul(&esk);
+    let pk_a = Jubjub::generator().mul(&esk);
 
     // Create a new instance of the struct
     let instance = UserB {
         esk,
-        epub: Ed25519::generator().mul(&esk),
+        epub: Jubjub::generator().mul(&esk),
     };
 
     // Compute the shared secret using the instance
@@ -63,40 +67,40 @@ fn test_compute_shared_secret() {
 #[test]
 fn test_compute_shared_secret_with_invalid_input() {
     // Generate a random ephemeral secret key
-    let esk = Fr::rand(&mut rand::thread_rng());
+    let esk = JubjubFr::rand(&mut rand::thread_rng());
 
     // Create a new instance of the struct
     let instance = UserB {
         esk,
-        epub: Ed25519::generator().mul(&esk),
+        epub: Jubjub::generator().mul(&esk),
     };
 
     // Create an invalid public key for User A: the zero point
-    let pk_a = Ed25519::zero();
+    let pk_a = Jubjub::zero();
 
     // Compute the shared secret using the instance
     let shared_secret = instance.compute_shared_secret(&pk_a);
 
     // Verify that the shared secret is the zero point
-    assert_eq!(shared_secret, Ed25519::zero());
+    assert_eq!(shared_secret, Jubjub::zero());
 }
 
 #[test]
 fn test_compute_shared_secret_with_multiple_inputs() {
     // Generate a random ephemeral secret key
-    let esk = Fr::rand(&mut rand::thread_rng());
+    let esk = JubjubFr::rand(&mut rand::thread_rng());
 
     // Create a new instance of the struct
     let instance = UserB {
         esk,
-        epub: Ed25519::generator().mul(&esk),
+        epub: Jubjub::generator().mul(&esk),
     };
 
     // Generate multiple random public keys for User A
     let pks_a = vec![
-        Ed25519::generator().mul(&esk),
-        Ed25519::generator().mul(&Fr::rand(&mut rand::thread_rng())),
-        Ed25519::generator().mul(&Fr::rand(&mut rand::thread_rng())),
+        Jubjub::generator().mul(&esk),
+        Jubjub::generator().mul(&JubjubFr::rand(&mut rand::thread_rng())),
+        Jubjub::generator().mul(&JubjubFr::rand(&mut rand::thread_rng())),
     ];
 
     // Compute the shared secrets using the instance
@@ -114,16 +118,16 @@ fn test_compute_shared_secret_with_multiple_inputs() {
 #[test]
 fn test_derived_keys_length() {
     let user_b = UserB::generate_ephemeral_keypair();
-    let sk_a = Fr::rand(&mut thread_rng());
-    let pk_a = Ed25519::generator().mul(sk_a);
+    let sk_a = JubjubFr::rand(&mut thread_rng());
+    let pk_a = Jubjub::generator().mul(sk_a);
     let shared_secret = user_b.compute_shared_secret(&pk_a);
-    let derived_keys = user_b.derive_keys(&shared_secret);
+    let derived_keys = user_b.derive_keys(&pk_a, &shared_secret, &MessageContext::default());
 
     // Check that derived keys have correct lengths
     assert_eq!(
         derived_keys.randomness.len(),
-        32,
-        "Randomness should be 32 bytes"
+        64,
+        "Randomness should be 64 bytes"
     );
     assert_eq!(
         derived_keys.enc_key.len(),
@@ -136,12 +140,12 @@ fn test_derived_keys_length() {
 fn test_derived_keys_deterministic() {
     // Generate two sets of keys with same inputs
     let user_b = UserB::generate_ephemeral_keypair();
-    let sk_a = Fr::rand(&mut thread_rng());
-    let pk_a = Ed25519::generator().mul(sk_a);
+    let sk_a = JubjubFr::rand(&mut thread_rng());
+    let pk_a = Jubjub::generator().mul(sk_a);
     let shared_secret = user_b.compute_shared_secret(&pk_a);
 
-    let derived_keys_1 = user_b.derive_keys(&shared_secret);
-    let derived_keys_2 = user_b.derive_keys(&shared_secret);
+    let derived_keys_1 = user_b.derive_keys(&pk_a, &shared_secret, &MessageContext::default());
+    let derived_keys_2 = user_b.derive_keys(&pk_a, &shared_secret, &MessageContext::default());
 
     // Same inputs should produce same outputs
     assert_eq!(
@@ -159,16 +163,16 @@ fn test_derived_keys_different_shared_secrets() {
     let user_b = UserB::generate_ephemeral_keypair();
 
     // Generate two different shared secrets
-    let sk_a1 = Fr::rand(&mut thread_rng());
-    let sk_a2 = Fr::rand(&mut thread_rng());
-    let pk_a1 = Ed25519::generator().mul(sk_a1);
-    let pk_a2 = Ed25519::generator().mul(sk_a2);
+    let sk_a1 = JubjubFr::rand(&mut thread_rng());
+    let sk_a2 = JubjubFr::rand(&mut thread_rng());
+    let pk_a1 = Jubjub::generator().mul(sk_a1);
+    let pk_a2 = Jubjub::generator().mul(sk_a2);
 
     let shared_secret1 = user_b.compute_shared_secret(&pk_a1);
     let shared_secret2 = user_b.compute_shared_secret(&pk_a2);
 
-    let derived_keys_1 = user_b.derive_keys(&shared_secret1);
-    let derived_keys_2 = user_b.derive_keys(&shared_secret2);
+    let derived_keys_1 = user_b.derive_keys(&pk_a1, &shared_secret1, &MessageContext::default());
+    let derived_keys_2 = user_b.derive_keys(&pk_a2, &shared_secret2, &MessageContext::default());
 
     // Different inputs should produce different outputs
     assert_ne!(
@@ -184,14 +188,14 @@ fn test_derived_keys_different_shared_secrets() {
 #[test]
 fn test_derived_keys_distinctness() {
     let user_b = UserB::generate_ephemeral_keypair();
-    let sk_a = Fr::rand(&mut thread_rng());
-    let pk_a = Ed25519::generator().mul(sk_a);
+    let sk_a = JubjubFr::rand(&mut thread_rng());
+    let pk_a = Jubjub::generator().mul(sk_a);
     let shared_secret = user_b.compute_shared_secret(&pk_a);
-    let derived_keys = user_b.derive_keys(&shared_secret);
+    let derived_keys = user_b.derive_keys(&pk_a, &shared_secret, &MessageContext::default());
 
     // Randomness and encryption key should be different
     assert_ne!(
-        derived_keys.randomness, derived_keys.enc_key,
+        derived_keys.randomness[..32], derived_keys.enc_key,
         "Randomness and encryption key should be distinct"
     );
 }
@@ -199,14 +203,14 @@ fn test_derived_keys_distinctness() {
 #[test]
 fn test_derived_keys_non_zero() {
     let user_b = UserB::generate_ephemeral_keypair();
-    let sk_a = Fr::rand(&mut thread_rng());
-    let pk_a = Ed25519::generator().mul(sk_a);
+    let sk_a = JubjubFr::rand(&mut thread_rng());
+    let pk_a = Jubjub::generator().mul(sk_a);
     let shared_secret = user_b.compute_shared_secret(&pk_a);
-    let derived_keys = user_b.derive_keys(&shared_secret);
+    let derived_keys = user_b.derive_keys(&pk_a, &shared_secret, &MessageContext::default());
 
     // Check that derived keys are not all zeros
     assert_ne!(
-        derived_keys.randomness, [0u8; 32],
+        derived_keys.randomness, [0u8; 64],
         "Randomness should not be all zeros"
     );
     assert_ne!(
@@ -218,12 +222,12 @@ fn test_derived_keys_non_zero() {
 #[test]
 fn test_key_derivation_with_identity_point() {
     let user_b = UserB::generate_ephemeral_keypair();
-    let identity = Ed25519::generator();
-    let derived_keys = user_b.derive_keys(&identity);
+    let identity = Jubjub::generator();
+    let derived_keys = user_b.derive_keys(&identity, &identity, &MessageContext::default());
 
     // Even with identity point, should still produce valid keys
     assert_ne!(
-        derived_keys.randomness, [0u8; 32],
+        derived_keys.randomness, [0u8; 64],
         "Randomness should be non-zero even with identity point"
     );
     assert_ne!(
@@ -235,8 +239,8 @@ fn test_key_derivation_with_identity_point() {
 #[test]
 fn test_serialize_deserialize_consistency() {
     let user_b = UserB::generate_ephemeral_keypair();
-    let sk_a = Fr::rand(&mut thread_rng());
-    let pk_a = Ed25519::generator().mul(sk_a);
+    let sk_a = JubjubFr::rand(&mut thread_rng());
+    let pk_a = Jubjub::generator().mul(sk_a);
     let shared_secret = user_b.compute_shared_secret(&pk_a);
 
     // Serialize and deserialize the shared secret
@@ -259,8 +263,8 @@ fn test_serialize_deserialize_consistency() {
 #[test]
 fn test_domain_separation() {
     let user_b = UserB::generate_ephemeral_keypair();
-    let sk_a = Fr::rand(&mut thread_rng());
-    let pk_a = Ed25519::generator().mul(sk_a);
+    let sk_a = JubjubFr::rand(&mut thread_rng());
+    let pk_a = Jubjub::generator().mul(sk_a);
     let shared_secret = user_b.compute_shared_secret(&pk_a);
 
     // Manual derivation with different domain tags
@@ -286,21 +290,25 @@ fn test_domain_separation() {
 #[test]
 fn test_encrypt_decrypt_roundtrip() {
     let user_b = UserB::generate_ephemeral_keypair();
-    let sk_a = Fr::rand(&mut thread_rng());
-    let pk_a = Ed25519::generator().mul(sk_a);
+    let sk_a = JubjubFr::rand(&mut thread_rng());
+    let pk_a = Jubjub::generator().mul(sk_a);
     let shared_secret = user_b.compute_shared_secret(&pk_a);
-    let derived_keys = user_b.derive_keys(&shared_secret);
+    let derived_keys = user_b.derive_keys(&pk_a, &shared_secret, &MessageContext::default());
 
     let message = b"Hello, World!";
 
     // Encrypt
     let ciphertext = user_b
-        .encrypt_message(message, &derived_keys.enc_key)
+        .encrypt_message(message, &derived_keys.enc_key, &MessageContext::default())
         .expect("Encryption should succeed");
 
     // Decrypt
     let decrypted = user_b
-        .decrypt_message(&ciphertext, &derived_keys.enc_key)
+        .decrypt_message(
+            &ciphertext,
+            &derived_keys.enc_key,
+            &MessageContext::default(),
+        )
         .expect("Decryption should succeed");
 
     assert_eq!(message, decrypted.as_slice());
@@ -310,7 +318,7 @@ fn test_encrypt_decrypt_roundtrip() {
 fn test_different_messages_produce_different_ciphertexts() {
     let user_b = UserB::generate_ephemeral_keypair();
     let derived_keys = DerivedKeys {
-        randomness: [0u8; 32],
+        randomness: [0u8; 64],
         enc_key: [1u8; 32],
     };
 
@@ -318,10 +326,10 @@ fn test_different_messages_produce_different_ciphertexts() {
     let message2 = b"World";
 
     let ciphertext1 = user_b
-        .encrypt_message(message1, &derived_keys.enc_key)
+        .encrypt_message(message1, &derived_keys.enc_key, &MessageContext::default())
         .expect("Encryption should succeed");
     let ciphertext2 = user_b
-        .encrypt_message(message2, &derived_keys.enc_key)
+        .encrypt_message(message2, &derived_keys.enc_key, &MessageContext::default())
         .expect("Encryption should succeed");
 
     assert_ne!(ciphertext1.encrypted, ciphertext2.encrypted);
@@ -331,17 +339,17 @@ fn test_different_messages_produce_different_ciphertexts() {
 fn test_same_message_different_nonces() {
     let user_b = UserB::generate_ephemeral_keypair();
     let derived_keys = DerivedKeys {
-        randomness: [0u8; 32],
+        randomness: [0u8; 64],
         enc_key: [1u8; 32],
     };
 
     let message = b"Same message";
 
     let ciphertext1 = user_b
-        .encrypt_message(message, &derived_keys.enc_key)
+        .encrypt_message(message, &derived_keys.enc_key, &MessageContext::default())
         .expect("Encryption should succeed");
     let ciphertext2 = user_b
-        .encrypt_message(message, &derived_keys.enc_key)
+        .encrypt_message(message, &derived_keys.enc_key, &MessageContext::default())
         .expect("Encryption should succeed");
 
     assert_ne!(ciphertext1.encrypted, ciphertext2.encrypted);
@@ -357,10 +365,11 @@ fn test_wrong_key_fails_decryption() {
     let message = b"Secret message";
 
     let ciphertext = user_b
-        .encrypt_message(message, &correct_key)
+        .encrypt_message(message, &correct_key, &MessageContext::default())
         .expect("Encryption should succeed");
 
-    let decryption_result = user_b.decrypt_message(&ciphertext, &wrong_key);
+    let decryption_result =
+        user_b.decrypt_message(&ciphertext, &wrong_key, &MessageContext::default());
     assert!(decryption_result.is_err());
 }
 
@@ -371,7 +380,7 @@ fn test_modified_ciphertext_fails_decryption() {
     let message = b"Secret message";
 
     let mut ciphertext = user_b
-        .encrypt_message(message, &key)
+        .encrypt_message(message, &key, &MessageContext::default())
         .expect("Encryption should succeed");
 
     // Modify the ciphertext
@@ -379,7 +388,7 @@ fn test_modified_ciphertext_fails_decryption() {
         *byte ^= 1;
     }
 
-    let decryption_result = user_b.decrypt_message(&ciphertext, &key);
+    let decryption_result = user_b.decrypt_message(&ciphertext, &key, &MessageContext::default());
     assert!(decryption_result.is_err());
 }
 
@@ -390,10 +399,10 @@ fn test_empty_message() {
     let message = b"";
 
     let ciphertext = user_b
-        .encrypt_message(message, &key)
+        .encrypt_message(message, &key, &MessageContext::default())
         .expect("Encryption should succeed");
     let decrypted = user_b
-        .decrypt_message(&ciphertext, &key)
+        .decrypt_message(&ciphertext, &key, &MessageContext::default())
         .expect("Decryption should succeed");
 
     assert_eq!(message, decrypted.as_slice());
@@ -406,10 +415,10 @@ fn test_large_message() {
     let message = vec![0u8; 1000000]; // 1MB of zeros
 
     let ciphertext = user_b
-        .encrypt_message(&message, &key)
+        .encrypt_message(&message, &key, &MessageContext::default())
         .expect("Encryption should succeed");
     let decrypted = user_b
-        .decrypt_message(&ciphertext, &key)
+        .decrypt_message(&ciphertext, &key, &MessageContext::default())
         .expect("Decryption should succeed");
 
     assert_eq!(message, decrypted);
@@ -426,7 +435,7 @@ fn test_pedersen_params_initialization() {
     assert!(!params.h.is_zero());
 
     // Verify H has large order
-    assert!(PedersenParams::has_large_order(&params.h));
+    assert!(!params.h.mul_by_cofactor().is_zero());
 
     // Verify H is different from G
     assert_ne!(params.g, params.h);
@@ -442,7 +451,7 @@ fn test_commitment_correctness() {
         encrypted: vec![1, 2, 3, 4],
     };
 
-    let randomness = [42u8; 32];
+    let randomness = [42u8; 64];
 
     let commitment = user_b
         .create_commitment(&ciphertext, &randomness, &params)
@@ -470,7 +479,7 @@ fn test_commitment_binding() {
         encrypted: vec![5, 6, 7, 8],
     };
 
-    let randomness = [42u8; 32];
+    let randomness = [42u8; 64];
 
     let com1 = user_b
         .create_commitment(&ct1, &randomness, &params)
@@ -485,3 +494,441 @@ fn test_commitment_binding() {
         "Different messages should produce different commitments"
     );
 }
+
+#[test]
+fn test_standard_params_are_reproducible() {
+    let params = PedersenParams::standard();
+
+    assert_eq!(params, PedersenParams::new());
+    assert_eq!(params.h, hash_to_curve(b"H", PEDERSEN_H_DST));
+
+    // Pinned so an accidental change to the derivation is caught
+    let mut h_bytes = Vec::new();
+    params.h.serialize_compressed(&mut h_bytes).unwrap();
+    assert_eq!(h_bytes, PINNED_H);
+}
+
+#[test]
+fn test_standard_h_is_in_prime_order_subgroup() {
+    let h = PedersenParams::standard().h;
+
+    assert!(h.is_on_curve());
+    assert!(h.is_in_correct_subgroup_assuming_on_curve());
+    assert!(Ed25519::from(h).mul_bigint(Fr::MODULUS).is_zero());
+    assert!(!h.mul_by_cofactor().is_zero());
+}
+
+#[test]
+fn test_standard_h_is_not_a_known_multiple_of_g() {
+    let params = PedersenParams::standard();
+    let h = Ed25519::from(params.h);
+
+    // Not a small multiple of G (or of -G)
+    let g = Ed25519::from(params.g);
+    let mut multiple = Ed25519::zero();
+    for _ in 0..4096 {
+        multiple += g;
+        assert_ne!(h, multiple);
+        assert_ne!(h, -multiple);
+    }
+
+    // Not the old derivation, H = G * Blake2b("ed25519_pedersen_h_v1" || G)
+    let mut g_bytes = Vec::new();
+    params.g.serialize_uncompressed(&mut g_bytes).unwrap();
+    let mut hasher = Blake2b512::new();
+    hasher.update(b"ed25519_pedersen_h_v1");
+    hasher.update(&g_bytes);
+    let scalar = Fr::from_le_bytes_mod_order(&hasher.finalize()[..32]);
+    assert_ne!(h, g.mul(scalar));
+}
+
+#[test]
+fn test_params_serialization_roundtrip() {
+    let params = PedersenParams::standard();
+
+    let decoded = PedersenParams::from_bytes(&params.to_bytes()).unwrap();
+
+    assert_eq!(decoded, params);
+}
+
+#[test]
+fn test_params_rejects_degenerate_generators() {
+    let g = EdwardsAffine::generator();
+    let same = PedersenParams { g, h: g };
+
+    assert!(matches!(
+        PedersenParams::from_bytes(&same.to_bytes()),
+        Err(CommitmentError::InvalidParameters)
+    ));
+    assert!(matches!(
+        PedersenParams::from_bytes(&[0u8; 3]),
+        Err(CommitmentError::InvalidParameters)
+    ));
+}
+
+#[test]
+fn test_commitment_verifies_across_param_instances() {
+    let user_b = UserB::generate_ephemeral_keypair();
+    let ciphertext = Ciphertext {
+        nonce: [0u8; 12],
+        encrypted: vec![1, 2, 3, 4],
+    };
+
+    let commitment = user_b
+        .create_commitment(&ciphertext, &[42u8; 64], &PedersenParams::standard())
+        .unwrap();
+    let params = PedersenParams::from_bytes(&PedersenParams::standard().to_bytes()).unwrap();
+
+    assert!(user_b
+        .verify_commitment(&commitment, &ciphertext, &params)
+        .unwrap());
+}
+
+fn context() -> MessageContext {
+    MessageContext {
+        sender_id: [1u8; 16],
+        recipient_id: [2u8; 16],
+        parent_id: None,
+    }
+}
+
+fn recipient_keypair() -> (JubjubFr, Jubjub) {
+    generate_keypair()
+}
+
+#[test]
+fn test_seal_open_roundtrip() {
+    let (sk_a, pk_a) = recipient_keypair();
+    let message = b"Hello, World!";
+
+    let sealed = seal(&pk_a, message, &context()).expect("Sealing should succeed");
+    let opened = open(&sk_a, &sealed, &context()).expect("Opening should succeed");
+
+    assert_eq!(opened, message);
+}
+
+#[test]
+fn test_seal_with_ephemeral_key_uses_that_key() {
+    let (sk_a, pk_a) = recipient_keypair();
+    let esk = JubjubFr::rand(&mut thread_rng());
+
+    let sealed = seal_with_ephemeral_key(esk, &pk_a, b"proven", &context()).unwrap();
+
+    assert_eq!(sealed.epub, Jubjub::generator().mul(esk));
+    assert_eq!(open(&sk_a, &sealed, &context()).unwrap(), b"proven");
+}
+
+#[test]
+fn test_seal_open_roundtrip_through_base64() {
+    let (sk_a, pk_a) = recipient_keypair();
+    let message = vec![7u8; 4096];
+
+    let encoded = seal(&pk_a, &message, &context()).unwrap().to_base64();
+    let sealed = SealedMessage::from_base64(&encoded).expect("Envelope should decode");
+
+    assert_eq!(open(&sk_a, &sealed, &context()).unwrap(), message);
+}
+
+#[test]
+fn test_seal_empty_message() {
+    let (sk_a, pk_a) = recipient_keypair();
+
+    let sealed =
+        SealedMessage::from_bytes(&seal(&pk_a, b"", &context()).unwrap().to_bytes()).unwrap();
+
+    assert!(open(&sk_a, &sealed, &context()).unwrap().is_empty());
+}
+
+#[test]
+fn test_envelope_layout() {
+    let (_, pk_a) = recipient_keypair();
+    let sealed = seal(&pk_a, b"abc", &context()).unwrap();
+    let bytes = sealed.to_bytes();
+
+    assert_eq!(bytes[0], SealedMessage::VERSION);
+    // 3 bytes of plaintext plus the 16-byte GCM tag
+    assert_eq!(bytes.len(), 1 + 32 + 12 + 32 + 3 + 16);
+    assert_eq!(&bytes[33..45], &sealed.nonce);
+}
+
+#[test]
+fn test_open_with_wrong_key_fails() {
+    let (_, pk_a) = recipient_keypair();
+    let (other_sk, _) = recipient_keypair();
+
+    let sealed = seal(&pk_a, b"secret", &context()).unwrap();
+
+    assert!(open(&other_sk, &sealed, &context()).is_err());
+}
+
+#[test]
+fn test_open_tampered_ciphertext_fails() {
+    let (sk_a, pk_a) = recipient_keypair();
+    let mut sealed = seal(&pk_a, b"secret", &context()).unwrap();
+    sealed.ciphertext[0] ^= 1;
+
+    assert!(matches!(
+        open(&sk_a, &sealed, &context()),
+        Err(SealError::CommitmentMismatch)
+    ));
+}
+
+#[test]
+fn test_envelope_rejects_unknown_version() {
+    let (_, pk_a) = recipient_keypair();
+    let mut bytes = seal(&pk_a, b"secret", &context()).unwrap().to_bytes();
+    bytes[0] = 3;
+
+    assert!(matches!(
+        SealedMessage::from_bytes(&bytes),
+        Err(SealError::UnsupportedVersion(3))
+    ));
+
+    // Version 1 envelopes carried an edwards25519 epub
+    bytes[0] = 1;
+    assert!(matches!(
+        SealedMessage::from_bytes(&bytes),
+        Err(SealError::UnsupportedVersion(1))
+    ));
+}
+
+#[test]
+fn test_envelope_rejects_truncated_input() {
+    let (_, pk_a) = recipient_keypair();
+    let bytes = seal(&pk_a, b"secret", &context()).unwrap().to_bytes();
+
+    assert!(matches!(
+        SealedMessage::from_bytes(&bytes[..40]),
+        Err(SealError::MalformedEnvelope)
+    ));
+    assert!(matches!(
+        SealedMessage::from_base64("not base64!"),
+        Err(SealError::MalformedEnvelope)
+    ));
+}
+
+#[test]
+fn test_open_in_other_thread_fails() {
+    let (sk_a, pk_a) = recipient_keypair();
+    let sealed = seal(&pk_a, b"secret", &context()).unwrap();
+
+    let other_thread = MessageContext {
+        parent_id: Some(7),
+        ..context()
+    };
+
+    assert!(open(&sk_a, &sealed, &other_thread).is_err());
+}
+
+#[test]
+fn test_open_in_other_conversation_fails() {
+    let (sk_a, pk_a) = recipient_keypair();
+    let sealed = seal(&pk_a, b"secret", &context()).unwrap();
+
+    let swapped = MessageContext {
+        sender_id: context().recipient_id,
+        recipient_id: context().sender_id,
+        parent_id: None,
+    };
+
+    assert!(open(&sk_a, &sealed, &swapped).is_err());
+}
+
+#[test]
+fn test_derived_keys_bind_context() {
+    let user_b = UserB::generate_ephemeral_keypair();
+    let (_, pk_a) = recipient_keypair();
+    let shared_secret = user_b.compute_shared_secret(&pk_a);
+
+    let keys_1 = user_b.derive_keys(&pk_a, &shared_secret, &context());
+    let keys_2 = user_b.derive_keys(
+        &pk_a,
+        &shared_secret,
+        &MessageContext {
+            parent_id: Some(1),
+            ..context()
+        },
+    );
+
+    assert_ne!(keys_1.enc_key, keys_2.enc_key);
+    assert_ne!(keys_1.randomness, keys_2.randomness);
+}
+
+#[test]
+fn test_derived_keys_bind_transcript_keys() {
+    let user_b = UserB::generate_ephemeral_keypair();
+    let (_, pk_a) = recipient_keypair();
+    let (_, other_pk) = recipient_keypair();
+    let shared_secret = user_b.compute_shared_secret(&pk_a);
+
+    let keys = DerivedKeys::derive(&shared_secret, &user_b.epub, &pk_a, &context());
+    let other_recipient = DerivedKeys::derive(&shared_secret, &user_b.epub, &other_pk, &context());
+    let other_epub = DerivedKeys::derive(&shared_secret, &other_pk, &pk_a, &context());
+
+    assert_ne!(keys.enc_key, other_recipient.enc_key);
+    assert_ne!(keys.enc_key, other_epub.enc_key);
+}
+
+#[test]
+fn test_decrypt_with_other_context_fails() {
+    let user_b = UserB::generate_ephemeral_keypair();
+    let key = [9u8; 32];
+
+    let ciphertext = user_b.encrypt_message(b"secret", &key, &context()).unwrap();
+
+    assert!(user_b
+        .decrypt_message(&ciphertext, &key, &MessageContext::default())
+        .is_err());
+}
+
+#[test]
+fn test_verify_delivery_with_recipient_opening() {
+    let (sk_a, pk_a) = recipient_keypair();
+    let sealed = seal(&pk_a, b"delivered", &context()).unwrap();
+    let opening = sealed.opening(&sk_a, &context()).unwrap();
+
+    assert!(verify_delivery(&sealed.commitment, &sealed, &opening).unwrap());
+}
+
+#[test]
+fn test_verify_delivery_rejects_swapped_ciphertext() {
+    let (sk_a, pk_a) = recipient_keypair();
+    let sealed = seal(&pk_a, b"delivered", &context()).unwrap();
+    let opening = sealed.opening(&sk_a, &context()).unwrap();
+
+    let mut delivered = sealed.clone();
+    delivered.ciphertext[0] ^= 1;
+    assert!(!verify_delivery(&sealed.commitment, &delivered, &opening).unwrap());
+
+    let other = seal(&pk_a, b"delivered", &context()).unwrap();
+    assert!(!verify_delivery(&sealed.commitment, &other, &opening).unwrap());
+}
+
+#[test]
+fn test_verify_delivery_rejects_wrong_opening() {
+    let (_, pk_a) = recipient_keypair();
+    let (other_sk, _) = recipient_keypair();
+    let sealed = seal(&pk_a, b"delivered", &context()).unwrap();
+    let opening = sealed.opening(&other_sk, &context()).unwrap();
+
+    assert!(!verify_delivery(&sealed.commitment, &sealed, &opening).unwrap());
+}
+
+#[test]
+fn test_verify_delivery_encoded() {
+    let (sk_a, pk_a) = recipient_keypair();
+    let sealed = seal(&pk_a, b"delivered", &context()).unwrap();
+
+    let mut commitment = Vec::new();
+    sealed.commitment.serialize_compressed(&mut commitment).unwrap();
+    let mut opening = Vec::new();
+    sealed
+        .opening(&sk_a, &context())
+        .unwrap()
+        .serialize_compressed(&mut opening)
+        .unwrap();
+
+    assert!(verify_delivery_encoded(&commitment, &sealed, &opening).unwrap());
+    assert!(matches!(
+        verify_delivery_encoded(&commitment[..31], &sealed, &opening),
+        Err(SealError::MalformedCommitment)
+    ));
+}
+
+fn random_ciphertext<R: Rng>(rng: &mut R) -> Ciphertext {
+    let len = rng.gen_range(0..256);
+    Ciphertext {
+        nonce: rng.gen(),
+        encrypted: (0..len).map(|_| rng.gen()).collect(),
+    }
+}
+
+#[test]
+fn test_commitments_never_fail_for_random_ciphertexts() {
+    let mut rng = thread_rng();
+    let user_b = UserB::generate_ephemeral_keypair();
+    let params = PedersenParams::standard();
+
+    for _ in 0..1000 {
+        let ciphertext = random_ciphertext(&mut rng);
+        let mut randomness = [0u8; 64];
+        rng.fill_bytes(&mut randomness);
+
+        let commitment = user_b
+            .create_commitment(&ciphertext, &randomness, &params)
+            .expect("Commitment creation should never fail");
+        assert!(user_b
+            .verify_commitment(&commitment, &ciphertext, &params)
+            .unwrap());
+    }
+}
+
+#[test]
+fn test_seal_never_fails_for_random_messages() {
+    let mut rng = thread_rng();
+    let (sk_a, pk_a) = recipient_keypair();
+
+    for _ in 0..200 {
+        let message: Vec<u8> = (0..rng.gen_range(0..512)).map(|_| rng.gen()).collect();
+        let sealed = seal(&pk_a, &message, &context()).expect("Sealing should never fail");
+        assert_eq!(open(&sk_a, &sealed, &context()).unwrap(), message);
+    }
+}
+
+#[test]
+fn test_hash_to_scalar_uses_full_digest() {
+    let mut rng = thread_rng();
+
+    for _ in 0..100 {
+        let ciphertext = random_ciphertext(&mut rng);
+        let digest: [u8; 64] = Blake2b512::new()
+            .chain_update(b"PEDERSEN_COMMITMENT_V1")
+            .chain_update((ciphertext.nonce.len() as u64).to_le_bytes())
+            .chain_update(ciphertext.nonce)
+            .chain_update((ciphertext.encrypted.len() as u64).to_le_bytes())
+            .chain_update(&ciphertext.encrypted)
+            .finalize()
+            .into();
+
+        assert_eq!(
+            UserB::hash_ciphertext_to_scalar(&ciphertext).unwrap(),
+            Fr::from_le_bytes_mod_order(&digest)
+        );
+    }
+}
+
+#[test]
+fn test_wide_reduction_depends_on_high_bytes() {
+    let mut low = [7u8; 64];
+    let mut high = low;
+    high[63] ^= 1;
+    assert_ne!(wide_bytes_to_scalar(&low), wide_bytes_to_scalar(&high));
+
+    // Values at or above the group order reduce instead of failing
+    low[..32].copy_from_slice(&[0xff; 32]);
+    low[32..].fill(0);
+    assert_eq!(
+        wide_bytes_to_scalar(&low),
+        Fr::from_le_bytes_mod_order(&[0xff; 32])
+    );
+}
+
+#[test]
+fn test_randomness_reducing_to_zero_is_rejected() {
+    let mut order = [0u8; 64];
+    order[..32].copy_from_slice(&Fr::MODULUS.to_bytes_le());
+
+    for randomness in [[0u8; 64], order] {
+        assert!(matches!(
+            UserB::randomness_to_scalar(&randomness),
+            Err(CommitmentError::InvalidRandomness)
+        ));
+    }
+}
+
+#[test]
+fn test_scalar_is_zero() {
+    assert!(bool::from(scalar_is_zero(&Fr::zero())));
+    assert!(!bool::from(scalar_is_zero(&Fr::one())));
+    assert!(!bool::from(scalar_is_zero(&-Fr::one())));
+}
@@ -1,63 +1,51 @@
-use ark_ff::BigInteger;
-use ark_ec::CurveGroup;
-use ark_relations::lc;
-//use ark_ec::{AffineCurve, ProjectiveCurve};
-use ark_ff::Field;
-use ark_ff::PrimeField;
-use ark_relations::r1cs::{ConstraintSynthesizer, ConstraintSystemRef, SynthesisError, Variable};
+use ark_ec::{CurveGroup, PrimeGroup};
+use ark_ed_on_bls12_381::{constraints::EdwardsVar, EdwardsProjective as Jubjub, Fq, Fr};
+use ark_ff::{BigInteger, One, PrimeField};
+use ark_r1cs_std::prelude::*;
+use ark_relations::r1cs::{ConstraintSynthesizer, ConstraintSystemRef, SynthesisError};
 use ark_std::{rand::Rng, UniformRand};
-use ark_ed25519::{EdwardsAffine, EdwardsConfig, EdwardsProjective as Ed25519, Fr, Fq};
-use ark_ec::PrimeGroup;
 use std::ops::Mul;
 
-// Structure to hold the circuit parameters
+// Structure to hold the circuit parameters.
+//
+// Points live on Jubjub, whose base field is the BLS12-381 scalar field, so
+// the circuit can be proven with Groth16 over BLS12-381 (see `crate::prover`).
 pub struct MessageProtocolCircuit {
-    // User A's public key
-    pub pk_a: Option<Ed25519>,
-    // User B's ephemeral secret key
+    // User A's public key (public input)
+    pub pk_a: Option<Jubjub>,
+    // User B's ephemeral secret key (witness)
     pub esk: Option<Fr>,
-    // User B's ephemeral public key
-    pub epub: Option<Ed25519>,
-    // Computed shared secret
-    pub shared_secret: Option<Ed25519>,
+    // User B's ephemeral public key (public input)
+    pub epub: Option<Jubjub>,
+    // Computed shared secret (witness, never revealed to the verifier)
+    pub shared_secret: Option<Jubjub>,
 }
 
 impl MessageProtocolCircuit {
-	/// Converts a Fq element to Fr (for circuit constraints)
-    fn fq_to_fr(fq: Fq) -> Fr {
-        // Convert to bytes and then to Fr
-        // Note: This is a simplified conversion and may need additional checks
-        let bytes = fq.into_bigint().to_bytes_le();
-        Fr::from_le_bytes_mod_order(&bytes)
-    }
-
     // Function to generate new ephemeral keys
-    pub fn generate_ephemeral_keys<R: Rng>(rng: &mut R) -> (Fr, Ed25519) {
-
-		// Generate ephemeral secret key (esk)
+    pub fn generate_ephemeral_keys<R: Rng>(rng: &mut R) -> (Fr, Jubjub) {
+        // Generate ephemeral secret key (esk)
         let esk = Fr::rand(rng);
 
-        // Compute ephemeral public key (epub)
-        // Get the generator point G and multiply by esk
-		let generator = Ed25519::generator();
-        let epub = generator.mul(esk).into();
+        // Compute ephemeral public key (epub) as G * esk
+        let epub = Jubjub::generator().mul(esk);
 
         (esk, epub)
     }
-    
+
     /// Computes the shared secret between User B's ephemeral secret key and User A's public key
     /// Returns the shared secret point on the curve
-    pub fn compute_shared_secret(esk: &Fr, pk_a: &Ed25519) -> Ed25519 {
+    pub fn compute_shared_secret(esk: &Fr, pk_a: &Jubjub) -> Jubjub {
         // Perform scalar multiplication: esk * pk_a
-        pk_a.mul(*esk).into()
+        pk_a.mul(*esk)
     }
 
-	/// Creates a new instance of the circuit with the given parameters
+    /// Creates a new instance of the circuit with the given parameters
     pub fn new(
-        pk_a: Option<Ed25519>,
+        pk_a: Option<Jubjub>,
         esk: Option<Fr>,
-        epub: Option<Ed25519>,
-        shared_secret: Option<Ed25519>,
+        epub: Option<Jubjub>,
+        shared_secret: Option<Jubjub>,
     ) -> Self {
         Self {
             pk_a,
@@ -67,56 +55,70 @@ impl MessageProtocolCircuit {
         }
     }
 
+    /// An instance with no assignments, used for key generation.
+    pub fn blank() -> Self {
+        Self::new(None, None, None, None)
+    }
+
+    /// The public inputs the verifier supplies, in allocation order:
+    /// `pk_a.x, pk_a.y, epub.x, epub.y`.
+    pub fn public_inputs(pk_a: &Jubjub, epub: &Jubjub) -> Vec<Fq> {
+        let pk_a = pk_a.into_affine();
+        let epub = epub.into_affine();
+        vec![pk_a.x, pk_a.y, epub.x, epub.y]
+    }
 }
 
-impl ConstraintSynthesizer<Fr> for MessageProtocolCircuit {
-    fn generate_constraints(self, cs: ConstraintSystemRef<Fr>) -> Result<(), SynthesisError> {
-
-		// Allocate variables for the circuit
-        let esk_var = cs.new_witness_variable(|| self.esk.ok_or(SynthesisError::AssignmentMissing))?;
-        
-        // Convert curve points to their affine representation
-        let pk_a_affine = self.pk_a.map(|p| p.into_affine());
-        let epub_affine = self.epub.map(|p| p.into_affine());
-        let shared_secret_affine = self.shared_secret.map(|p| p.into_affine());
-
-		// Handle coordinates as field elements in the base field (Fq)
-        let pk_a_coords = cs.new_input_variable(|| {
-            let point = pk_a_affine.ok_or(SynthesisError::AssignmentMissing)?;
-			Ok(Self::fq_to_fr(point.x))
+impl ConstraintSynthesizer<Fq> for MessageProtocolCircuit {
+    fn generate_constraints(self, cs: ConstraintSystemRef<Fq>) -> Result<(), SynthesisError> {
+        // Public inputs
+        let pk_a_var = EdwardsVar::new_input(cs.clone(), || {
+            self.pk_a.ok_or(SynthesisError::AssignmentMissing)
         })?;
-
-		let epub_coords = cs.new_input_variable(|| {
-            let point = self.epub.ok_or(SynthesisError::AssignmentMissing)?.into_affine();
-            Ok(Self::fq_to_fr(point.x))
+        let epub_var = EdwardsVar::new_input(cs.clone(), || {
+            self.epub.ok_or(SynthesisError::AssignmentMissing)
         })?;
 
-        let shared_secret_coords = cs.new_input_variable(|| {
-            let point = self.shared_secret.ok_or(SynthesisError::AssignmentMissing)?.into_affine();
-            Ok(Self::fq_to_fr(point.x))
+        // The ephemeral secret, allocated bit by bit (little-endian) so it
+        // can drive the scalar multiplication gadgets
+        let esk_bits = self.esk.map(|esk| esk.into_bigint().to_bits_le());
+        let esk_var = (0..Fr::MODULUS_BIT_SIZE as usize)
+            .map(|i| {
+                Boolean::new_witness(cs.clone(), || {
+                    esk_bits
+                        .as_ref()
+                        .map(|bits| bits[i])
+                        .ok_or(SynthesisError::AssignmentMissing)
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let shared_secret_var = EdwardsVar::new_witness(cs.clone(), || {
+            self.shared_secret.ok_or(SynthesisError::AssignmentMissing)
         })?;
 
-		// Get generator point coordinates converted to Fr
-        let generator = Ed25519::generator().into_affine();
-        let generator_x_fr = Self::fq_to_fr(generator.x);
+        // The bits must encode a canonical scalar, i.e. esk < r
+        Boolean::enforce_smaller_or_equal_than_le(&esk_var, (-Fr::one()).into_bigint())?;
 
-		let one = Variable::One;
+        // Public points are only checked to be on the curve when allocated;
+        // a small-order component in pk_a would leak bits of esk through the
+        // shared secret, and the identity would make the ECDH meaningless.
+        pk_a_var.enforce_prime_order()?;
+        pk_a_var.is_zero()?.enforce_equal(&Boolean::FALSE)?;
+        epub_var.is_zero()?.enforce_equal(&Boolean::FALSE)?;
 
-		// Constraint: epub = G * esk
-        cs.enforce_constraint(
-            lc!() + esk_var,
-            lc!() + (generator_x_fr, one),
-            lc!() + epub_coords
-        )?;
+        // Constraint: epub = G * esk
+        let generator = EdwardsVar::constant(Jubjub::generator());
+        generator
+            .scalar_mul_le(esk_var.iter())?
+            .enforce_equal(&epub_var)?;
 
-		// Constraint: shared_secret = pk_a * esk
-        cs.enforce_constraint(
-            lc!() + esk_var,
-            lc!() + pk_a_coords,
-            lc!() + shared_secret_coords
-        )?;
+        // Constraint: shared_secret = pk_a * esk
+        pk_a_var
+            .scalar_mul_le(esk_var.iter())?
+            .enforce_equal(&shared_secret_var)?;
 
-		Ok(())
+        Ok(())
     }
 }
 
@@ -124,23 +126,24 @@ impl ConstraintSynthesizer<Fr> for MessageProtocolCircuit {
 mod tests {
     use super::*;
     use ark_relations::r1cs::ConstraintSystem;
-    use ark_std::test_rng;
-	use ark_std::Zero;
+    use ark_ec::AdditiveGroup;
+    use ark_ed_on_bls12_381::EdwardsAffine;
+    use ark_std::{test_rng, Zero};
 
     #[test]
     fn test_message_protocol_circuit() {
         let mut rng = test_rng();
-        
+
         // Generate User A's key pair (in practice, pk_a would be provided)
         let sk_a = Fr::rand(&mut rng);
-        let pk_a = Ed25519::generator().mul(sk_a).into();
-        
+        let pk_a = Jubjub::generator().mul(sk_a);
+
         // Generate User B's ephemeral keys
         let (esk, epub) = MessageProtocolCircuit::generate_ephemeral_keys(&mut rng);
-        
+
         // Compute shared secret
         let shared_secret = MessageProtocolCircuit::compute_shared_secret(&esk, &pk_a);
-        
+
         // Create circuit instance
         let circuit = MessageProtocolCircuit::new(
             Some(pk_a),
@@ -148,28 +151,115 @@ mod tests {
             Some(epub),
             Some(shared_secret),
         );
-        
+
         // Test constraint satisfaction
         let cs = ConstraintSystem::new_ref();
         circuit.generate_constraints(cs.clone()).unwrap();
         assert!(cs.is_satisfied().unwrap());
     }
 
+    struct Instance {
+        pk_a: Jubjub,
+        esk: Fr,
+        epub: Jubjub,
+        shared_secret: Jubjub,
+    }
+
+    fn honest_instance() -> Instance {
+        let mut rng = test_rng();
+        let pk_a = Jubjub::generator().mul(Fr::rand(&mut rng));
+        let (esk, epub) = MessageProtocolCircuit::generate_ephemeral_keys(&mut rng);
+        let shared_secret = MessageProtocolCircuit::compute_shared_secret(&esk, &pk_a);
+        Instance {
+            pk_a,
+            esk,
+            epub,
+            shared_secret,
+        }
+    }
+
+    fn is_satisfied(instance: Instance) -> bool {
+        let circuit = MessageProtocolCircuit::new(
+            Some(instance.pk_a),
+            Some(instance.esk),
+            Some(instance.epub),
+            Some(instance.shared_secret),
+        );
+        let cs = ConstraintSystem::new_ref();
+        circuit.generate_constraints(cs.clone()).unwrap();
+        cs.is_satisfied().unwrap()
+    }
+
+    // A point of order 2 on Jubjub: (0, -1)
+    fn small_order_point() -> Jubjub {
+        EdwardsAffine::new_unchecked(Fq::zero(), -Fq::one()).into()
+    }
+
+    #[test]
+    fn test_wrong_esk_fails() {
+        let mut instance = honest_instance();
+        instance.esk += Fr::one();
+        assert!(!is_satisfied(instance));
+    }
+
+    #[test]
+    fn test_wrong_epub_fails() {
+        let mut instance = honest_instance();
+        instance.epub += Jubjub::generator();
+        assert!(!is_satisfied(instance));
+    }
+
+    #[test]
+    fn test_wrong_shared_secret_fails() {
+        let mut instance = honest_instance();
+        instance.shared_secret = instance.epub;
+        assert!(!is_satisfied(instance));
+    }
+
+    #[test]
+    fn test_shared_secret_for_other_recipient_fails() {
+        let mut instance = honest_instance();
+        instance.pk_a = instance.pk_a.double();
+        assert!(!is_satisfied(instance));
+    }
+
+    #[test]
+    fn test_small_order_recipient_key_fails() {
+        let mut instance = honest_instance();
+        instance.pk_a += small_order_point();
+        instance.shared_secret =
+            MessageProtocolCircuit::compute_shared_secret(&instance.esk, &instance.pk_a);
+        assert!(!is_satisfied(instance));
+    }
+
+    #[test]
+    fn test_identity_epub_fails() {
+        let mut instance = honest_instance();
+        instance.esk = Fr::zero();
+        instance.epub = Jubjub::zero();
+        instance.shared_secret = Jubjub::zero();
+        assert!(!is_satisfied(instance));
+    }
+
     #[test]
     fn test_key_generation() {
         let mut rng = test_rng();
         let (esk, epub) = MessageProtocolCircuit::generate_ephemeral_keys(&mut rng);
-        
+
         // Verify that epub = G * esk
-        let expected_epub: Ed25519 = Ed25519::generator().mul(esk).into();
+        let expected_epub: Jubjub = Jubjub::generator().mul(esk);
         assert_eq!(epub, expected_epub);
     }
 
     #[test]
-    fn test_field_conversion() {
+    fn test_public_inputs_order() {
         let mut rng = test_rng();
-        let fq = Fq::rand(&mut rng);
-        let fr = MessageProtocolCircuit::fq_to_fr(fq);
-        assert!(!fr.is_zero(), "Conversion should not produce zero for random input");
+        let pk_a = Jubjub::rand(&mut rng);
+        let epub = Jubjub::rand(&mut rng);
+
+        let inputs = MessageProtocolCircuit::public_inputs(&pk_a, &epub);
+        assert_eq!(inputs.len(), 4);
+        assert_eq!(inputs[0], pk_a.into_affine().x);
+        assert_eq!(inputs[3], epub.into_affine().y);
     }
 }
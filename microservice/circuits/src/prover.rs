@@ -0,0 +1,92 @@
+//! Groth16 proving and verification for [`MessageProtocolCircuit`].
+//!
+//! A proof convinces a verifier that the sender knows the ephemeral secret
+//! behind `epub` and used it to derive a shared secret with `pk_a`, without
+//! revealing either. Keys and proofs cross process boundaries as compressed
+//! `ark-serialize` bytes.
+
+use crate::message_protocol::MessageProtocolCircuit;
+use ark_bls12_381::Bls12_381;
+use ark_ec::PrimeGroup;
+use ark_ed_on_bls12_381::{EdwardsProjective as Jubjub, Fr};
+use ark_groth16::Groth16;
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use ark_snark::SNARK;
+use ark_std::rand::{CryptoRng, RngCore};
+use std::ops::Mul;
+
+#[cfg(test)]
+#[path = "prover.test.rs"]
+mod tests;
+
+pub type Proof = ark_groth16::Proof<Bls12_381>;
+pub type ProvingKey = ark_groth16::ProvingKey<Bls12_381>;
+pub type VerifyingKey = ark_groth16::VerifyingKey<Bls12_381>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProverError {
+    #[error("Circuit synthesis failed: {0}")]
+    Synthesis(#[from] ark_relations::r1cs::SynthesisError),
+
+    #[error("Malformed key or proof encoding: {0}")]
+    Serialization(#[from] ark_serialize::SerializationError),
+}
+
+/// Runs the circuit-specific setup and returns the proving and verifying keys.
+pub fn setup<R: RngCore + CryptoRng>(
+    rng: &mut R,
+) -> Result<(ProvingKey, VerifyingKey), ProverError> {
+    Ok(Groth16::<Bls12_381>::circuit_specific_setup(
+        MessageProtocolCircuit::blank(),
+        rng,
+    )?)
+}
+
+/// Proves knowledge of `esk` such that `epub = G * esk`, deriving `epub` and
+/// the shared secret with `pk_a` from it.
+pub fn prove<R: RngCore + CryptoRng>(
+    pk: &ProvingKey,
+    pk_a: Jubjub,
+    esk: Fr,
+    rng: &mut R,
+) -> Result<Proof, ProverError> {
+    let epub = Jubjub::generator().mul(esk);
+    let shared_secret = MessageProtocolCircuit::compute_shared_secret(&esk, &pk_a);
+    let circuit =
+        MessageProtocolCircuit::new(Some(pk_a), Some(esk), Some(epub), Some(shared_secret));
+
+    Ok(Groth16::<Bls12_381>::prove(pk, circuit, rng)?)
+}
+
+/// Checks `proof` against the public inputs `pk_a` and `epub`.
+pub fn verify(
+    vk: &VerifyingKey,
+    pk_a: &Jubjub,
+    epub: &Jubjub,
+    proof: &Proof,
+) -> Result<bool, ProverError> {
+    let inputs = MessageProtocolCircuit::public_inputs(pk_a, epub);
+    Ok(Groth16::<Bls12_381>::verify(vk, &inputs, proof)?)
+}
+
+/// Like [`verify`], with the public inputs and proof in [`to_bytes`] form.
+pub fn verify_encoded(
+    vk: &VerifyingKey,
+    pk_a: &[u8],
+    epub: &[u8],
+    proof: &[u8],
+) -> Result<bool, ProverError> {
+    verify(vk, &from_bytes(pk_a)?, &from_bytes(epub)?, &from_bytes(proof)?)
+}
+
+/// Serializes any key, proof or curve point in compressed form.
+pub fn to_bytes<T: CanonicalSerialize>(value: &T) -> Result<Vec<u8>, ProverError> {
+    let mut bytes = Vec::with_capacity(value.compressed_size());
+    value.serialize_compressed(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Deserializes a value written by [`to_bytes`], validating curve points.
+pub fn from_bytes<T: CanonicalDeserialize>(bytes: &[u8]) -> Result<T, ProverError> {
+    Ok(T::deserialize_compressed(bytes)?)
+}
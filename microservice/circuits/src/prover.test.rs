@@ -0,0 +1,102 @@
+use super::*;
+use ark_std::rand::{rngs::StdRng, SeedableRng};
+use ark_std::UniformRand;
+
+// Groth16 requires a `CryptoRng`, which `ark_std::test_rng` is not
+fn test_rng() -> StdRng {
+    StdRng::seed_from_u64(0x5eed)
+}
+
+fn recipient_key<R: RngCore>(rng: &mut R) -> Jubjub {
+    Jubjub::generator().mul(Fr::rand(rng))
+}
+
+#[test]
+fn test_prove_and_verify() {
+    let mut rng = test_rng();
+    let (pk, vk) = setup(&mut rng).unwrap();
+
+    let pk_a = recipient_key(&mut rng);
+    let (esk, epub) = MessageProtocolCircuit::generate_ephemeral_keys(&mut rng);
+
+    let proof = prove(&pk, pk_a, esk, &mut rng).unwrap();
+    assert!(verify(&vk, &pk_a, &epub, &proof).unwrap());
+}
+
+#[test]
+fn test_proof_rejects_other_epub() {
+    let mut rng = test_rng();
+    let (pk, vk) = setup(&mut rng).unwrap();
+
+    let pk_a = recipient_key(&mut rng);
+    let (esk, _) = MessageProtocolCircuit::generate_ephemeral_keys(&mut rng);
+    let (_, other_epub) = MessageProtocolCircuit::generate_ephemeral_keys(&mut rng);
+
+    let proof = prove(&pk, pk_a, esk, &mut rng).unwrap();
+    assert!(!verify(&vk, &pk_a, &other_epub, &proof).unwrap());
+}
+
+#[test]
+fn test_proof_rejects_other_recipient() {
+    let mut rng = test_rng();
+    let (pk, vk) = setup(&mut rng).unwrap();
+
+    let pk_a = recipient_key(&mut rng);
+    let other_pk_a = recipient_key(&mut rng);
+    let (esk, epub) = MessageProtocolCircuit::generate_ephemeral_keys(&mut rng);
+
+    let proof = prove(&pk, pk_a, esk, &mut rng).unwrap();
+    assert!(!verify(&vk, &other_pk_a, &epub, &proof).unwrap());
+}
+
+#[test]
+fn test_serialization_round_trip() {
+    let mut rng = test_rng();
+    let (pk, vk) = setup(&mut rng).unwrap();
+
+    let pk_a = recipient_key(&mut rng);
+    let (esk, epub) = MessageProtocolCircuit::generate_ephemeral_keys(&mut rng);
+    let proof = prove(&pk, pk_a, esk, &mut rng).unwrap();
+
+    let vk: VerifyingKey = from_bytes(&to_bytes(&vk).unwrap()).unwrap();
+    let proof: Proof = from_bytes(&to_bytes(&proof).unwrap()).unwrap();
+    let pk_a: Jubjub = from_bytes(&to_bytes(&pk_a).unwrap()).unwrap();
+    let epub: Jubjub = from_bytes(&to_bytes(&epub).unwrap()).unwrap();
+
+    assert!(verify(&vk, &pk_a, &epub, &proof).unwrap());
+}
+
+#[test]
+fn test_verify_encoded() {
+    let mut rng = test_rng();
+    let (pk, vk) = setup(&mut rng).unwrap();
+
+    let pk_a = recipient_key(&mut rng);
+    let (esk, epub) = MessageProtocolCircuit::generate_ephemeral_keys(&mut rng);
+    let proof = prove(&pk, pk_a, esk, &mut rng).unwrap();
+
+    let verified = verify_encoded(
+        &vk,
+        &to_bytes(&pk_a).unwrap(),
+        &to_bytes(&epub).unwrap(),
+        &to_bytes(&proof).unwrap(),
+    )
+    .unwrap();
+    assert!(verified);
+}
+
+#[test]
+fn test_malformed_proof_is_rejected() {
+    let mut rng = test_rng();
+    let (pk, _) = setup(&mut rng).unwrap();
+
+    let pk_a = recipient_key(&mut rng);
+    let (esk, _) = MessageProtocolCircuit::generate_ephemeral_keys(&mut rng);
+    let mut bytes = to_bytes(&prove(&pk, pk_a, esk, &mut rng).unwrap()).unwrap();
+    bytes.truncate(bytes.len() - 1);
+
+    assert!(matches!(
+        from_bytes::<Proof>(&bytes),
+        Err(ProverError::Serialization(_))
+    ));
+}
@@ -0,0 +1,594 @@
+//! Signal-style sessions: X3DH to agree on a secret with a recipient who may
+//! be offline, from the prekeys they published, and the double ratchet to
+//! derive a fresh key for every message from it.
+//!
+//! [`crate::message_preparation::seal`] encrypts every message to the
+//! recipient's long-term key, so that key opens the whole history. A
+//! session's message keys are instead deleted once used, and its DH ratchet
+//! steps forward each time the conversation changes direction. Compromised
+//! session state therefore exposes neither earlier messages (forward
+//! secrecy) nor, once both sides have ratcheted again, later ones
+//! (post-compromise security).
+//!
+//! Keys are Ed25519 points and scalars: DH is scalar multiplication, the
+//! KDFs are HKDF-SHA256 and messages are sealed with AES-256-GCM.
+
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use ark_ec::PrimeGroup;
+pub use ark_ed25519::{EdwardsProjective as Ed25519, Fr};
+use ark_ff::{UniformRand, Zero};
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use ark_std::rand::thread_rng;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::ops::Mul;
+
+#[cfg(test)]
+#[path = "ratchet.test.rs"]
+mod tests;
+
+// HKDF info strings and labels; bump the version when a derivation changes
+const X3DH_INFO: &[u8] = b"anon-messaging/v1/x3dh";
+const ROOT_INFO: &[u8] = b"anon-messaging/v1/ratchet-root";
+const CHAIN_KEY_INFO: &[u8] = b"anon-messaging/v1/ratchet-chain-key";
+const MESSAGE_KEY_INFO: &[u8] = b"anon-messaging/v1/ratchet-message-key";
+const AEAD_INFO: &[u8] = b"anon-messaging/v1/ratchet-aead";
+const AAD_LABEL: &[u8] = b"anon-messaging/v1/ratchet-aad";
+const SIGNED_PREKEY_LABEL: &[u8] = b"anon-messaging/v1/signed-prekey";
+const ONE_TIME_PREKEY_LABEL: &[u8] = b"anon-messaging/v1/one-time-prekey";
+
+/// How many message keys one message may make the receiver skip over, and
+/// how many skipped keys a session keeps. Bounds the work and memory a
+/// forged header can cost.
+pub const MAX_SKIP: u32 = 1000;
+
+const POINT_LEN: usize = 32;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RatchetError {
+    #[error("Malformed public key")]
+    MalformedKey,
+
+    #[error("Malformed ratchet message")]
+    MalformedMessage,
+
+    #[error("Unsupported ratchet message version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("One-time prekey does not match the initial message")]
+    PrekeyMismatch,
+
+    #[error("Session cannot send before it has received a message")]
+    NotReadyToSend,
+
+    #[error("Too many skipped messages")]
+    TooManySkipped,
+
+    #[error("Encryption failed")]
+    EncryptionFailed,
+
+    #[error("Decryption failed")]
+    DecryptionFailed,
+}
+
+/// A DH key pair `(sk, sk * G)`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyPair {
+    pub secret: Fr,
+    pub public: Ed25519,
+}
+
+impl KeyPair {
+    pub fn generate() -> Self {
+        Self::from_secret(Fr::rand(&mut thread_rng()))
+    }
+
+    pub fn from_secret(secret: Fr) -> Self {
+        Self {
+            secret,
+            public: Ed25519::generator().mul(secret),
+        }
+    }
+
+    fn dh(&self, public: &Ed25519) -> [u8; POINT_LEN] {
+        encode_public_key(&public.mul(self.secret))
+    }
+}
+
+pub fn encode_public_key(key: &Ed25519) -> [u8; POINT_LEN] {
+    let mut bytes = [0u8; POINT_LEN];
+    key.serialize_compressed(&mut bytes[..])
+        .expect("A compressed point fits in 32 bytes");
+    bytes
+}
+
+/// Decodes a compressed public key, rejecting points outside the prime-order
+/// subgroup and the identity, whose DH output would be known to everyone.
+pub fn decode_public_key(bytes: &[u8]) -> Result<Ed25519, RatchetError> {
+    let key = Ed25519::deserialize_compressed(bytes).map_err(|_| RatchetError::MalformedKey)?;
+    if key.is_zero() {
+        return Err(RatchetError::MalformedKey);
+    }
+    Ok(key)
+}
+
+/// The bytes the owner of a signed prekey signs with their account key, and
+/// that whoever distributes or fetches the prekey checks the signature over.
+/// Binding the identity key in makes the signature vouch for both.
+pub fn signed_prekey_payload(
+    identity_key: &Ed25519,
+    signed_prekey_id: u32,
+    signed_prekey: &Ed25519,
+) -> Vec<u8> {
+    let mut payload = SIGNED_PREKEY_LABEL.to_vec();
+    payload.extend_from_slice(&encode_public_key(identity_key));
+    payload.extend_from_slice(&signed_prekey_id.to_le_bytes());
+    payload.extend_from_slice(&encode_public_key(signed_prekey));
+    payload
+}
+
+/// The bytes the owner of a one-time prekey signs with their account key.
+/// Signing each key on its own lets the server hand them out one at a time
+/// while senders can still tell that the owner published it.
+pub fn one_time_prekey_payload(prekey_id: u32, prekey: &Ed25519) -> Vec<u8> {
+    let mut payload = ONE_TIME_PREKEY_LABEL.to_vec();
+    payload.extend_from_slice(&prekey_id.to_le_bytes());
+    payload.extend_from_slice(&encode_public_key(prekey));
+    payload
+}
+
+/// What a recipient publishes so that senders can start sessions with them
+/// while they are offline. Its signature is checked outside this module,
+/// over [`signed_prekey_payload`], before the bundle is used.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrekeyBundle {
+    pub identity_key: Ed25519,
+    pub signed_prekey_id: u32,
+    pub signed_prekey: Ed25519,
+    /// Used by at most one sender; absent once the recipient has run out
+    pub one_time_prekey: Option<(u32, Ed25519)>,
+}
+
+/// Sent along with the messages of a new session until the recipient
+/// answers, so they can run their half of X3DH with [`respond`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InitialHeader {
+    pub identity_key: Ed25519,
+    pub ephemeral_key: Ed25519,
+    pub signed_prekey_id: u32,
+    pub one_time_prekey_id: Option<u32>,
+}
+
+impl InitialHeader {
+    /// `identity_key (32) || ephemeral_key (32) || signed_prekey_id (4) ||
+    /// has_one_time_prekey (1) || [one_time_prekey_id (4)]`, integers little
+    /// endian.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(2 * POINT_LEN + 9);
+        bytes.extend_from_slice(&encode_public_key(&self.identity_key));
+        bytes.extend_from_slice(&encode_public_key(&self.ephemeral_key));
+        bytes.extend_from_slice(&self.signed_prekey_id.to_le_bytes());
+        match self.one_time_prekey_id {
+            Some(id) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&id.to_le_bytes());
+            }
+            None => bytes.push(0),
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, RatchetError> {
+        if bytes.len() < 2 * POINT_LEN + 5 {
+            return Err(RatchetError::MalformedMessage);
+        }
+        let (identity_key, rest) = bytes.split_at(POINT_LEN);
+        let (ephemeral_key, rest) = rest.split_at(POINT_LEN);
+        let (signed_prekey_id, rest) = rest.split_at(4);
+        let one_time_prekey_id = match rest {
+            [0] => None,
+            [1, id @ ..] if id.len() == 4 => Some(read_u32(id)),
+            _ => return Err(RatchetError::MalformedMessage),
+        };
+
+        Ok(Self {
+            identity_key: decode_public_key(identity_key)?,
+            ephemeral_key: decode_public_key(ephemeral_key)?,
+            signed_prekey_id: read_u32(signed_prekey_id),
+            one_time_prekey_id,
+        })
+    }
+}
+
+/// The sender's half of X3DH against `bundle`. Returns a session that can
+/// encrypt straight away, and the header to send with its messages until
+/// the recipient answers.
+pub fn initiate(identity: &KeyPair, bundle: &PrekeyBundle) -> (Session, InitialHeader) {
+    let ephemeral = KeyPair::generate();
+
+    let mut dh = vec![
+        identity.dh(&bundle.signed_prekey),
+        ephemeral.dh(&bundle.identity_key),
+        ephemeral.dh(&bundle.signed_prekey),
+    ];
+    if let Some((_, one_time_prekey)) = &bundle.one_time_prekey {
+        dh.push(ephemeral.dh(one_time_prekey));
+    }
+
+    let secret = x3dh_secret(&dh);
+    let associated_data = associated_data(&identity.public, &bundle.identity_key);
+    let session = Session::initiate(secret, associated_data, bundle.signed_prekey);
+    let header = InitialHeader {
+        identity_key: identity.public,
+        ephemeral_key: ephemeral.public,
+        signed_prekey_id: bundle.signed_prekey_id,
+        one_time_prekey_id: bundle.one_time_prekey.map(|(id, _)| id),
+    };
+    (session, header)
+}
+
+/// The recipient's half of X3DH, with the prekeys `header` names. The
+/// one-time prekey should be deleted once the first message decrypts.
+pub fn respond(
+    identity: &KeyPair,
+    signed_prekey: &KeyPair,
+    one_time_prekey: Option<&KeyPair>,
+    header: &InitialHeader,
+) -> Result<Session, RatchetError> {
+    if header.one_time_prekey_id.is_some() != one_time_prekey.is_some() {
+        return Err(RatchetError::PrekeyMismatch);
+    }
+
+    let mut dh = vec![
+        signed_prekey.dh(&header.identity_key),
+        identity.dh(&header.ephemeral_key),
+        signed_prekey.dh(&header.ephemeral_key),
+    ];
+    if let Some(one_time_prekey) = one_time_prekey {
+        dh.push(one_time_prekey.dh(&header.ephemeral_key));
+    }
+
+    let secret = x3dh_secret(&dh);
+    let associated_data = associated_data(&header.identity_key, &identity.public);
+    Ok(Session::respond(
+        secret,
+        associated_data,
+        signed_prekey.clone(),
+    ))
+}
+
+fn x3dh_secret(dh: &[[u8; POINT_LEN]]) -> [u8; 32] {
+    // As in X3DH over X25519, 32 0xff bytes ahead of the DH outputs keep the
+    // input distinct from other uses of the same keys
+    let mut input = vec![0xff; 32];
+    for output in dh {
+        input.extend_from_slice(output);
+    }
+
+    let mut secret = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&[0u8; 32]), &input)
+        .expand(X3DH_INFO, &mut secret)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    secret
+}
+
+/// `initiator_identity || responder_identity`, authenticated with every
+/// message of the session.
+fn associated_data(initiator: &Ed25519, responder: &Ed25519) -> Vec<u8> {
+    let mut ad = encode_public_key(initiator).to_vec();
+    ad.extend_from_slice(&encode_public_key(responder));
+    ad
+}
+
+/// The public part of a message key's position: which ratchet key it was
+/// sent under, and where in that chain.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub ratchet_key: Ed25519,
+    /// Length of the sender's previous sending chain
+    pub previous_chain_length: u32,
+    pub message_number: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RatchetMessage {
+    pub header: Header,
+    pub ciphertext: Vec<u8>,
+}
+
+impl RatchetMessage {
+    /// Current format: `version (1) || ratchet_key (32) ||
+    /// previous_chain_length (4) || message_number (4) || ciphertext`,
+    /// integers little endian.
+    pub const VERSION: u8 = 1;
+
+    const HEADER_LEN: usize = 1 + POINT_LEN + 4 + 4;
+
+    fn header_bytes(header: &Header) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::HEADER_LEN);
+        bytes.push(Self::VERSION);
+        bytes.extend_from_slice(&encode_public_key(&header.ratchet_key));
+        bytes.extend_from_slice(&header.previous_chain_length.to_le_bytes());
+        bytes.extend_from_slice(&header.message_number.to_le_bytes());
+        bytes
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Self::header_bytes(&self.header);
+        bytes.extend_from_slice(&self.ciphertext);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, RatchetError> {
+        let (&version, rest) = bytes.split_first().ok_or(RatchetError::MalformedMessage)?;
+        if version != Self::VERSION {
+            return Err(RatchetError::UnsupportedVersion(version));
+        }
+        if bytes.len() < Self::HEADER_LEN {
+            return Err(RatchetError::MalformedMessage);
+        }
+
+        let (ratchet_key, rest) = rest.split_at(POINT_LEN);
+        let (previous_chain_length, rest) = rest.split_at(4);
+        let (message_number, ciphertext) = rest.split_at(4);
+
+        Ok(Self {
+            header: Header {
+                ratchet_key: decode_public_key(ratchet_key)
+                    .map_err(|_| RatchetError::MalformedMessage)?,
+                previous_chain_length: read_u32(previous_chain_length),
+                message_number: read_u32(message_number),
+            },
+            ciphertext: ciphertext.to_vec(),
+        })
+    }
+
+    /// Encodes the message for `CreateMessageRequest.encrypted_content`.
+    pub fn to_base64(&self) -> String {
+        URL_SAFE_NO_PAD.encode(self.to_bytes())
+    }
+
+    pub fn from_base64(encoded: &str) -> Result<Self, RatchetError> {
+        let bytes = URL_SAFE_NO_PAD
+            .decode(encoded)
+            .map_err(|_| RatchetError::MalformedMessage)?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+/// One side of a double-ratchet conversation. Keep it secret and persist it
+/// after every call; an old copy can decrypt messages the live one already
+/// has.
+#[derive(Clone)]
+pub struct Session {
+    associated_data: Vec<u8>,
+    root_key: [u8; 32],
+    ratchet_key: KeyPair,
+    remote_ratchet_key: Option<Ed25519>,
+    sending_chain: Option<[u8; 32]>,
+    receiving_chain: Option<[u8; 32]>,
+    sent: u32,
+    received: u32,
+    previous_chain_length: u32,
+    // Keys of messages skipped over, by the ratchet key and message number
+    // they were sent under
+    skipped: HashMap<([u8; POINT_LEN], u32), [u8; 32]>,
+}
+
+impl Session {
+    fn initiate(secret: [u8; 32], associated_data: Vec<u8>, remote_ratchet_key: Ed25519) -> Self {
+        let ratchet_key = KeyPair::generate();
+        let (root_key, sending_chain) = kdf_root(&secret, &ratchet_key.dh(&remote_ratchet_key));
+
+        Self {
+            associated_data,
+            root_key,
+            ratchet_key,
+            remote_ratchet_key: Some(remote_ratchet_key),
+            sending_chain: Some(sending_chain),
+            receiving_chain: None,
+            sent: 0,
+            received: 0,
+            previous_chain_length: 0,
+            skipped: HashMap::new(),
+        }
+    }
+
+    fn respond(secret: [u8; 32], associated_data: Vec<u8>, ratchet_key: KeyPair) -> Self {
+        Self {
+            associated_data,
+            root_key: secret,
+            ratchet_key,
+            remote_ratchet_key: None,
+            sending_chain: None,
+            receiving_chain: None,
+            sent: 0,
+            received: 0,
+            previous_chain_length: 0,
+            skipped: HashMap::new(),
+        }
+    }
+
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<RatchetMessage, RatchetError> {
+        let chain = self.sending_chain.ok_or(RatchetError::NotReadyToSend)?;
+        let (next_chain, message_key) = kdf_chain(&chain);
+
+        let header = Header {
+            ratchet_key: self.ratchet_key.public,
+            previous_chain_length: self.previous_chain_length,
+            message_number: self.sent,
+        };
+        let (key, nonce) = aead_key(&message_key);
+        let ciphertext = Aes256Gcm::new(key.as_slice().into())
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: &self.aad(&header),
+                },
+            )
+            .map_err(|_| RatchetError::EncryptionFailed)?;
+
+        self.sending_chain = Some(next_chain);
+        self.sent = self
+            .sent
+            .checked_add(1)
+            .ok_or(RatchetError::EncryptionFailed)?;
+        Ok(RatchetMessage { header, ciphertext })
+    }
+
+    /// Decrypts `message`, ratcheting forward as its header requires. The
+    /// session is left untouched if it fails, so a forged message cannot
+    /// knock it out of step.
+    pub fn decrypt(&mut self, message: &RatchetMessage) -> Result<Vec<u8>, RatchetError> {
+        let mut next = self.clone();
+        let plaintext = next.decrypt_in_place(message)?;
+        *self = next;
+        Ok(plaintext)
+    }
+
+    fn decrypt_in_place(&mut self, message: &RatchetMessage) -> Result<Vec<u8>, RatchetError> {
+        let header = &message.header;
+        let position = (
+            encode_public_key(&header.ratchet_key),
+            header.message_number,
+        );
+        if let Some(message_key) = self.skipped.remove(&position) {
+            return self.open(&message_key, message);
+        }
+
+        if self.remote_ratchet_key != Some(header.ratchet_key) {
+            self.skip_until(header.previous_chain_length)?;
+            self.step(header.ratchet_key);
+        }
+        self.skip_until(header.message_number)?;
+
+        let chain = self.receiving_chain.ok_or(RatchetError::DecryptionFailed)?;
+        let (next_chain, message_key) = kdf_chain(&chain);
+        self.receiving_chain = Some(next_chain);
+        self.received = self
+            .received
+            .checked_add(1)
+            .ok_or(RatchetError::DecryptionFailed)?;
+        self.open(&message_key, message)
+    }
+
+    /// Stores the keys of the receiving chain's messages up to `until`.
+    fn skip_until(&mut self, until: u32) -> Result<(), RatchetError> {
+        let (Some(mut chain), Some(remote)) = (self.receiving_chain, self.remote_ratchet_key)
+        else {
+            return Ok(());
+        };
+        if until < self.received {
+            return Ok(());
+        }
+        let count = until - self.received;
+        if count > MAX_SKIP || self.skipped.len() + count as usize > MAX_SKIP as usize {
+            return Err(RatchetError::TooManySkipped);
+        }
+
+        let remote = encode_public_key(&remote);
+        while self.received < until {
+            let (next_chain, message_key) = kdf_chain(&chain);
+            self.skipped.insert((remote, self.received), message_key);
+            chain = next_chain;
+            self.received += 1;
+        }
+        self.receiving_chain = Some(chain);
+        Ok(())
+    }
+
+    /// The DH ratchet: a new receiving chain under the remote's new key, then
+    /// a new key pair of our own and a sending chain under it.
+    fn step(&mut self, remote_ratchet_key: Ed25519) {
+        self.previous_chain_length = self.sent;
+        self.sent = 0;
+        self.received = 0;
+        self.remote_ratchet_key = Some(remote_ratchet_key);
+
+        let (root_key, receiving_chain) =
+            kdf_root(&self.root_key, &self.ratchet_key.dh(&remote_ratchet_key));
+        self.ratchet_key = KeyPair::generate();
+        let (root_key, sending_chain) =
+            kdf_root(&root_key, &self.ratchet_key.dh(&remote_ratchet_key));
+
+        self.root_key = root_key;
+        self.receiving_chain = Some(receiving_chain);
+        self.sending_chain = Some(sending_chain);
+    }
+
+    fn open(
+        &self,
+        message_key: &[u8; 32],
+        message: &RatchetMessage,
+    ) -> Result<Vec<u8>, RatchetError> {
+        let (key, nonce) = aead_key(message_key);
+        Aes256Gcm::new(key.as_slice().into())
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &message.ciphertext,
+                    aad: &self.aad(&message.header),
+                },
+            )
+            .map_err(|_| RatchetError::DecryptionFailed)
+    }
+
+    /// AES-GCM associated data: the label, both identities and the header.
+    fn aad(&self, header: &Header) -> Vec<u8> {
+        let mut aad = AAD_LABEL.to_vec();
+        aad.extend_from_slice(&self.associated_data);
+        aad.extend_from_slice(&RatchetMessage::header_bytes(header));
+        aad
+    }
+}
+
+/// `(root_key, chain_key)` from the current root key and a DH output.
+fn kdf_root(root_key: &[u8; 32], dh: &[u8; POINT_LEN]) -> ([u8; 32], [u8; 32]) {
+    let mut output = [0u8; 64];
+    Hkdf::<Sha256>::new(Some(root_key), dh)
+        .expand(ROOT_INFO, &mut output)
+        .expect("64 bytes is a valid HKDF-SHA256 output length");
+
+    let (root_key, chain_key) = output.split_at(32);
+    (
+        root_key.try_into().expect("32 bytes"),
+        chain_key.try_into().expect("32 bytes"),
+    )
+}
+
+/// `(next_chain_key, message_key)` from a chain key.
+fn kdf_chain(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let hkdf = Hkdf::<Sha256>::from_prk(chain_key).expect("A chain key is a full-length PRK");
+    let mut next_chain_key = [0u8; 32];
+    let mut message_key = [0u8; 32];
+    hkdf.expand(CHAIN_KEY_INFO, &mut next_chain_key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    hkdf.expand(MESSAGE_KEY_INFO, &mut message_key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    (next_chain_key, message_key)
+}
+
+/// The AES-256-GCM key and nonce for a message key. Each message key seals
+/// exactly one message, so a derived nonce is never reused under one key.
+fn aead_key(message_key: &[u8; 32]) -> ([u8; 32], [u8; 12]) {
+    let mut output = [0u8; 44];
+    Hkdf::<Sha256>::new(None, message_key)
+        .expand(AEAD_INFO, &mut output)
+        .expect("44 bytes is a valid HKDF-SHA256 output length");
+
+    let (key, nonce) = output.split_at(32);
+    (
+        key.try_into().expect("32 bytes"),
+        nonce.try_into().expect("12 bytes"),
+    )
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes.try_into().expect("4 bytes"))
+}
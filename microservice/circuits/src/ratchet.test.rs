@@ -0,0 +1,316 @@
+use super::*;
+use std::collections::HashSet;
+
+struct Recipient {
+    identity: KeyPair,
+    signed_prekey: KeyPair,
+    one_time_prekey: KeyPair,
+}
+
+impl Recipient {
+    fn new() -> Self {
+        Self {
+            identity: KeyPair::generate(),
+            signed_prekey: KeyPair::generate(),
+            one_time_prekey: KeyPair::generate(),
+        }
+    }
+
+    fn bundle(&self, with_one_time_prekey: bool) -> PrekeyBundle {
+        PrekeyBundle {
+            identity_key: self.identity.public,
+            signed_prekey_id: 7,
+            signed_prekey: self.signed_prekey.public,
+            one_time_prekey: with_one_time_prekey.then_some((11, self.one_time_prekey.public)),
+        }
+    }
+}
+
+/// Alice's and Bob's sessions after Alice starts one against Bob's bundle
+/// and Bob receives her first message.
+fn established() -> (Session, Session) {
+    let bob = Recipient::new();
+    let (mut alice, header) = initiate(&KeyPair::generate(), &bob.bundle(true));
+
+    let first = alice.encrypt(b"hello").unwrap();
+    let mut bob = respond(
+        &bob.identity,
+        &bob.signed_prekey,
+        Some(&bob.one_time_prekey),
+        &header,
+    )
+    .unwrap();
+    assert_eq!(bob.decrypt(&first).unwrap(), b"hello");
+
+    (alice, bob)
+}
+
+#[test]
+fn test_x3dh_agrees_with_and_without_one_time_prekey() {
+    for with_one_time_prekey in [true, false] {
+        let bob = Recipient::new();
+        let alice_identity = KeyPair::generate();
+        let (mut alice, header) = initiate(&alice_identity, &bob.bundle(with_one_time_prekey));
+
+        assert_eq!(header.identity_key, alice_identity.public);
+        assert_eq!(header.signed_prekey_id, 7);
+        assert_eq!(
+            header.one_time_prekey_id,
+            with_one_time_prekey.then_some(11)
+        );
+
+        let message = alice.encrypt(b"first").unwrap();
+        let mut bob = respond(
+            &bob.identity,
+            &bob.signed_prekey,
+            with_one_time_prekey.then_some(&bob.one_time_prekey),
+            &header,
+        )
+        .unwrap();
+        assert_eq!(bob.decrypt(&message).unwrap(), b"first");
+    }
+}
+
+#[test]
+fn test_respond_requires_the_named_one_time_prekey() {
+    let bob = Recipient::new();
+    let (_, header) = initiate(&KeyPair::generate(), &bob.bundle(true));
+
+    assert!(matches!(
+        respond(&bob.identity, &bob.signed_prekey, None, &header),
+        Err(RatchetError::PrekeyMismatch)
+    ));
+
+    // The wrong one-time prekey derives a different secret
+    let (mut alice, header) = initiate(&KeyPair::generate(), &bob.bundle(true));
+    let message = alice.encrypt(b"first").unwrap();
+    let mut bob = respond(
+        &bob.identity,
+        &bob.signed_prekey,
+        Some(&KeyPair::generate()),
+        &header,
+    )
+    .unwrap();
+    assert!(matches!(
+        bob.decrypt(&message),
+        Err(RatchetError::DecryptionFailed)
+    ));
+}
+
+#[test]
+fn test_responder_cannot_send_first() {
+    let bob = Recipient::new();
+    let (_, header) = initiate(&KeyPair::generate(), &bob.bundle(false));
+    let mut bob = respond(&bob.identity, &bob.signed_prekey, None, &header).unwrap();
+
+    assert!(matches!(
+        bob.encrypt(b"too early"),
+        Err(RatchetError::NotReadyToSend)
+    ));
+}
+
+#[test]
+fn test_conversation_ratchets_on_each_turn() {
+    let (mut alice, mut bob) = established();
+    let mut ratchet_keys = HashSet::new();
+
+    for turn in 0..4 {
+        let reply = bob.encrypt(format!("reply {turn}").as_bytes()).unwrap();
+        assert_eq!(
+            alice.decrypt(&reply).unwrap(),
+            format!("reply {turn}").as_bytes()
+        );
+        assert!(ratchet_keys.insert(encode_public_key(&reply.header.ratchet_key)));
+
+        let message = alice.encrypt(format!("message {turn}").as_bytes()).unwrap();
+        assert_eq!(
+            bob.decrypt(&message).unwrap(),
+            format!("message {turn}").as_bytes()
+        );
+        assert!(ratchet_keys.insert(encode_public_key(&message.header.ratchet_key)));
+    }
+}
+
+#[test]
+fn test_out_of_order_messages_decrypt() {
+    let (mut alice, mut bob) = established();
+
+    let first = alice.encrypt(b"one").unwrap();
+    let second = alice.encrypt(b"two").unwrap();
+    let third = alice.encrypt(b"three").unwrap();
+
+    assert_eq!(bob.decrypt(&third).unwrap(), b"three");
+    assert_eq!(bob.decrypt(&first).unwrap(), b"one");
+
+    // Across a ratchet step: Bob answers, Alice ratchets and sends again
+    let reply = bob.encrypt(b"reply").unwrap();
+    assert_eq!(alice.decrypt(&reply).unwrap(), b"reply");
+    let fourth = alice.encrypt(b"four").unwrap();
+    let fifth = alice.encrypt(b"five").unwrap();
+
+    assert_eq!(bob.decrypt(&fifth).unwrap(), b"five");
+    assert_eq!(bob.decrypt(&second).unwrap(), b"two");
+    assert_eq!(bob.decrypt(&fourth).unwrap(), b"four");
+}
+
+#[test]
+fn test_message_keys_are_single_use() {
+    let (mut alice, mut bob) = established();
+
+    let message = alice.encrypt(b"once").unwrap();
+    assert_eq!(bob.decrypt(&message).unwrap(), b"once");
+    assert!(matches!(
+        bob.decrypt(&message),
+        Err(RatchetError::DecryptionFailed)
+    ));
+
+    // Skipped keys too
+    let first = alice.encrypt(b"one").unwrap();
+    let second = alice.encrypt(b"two").unwrap();
+    bob.decrypt(&second).unwrap();
+    bob.decrypt(&first).unwrap();
+    assert!(matches!(
+        bob.decrypt(&first),
+        Err(RatchetError::DecryptionFailed)
+    ));
+}
+
+#[test]
+fn test_failed_decryption_leaves_session_in_step() {
+    let (mut alice, mut bob) = established();
+
+    let mut forged = alice.encrypt(b"genuine").unwrap();
+    forged.ciphertext[0] ^= 1;
+    assert!(matches!(
+        bob.decrypt(&forged),
+        Err(RatchetError::DecryptionFailed)
+    ));
+
+    // A header claiming a new ratchet key must not move Bob's ratchet either
+    let mut forged_step = alice.encrypt(b"genuine").unwrap();
+    forged_step.header.ratchet_key = KeyPair::generate().public;
+    assert!(bob.decrypt(&forged_step).is_err());
+
+    forged.ciphertext[0] ^= 1;
+    assert_eq!(bob.decrypt(&forged).unwrap(), b"genuine");
+}
+
+#[test]
+fn test_header_is_authenticated() {
+    let (mut alice, mut bob) = established();
+
+    let mut message = alice.encrypt(b"hello").unwrap();
+    message.header.previous_chain_length += 1;
+
+    assert!(matches!(
+        bob.decrypt(&message),
+        Err(RatchetError::DecryptionFailed)
+    ));
+}
+
+#[test]
+fn test_too_many_skipped_messages_are_refused() {
+    let (mut alice, mut bob) = established();
+
+    let mut message = alice.encrypt(b"far ahead").unwrap();
+    message.header.message_number = MAX_SKIP + 2;
+
+    assert!(matches!(
+        bob.decrypt(&message),
+        Err(RatchetError::TooManySkipped)
+    ));
+}
+
+#[test]
+fn test_earlier_state_cannot_read_later_messages() {
+    let (mut alice, mut bob) = established();
+    let mut compromised = bob.clone();
+
+    // Both sides ratchet once more after the compromise
+    let reply = bob.encrypt(b"reply").unwrap();
+    alice.decrypt(&reply).unwrap();
+    let message = alice.encrypt(b"message").unwrap();
+    bob.decrypt(&message).unwrap();
+    let reply = bob.encrypt(b"reply").unwrap();
+    alice.decrypt(&reply).unwrap();
+
+    let secret = alice.encrypt(b"secret").unwrap();
+    assert_eq!(bob.decrypt(&secret).unwrap(), b"secret");
+    assert!(compromised.decrypt(&secret).is_err());
+}
+
+#[test]
+fn test_message_bytes_round_trip() {
+    let (mut alice, mut bob) = established();
+    let message = alice.encrypt(b"over the wire").unwrap();
+
+    let decoded = RatchetMessage::from_base64(&message.to_base64()).unwrap();
+    assert_eq!(decoded, message);
+    assert_eq!(bob.decrypt(&decoded).unwrap(), b"over the wire");
+
+    let mut bytes = message.to_bytes();
+    bytes[0] = 9;
+    assert!(matches!(
+        RatchetMessage::from_bytes(&bytes),
+        Err(RatchetError::UnsupportedVersion(9))
+    ));
+    assert!(matches!(
+        RatchetMessage::from_bytes(&bytes[..10]),
+        Err(RatchetError::UnsupportedVersion(9))
+    ));
+    assert!(matches!(
+        RatchetMessage::from_bytes(&message.to_bytes()[..10]),
+        Err(RatchetError::MalformedMessage)
+    ));
+}
+
+#[test]
+fn test_initial_header_round_trips() {
+    let bob = Recipient::new();
+    for with_one_time_prekey in [true, false] {
+        let (_, header) = initiate(&KeyPair::generate(), &bob.bundle(with_one_time_prekey));
+        let bytes = header.to_bytes();
+
+        assert_eq!(InitialHeader::from_bytes(&bytes).unwrap(), header);
+        assert!(matches!(
+            InitialHeader::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(RatchetError::MalformedMessage)
+        ));
+    }
+}
+
+#[test]
+fn test_identity_point_is_not_a_public_key() {
+    let identity = encode_public_key(&Ed25519::zero());
+    assert!(matches!(
+        decode_public_key(&identity),
+        Err(RatchetError::MalformedKey)
+    ));
+
+    let key = KeyPair::generate().public;
+    assert_eq!(decode_public_key(&encode_public_key(&key)).unwrap(), key);
+}
+
+#[test]
+fn test_signed_prekey_payload_binds_every_field() {
+    let identity = KeyPair::generate().public;
+    let prekey = KeyPair::generate().public;
+    let payload = signed_prekey_payload(&identity, 1, &prekey);
+
+    assert_ne!(payload, signed_prekey_payload(&identity, 2, &prekey));
+    assert_ne!(payload, signed_prekey_payload(&prekey, 1, &identity));
+    assert_ne!(
+        payload,
+        signed_prekey_payload(&KeyPair::generate().public, 1, &prekey)
+    );
+}
+
+#[test]
+fn test_one_time_prekey_payload_differs_from_signed_prekey_payload() {
+    let prekey = KeyPair::generate().public;
+    let payload = one_time_prekey_payload(1, &prekey);
+
+    assert_ne!(payload, one_time_prekey_payload(2, &prekey));
+    assert_ne!(payload, signed_prekey_payload(&prekey, 1, &prekey));
+    assert!(payload.ends_with(&encode_public_key(&prekey)));
+}
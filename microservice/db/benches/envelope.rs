@@ -0,0 +1,106 @@
+//! Overhead of envelope-encrypting message rows, per message.
+//!
+//! ```text
+//! cargo bench -p db --bench envelope
+//! ```
+
+use aes_gcm::{
+    aead::{Aead, KeyInit, OsRng, Payload},
+    AeadCore, Aes256Gcm, Nonce,
+};
+use chrono::Utc;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use db::{
+    envelope::{MasterKey, MessageEnvelope, SealedRow},
+    models::Message,
+};
+use sqlx::error::BoxDynError;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Wraps with AES-GCM, which is what the server's key ring does.
+struct BenchMasterKey(Aes256Gcm);
+
+impl MasterKey for BenchMasterKey {
+    fn wrap(&self, data_key: &[u8], associated_data: &[u8]) -> Vec<u8> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let mut wrapped = nonce.to_vec();
+        wrapped.extend(
+            self.0
+                .encrypt(
+                    &nonce,
+                    Payload {
+                        msg: data_key,
+                        aad: associated_data,
+                    },
+                )
+                .unwrap(),
+        );
+        wrapped
+    }
+
+    fn unwrap(&self, wrapped: &[u8], associated_data: &[u8]) -> Result<Vec<u8>, BoxDynError> {
+        let (nonce, ciphertext) = wrapped.split_at(12);
+        self.0
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: associated_data,
+                },
+            )
+            .map_err(|_| "wrong master key".into())
+    }
+
+    fn needs_rewrap(&self, _wrapped: &[u8]) -> bool {
+        false
+    }
+}
+
+fn envelope_overhead(c: &mut Criterion) {
+    let key = Aes256Gcm::generate_key(&mut OsRng);
+    let envelope = MessageEnvelope::new(Arc::new(BenchMasterKey(Aes256Gcm::new(&key))));
+    let (sender_id, recipient_id) = (Uuid::now_v7(), Uuid::now_v7());
+    let row = SealedRow::Message {
+        id: 1,
+        sender_id,
+        recipient_id,
+    };
+    let signature = "s".repeat(86);
+
+    let mut group = c.benchmark_group("envelope");
+    // Client ciphertexts, base64-encoded, from a short note up to a long message
+    for size in [128, 1024, 16 * 1024] {
+        let content = "c".repeat(size);
+
+        group.bench_with_input(BenchmarkId::new("seal", size), &content, |b, content| {
+            b.iter(|| {
+                envelope.seal(black_box(row), black_box(content), Some(&signature))
+            })
+        });
+
+        let (encrypted_content, sealed_signature) =
+            envelope.seal(row, &content, Some(&signature));
+        let stored = Message {
+            id: 1,
+            sender_id,
+            recipient_id,
+            encrypted_content,
+            parent_id: None,
+            signature: sealed_signature,
+            created_at: Utc::now(),
+            is_read: false,
+        };
+        group.bench_with_input(BenchmarkId::new("open", size), &stored, |b, stored| {
+            b.iter_batched(
+                || stored.clone(),
+                |mut message| envelope.open(&mut message).unwrap(),
+                criterion::BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, envelope_overhead);
+criterion_main!(benches);
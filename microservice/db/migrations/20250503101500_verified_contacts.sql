@@ -0,0 +1,16 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS verified_contacts (
+    user_id TEXT NOT NULL,
+    contact_id TEXT NOT NULL,
+    public_key_hash TEXT NOT NULL,
+    verified_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, contact_id),
+    CONSTRAINT fk_verifier
+        FOREIGN KEY (user_id)
+        REFERENCES users(id)
+        ON DELETE CASCADE,
+    CONSTRAINT fk_contact
+        FOREIGN KEY (contact_id)
+        REFERENCES users(id)
+        ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+-- Pedersen commitment to the ciphertext, supplied by the sender
+ALTER TABLE messages ADD COLUMN commitment TEXT;
@@ -0,0 +1,21 @@
+-- The share each anonymous message revealed, under its per-epoch nullifier.
+-- A second share under the same nullifier recovers the sender's secret.
+CREATE TABLE IF NOT EXISTS rate_limit_nullifiers (
+    nullifier TEXT PRIMARY KEY NOT NULL,
+    epoch INTEGER NOT NULL,
+    share_x TEXT NOT NULL,
+    share_y TEXT NOT NULL,
+    message_id INTEGER NOT NULL,
+    CONSTRAINT fk_rate_limited_message
+        FOREIGN KEY (message_id)
+        REFERENCES anonymous_messages(id)
+        ON DELETE CASCADE
+);
+
+-- Identities caught exceeding the rate limit, keyed by the commitment they
+-- registered. The recovered secret lets later nullifiers be recognized.
+CREATE TABLE IF NOT EXISTS blocked_identities (
+    identity_commitment TEXT PRIMARY KEY NOT NULL,
+    identity_secret TEXT NOT NULL,
+    blocked_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,14 @@
+-- Each user's current X3DH identity key and signed prekey. Replaced in place
+-- when the user rotates their signed prekey.
+CREATE TABLE IF NOT EXISTS signed_prekeys (
+    user_id TEXT PRIMARY KEY NOT NULL,
+    identity_key TEXT NOT NULL,
+    prekey_id INTEGER NOT NULL,
+    prekey TEXT NOT NULL,
+    signature TEXT NOT NULL,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT fk_prekey_owner
+        FOREIGN KEY (user_id)
+        REFERENCES users(id)
+        ON DELETE CASCADE
+);
@@ -0,0 +1,20 @@
+-- One-time prekeys, each handed to at most one sender. Claimed keys are kept
+-- with their claim time so that replaying an old upload cannot put them back
+-- into circulation.
+CREATE TABLE IF NOT EXISTS one_time_prekeys (
+    user_id TEXT NOT NULL,
+    prekey_id INTEGER NOT NULL,
+    prekey TEXT NOT NULL,
+    signature TEXT NOT NULL,
+    claimed_at TIMESTAMP,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, prekey_id),
+    CONSTRAINT fk_one_time_prekey_owner
+        FOREIGN KEY (user_id)
+        REFERENCES users(id)
+        ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_one_time_prekeys_unclaimed
+    ON one_time_prekeys(user_id, prekey_id)
+    WHERE claimed_at IS NULL;
@@ -0,0 +1,3 @@
+-- Proof that the sender knows the ephemeral secret behind the sealed
+-- message's epub, kept once it has been verified against the message
+ALTER TABLE messages ADD COLUMN ephemeral_key_proof TEXT;
@@ -0,0 +1,3 @@
+-- The Jubjub key messages to the user are sealed to. Ephemeral key proofs
+-- on messages to the user are checked against it.
+ALTER TABLE users ADD COLUMN message_key TEXT;
@@ -0,0 +1,88 @@
+-- PostgreSQL counterpart of the SQLite schema in ../migrations, consolidated
+-- into a single migration. Timestamps that are decoded as NaiveDateTime are
+-- stored as UTC without a time zone, matching SQLite's CURRENT_TIMESTAMP.
+
+CREATE TABLE users (
+    id UUID PRIMARY KEY NOT NULL,
+    username TEXT NOT NULL UNIQUE CHECK (length(username) >= 3 AND length(username) <= 50),
+    public_key TEXT NOT NULL CHECK (
+        length(public_key) % 4 = 0 AND
+        public_key ~ '^[A-Za-z0-9_-]*$'
+    ),
+    public_key_hash TEXT NOT NULL UNIQUE CHECK (
+        length(public_key_hash) = 43 AND
+        public_key_hash ~ '^[A-Za-z0-9_-]*$'
+    ),
+    created_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    updated_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    last_login TIMESTAMP,
+    CONSTRAINT updated_after_creation CHECK (updated_at >= created_at),
+    CONSTRAINT valid_login_time CHECK (last_login IS NULL OR last_login >= created_at)
+);
+
+CREATE INDEX idx_users_username ON users(username);
+
+CREATE INDEX idx_users_pubkey_hash ON users(public_key_hash);
+
+CREATE FUNCTION update_users_timestamp() RETURNS TRIGGER AS $$
+BEGIN
+    NEW.updated_at = NOW() AT TIME ZONE 'utc';
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER update_users_timestamp
+BEFORE UPDATE ON users
+FOR EACH ROW
+EXECUTE FUNCTION update_users_timestamp();
+
+CREATE TABLE messages (
+    id BIGSERIAL PRIMARY KEY,
+    sender_id UUID NOT NULL,
+    recipient_id UUID NOT NULL,
+    encrypted_content TEXT NOT NULL,
+    parent_id BIGINT,
+    signature TEXT,
+    is_read BOOLEAN DEFAULT FALSE NOT NULL,
+    created_at BIGINT NOT NULL,
+    CONSTRAINT fk_sender
+        FOREIGN KEY (sender_id)
+        REFERENCES users(id)
+        ON DELETE CASCADE,
+    CONSTRAINT fk_recipient
+        FOREIGN KEY (recipient_id)
+        REFERENCES users(id)
+        ON DELETE CASCADE
+);
+
+CREATE TABLE refresh_tokens (
+    id BIGSERIAL PRIMARY KEY,
+    user_id UUID NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    device_info TEXT,
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT NOW(),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE TABLE revoked_tokens (
+    token_hash TEXT PRIMARY KEY NOT NULL,
+    revoked_at TIMESTAMPTZ DEFAULT NOW(),
+    reason TEXT
+);
+
+CREATE TABLE verified_contacts (
+    user_id UUID NOT NULL,
+    contact_id UUID NOT NULL,
+    public_key_hash TEXT NOT NULL,
+    verified_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    PRIMARY KEY (user_id, contact_id),
+    CONSTRAINT fk_verifier
+        FOREIGN KEY (user_id)
+        REFERENCES users(id)
+        ON DELETE CASCADE,
+    CONSTRAINT fk_contact
+        FOREIGN KEY (contact_id)
+        REFERENCES users(id)
+        ON DELETE CASCADE
+);
@@ -0,0 +1,26 @@
+-- Leaves of the anonymity set, in insertion order. Rows are never deleted, so
+-- a leaf's position in the Merkle tree never changes.
+CREATE TABLE membership_leaves (
+    leaf_index BIGSERIAL PRIMARY KEY,
+    user_id UUID NOT NULL UNIQUE,
+    leaf TEXT NOT NULL,
+    CONSTRAINT fk_member
+        FOREIGN KEY (user_id)
+        REFERENCES users(id)
+);
+
+-- Messages whose sender is only known to be a member of the anonymity set
+CREATE TABLE anonymous_messages (
+    id BIGSERIAL PRIMARY KEY,
+    recipient_id UUID NOT NULL,
+    encrypted_content TEXT NOT NULL,
+    nullifier TEXT NOT NULL UNIQUE,
+    membership_root TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    CONSTRAINT fk_anonymous_recipient
+        FOREIGN KEY (recipient_id)
+        REFERENCES users(id)
+        ON DELETE CASCADE
+);
+
+CREATE INDEX idx_anonymous_messages_recipient ON anonymous_messages(recipient_id);
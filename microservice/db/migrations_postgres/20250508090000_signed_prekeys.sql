@@ -0,0 +1,14 @@
+-- Each user's current X3DH identity key and signed prekey. Replaced in place
+-- when the user rotates their signed prekey.
+CREATE TABLE signed_prekeys (
+    user_id UUID PRIMARY KEY,
+    identity_key TEXT NOT NULL,
+    prekey_id BIGINT NOT NULL,
+    prekey TEXT NOT NULL,
+    signature TEXT NOT NULL,
+    updated_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    CONSTRAINT fk_prekey_owner
+        FOREIGN KEY (user_id)
+        REFERENCES users(id)
+        ON DELETE CASCADE
+);
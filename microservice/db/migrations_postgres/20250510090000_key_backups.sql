@@ -0,0 +1,35 @@
+-- Each user's passphrase-sealed account key backup. `encrypted_key` and
+-- `recovery_key` are additionally encrypted with the server's key. Failed
+-- recovery-key attempts push back when the next one is accepted, instead of
+-- locking the backup for good.
+CREATE TABLE key_backups (
+    user_id UUID PRIMARY KEY,
+    version BIGINT NOT NULL,
+    encrypted_key TEXT NOT NULL,
+    salt TEXT NOT NULL,
+    kdf_iterations BIGINT NOT NULL,
+    recovery_key TEXT NOT NULL,
+    failed_attempts BIGINT NOT NULL DEFAULT 0,
+    recovery_retry_at TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    CONSTRAINT fk_key_backup_owner
+        FOREIGN KEY (user_id)
+        REFERENCES users(id)
+        ON DELETE CASCADE
+);
+
+-- Recovery challenges get a row each, so issuing one doesn't replace another
+-- that is still being answered. A challenge only answers for the backup
+-- version it was issued against.
+CREATE TABLE key_backup_challenges (
+    challenge TEXT PRIMARY KEY,
+    user_id UUID NOT NULL,
+    version BIGINT NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    CONSTRAINT fk_key_backup_challenge_backup
+        FOREIGN KEY (user_id)
+        REFERENCES key_backups(user_id)
+        ON DELETE CASCADE
+);
+
+CREATE INDEX idx_key_backup_challenges_user ON key_backup_challenges(user_id);
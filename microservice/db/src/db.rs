@@ -1,13 +1,25 @@
-use crate::models::{Message, RawMessage, User};
+use crate::models::{
+    AnonymousMessage, KeyBackup, MembershipLeaf, Message, OneTimePrekey, RateLimitShare,
+    RawMessage, SignedPrekey, User, VerifiedContact,
+};
+use crate::envelope::{rewrap_row, MessageEnvelope, SealedRow};
 use crate::{public_key::PublicKey, public_key_hash::PublicKeyHash};
 use dotenv::dotenv;
 use serde::{Deserialize, Serialize};
 use sqlx::types::chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
-use sqlx::{sqlite::SqliteArguments, Arguments, Row};
-use sqlx::{Error, SqlitePool};
+use sqlx::{
+    migrate::Migrator,
+    sqlite::{SqliteArguments, SqliteConnectOptions},
+    Arguments, Row,
+};
+use sqlx::{
+    Connection, Error, Executor, QueryBuilder, Sqlite, SqliteConnection, SqlitePool, Transaction,
+};
 use std::env;
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Once;
+use tokio::sync::Mutex;
 use uuid::Uuid;
 
 use futures::future::BoxFuture;
@@ -16,14 +28,158 @@ use std::cmp::Ordering;
 
 static INIT: Once = Once::new();
 
+/// The schema migrations, which are applied ahead of deploys rather than at
+/// startup.
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
 #[derive(Clone)]
 pub struct SqliteDb {
     pub pool: SqlitePool,
+    pub(crate) envelope: Option<MessageEnvelope>,
 }
 
 impl SqliteDb {
     pub fn new(pool: SqlitePool) -> Self {
-        SqliteDb { pool }
+        SqliteDb { pool, envelope: None }
+    }
+
+    /// Envelope-encrypt message bodies at rest with `envelope`.
+    pub fn with_envelope(mut self, envelope: MessageEnvelope) -> Self {
+        self.envelope = Some(envelope);
+        self
+    }
+
+    /// Envelope-encrypts messages and anonymous messages stored before
+    /// envelope encryption was turned on, `batch_size` rows at a time. Safe
+    /// to rerun; returns the number of rows sealed.
+    pub async fn seal_existing_messages(&self, batch_size: i64) -> Result<u64, Error> {
+        let envelope = self.envelope.as_ref().ok_or_else(|| {
+            Error::Configuration("sealing messages needs a master key".into())
+        })?;
+
+        let mut sealed = 0;
+        loop {
+            let messages = get_unsealed_messages(&self.pool, batch_size).await?;
+            if messages.is_empty() {
+                break;
+            }
+            for message in messages {
+                let (encrypted_content, signature) = envelope.seal(
+                    SealedRow::from(&message),
+                    &message.encrypted_content,
+                    message.signature.as_deref(),
+                );
+                if replace_message_body(
+                    &self.pool,
+                    message.id,
+                    &message.encrypted_content,
+                    &encrypted_content,
+                    signature.as_deref(),
+                )
+                .await?
+                {
+                    sealed += 1;
+                }
+            }
+        }
+        loop {
+            let messages = get_unsealed_anonymous_messages(&self.pool, batch_size).await?;
+            if messages.is_empty() {
+                return Ok(sealed);
+            }
+            for message in messages {
+                let (encrypted_content, _) =
+                    envelope.seal(SealedRow::from(&message), &message.encrypted_content, None);
+                if replace_anonymous_message_content(
+                    &self.pool,
+                    message.id,
+                    &message.encrypted_content,
+                    &encrypted_content,
+                )
+                .await?
+                {
+                    sealed += 1;
+                }
+            }
+        }
+    }
+
+    /// Wraps the data keys of sealed messages and anonymous messages again
+    /// under the current master key, `batch_size` rows at a time, so that
+    /// retired master keys can be dropped afterwards. Rows that fail to rewrap
+    /// are logged and skipped. Safe to rerun; returns the number of rows
+    /// rewrapped.
+    pub async fn rewrap_sealed_messages(&self, batch_size: i64) -> Result<u64, Error> {
+        let envelope = self.envelope.as_ref().ok_or_else(|| {
+            Error::Configuration("rewrapping messages needs a master key".into())
+        })?;
+
+        let (mut rewrapped, mut after_id) = (0, 0);
+        loop {
+            let messages = get_sealed_messages_after(&self.pool, after_id, batch_size).await?;
+            let Some(last) = messages.last() else {
+                break;
+            };
+            after_id = last.id;
+            for message in messages {
+                let Some(encrypted_content) = rewrap_row(envelope, &message) else {
+                    continue;
+                };
+                if replace_message_body(
+                    &self.pool,
+                    message.id,
+                    &message.encrypted_content,
+                    &encrypted_content,
+                    message.signature.as_deref(),
+                )
+                .await?
+                {
+                    rewrapped += 1;
+                }
+            }
+        }
+
+        after_id = 0;
+        loop {
+            let messages =
+                get_sealed_anonymous_messages_after(&self.pool, after_id, batch_size).await?;
+            let Some(last) = messages.last() else {
+                return Ok(rewrapped);
+            };
+            after_id = last.id;
+            for message in messages {
+                let Some(encrypted_content) = rewrap_row(envelope, &message) else {
+                    continue;
+                };
+                if replace_anonymous_message_content(
+                    &self.pool,
+                    message.id,
+                    &message.encrypted_content,
+                    &encrypted_content,
+                )
+                .await?
+                {
+                    rewrapped += 1;
+                }
+            }
+        }
+    }
+}
+
+/// A transaction opened by [`SqliteDb`]. Statements run through it are only
+/// visible to other connections once it is committed; dropping it without
+/// committing rolls everything back.
+pub struct SqliteTx {
+    pub(crate) tx: Mutex<Transaction<'static, Sqlite>>,
+    pub(crate) envelope: Option<MessageEnvelope>,
+}
+
+impl SqliteTx {
+    pub fn new(tx: Transaction<'static, Sqlite>) -> Self {
+        SqliteTx {
+            tx: Mutex::new(tx),
+            envelope: None,
+        }
     }
 }
 
@@ -35,24 +191,52 @@ pub async fn create_db_pool() -> Result<SqlitePool, Error> {
     });
 
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    SqlitePool::connect(&database_url).await
+    let options = SqliteConnectOptions::from_str(&database_url)?;
+    SqlitePool::connect_with(crate::log_queries(options)).await
 }
 
-pub async fn insert_user(
-    pool: &SqlitePool,
+/// Reads the schema, so that fails if the database file can't be opened or
+/// is locked, unlike `SELECT 1`.
+pub async fn ping(pool: &SqlitePool) -> Result<(), Error> {
+    sqlx::query("SELECT COUNT(*) FROM sqlite_master")
+        .fetch_one(pool)
+        .await?;
+    Ok(())
+}
+
+/// Versions of the migrations in [`MIGRATOR`] not yet successfully applied.
+pub async fn pending_migrations(pool: &SqlitePool) -> Result<Vec<i64>, Error> {
+    let tracked: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations')",
+    )
+    .fetch_one(pool)
+    .await?;
+    let applied: Vec<i64> = if tracked {
+        sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success = TRUE")
+            .fetch_all(pool)
+            .await?
+    } else {
+        Vec::new()
+    };
+
+    Ok(MIGRATOR
+        .iter()
+        .filter(|migration| migration.migration_type.is_up_migration())
+        .map(|migration| migration.version)
+        .filter(|version| !applied.contains(version))
+        .collect())
+}
+
+pub async fn insert_user<'e, E>(
+    executor: E,
     public_key_hash: &PublicKeyHash,
     public_key: &PublicKey,
     username: &str,
-) -> Result<Uuid, Error> {
+) -> Result<Uuid, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
     let id = Uuid::now_v7();
-    let id_str = id.to_string();
-    println!("{} {} {}", id, id.to_string().len(), id.as_bytes().len());
-    println!(
-        "{} {} {}",
-        public_key_hash.as_str(),
-        public_key_hash.as_str().to_string().len(),
-        public_key_hash.as_str().as_bytes().len()
-    );
 
     let mut args = SqliteArguments::default();
 
@@ -67,13 +251,16 @@ pub async fn insert_user(
          "#,
         args,
     )
-    .execute(pool)
+    .execute(executor)
     .await?;
 
     Ok(id)
 }
 
-pub async fn get_user_by_id(pool: &SqlitePool, user_id: Uuid) -> Result<User, Error> {
+pub async fn get_user_by_id<'e, E>(executor: E, user_id: Uuid) -> Result<User, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
     let mut args = SqliteArguments::default();
 
     args.add(user_id);
@@ -90,15 +277,18 @@ pub async fn get_user_by_id(pool: &SqlitePool, user_id: Uuid) -> Result<User, Er
          FROM users WHERE id = $1"#,
         args,
     )
-    .fetch_one(pool)
+    .fetch_one(executor)
     .await?;
     Ok(user)
 }
 
-pub async fn get_user_by_pubkey(
-    pool: &SqlitePool,
+pub async fn get_user_by_pubkey<'e, E>(
+    executor: E,
     pubkey_hash: &PublicKeyHash,
-) -> Result<User, Error> {
+) -> Result<User, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
     let mut args = SqliteArguments::default();
 
     args.add(pubkey_hash);
@@ -115,12 +305,15 @@ pub async fn get_user_by_pubkey(
          FROM users WHERE public_key_hash = $1"#,
         args,
     )
-    .fetch_one(pool)
+    .fetch_one(executor)
     .await?;
     Ok(user)
 }
 
-pub async fn get_users(pool: &SqlitePool, limit: Option<i64>) -> Result<Vec<User>, Error> {
+pub async fn get_users<'e, E>(executor: E, limit: Option<i64>) -> Result<Vec<User>, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
     let users = sqlx::query_as::<_, User>(
         r#"
             SELECT id,
@@ -135,7 +328,7 @@ pub async fn get_users(pool: &SqlitePool, limit: Option<i64>) -> Result<Vec<User
         "#,
     )
     .bind(limit.unwrap_or(1000))
-    .fetch_all(pool)
+    .fetch_all(executor)
     .await?;
 
     Ok(users)
@@ -168,10 +361,65 @@ pub async fn get_users(pool: &SqlitePool, limit: Option<i64>) -> Result<Vec<User
 //     Ok(())
 // }
 
-pub async fn fetch_public_key_hash(
-    pool: &sqlx::SqlitePool,
+/// A user's public key, or `None` if there is no such user.
+/// The Jubjub key messages to `user_id` are sealed to, or `None` if there is
+/// no such user or they haven't registered one.
+pub async fn fetch_message_key<'e, E>(
+    executor: E,
+    user_id: Uuid,
+) -> Result<Option<String>, sqlx::Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let message_key = sqlx::query_scalar::<_, Option<String>>(
+        r#"
+        SELECT message_key
+        FROM users
+        WHERE id = ?
+        "#,
+    )
+    .bind(user_id)
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(message_key.flatten())
+}
+
+pub async fn set_message_key<'e, E>(
+    executor: E,
+    user_id: Uuid,
+    message_key: &str,
+) -> Result<(), Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let result = sqlx::query(
+        r#"
+        UPDATE users
+        SET message_key = $1, updated_at = $2
+        WHERE id = $3
+        "#,
+    )
+    .bind(message_key)
+    .bind(Utc::now().naive_utc())
+    .bind(user_id)
+    .execute(executor)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::RowNotFound);
+    }
+
+    Ok(())
+}
+
+pub async fn fetch_public_key_hash<'e, E>(
+    executor: E,
     user_id: Uuid,
-) -> Result<String, sqlx::Error> {
+) -> Result<String, sqlx::Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
     let public_key_hash = sqlx::query(
         r#"
         SELECT public_key_hash
@@ -180,22 +428,23 @@ pub async fn fetch_public_key_hash(
         "#,
     )
     .bind(user_id)
-    .fetch_one(pool)
+    .fetch_one(executor)
     .await?
     .try_get("public_key_hash")?;
 
     Ok(public_key_hash)
 }
 
-pub async fn update_user(
-    pool: &SqlitePool,
+pub async fn update_user<'e, E>(
+    executor: E,
     user_id: Uuid,
     new_username: Option<&str>,
     new_public_key: Option<&PublicKey>,
     new_public_key_hash: Option<&PublicKeyHash>,
-) -> Result<(), Error> {
-    let current_user = get_user_by_id(&pool, user_id).await?;
-
+) -> Result<(), Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
     if let Some(username) = new_username {
         if username.is_empty() {
             return Err(Error::InvalidArgument(
@@ -220,53 +469,558 @@ pub async fn update_user(
         }
     }
 
-    let username = new_username.unwrap_or(&current_user.username);
-    let public_key = new_public_key.unwrap_or(&current_user.public_key);
-    let public_key_hash = new_public_key_hash.unwrap_or(&current_user.public_key_hash);
-    let updated_at = Utc::now().naive_utc();
+    // A single statement keeps the fields that aren't being changed, instead
+    // of reading the row first and racing concurrent updates.
+    let result = sqlx::query(
+        r#"
+        UPDATE users
+        SET
+            username = COALESCE($1, username),
+            public_key = COALESCE($2, public_key),
+            public_key_hash = COALESCE($3, public_key_hash),
+            updated_at = $4
+        WHERE id = $5
+        "#,
+    )
+    .bind(new_username)
+    .bind(new_public_key)
+    .bind(new_public_key_hash)
+    .bind(Utc::now().naive_utc())
+    .bind(user_id)
+    .execute(executor)
+    .await?;
 
-    let mut args = SqliteArguments::default();
+    if result.rows_affected() == 0 {
+        return Err(Error::RowNotFound);
+    }
 
-    args.add(username);
-    args.add(public_key);
-    args.add(public_key_hash);
-    args.add(updated_at);
-    args.add(user_id);
+    Ok(())
+}
 
-    sqlx::query_with(
+pub async fn mark_contact_verified<'e, E>(
+    executor: E,
+    user_id: Uuid,
+    contact_id: Uuid,
+    public_key_hash: &PublicKeyHash,
+) -> Result<(), Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    sqlx::query(
         r#"
-        UPDATE users 
-        SET 
-            username = $1,
-            public_key = $2,
-            public_key_hash = $3,
-            updated_at = $4
-        WHERE id = $5
+        INSERT INTO verified_contacts (user_id, contact_id, public_key_hash)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (user_id, contact_id) DO UPDATE SET
+            public_key_hash = excluded.public_key_hash,
+            verified_at = CURRENT_TIMESTAMP
         "#,
-        args,
     )
-    .execute(pool)
+    .bind(user_id)
+    .bind(contact_id)
+    .bind(public_key_hash)
+    .execute(executor)
     .await?;
 
     Ok(())
 }
 
-pub async fn create_message(
-    pool: &SqlitePool,
+pub async fn get_verified_contact<'e, E>(
+    executor: E,
+    user_id: Uuid,
+    contact_id: Uuid,
+) -> Result<Option<VerifiedContact>, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let contact = sqlx::query_as::<_, VerifiedContact>(
+        r#"
+        SELECT user_id, contact_id, public_key_hash, verified_at
+        FROM verified_contacts
+        WHERE user_id = $1 AND contact_id = $2
+        "#,
+    )
+    .bind(user_id)
+    .bind(contact_id)
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(contact)
+}
+
+/// Appends `leaf` to the anonymity set, returning its index. Indices start
+/// at 1 and follow insertion order.
+pub async fn insert_membership_leaf<'e, E>(
+    executor: E,
+    user_id: Uuid,
+    leaf: &str,
+) -> Result<i64, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let leaf_index = sqlx::query_scalar::<_, i64>(
+        r#"
+        INSERT INTO membership_leaves (user_id, leaf)
+        VALUES ($1, $2)
+        RETURNING leaf_index
+        "#,
+    )
+    .bind(user_id)
+    .bind(leaf)
+    .fetch_one(executor)
+    .await?;
+
+    Ok(leaf_index)
+}
+
+/// Returns the leaves with an index above `after_index`, in index order.
+pub async fn get_membership_leaves<'e, E>(
+    executor: E,
+    after_index: i64,
+) -> Result<Vec<MembershipLeaf>, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let leaves = sqlx::query_as::<_, MembershipLeaf>(
+        r#"
+        SELECT leaf_index, user_id, leaf
+        FROM membership_leaves
+        WHERE leaf_index > $1
+        ORDER BY leaf_index ASC
+        "#,
+    )
+    .bind(after_index)
+    .fetch_all(executor)
+    .await?;
+
+    Ok(leaves)
+}
+
+/// Stores `user_id`'s signed prekey, replacing the current one only if
+/// `prekey_id` is newer. Returns whether it was stored.
+pub async fn upsert_signed_prekey<'e, E>(
+    executor: E,
+    user_id: Uuid,
+    identity_key: &str,
+    prekey_id: i64,
+    prekey: &str,
+    signature: &str,
+) -> Result<bool, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let result = sqlx::query(
+        r#"
+        INSERT INTO signed_prekeys (user_id, identity_key, prekey_id, prekey, signature)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (user_id) DO UPDATE SET
+            identity_key = excluded.identity_key,
+            prekey_id = excluded.prekey_id,
+            prekey = excluded.prekey,
+            signature = excluded.signature,
+            updated_at = CURRENT_TIMESTAMP
+        WHERE excluded.prekey_id > signed_prekeys.prekey_id
+        "#,
+    )
+    .bind(user_id)
+    .bind(identity_key)
+    .bind(prekey_id)
+    .bind(prekey)
+    .bind(signature)
+    .execute(executor)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn get_signed_prekey<'e, E>(
+    executor: E,
+    user_id: Uuid,
+) -> Result<Option<SignedPrekey>, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let prekey = sqlx::query_as::<_, SignedPrekey>(
+        r#"
+        SELECT user_id, identity_key, prekey_id, prekey, signature, updated_at
+        FROM signed_prekeys
+        WHERE user_id = $1
+        "#,
+    )
+    .bind(user_id)
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(prekey)
+}
+
+/// Adds `prekeys` to `user_id`'s one-time prekeys, skipping ids the user
+/// has already uploaded, claimed or not. Returns how many were added.
+pub async fn insert_one_time_prekeys<'e, E>(
+    executor: E,
+    user_id: Uuid,
+    prekeys: &[OneTimePrekey],
+) -> Result<u64, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    if prekeys.is_empty() {
+        return Ok(0);
+    }
+
+    let mut query = QueryBuilder::<Sqlite>::new(
+        "INSERT INTO one_time_prekeys (user_id, prekey_id, prekey, signature) ",
+    );
+    query.push_values(prekeys, |mut row, prekey| {
+        row.push_bind(user_id)
+            .push_bind(prekey.prekey_id)
+            .push_bind(&prekey.prekey)
+            .push_bind(&prekey.signature);
+    });
+    query.push(" ON CONFLICT (user_id, prekey_id) DO NOTHING");
+
+    let result = query.build().execute(executor).await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Marks `user_id`'s unclaimed one-time prekey with the lowest id as claimed
+/// and returns it, or `None` if none are left. A key is only ever returned
+/// once, however many callers race for it.
+pub async fn claim_one_time_prekey<'e, E>(
+    executor: E,
+    user_id: Uuid,
+) -> Result<Option<OneTimePrekey>, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let prekey = sqlx::query_as::<_, OneTimePrekey>(
+        r#"
+        UPDATE one_time_prekeys
+        SET claimed_at = CURRENT_TIMESTAMP
+        WHERE user_id = $1
+          AND claimed_at IS NULL
+          AND prekey_id = (
+            SELECT prekey_id
+            FROM one_time_prekeys
+            WHERE user_id = $1 AND claimed_at IS NULL
+            ORDER BY prekey_id
+            LIMIT 1
+          )
+        RETURNING prekey_id, prekey, signature
+        "#,
+    )
+    .bind(user_id)
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(prekey)
+}
+
+pub async fn count_one_time_prekeys<'e, E>(executor: E, user_id: Uuid) -> Result<i64, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let count = sqlx::query_scalar::<_, i64>(
+        r#"
+        SELECT COUNT(*)
+        FROM one_time_prekeys
+        WHERE user_id = $1 AND claimed_at IS NULL
+        "#,
+    )
+    .bind(user_id)
+    .fetch_one(executor)
+    .await?;
+
+    Ok(count)
+}
+
+/// Stores `user_id`'s key backup unless the stored one has the same or a
+/// newer `version`. Replacing a backup forgets failed recovery attempts, and
+/// challenges issued for the old version no longer answer. Returns whether
+/// it was stored.
+pub async fn upsert_key_backup<'e, E>(
+    executor: E,
+    user_id: Uuid,
+    version: i64,
+    encrypted_key: &str,
+    salt: &str,
+    kdf_iterations: i64,
+    recovery_key: &str,
+) -> Result<bool, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let result = sqlx::query(
+        r#"
+        INSERT INTO key_backups
+            (user_id, version, encrypted_key, salt, kdf_iterations, recovery_key)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (user_id) DO UPDATE SET
+            version = excluded.version,
+            encrypted_key = excluded.encrypted_key,
+            salt = excluded.salt,
+            kdf_iterations = excluded.kdf_iterations,
+            recovery_key = excluded.recovery_key,
+            failed_attempts = 0,
+            recovery_retry_at = NULL,
+            updated_at = CURRENT_TIMESTAMP
+        WHERE excluded.version > key_backups.version
+        "#,
+    )
+    .bind(user_id)
+    .bind(version)
+    .bind(encrypted_key)
+    .bind(salt)
+    .bind(kdf_iterations)
+    .bind(recovery_key)
+    .execute(executor)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn get_key_backup<'e, E>(executor: E, user_id: Uuid) -> Result<Option<KeyBackup>, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let backup = sqlx::query_as::<_, KeyBackup>(
+        r#"
+        SELECT user_id, version, encrypted_key, salt, kdf_iterations, recovery_key,
+               failed_attempts, recovery_retry_at, updated_at
+        FROM key_backups
+        WHERE user_id = $1
+        "#,
+    )
+    .bind(user_id)
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(backup)
+}
+
+/// Adds a recovery challenge for `user_id`'s current key backup, leaving
+/// any others pending, and drops the user's challenges that expired by
+/// `now`. Returns whether it was added, which it isn't if the user has no
+/// backup or already has `max_pending` challenges pending.
+pub async fn set_key_backup_challenge(
+    conn: &mut SqliteConnection,
+    user_id: Uuid,
+    challenge: &str,
+    now: NaiveDateTime,
+    expires_at: NaiveDateTime,
+    max_pending: i64,
+) -> Result<bool, Error> {
+    let mut tx = conn.begin().await?;
+    sqlx::query(
+        r#"
+        DELETE FROM key_backup_challenges
+        WHERE user_id = $1 AND expires_at <= $2
+        "#,
+    )
+    .bind(user_id)
+    .bind(now)
+    .execute(&mut *tx)
+    .await?;
+
+    let result = sqlx::query(
+        r#"
+        INSERT INTO key_backup_challenges (challenge, user_id, version, expires_at)
+        SELECT $2, user_id, version, $3
+        FROM key_backups
+        WHERE user_id = $1
+          AND (SELECT COUNT(*) FROM key_backup_challenges WHERE user_id = $1) < $4
+        "#,
+    )
+    .bind(user_id)
+    .bind(challenge)
+    .bind(expires_at)
+    .bind(max_pending)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Removes `user_id`'s recovery challenge `challenge` if it expires after
+/// `now`, returning the backup it was issued for unless a newer one has
+/// been uploaded since. Each challenge can be taken once. Unless
+/// `ignore_backoff`, nothing is taken while failed recoveries hold the
+/// backup back at `now`; this is checked in the same statement, so
+/// concurrent requests can't slip past a hold once it is recorded.
+pub async fn take_key_backup_challenge(
+    conn: &mut SqliteConnection,
+    user_id: Uuid,
+    challenge: &str,
+    now: NaiveDateTime,
+    ignore_backoff: bool,
+) -> Result<Option<KeyBackup>, Error> {
+    let mut tx = conn.begin().await?;
+    let version = sqlx::query_scalar::<_, i64>(
+        r#"
+        DELETE FROM key_backup_challenges
+        WHERE user_id = $1 AND challenge = $2 AND expires_at > $3
+          AND ($4 OR NOT EXISTS (
+              SELECT 1 FROM key_backups
+              WHERE user_id = $1 AND recovery_retry_at > $3
+          ))
+        RETURNING version
+        "#,
+    )
+    .bind(user_id)
+    .bind(challenge)
+    .bind(now)
+    .bind(ignore_backoff)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(version) = version else {
+        return Ok(None);
+    };
+
+    let backup = sqlx::query_as::<_, KeyBackup>(
+        r#"
+        SELECT user_id, version, encrypted_key, salt, kdf_iterations, recovery_key,
+               failed_attempts, recovery_retry_at, updated_at
+        FROM key_backups
+        WHERE user_id = $1 AND version = $2
+        "#,
+    )
+    .bind(user_id)
+    .bind(version)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(backup)
+}
+
+/// Replaces the stored encryption of `user_id`'s key backup, as long as it
+/// is still at `version`, so a concurrent upload is not overwritten. The
+/// backup's contents and pending challenges are unchanged. Returns whether it
+/// was replaced.
+pub async fn rewrap_key_backup<'e, E>(
+    executor: E,
+    user_id: Uuid,
+    version: i64,
+    encrypted_key: &str,
+    recovery_key: &str,
+) -> Result<bool, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let result = sqlx::query(
+        r#"
+        UPDATE key_backups
+        SET encrypted_key = $3, recovery_key = $4
+        WHERE user_id = $1 AND version = $2
+        "#,
+    )
+    .bind(user_id)
+    .bind(version)
+    .bind(encrypted_key)
+    .bind(recovery_key)
+    .execute(executor)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Counts a failed recovery-key attempt on `user_id`'s key backup,
+/// returning how many there have been, or `None` if there is no backup.
+pub async fn record_failed_key_recovery<'e, E>(
+    executor: E,
+    user_id: Uuid,
+) -> Result<Option<i64>, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let failed_attempts = sqlx::query_scalar::<_, i64>(
+        r#"
+        UPDATE key_backups
+        SET failed_attempts = failed_attempts + 1
+        WHERE user_id = $1
+        RETURNING failed_attempts
+        "#,
+    )
+    .bind(user_id)
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(failed_attempts)
+}
+
+/// Refuses recovery-key attempts on `user_id`'s key backup until
+/// `retry_at`, unless they are already refused for longer.
+pub async fn hold_back_key_recovery<'e, E>(
+    executor: E,
+    user_id: Uuid,
+    retry_at: NaiveDateTime,
+) -> Result<(), Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    sqlx::query(
+        r#"
+        UPDATE key_backups
+        SET recovery_retry_at = MAX(COALESCE(recovery_retry_at, $2), $2)
+        WHERE user_id = $1
+        "#,
+    )
+    .bind(user_id)
+    .bind(retry_at)
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn create_message<'e, E>(
+    executor: E,
+    sender_id: Uuid,
+    recipient_id: Uuid,
+    encrypted_content: &str,
+    signature: Option<&str>,
+    parent_id: Option<i64>,
+) -> Result<Option<i64>, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    create_attested_message(
+        executor,
+        sender_id,
+        recipient_id,
+        encrypted_content,
+        signature,
+        parent_id,
+        None,
+        None,
+    )
+    .await
+}
+
+/// Like [`create_message`], storing the sender's commitment to
+/// `encrypted_content` and their verified ephemeral key proof in the same row.
+pub async fn create_attested_message<'e, E>(
+    executor: E,
     sender_id: Uuid,
     recipient_id: Uuid,
     encrypted_content: &str,
     signature: Option<&str>,
     parent_id: Option<i64>,
-) -> Result<Option<i64>, Error> {
-    let conn = pool.acquire().await?;
+    commitment: Option<&str>,
+    ephemeral_key_proof: Option<&str>,
+) -> Result<Option<i64>, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
     let current_time = Utc::now().timestamp();
-    println!("{}", current_time);
 
     let message_id = sqlx::query!(
         r#"
-        INSERT INTO messages (sender_id, recipient_id, encrypted_content, signature, parent_id, created_at)
-        VALUES ($1, $2, $3, $4, $5, $6)
+        INSERT INTO messages (sender_id, recipient_id, encrypted_content, signature, parent_id, created_at, commitment, ephemeral_key_proof)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
         RETURNING id
         "#,
         sender_id,
@@ -275,15 +1029,198 @@ pub async fn create_message(
         signature,
         parent_id,
         current_time,
+        commitment,
+        ephemeral_key_proof,
     )
-    .fetch_one(pool)
+    .fetch_one(executor)
     .await?
     .id;
 
     Ok(message_id)
 }
 
-pub async fn get_message(pool: &SqlitePool, message_id: i64) -> Result<Option<Message>, Error> {
+/// The commitment stored with a message, or `None` if the message does not
+/// exist or was sent without one.
+pub async fn get_message_commitment<'e, E>(
+    executor: E,
+    message_id: i64,
+) -> Result<Option<String>, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let row = sqlx::query!(
+        r#"
+        SELECT commitment
+        FROM messages
+        WHERE id = $1
+        "#,
+        message_id
+    )
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(row.and_then(|row| row.commitment))
+}
+
+/// The verified ephemeral key proof stored with a message, or `None` if the
+/// message does not exist or was sent without one.
+pub async fn get_ephemeral_key_proof<'e, E>(
+    executor: E,
+    message_id: i64,
+) -> Result<Option<String>, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let row = sqlx::query!(
+        r#"
+        SELECT ephemeral_key_proof
+        FROM messages
+        WHERE id = $1
+        "#,
+        message_id
+    )
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(row.and_then(|row| row.ephemeral_key_proof))
+}
+
+/// Stores an anonymous message and the rate-limit share it revealed in one
+/// transaction (a savepoint when `conn` is already inside one). Fails with a
+/// unique violation if `share.nullifier` was used before.
+pub async fn create_anonymous_message(
+    conn: &mut SqliteConnection,
+    recipient_id: Uuid,
+    encrypted_content: &str,
+    membership_root: &str,
+    share: &RateLimitShare,
+) -> Result<i64, Error> {
+    let mut tx = conn.begin().await?;
+    let message_id = sqlx::query_scalar::<_, i64>(
+        r#"
+        INSERT INTO anonymous_messages (recipient_id, encrypted_content, nullifier, membership_root)
+        VALUES ($1, $2, $3, $4)
+        RETURNING id
+        "#,
+    )
+    .bind(recipient_id)
+    .bind(encrypted_content)
+    .bind(&share.nullifier)
+    .bind(membership_root)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO rate_limit_nullifiers (nullifier, epoch, share_x, share_y, message_id)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+    )
+    .bind(&share.nullifier)
+    .bind(share.epoch)
+    .bind(&share.share_x)
+    .bind(&share.share_y)
+    .bind(message_id)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(message_id)
+}
+
+pub async fn get_rate_limit_share<'e, E>(
+    executor: E,
+    nullifier: &str,
+) -> Result<Option<RateLimitShare>, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let share = sqlx::query_as::<_, RateLimitShare>(
+        r#"
+        SELECT nullifier, epoch, share_x, share_y
+        FROM rate_limit_nullifiers
+        WHERE nullifier = $1
+        "#,
+    )
+    .bind(nullifier)
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(share)
+}
+
+/// Records an identity caught exceeding the rate limit. Blocking an identity
+/// twice keeps the first record.
+pub async fn block_identity<'e, E>(
+    executor: E,
+    identity_commitment: &str,
+    identity_secret: &str,
+) -> Result<(), Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    sqlx::query(
+        r#"
+        INSERT INTO blocked_identities (identity_commitment, identity_secret)
+        VALUES ($1, $2)
+        ON CONFLICT (identity_commitment) DO NOTHING
+        "#,
+    )
+    .bind(identity_commitment)
+    .bind(identity_secret)
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_blocked_identity_secrets<'e, E>(executor: E) -> Result<Vec<String>, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let secrets = sqlx::query_scalar::<_, String>(
+        r#"
+        SELECT identity_secret
+        FROM blocked_identities
+        "#,
+    )
+    .fetch_all(executor)
+    .await?;
+
+    Ok(secrets)
+}
+
+/// Returns the recipient's anonymous messages, newest first.
+pub async fn get_anonymous_messages<'e, E>(
+    executor: E,
+    recipient_id: Uuid,
+    limit: i64,
+) -> Result<Vec<AnonymousMessage>, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let messages = sqlx::query_as::<_, AnonymousMessage>(
+        r#"
+        SELECT id, recipient_id, encrypted_content, nullifier, membership_root, created_at
+        FROM anonymous_messages
+        WHERE recipient_id = $1
+        ORDER BY id DESC
+        LIMIT $2
+        "#,
+    )
+    .bind(recipient_id)
+    .bind(limit)
+    .fetch_all(executor)
+    .await?;
+
+    Ok(messages)
+}
+
+pub async fn get_message<'e, E>(executor: E, message_id: i64) -> Result<Option<Message>, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
     let raw = sqlx::query_as!(
         RawMessage,
         r#"
@@ -301,13 +1238,16 @@ pub async fn get_message(pool: &SqlitePool, message_id: i64) -> Result<Option<Me
         "#,
         message_id
     )
-    .fetch_optional(pool)
+    .fetch_optional(executor)
     .await?;
 
     Ok(raw.map(RawMessage::into_message))
 }
 
-pub async fn mark_message_read(pool: &SqlitePool, message_id: i64) -> Result<(), Error> {
+pub async fn mark_message_read<'e, E>(executor: E, message_id: i64) -> Result<(), Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
     sqlx::query!(
         r#"
         UPDATE messages
@@ -316,18 +1256,21 @@ pub async fn mark_message_read(pool: &SqlitePool, message_id: i64) -> Result<(),
         "#,
         message_id
     )
-    .execute(pool)
+    .execute(executor)
     .await?;
 
     Ok(())
 }
 
-pub async fn get_conversation(
-    pool: &SqlitePool,
+pub async fn get_conversation<'e, E>(
+    executor: E,
     user1_id: Uuid,
     user2_id: Uuid,
     limit: Option<i64>,
-) -> Result<Vec<Message>, Error> {
+) -> Result<Vec<Message>, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
     #[derive(sqlx::FromRow)]
     struct DbMessage {
         id: i64,
@@ -361,7 +1304,7 @@ pub async fn get_conversation(
     .bind(user1_id)
     .bind(user2_id)
     .bind(limit.unwrap_or(100))
-    .fetch_all(pool)
+    .fetch_all(executor)
     .await?;
 
     let messages = db_messages
@@ -382,7 +1325,10 @@ pub async fn get_conversation(
     Ok(messages)
 }
 
-pub async fn get_unread_messages(pool: &SqlitePool, user_id: Uuid) -> Result<Vec<Message>, Error> {
+pub async fn get_unread_messages<'e, E>(executor: E, user_id: Uuid) -> Result<Vec<Message>, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
     #[derive(sqlx::FromRow)]
     struct DbMessage {
         id: i64,
@@ -412,7 +1358,7 @@ pub async fn get_unread_messages(pool: &SqlitePool, user_id: Uuid) -> Result<Vec
         "#,
     )
     .bind(user_id)
-    .fetch_all(pool)
+    .fetch_all(executor)
     .await?;
 
     let messages = unread_messages
@@ -433,12 +1379,15 @@ pub async fn get_unread_messages(pool: &SqlitePool, user_id: Uuid) -> Result<Vec
     Ok(messages)
 }
 
-pub async fn get_thread_replies(
-    pool: &SqlitePool,
+pub async fn get_thread_replies<'e, E>(
+    executor: E,
     parent_id: i64,
     limit: Option<i64>,
     offset: Option<i64>,
-) -> Result<Vec<Message>, Error> {
+) -> Result<Vec<Message>, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
     let raw_messages = sqlx::query_as::<_, RawMessage>(
         r#"
         SELECT
@@ -459,7 +1408,7 @@ pub async fn get_thread_replies(
     .bind(parent_id)
     .bind(limit.unwrap_or(100))
     .bind(offset.unwrap_or(0))
-    .fetch_all(pool)
+    .fetch_all(executor)
     .await?;
 
     Ok(raw_messages
@@ -493,11 +1442,11 @@ pub async fn get_complete_thread(
 /// Gets a complete thread including the parent message and all nested replies (recursively),
 /// ordered by timestamp ascending.
 pub async fn get_complete_thread(
-    pool: &SqlitePool,
+    conn: &mut SqliteConnection,
     thread_root_id: i64,
     limit: Option<i64>,
 ) -> Result<Vec<Message>, Error> {
-    let parent_message = match get_message(pool, thread_root_id).await? {
+    let parent_message = match get_message(&mut *conn, thread_root_id).await? {
         Some(msg) => msg,
         None => return Err(Error::RowNotFound),
     };
@@ -507,33 +1456,36 @@ pub async fn get_complete_thread(
 
     // Recursive fetcher, boxed to allow async recursion
     fn fetch_replies_recursive<'a>(
-        pool: &'a SqlitePool,
+        conn: &'a mut SqliteConnection,
         parent_id: i64,
         limit: Option<i64>,
         acc: &'a mut Vec<Message>,
     ) -> BoxFuture<'a, Result<(), Error>> {
         Box::pin(async move {
-            let replies = get_thread_replies(pool, parent_id, limit, None).await?;
+            let replies = get_thread_replies(&mut *conn, parent_id, limit, None).await?;
             for reply in replies.iter() {
                 acc.push(reply.clone());
-                fetch_replies_recursive(pool, reply.id, limit, acc).await?;
+                fetch_replies_recursive(conn, reply.id, limit, acc).await?;
             }
             Ok(())
         })
     }
 
-    fetch_replies_recursive(pool, parent_message.id, limit, &mut messages).await?;
+    fetch_replies_recursive(&mut *conn, parent_message.id, limit, &mut messages).await?;
 
     messages.sort_by(|a, b| a.created_at.cmp(&b.created_at));
 
     Ok(messages)
 }
 
-pub async fn get_user_threads(
-    pool: &SqlitePool,
+pub async fn get_user_threads<'e, E>(
+    executor: E,
     user_id: Uuid,
     limit: Option<i64>,
-) -> Result<Vec<Message>, Error> {
+) -> Result<Vec<Message>, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
     let raw_messages = sqlx::query_as::<_, RawMessage>(
         r#"
         SELECT DISTINCT m.id, 
@@ -550,7 +1502,7 @@ pub async fn get_user_threads(
     )
     .bind(user_id)
     .bind(limit.unwrap_or(20))
-    .fetch_all(pool)
+    .fetch_all(executor)
     .await?;
 
     Ok(raw_messages
@@ -559,13 +1511,179 @@ pub async fn get_user_threads(
         .collect())
 }
 
-pub async fn store_refresh_token(
-    db: &SqlitePool,
+/// Messages whose bodies are not envelope-encrypted yet, oldest first.
+pub async fn get_unsealed_messages<'e, E>(executor: E, limit: i64) -> Result<Vec<Message>, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let raw_messages = sqlx::query_as::<_, RawMessage>(
+        r#"
+        SELECT id, sender_id, recipient_id, encrypted_content, signature, parent_id,
+               created_at, is_read
+        FROM messages
+        WHERE encrypted_content NOT LIKE 'env1.%'
+        ORDER BY id
+        LIMIT $1
+        "#,
+    )
+    .bind(limit)
+    .fetch_all(executor)
+    .await?;
+
+    Ok(raw_messages
+        .into_iter()
+        .map(RawMessage::into_message)
+        .collect())
+}
+
+/// Envelope-encrypted messages with an id above `after_id`, oldest first.
+pub async fn get_sealed_messages_after<'e, E>(
+    executor: E,
+    after_id: i64,
+    limit: i64,
+) -> Result<Vec<Message>, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let raw_messages = sqlx::query_as::<_, RawMessage>(
+        r#"
+        SELECT id, sender_id, recipient_id, encrypted_content, signature, parent_id,
+               created_at, is_read
+        FROM messages
+        WHERE encrypted_content LIKE 'env1.%' AND id > $1
+        ORDER BY id
+        LIMIT $2
+        "#,
+    )
+    .bind(after_id)
+    .bind(limit)
+    .fetch_all(executor)
+    .await?;
+
+    Ok(raw_messages
+        .into_iter()
+        .map(RawMessage::into_message)
+        .collect())
+}
+
+/// Replaces a message's `encrypted_content` and `signature`, as long as its
+/// content is still `expected_content`. Returns whether it was replaced.
+pub async fn replace_message_body<'e, E>(
+    executor: E,
+    message_id: i64,
+    expected_content: &str,
+    encrypted_content: &str,
+    signature: Option<&str>,
+) -> Result<bool, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let result = sqlx::query(
+        r#"
+        UPDATE messages
+        SET encrypted_content = $3, signature = $4
+        WHERE id = $1 AND encrypted_content = $2
+        "#,
+    )
+    .bind(message_id)
+    .bind(expected_content)
+    .bind(encrypted_content)
+    .bind(signature)
+    .execute(executor)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Anonymous messages stored before envelope encryption was turned on,
+/// oldest first.
+pub async fn get_unsealed_anonymous_messages<'e, E>(
+    executor: E,
+    limit: i64,
+) -> Result<Vec<AnonymousMessage>, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let messages = sqlx::query_as::<_, AnonymousMessage>(
+        r#"
+        SELECT id, recipient_id, encrypted_content, nullifier, membership_root, created_at
+        FROM anonymous_messages
+        WHERE encrypted_content NOT LIKE 'env1.%'
+        ORDER BY id
+        LIMIT $1
+        "#,
+    )
+    .bind(limit)
+    .fetch_all(executor)
+    .await?;
+
+    Ok(messages)
+}
+
+/// Envelope-encrypted anonymous messages with an id above `after_id`, oldest
+/// first.
+pub async fn get_sealed_anonymous_messages_after<'e, E>(
+    executor: E,
+    after_id: i64,
+    limit: i64,
+) -> Result<Vec<AnonymousMessage>, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let messages = sqlx::query_as::<_, AnonymousMessage>(
+        r#"
+        SELECT id, recipient_id, encrypted_content, nullifier, membership_root, created_at
+        FROM anonymous_messages
+        WHERE encrypted_content LIKE 'env1.%' AND id > $1
+        ORDER BY id
+        LIMIT $2
+        "#,
+    )
+    .bind(after_id)
+    .bind(limit)
+    .fetch_all(executor)
+    .await?;
+
+    Ok(messages)
+}
+
+/// Replaces an anonymous message's `encrypted_content`, as long as it is
+/// still `expected_content`. Returns whether it was replaced.
+pub async fn replace_anonymous_message_content<'e, E>(
+    executor: E,
+    message_id: i64,
+    expected_content: &str,
+    encrypted_content: &str,
+) -> Result<bool, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let result = sqlx::query(
+        r#"
+        UPDATE anonymous_messages
+        SET encrypted_content = $3
+        WHERE id = $1 AND encrypted_content = $2
+        "#,
+    )
+    .bind(message_id)
+    .bind(expected_content)
+    .bind(encrypted_content)
+    .execute(executor)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn store_refresh_token<'e, E>(
+    executor: E,
     user_id: Uuid,
     token_hash: &str,
     expires_at: i64,
     device_info: Option<&str>,
-) -> Result<(), Error> {
+) -> Result<(), Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
     sqlx::query!(
         r#"
             INSERT INTO refresh_tokens 
@@ -577,17 +1695,20 @@ pub async fn store_refresh_token(
         expires_at,
         device_info
     )
-    .execute(db)
+    .execute(executor)
     .await?;
 
     Ok(())
 }
 
-pub async fn validate_refresh_token(
-    db: &SqlitePool,
+pub async fn validate_refresh_token<'e, E>(
+    executor: E,
     user_id: Uuid,
     token_hash: &str,
-) -> Result<bool, Error> {
+) -> Result<bool, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
     let record = sqlx::query!(
         r#"
         SELECT id FROM refresh_tokens
@@ -596,17 +1717,20 @@ pub async fn validate_refresh_token(
         user_id,
         token_hash
     )
-    .fetch_optional(db)
+    .fetch_optional(executor)
     .await?;
 
     Ok(record.is_some())
 }
 
+/// Both statements run in one transaction (a savepoint when `conn` is already
+/// inside one), so a token is never left both active and revoked.
 pub async fn revoke_refresh_token(
-    db: &SqlitePool,
+    conn: &mut SqliteConnection,
     token_hash: &str,
     reason: Option<&str>,
 ) -> Result<(), Error> {
+    let mut tx = conn.begin().await?;
     sqlx::query!(
         r#"
         INSERT INTO revoked_tokens (token_hash, reason)
@@ -616,7 +1740,7 @@ pub async fn revoke_refresh_token(
         reason,
         token_hash
     )
-    .execute(db)
+    .execute(&mut *tx)
     .await?;
 
     sqlx::query!(
@@ -626,25 +1750,70 @@ pub async fn revoke_refresh_token(
         "#,
         token_hash
     )
-    .execute(db)
+    .execute(&mut *tx)
     .await?;
 
+    tx.commit().await?;
+
     Ok(())
 }
 
-pub async fn cleanup_expired_tokens(db: &SqlitePool) -> Result<u64, Error> {
+/// Revokes every refresh token issued to `user_id`, returning how many were revoked.
+pub async fn revoke_user_refresh_tokens(
+    conn: &mut SqliteConnection,
+    user_id: Uuid,
+    reason: Option<&str>,
+) -> Result<u64, Error> {
+    let mut tx = conn.begin().await?;
+    sqlx::query(
+        r#"
+        INSERT OR IGNORE INTO revoked_tokens (token_hash, reason)
+        SELECT token_hash, $1 FROM refresh_tokens
+        WHERE user_id = $2
+        "#,
+    )
+    .bind(reason)
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?;
+
+    let result = sqlx::query(
+        r#"
+        DELETE FROM refresh_tokens
+        WHERE user_id = $1
+        "#,
+    )
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn cleanup_expired_tokens<'e, E>(executor: E) -> Result<u64, Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
     let result = sqlx::query!(
         r#"
         DELETE FROM refresh_tokens
         WHERE expires_at <= CURRENT_TIMESTAMP
         "#
     )
-    .execute(db)
+    .execute(executor)
     .await?;
 
     Ok(result.rows_affected())
 }
 
+#[cfg(test)]
+type TestPool = SqlitePool;
+
+#[cfg(test)]
+type TestDb = SqliteDb;
+
 #[cfg(test)]
 #[path = "db.test.rs"]
 mod tests;
@@ -1,94 +1,172 @@
-use crate::models::{Message, RawMessage, User};
-use crate::{public_key::PublicKey, public_key_hash::PublicKeyHash};
+use crate::db::{SqliteDb, SqliteTx};
+use crate::envelope::MessageEnvelope;
+use crate::message_db::MessageDb;
+#[cfg(feature = "postgres")]
+use crate::pg::{PgDb, PgTx};
+use crate::token_db::TokenDb;
+use crate::user_db::UserDb;
 use async_trait::async_trait;
-use sqlx::{Error, SqlitePool};
-use uuid::Uuid;
+#[cfg(feature = "postgres")]
+use sqlx::Postgres;
+use sqlx::{pool::PoolConnection, Database, Error, Sqlite, Transaction};
+use std::ops::{Deref, DerefMut};
+use tokio::sync::MutexGuard;
+
+/// A complete storage backend: everything in [`UserDb`], [`MessageDb`] and
+/// [`TokenDb`], plus the ability to group several calls into one transaction.
+#[async_trait]
+pub trait Db: UserDb + MessageDb + TokenDb + Send + Sync {
+    type Transaction: DbTransaction;
+
+    async fn begin(&self) -> Result<Self::Transaction, Error>;
+}
+
+/// A unit of work returned by [`Db::begin`]. It exposes the same operations
+/// as the backend it came from; nothing is persisted until [`commit`] is
+/// called, and dropping it without committing rolls it back.
+///
+/// [`commit`]: DbTransaction::commit
+#[async_trait]
+pub trait DbTransaction: UserDb + MessageDb + TokenDb + Send + Sync {
+    async fn commit(self) -> Result<(), Error>;
+
+    async fn rollback(self) -> Result<(), Error>;
+}
+
+#[async_trait]
+impl Db for SqliteDb {
+    type Transaction = SqliteTx;
+
+    async fn begin(&self) -> Result<SqliteTx, Error> {
+        let mut tx = SqliteTx::new(self.pool.begin().await?);
+        tx.envelope = self.envelope.clone();
+        Ok(tx)
+    }
+}
+
+#[async_trait]
+impl DbTransaction for SqliteTx {
+    async fn commit(self) -> Result<(), Error> {
+        self.tx.into_inner().commit().await
+    }
+
+    async fn rollback(self) -> Result<(), Error> {
+        self.tx.into_inner().rollback().await
+    }
+}
+
+#[cfg(feature = "postgres")]
+#[async_trait]
+impl Db for PgDb {
+    type Transaction = PgTx;
+
+    async fn begin(&self) -> Result<PgTx, Error> {
+        let mut tx = PgTx::new(self.pool.begin().await?);
+        tx.envelope = self.envelope.clone();
+        Ok(tx)
+    }
+}
+
+#[cfg(feature = "postgres")]
+#[async_trait]
+impl DbTransaction for PgTx {
+    async fn commit(self) -> Result<(), Error> {
+        self.tx.into_inner().commit().await
+    }
+
+    async fn rollback(self) -> Result<(), Error> {
+        self.tx.into_inner().rollback().await
+    }
+}
+
+/// A backend's pool or one of its transactions, seen as a connection to run
+/// statements on and the envelope message bodies are sealed under, so that
+/// one implementation can serve both.
+#[async_trait]
+pub(crate) trait DbHandle: Send + Sync {
+    type Database: Database;
+
+    async fn connection(&self) -> Result<HandleConnection<'_, Self::Database>, Error>;
+
+    fn envelope(&self) -> Option<&MessageEnvelope>;
+}
+
+/// A connection taken from a pool, or the one an open transaction runs on.
+pub(crate) enum HandleConnection<'a, DB: Database> {
+    Pooled(PoolConnection<DB>),
+    Transaction(MutexGuard<'a, Transaction<'static, DB>>),
+}
+
+impl<DB: Database> Deref for HandleConnection<'_, DB> {
+    type Target = DB::Connection;
+
+    fn deref(&self) -> &DB::Connection {
+        match self {
+            HandleConnection::Pooled(conn) => conn,
+            HandleConnection::Transaction(tx) => tx,
+        }
+    }
+}
+
+impl<DB: Database> DerefMut for HandleConnection<'_, DB> {
+    fn deref_mut(&mut self) -> &mut DB::Connection {
+        match self {
+            HandleConnection::Pooled(conn) => conn,
+            HandleConnection::Transaction(tx) => tx,
+        }
+    }
+}
+
+#[async_trait]
+impl DbHandle for SqliteDb {
+    type Database = Sqlite;
+
+    async fn connection(&self) -> Result<HandleConnection<'_, Sqlite>, Error> {
+        Ok(HandleConnection::Pooled(self.pool.acquire().await?))
+    }
+
+    fn envelope(&self) -> Option<&MessageEnvelope> {
+        self.envelope.as_ref()
+    }
+}
 
 #[async_trait]
-pub trait Db {
-    async fn insert_user(
-        &self,
-        public_key_hash: &PublicKeyHash,
-        public_key: &PublicKey,
-        username: &str,
-    ) -> Result<Uuid, Error>;
-
-    async fn get_user_by_id(&self, user_id: Uuid) -> Result<User, Error>;
-
-    async fn get_user_by_pubkey(&self, pubkey_hash: &PublicKeyHash) -> Result<User, Error>;
-
-    async fn get_users(&self, limit: Option<i64>) -> Result<Vec<User>, Error>;
-
-    async fn create_message(
-        &self,
-        sender_id: Uuid,
-        recipient_id: Uuid,
-        encrypted_content: &str,
-        signature: Option<&str>,
-        parent_id: Option<i64>,
-    ) -> Result<Option<i64>, Error>;
-
-    async fn get_message(&self, message_id: i64) -> Result<Option<Message>, Error>;
-
-    async fn mark_message_read(&self, message_id: i64) -> Result<(), Error>;
-
-    async fn get_conversation(
-        &self,
-        user1_id: Uuid,
-        user2_id: Uuid,
-        limit: Option<i64>,
-    ) -> Result<Vec<Message>, Error>;
-
-    async fn get_unread_messages(&self, user_id: Uuid) -> Result<Vec<Message>, Error>;
-
-    async fn get_thread_replies(
-        &self,
-        parent_id: i64,
-        limit: Option<i64>,
-        offset: Option<i64>,
-    ) -> Result<Vec<Message>, Error>;
-
-    async fn get_complete_thread(
-        &self,
-        thread_root_id: i64,
-        limit: Option<i64>,
-    ) -> Result<Vec<Message>, Error>;
-
-    async fn get_user_threads(
-        &self,
-        user_id: Uuid,
-        limit: Option<i64>,
-    ) -> Result<Vec<Message>, Error>;
-
-    async fn store_refresh_token(
-        &self,
-        user_id: Uuid,
-        token_hash: &str,
-        expires_at: i64,
-        device_info: Option<&str>,
-    ) -> Result<(), Error>;
-
-    async fn validate_refresh_token(
-        &self,
-        user_id: Uuid,
-        token_hash: &str,
-    ) -> Result<bool, Error>;
-
-    async fn revoke_refresh_token(
-        &self,
-        token_hash: &str,
-        reason: Option<&str>,
-    ) -> Result<(), Error>;
-
-    async fn cleanup_expired_tokens(&self) -> Result<u64, Error>;
-
-    async fn fetch_public_key_hash(&self, user_id: Uuid) -> Result<String, Error>;
-
-    async fn update_user(
-        &self,
-        user_id: Uuid,
-        new_username: Option<&str>,
-        new_public_key: Option<&PublicKey>,
-        new_public_key_hash: Option<&PublicKeyHash>,
-    ) -> Result<(), Error>;
+impl DbHandle for SqliteTx {
+    type Database = Sqlite;
+
+    async fn connection(&self) -> Result<HandleConnection<'_, Sqlite>, Error> {
+        Ok(HandleConnection::Transaction(self.tx.lock().await))
+    }
+
+    fn envelope(&self) -> Option<&MessageEnvelope> {
+        self.envelope.as_ref()
+    }
+}
+
+#[cfg(feature = "postgres")]
+#[async_trait]
+impl DbHandle for PgDb {
+    type Database = Postgres;
+
+    async fn connection(&self) -> Result<HandleConnection<'_, Postgres>, Error> {
+        Ok(HandleConnection::Pooled(self.pool.acquire().await?))
+    }
+
+    fn envelope(&self) -> Option<&MessageEnvelope> {
+        self.envelope.as_ref()
+    }
+}
+
+#[cfg(feature = "postgres")]
+#[async_trait]
+impl DbHandle for PgTx {
+    type Database = Postgres;
+
+    async fn connection(&self) -> Result<HandleConnection<'_, Postgres>, Error> {
+        Ok(HandleConnection::Transaction(self.tx.lock().await))
+    }
+
+    fn envelope(&self) -> Option<&MessageEnvelope> {
+        self.envelope.as_ref()
+    }
 }
@@ -0,0 +1,650 @@
+//! Server-side envelope encryption of message bodies at rest.
+//!
+//! Each `messages` and `anonymous_messages` row gets its own random data key,
+//! which encrypts the row's `encrypted_content` and `signature`. The data key
+//! is stored wrapped by a [`MasterKey`] held outside this crate, at the front
+//! of the sealed `encrypted_content`, and is bound to the row it was made for
+//! (see [`SealedRow`]):
+//!
+//! ```text
+//! encrypted_content = "env1." base64(wrapped key length (2 bytes, big-endian) | wrapped key | nonce | ciphertext)
+//! signature         = "env1." base64(nonce | ciphertext)
+//! ```
+//!
+//! Clients only send base64, which never contains a `.`, so rows stored
+//! before envelope encryption was turned on are told apart by the prefix and
+//! read back unchanged until `seal_existing_messages` encrypts them.
+//!
+//! Rotating the master key leaves the data keys wrapped under the old one
+//! until `rewrap_sealed_messages` wraps them again under the new one; only
+//! the wrapped key changes, so the ciphertexts are left as they are.
+
+use crate::models::{AnonymousMessage, Message};
+use aes_gcm::{
+    aead::{Aead, KeyInit, OsRng, Payload},
+    AeadCore, Aes256Gcm, Nonce,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use sqlx::error::BoxDynError;
+use std::sync::Arc;
+use thiserror::Error;
+use uuid::Uuid;
+
+/// Marks a column value as sealed by a [`MessageEnvelope`].
+pub const SEALED_PREFIX: &str = "env1.";
+
+const DATA_KEY_LABEL: &[u8] = b"messages.data_key/";
+const ANONYMOUS_DATA_KEY_LABEL: &[u8] = b"anonymous_messages.data_key/";
+const CONTENT_LABEL: &[u8] = b"messages.encrypted_content";
+const SIGNATURE_LABEL: &[u8] = b"messages.signature";
+const NONCE_LEN: usize = 12;
+
+/// Wraps the per-row data keys. `associated_data` must be given again to
+/// unwrap.
+pub trait MasterKey: Send + Sync {
+    fn wrap(&self, data_key: &[u8], associated_data: &[u8]) -> Vec<u8>;
+
+    fn unwrap(&self, wrapped: &[u8], associated_data: &[u8]) -> Result<Vec<u8>, BoxDynError>;
+
+    /// Whether `wrapped` was wrapped under a key other than the one `wrap`
+    /// uses now, and should be wrapped again.
+    fn needs_rewrap(&self, wrapped: &[u8]) -> bool;
+}
+
+#[derive(Debug, Error)]
+pub enum EnvelopeError {
+    #[error("Sealed message is malformed")]
+    Malformed,
+
+    #[error("Message is sealed, but no master key is configured")]
+    NoMasterKey,
+
+    #[error("Could not unwrap the message's data key: {0}")]
+    Unwrap(BoxDynError),
+
+    #[error("Message failed to decrypt")]
+    DecryptionFailed,
+}
+
+impl From<EnvelopeError> for sqlx::Error {
+    fn from(err: EnvelopeError) -> Self {
+        sqlx::Error::Decode(Box::new(err))
+    }
+}
+
+/// The row a sealed body belongs to. Its data key is wrapped with the row's
+/// table, id and parties as associated data, so a body copied to another row,
+/// even one between the same users, fails to open.
+#[derive(Clone, Copy, Debug)]
+pub enum SealedRow {
+    Message {
+        id: i64,
+        sender_id: Uuid,
+        recipient_id: Uuid,
+    },
+    AnonymousMessage {
+        id: i64,
+        recipient_id: Uuid,
+    },
+}
+
+impl From<&Message> for SealedRow {
+    fn from(message: &Message) -> Self {
+        SealedRow::Message {
+            id: message.id,
+            sender_id: message.sender_id,
+            recipient_id: message.recipient_id,
+        }
+    }
+}
+
+impl From<&AnonymousMessage> for SealedRow {
+    fn from(message: &AnonymousMessage) -> Self {
+        SealedRow::AnonymousMessage {
+            id: message.id,
+            recipient_id: message.recipient_id,
+        }
+    }
+}
+
+/// Seals and opens message rows under a [`MasterKey`].
+#[derive(Clone)]
+pub struct MessageEnvelope {
+    master_key: Arc<dyn MasterKey>,
+}
+
+impl MessageEnvelope {
+    pub fn new(master_key: Arc<dyn MasterKey>) -> Self {
+        Self { master_key }
+    }
+
+    /// Encrypts a row's `encrypted_content` and `signature` under a fresh
+    /// data key, bound to `row`.
+    pub fn seal(
+        &self,
+        row: SealedRow,
+        encrypted_content: &str,
+        signature: Option<&str>,
+    ) -> (String, Option<String>) {
+        let data_key = Aes256Gcm::generate_key(&mut OsRng);
+        let cipher = Aes256Gcm::new(&data_key);
+        let wrapped = self.master_key.wrap(&data_key, &data_key_aad(row));
+        let wrapped_len = u16::try_from(wrapped.len()).expect("wrapped data keys are short");
+
+        let mut content = wrapped_len.to_be_bytes().to_vec();
+        content.extend(wrapped);
+        content.extend(encrypt(&cipher, encrypted_content.as_bytes(), CONTENT_LABEL));
+
+        (
+            encode(&content),
+            signature.map(|signature| encode(&encrypt(&cipher, signature.as_bytes(), SIGNATURE_LABEL))),
+        )
+    }
+
+    /// Reverses [`seal`](Self::seal) in place. Rows stored before envelope
+    /// encryption are left as they are.
+    pub fn open(&self, message: &mut Message) -> Result<(), EnvelopeError> {
+        let row = SealedRow::from(&*message);
+        self.open_body(row, &mut message.encrypted_content, &mut message.signature)
+    }
+
+    /// Like [`open`](Self::open), for an anonymous message, which has no
+    /// signature.
+    pub fn open_anonymous(&self, message: &mut AnonymousMessage) -> Result<(), EnvelopeError> {
+        let row = SealedRow::from(&*message);
+        self.open_body(row, &mut message.encrypted_content, &mut None)
+    }
+
+    fn open_body(
+        &self,
+        row: SealedRow,
+        encrypted_content: &mut String,
+        signature: &mut Option<String>,
+    ) -> Result<(), EnvelopeError> {
+        let Some(content) = decode(encrypted_content)? else {
+            if signature.as_deref().is_some_and(is_sealed) {
+                return Err(EnvelopeError::Malformed);
+            }
+            return Ok(());
+        };
+
+        let (wrapped, ciphertext) = split_wrapped_key(&content)?;
+        let data_key = self
+            .master_key
+            .unwrap(wrapped, &data_key_aad(row))
+            .map_err(EnvelopeError::Unwrap)?;
+        let cipher = Aes256Gcm::new_from_slice(&data_key).map_err(|_| EnvelopeError::Malformed)?;
+
+        let content = decrypt(&cipher, ciphertext, CONTENT_LABEL)?;
+        *signature = match signature.as_deref().map(decode).transpose()? {
+            Some(Some(signature)) => Some(decrypt(&cipher, &signature, SIGNATURE_LABEL)?),
+            // A sealed row never keeps a plaintext signature
+            Some(None) => return Err(EnvelopeError::Malformed),
+            None => None,
+        };
+        *encrypted_content = content;
+        Ok(())
+    }
+
+    /// A sealed `encrypted_content` with its data key wrapped again under
+    /// the current master key, or `None` if it already is or was never
+    /// sealed. The signature is sealed under the same data key, so it stays
+    /// as it is.
+    pub fn rewrap(
+        &self,
+        row: SealedRow,
+        encrypted_content: &str,
+    ) -> Result<Option<String>, EnvelopeError> {
+        let Some(content) = decode(encrypted_content)? else {
+            return Ok(None);
+        };
+        let (wrapped, ciphertext) = split_wrapped_key(&content)?;
+        if !self.master_key.needs_rewrap(wrapped) {
+            return Ok(None);
+        }
+
+        let aad = data_key_aad(row);
+        let data_key = self
+            .master_key
+            .unwrap(wrapped, &aad)
+            .map_err(EnvelopeError::Unwrap)?;
+        let rewrapped = self.master_key.wrap(&data_key, &aad);
+        let rewrapped_len = u16::try_from(rewrapped.len()).expect("wrapped data keys are short");
+
+        let mut content = rewrapped_len.to_be_bytes().to_vec();
+        content.extend(rewrapped);
+        content.extend(ciphertext);
+        Ok(Some(encode(&content)))
+    }
+}
+
+pub fn is_sealed(value: &str) -> bool {
+    value.starts_with(SEALED_PREFIX)
+}
+
+/// A stored row with a body [`MessageEnvelope`] seals.
+pub(crate) trait Sealed {
+    /// What the row is called in logs.
+    const KIND: &'static str;
+
+    fn id(&self) -> i64;
+
+    fn row(&self) -> SealedRow;
+
+    fn encrypted_content(&self) -> &str;
+
+    fn is_sealed(&self) -> bool {
+        is_sealed(self.encrypted_content())
+    }
+
+    fn open_with(&mut self, envelope: &MessageEnvelope) -> Result<(), EnvelopeError>;
+}
+
+impl Sealed for Message {
+    const KIND: &'static str = "message";
+
+    fn id(&self) -> i64 {
+        self.id
+    }
+
+    fn row(&self) -> SealedRow {
+        SealedRow::from(self)
+    }
+
+    fn encrypted_content(&self) -> &str {
+        &self.encrypted_content
+    }
+
+    fn open_with(&mut self, envelope: &MessageEnvelope) -> Result<(), EnvelopeError> {
+        envelope.open(self)
+    }
+}
+
+impl Sealed for AnonymousMessage {
+    const KIND: &'static str = "anonymous message";
+
+    fn id(&self) -> i64 {
+        self.id
+    }
+
+    fn row(&self) -> SealedRow {
+        SealedRow::from(self)
+    }
+
+    fn encrypted_content(&self) -> &str {
+        &self.encrypted_content
+    }
+
+    fn open_with(&mut self, envelope: &MessageEnvelope) -> Result<(), EnvelopeError> {
+        envelope.open_anonymous(self)
+    }
+}
+
+/// Opens a stored row. Sealed rows fail to open without `envelope`.
+pub(crate) fn open<T: Sealed>(
+    envelope: Option<&MessageEnvelope>,
+    mut row: T,
+) -> Result<T, sqlx::Error> {
+    match envelope {
+        Some(envelope) => row.open_with(envelope)?,
+        None if row.is_sealed() => return Err(EnvelopeError::NoMasterKey.into()),
+        None => {}
+    }
+    Ok(row)
+}
+
+/// Opens the rows of a listing. A row that fails to open is logged and left
+/// out, so that one bad row doesn't hide the rest.
+pub(crate) fn open_all<T: Sealed>(envelope: Option<&MessageEnvelope>, rows: Vec<T>) -> Vec<T> {
+    rows.into_iter()
+        .filter_map(|row| {
+            let id = row.id();
+            open(envelope, row)
+                .inspect_err(|e| log::error!("{} {id} could not be opened: {e}", T::KIND))
+                .ok()
+        })
+        .collect()
+}
+
+/// `row`'s content with its data key wrapped again under the current master
+/// key, or `None` if it needs no rewrapping. Rows that fail to rewrap are
+/// logged and left as they are.
+pub(crate) fn rewrap_row<T: Sealed>(envelope: &MessageEnvelope, row: &T) -> Option<String> {
+    envelope
+        .rewrap(row.row(), row.encrypted_content())
+        .inspect_err(|e| log::error!("{} {} could not be rewrapped: {e}", T::KIND, row.id()))
+        .ok()
+        .flatten()
+}
+
+/// Splits sealed content into the wrapped data key and the ciphertext.
+fn split_wrapped_key(content: &[u8]) -> Result<(&[u8], &[u8]), EnvelopeError> {
+    let wrapped_len = content
+        .get(..2)
+        .map(|len| usize::from(u16::from_be_bytes([len[0], len[1]])))
+        .ok_or(EnvelopeError::Malformed)?;
+    let wrapped = content
+        .get(2..2 + wrapped_len)
+        .ok_or(EnvelopeError::Malformed)?;
+    Ok((wrapped, &content[2 + wrapped_len..]))
+}
+
+fn data_key_aad(row: SealedRow) -> Vec<u8> {
+    match row {
+        SealedRow::Message {
+            id,
+            sender_id,
+            recipient_id,
+        } => [
+            DATA_KEY_LABEL,
+            &id.to_be_bytes(),
+            sender_id.as_bytes(),
+            recipient_id.as_bytes(),
+        ]
+        .concat(),
+        SealedRow::AnonymousMessage { id, recipient_id } => [
+            ANONYMOUS_DATA_KEY_LABEL,
+            &id.to_be_bytes(),
+            recipient_id.as_bytes(),
+        ]
+        .concat(),
+    }
+}
+
+fn encrypt(cipher: &Aes256Gcm, plaintext: &[u8], label: &[u8]) -> Vec<u8> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad: label,
+            },
+        )
+        .expect("encrypting into a Vec cannot fail");
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend(ciphertext);
+    sealed
+}
+
+fn decrypt(cipher: &Aes256Gcm, sealed: &[u8], label: &[u8]) -> Result<String, EnvelopeError> {
+    if sealed.len() < NONCE_LEN {
+        return Err(EnvelopeError::Malformed);
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let plaintext = cipher
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: label,
+            },
+        )
+        .map_err(|_| EnvelopeError::DecryptionFailed)?;
+    String::from_utf8(plaintext).map_err(|_| EnvelopeError::Malformed)
+}
+
+fn encode(sealed: &[u8]) -> String {
+    format!("{SEALED_PREFIX}{}", URL_SAFE_NO_PAD.encode(sealed))
+}
+
+/// The sealed bytes of `value`, or `None` if it was never sealed.
+fn decode(value: &str) -> Result<Option<Vec<u8>>, EnvelopeError> {
+    value
+        .strip_prefix(SEALED_PREFIX)
+        .map(|sealed| {
+            URL_SAFE_NO_PAD
+                .decode(sealed)
+                .map_err(|_| EnvelopeError::Malformed)
+        })
+        .transpose()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    /// Wraps with AES-GCM, like the real master key does, under the last of
+    /// its keys. Wrapped keys start with the index of the key they are
+    /// wrapped under.
+    pub(crate) struct TestMasterKey(pub(crate) Vec<Aes256Gcm>);
+
+    impl TestMasterKey {
+        fn current(&self) -> u8 {
+            u8::try_from(self.0.len() - 1).unwrap()
+        }
+    }
+
+    impl MasterKey for TestMasterKey {
+        fn wrap(&self, data_key: &[u8], associated_data: &[u8]) -> Vec<u8> {
+            let current = self.current();
+            let mut wrapped = vec![current];
+            wrapped.extend(encrypt(
+                &self.0[usize::from(current)],
+                data_key,
+                associated_data,
+            ));
+            wrapped
+        }
+
+        fn unwrap(&self, wrapped: &[u8], associated_data: &[u8]) -> Result<Vec<u8>, BoxDynError> {
+            let (key, sealed) = wrapped.split_first().ok_or("empty wrapped key")?;
+            let key = self.0.get(usize::from(*key)).ok_or("unknown master key")?;
+            let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+            key.decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: associated_data,
+                },
+            )
+            .map_err(|_| "wrong master key".into())
+        }
+
+        fn needs_rewrap(&self, wrapped: &[u8]) -> bool {
+            wrapped.first() != Some(&self.current())
+        }
+    }
+
+    pub(crate) fn master_key() -> Aes256Gcm {
+        Aes256Gcm::new(&Aes256Gcm::generate_key(&mut OsRng))
+    }
+
+    pub(crate) fn envelope() -> MessageEnvelope {
+        MessageEnvelope::new(Arc::new(TestMasterKey(vec![master_key()])))
+    }
+
+    fn message(encrypted_content: &str, signature: Option<&str>) -> Message {
+        Message {
+            id: 1,
+            sender_id: Uuid::now_v7(),
+            recipient_id: Uuid::now_v7(),
+            encrypted_content: encrypted_content.to_string(),
+            parent_id: None,
+            signature: signature.map(str::to_string),
+            created_at: Utc::now(),
+            is_read: false,
+        }
+    }
+
+    fn sealed(envelope: &MessageEnvelope, plain: &Message) -> Message {
+        let (encrypted_content, signature) = envelope.seal(
+            SealedRow::from(plain),
+            &plain.encrypted_content,
+            plain.signature.as_deref(),
+        );
+        Message {
+            encrypted_content,
+            signature,
+            ..plain.clone()
+        }
+    }
+
+    #[test]
+    fn test_seal_open_round_trip() {
+        let envelope = envelope();
+        for plain in [message("Y29udGVudA", Some("c2lnbmF0dXJl")), message("Y29udGVudA", None)] {
+            let mut stored = sealed(&envelope, &plain);
+            assert!(is_sealed(&stored.encrypted_content));
+            assert!(!stored.encrypted_content.contains("Y29udGVudA"));
+            assert_eq!(stored.signature.is_some(), plain.signature.is_some());
+
+            envelope.open(&mut stored).unwrap();
+            assert_eq!(stored.encrypted_content, plain.encrypted_content);
+            assert_eq!(stored.signature, plain.signature);
+        }
+    }
+
+    #[test]
+    fn test_each_row_gets_its_own_data_key() {
+        let envelope = envelope();
+        let plain = message("Y29udGVudA", None);
+        assert_ne!(
+            sealed(&envelope, &plain).encrypted_content,
+            sealed(&envelope, &plain).encrypted_content
+        );
+    }
+
+    #[test]
+    fn test_unsealed_rows_pass_through() {
+        let plain = message("Y29udGVudA", Some("c2lnbmF0dXJl"));
+        let mut stored = plain.clone();
+        envelope().open(&mut stored).unwrap();
+        assert_eq!(stored.encrypted_content, plain.encrypted_content);
+        assert_eq!(stored.signature, plain.signature);
+
+        let stored = open(None, plain.clone()).unwrap();
+        assert_eq!(stored.encrypted_content, plain.encrypted_content);
+        let stored = sealed(&envelope(), &plain);
+        assert!(open(None, stored).is_err());
+    }
+
+    #[test]
+    fn test_tampered_rows_fail_to_open() {
+        let envelope = envelope();
+        let plain = message("Y29udGVudA", Some("c2lnbmF0dXJl"));
+
+        // Moved to another conversation, or another row of the same one
+        let mut moved = sealed(&envelope, &plain);
+        moved.recipient_id = Uuid::now_v7();
+        assert!(matches!(envelope.open(&mut moved), Err(EnvelopeError::Unwrap(_))));
+        let mut moved = sealed(&envelope, &plain);
+        moved.id = 2;
+        assert!(matches!(envelope.open(&mut moved), Err(EnvelopeError::Unwrap(_))));
+
+        // Signature taken from another row
+        let mut swapped = sealed(&envelope, &plain);
+        swapped.signature = sealed(&envelope, &plain).signature;
+        assert!(matches!(
+            envelope.open(&mut swapped),
+            Err(EnvelopeError::DecryptionFailed)
+        ));
+
+        // Signature stripped of its seal
+        let mut unsealed_signature = sealed(&envelope, &plain);
+        unsealed_signature.signature = plain.signature.clone();
+        assert!(matches!(
+            envelope.open(&mut unsealed_signature),
+            Err(EnvelopeError::Malformed)
+        ));
+
+        let mut truncated = sealed(&envelope, &plain);
+        truncated.encrypted_content.truncate(SEALED_PREFIX.len() + 2);
+        assert!(matches!(
+            envelope.open(&mut truncated),
+            Err(EnvelopeError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn test_anonymous_messages_are_bound_to_their_row() {
+        let envelope = envelope();
+        let plain = AnonymousMessage {
+            id: 1,
+            recipient_id: Uuid::now_v7(),
+            encrypted_content: "Y29udGVudA".to_string(),
+            nullifier: "nullifier".to_string(),
+            membership_root: "root".to_string(),
+            created_at: Utc::now().naive_utc(),
+        };
+        let (encrypted_content, signature) =
+            envelope.seal(SealedRow::from(&plain), &plain.encrypted_content, None);
+        assert!(signature.is_none());
+        let stored = AnonymousMessage {
+            encrypted_content,
+            ..plain.clone()
+        };
+
+        let mut opened = stored.clone();
+        envelope.open_anonymous(&mut opened).unwrap();
+        assert_eq!(opened.encrypted_content, plain.encrypted_content);
+
+        let mut moved = AnonymousMessage { id: 2, ..stored.clone() };
+        assert!(matches!(
+            envelope.open_anonymous(&mut moved),
+            Err(EnvelopeError::Unwrap(_))
+        ));
+        // Nor does it open as a direct message with the same id
+        let mut direct = message(&stored.encrypted_content, None);
+        direct.recipient_id = plain.recipient_id;
+        assert!(matches!(envelope.open(&mut direct), Err(EnvelopeError::Unwrap(_))));
+    }
+
+    #[test]
+    fn test_listings_leave_out_rows_that_fail_to_open() {
+        let envelope = envelope();
+        let plain = message("Y29udGVudA", Some("c2lnbmF0dXJl"));
+        let mut tampered = sealed(&envelope, &plain);
+        tampered.id = 2;
+        tampered.recipient_id = Uuid::now_v7();
+        let unsealed = Message { id: 3, ..plain.clone() };
+
+        let opened = open_all(
+            Some(&envelope),
+            vec![sealed(&envelope, &plain), tampered, unsealed],
+        );
+        let ids: Vec<i64> = opened.iter().map(|message| message.id).collect();
+        assert_eq!(ids, [1, 3]);
+        assert_eq!(opened[0].encrypted_content, plain.encrypted_content);
+    }
+
+    #[test]
+    fn test_rewrap_moves_data_keys_to_the_current_master_key() {
+        let old = master_key();
+        let new = master_key();
+        let before = MessageEnvelope::new(Arc::new(TestMasterKey(vec![old.clone()])));
+        let rotated = MessageEnvelope::new(Arc::new(TestMasterKey(vec![old, new.clone()])));
+
+        let plain = message("Y29udGVudA", Some("c2lnbmF0dXJl"));
+        let stored = sealed(&before, &plain);
+        assert!(before
+            .rewrap(SealedRow::from(&plain), &stored.encrypted_content)
+            .unwrap()
+            .is_none());
+        assert!(rotated
+            .rewrap(SealedRow::from(&plain), &plain.encrypted_content)
+            .unwrap()
+            .is_none());
+
+        let encrypted_content = rotated
+            .rewrap(SealedRow::from(&plain), &stored.encrypted_content)
+            .unwrap()
+            .unwrap();
+        let mut rewrapped = Message {
+            encrypted_content,
+            ..stored
+        };
+        assert!(rotated
+            .rewrap(SealedRow::from(&plain), &rewrapped.encrypted_content)
+            .unwrap()
+            .is_none());
+
+        // Opens once the old master key is gone
+        let new_only = MessageEnvelope::new(Arc::new(TestMasterKey(vec![master_key(), new])));
+        new_only.open(&mut rewrapped).unwrap();
+        assert_eq!(rewrapped.encrypted_content, plain.encrypted_content);
+        assert_eq!(rewrapped.signature, plain.signature);
+    }
+}
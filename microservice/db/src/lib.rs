@@ -1,10 +1,16 @@
 pub use faker_rand;
 pub use sqlx::{Error, SqlitePool};
+#[cfg(feature = "postgres")]
+pub use sqlx::PgPool;
 pub use uuid;
 
 pub mod db;
+pub mod db_trait;
+pub mod envelope;
 pub mod hyphenated_uuid;
 pub mod models;
+#[cfg(feature = "postgres")]
+pub mod pg;
 pub mod public_key;
 pub mod public_key_hash;
 pub mod unix_timestamp;
@@ -12,4 +18,13 @@ pub mod token_db;
 pub mod user_db;
 pub mod message_db;
 
+/// Every query is logged with its timing at debug level, under the
+/// `sqlx::query` target; queries slower than this are also logged as warnings.
+pub(crate) const SLOW_QUERY_THRESHOLD: std::time::Duration = std::time::Duration::from_millis(250);
 
+/// `options` with per-query timing logged.
+pub(crate) fn log_queries<O: sqlx::ConnectOptions>(options: O) -> O {
+    options
+        .log_statements(log::LevelFilter::Debug)
+        .log_slow_statements(log::LevelFilter::Warn, SLOW_QUERY_THRESHOLD)
+}
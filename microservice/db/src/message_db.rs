@@ -1,9 +1,15 @@
-use crate::models::Message;
+use crate::db;
+use crate::db_trait::DbHandle;
+use crate::envelope::{self, SealedRow};
+use crate::models::{AnonymousMessage, Message, RateLimitShare};
+#[cfg(feature = "postgres")]
+use crate::pg;
 use async_trait::async_trait;
-use sqlx::Error;
+use mockall::automock;
+#[cfg(feature = "postgres")]
+use sqlx::{PgConnection, Postgres};
+use sqlx::{Connection, Error, Sqlite, SqliteConnection};
 use uuid::Uuid;
-use crate::db::{self, SqliteDb};
-use mockall::{automock};
 
 #[automock]
 #[async_trait]
@@ -17,8 +23,53 @@ pub trait MessageDb {
         parent_id: Option<i64>,
     ) -> Result<Option<i64>, Error>;
 
+    /// Stores a message with what the sender attached to vouch for it: a
+    /// commitment to the ciphertext and a verified ephemeral key proof.
+    async fn create_attested_message<'a>(
+        &self,
+        sender_id: Uuid,
+        recipient_id: Uuid,
+        encrypted_content: &str,
+        signature: Option<String>,
+        parent_id: Option<i64>,
+        commitment: Option<&'a str>,
+        ephemeral_key_proof: Option<&'a str>,
+    ) -> Result<Option<i64>, Error>;
+
     async fn get_message(&self, message_id: i64) -> Result<Option<Message>, Error>;
 
+    async fn get_message_commitment(&self, message_id: i64) -> Result<Option<String>, Error>;
+
+    async fn get_ephemeral_key_proof(&self, message_id: i64) -> Result<Option<String>, Error>;
+
+    /// The Jubjub key messages to `user_id` are sealed to, or `None` if there
+    /// is no such user or they haven't registered one.
+    async fn get_user_message_key(&self, user_id: Uuid) -> Result<Option<String>, Error>;
+
+    async fn create_anonymous_message(
+        &self,
+        recipient_id: Uuid,
+        encrypted_content: &str,
+        membership_root: &str,
+        share: &RateLimitShare,
+    ) -> Result<i64, Error>;
+
+    async fn get_rate_limit_share(&self, nullifier: &str) -> Result<Option<RateLimitShare>, Error>;
+
+    async fn block_identity(
+        &self,
+        identity_commitment: &str,
+        identity_secret: &str,
+    ) -> Result<(), Error>;
+
+    async fn get_blocked_identity_secrets(&self) -> Result<Vec<String>, Error>;
+
+    async fn get_anonymous_messages(
+        &self,
+        recipient_id: Uuid,
+        limit: i64,
+    ) -> Result<Vec<AnonymousMessage>, Error>;
+
     async fn mark_message_read(&self, message_id: i64) -> Result<(), Error>;
 
     async fn get_conversation(
@@ -50,8 +101,125 @@ pub trait MessageDb {
     ) -> Result<Vec<Message>, Error>;
 }
 
+/// The statements behind [`MessageDb`] for one backend, each run on the
+/// connection it is given. Sealing and opening message bodies is left to the
+/// one [`MessageDb`] implementation every backend shares.
 #[async_trait]
-impl MessageDb for SqliteDb {
+pub(crate) trait MessageQueries: sqlx::Database {
+    #[allow(clippy::too_many_arguments)]
+    async fn create_attested_message(
+        conn: &mut Self::Connection,
+        sender_id: Uuid,
+        recipient_id: Uuid,
+        encrypted_content: &str,
+        signature: Option<&str>,
+        parent_id: Option<i64>,
+        commitment: Option<&str>,
+        ephemeral_key_proof: Option<&str>,
+    ) -> Result<Option<i64>, Error>;
+
+    async fn replace_message_body(
+        conn: &mut Self::Connection,
+        message_id: i64,
+        expected_content: &str,
+        encrypted_content: &str,
+        signature: Option<&str>,
+    ) -> Result<bool, Error>;
+
+    async fn get_message(
+        conn: &mut Self::Connection,
+        message_id: i64,
+    ) -> Result<Option<Message>, Error>;
+
+    async fn get_message_commitment(
+        conn: &mut Self::Connection,
+        message_id: i64,
+    ) -> Result<Option<String>, Error>;
+
+    async fn get_ephemeral_key_proof(
+        conn: &mut Self::Connection,
+        message_id: i64,
+    ) -> Result<Option<String>, Error>;
+
+    async fn fetch_message_key(
+        conn: &mut Self::Connection,
+        user_id: Uuid,
+    ) -> Result<Option<String>, Error>;
+
+    async fn create_anonymous_message(
+        conn: &mut Self::Connection,
+        recipient_id: Uuid,
+        encrypted_content: &str,
+        membership_root: &str,
+        share: &RateLimitShare,
+    ) -> Result<i64, Error>;
+
+    async fn replace_anonymous_message_content(
+        conn: &mut Self::Connection,
+        message_id: i64,
+        expected_content: &str,
+        encrypted_content: &str,
+    ) -> Result<bool, Error>;
+
+    async fn get_rate_limit_share(
+        conn: &mut Self::Connection,
+        nullifier: &str,
+    ) -> Result<Option<RateLimitShare>, Error>;
+
+    async fn block_identity(
+        conn: &mut Self::Connection,
+        identity_commitment: &str,
+        identity_secret: &str,
+    ) -> Result<(), Error>;
+
+    async fn get_blocked_identity_secrets(conn: &mut Self::Connection) -> Result<Vec<String>, Error>;
+
+    async fn get_anonymous_messages(
+        conn: &mut Self::Connection,
+        recipient_id: Uuid,
+        limit: i64,
+    ) -> Result<Vec<AnonymousMessage>, Error>;
+
+    async fn mark_message_read(conn: &mut Self::Connection, message_id: i64) -> Result<(), Error>;
+
+    async fn get_conversation(
+        conn: &mut Self::Connection,
+        user1_id: Uuid,
+        user2_id: Uuid,
+        limit: Option<i64>,
+    ) -> Result<Vec<Message>, Error>;
+
+    async fn get_unread_messages(
+        conn: &mut Self::Connection,
+        user_id: Uuid,
+    ) -> Result<Vec<Message>, Error>;
+
+    async fn get_thread_replies(
+        conn: &mut Self::Connection,
+        parent_id: i64,
+        limit: Option<i64>,
+        offset: Option<i64>,
+    ) -> Result<Vec<Message>, Error>;
+
+    async fn get_complete_thread(
+        conn: &mut Self::Connection,
+        thread_root_id: i64,
+        limit: Option<i64>,
+    ) -> Result<Vec<Message>, Error>;
+
+    async fn get_user_threads(
+        conn: &mut Self::Connection,
+        user_id: Uuid,
+        limit: Option<i64>,
+    ) -> Result<Vec<Message>, Error>;
+}
+
+#[async_trait]
+impl<T> MessageDb for T
+where
+    T: DbHandle,
+    T::Database: MessageQueries,
+{
     async fn create_message(
         &self,
         sender_id: Uuid,
@@ -60,15 +228,175 @@ impl MessageDb for SqliteDb {
         signature: Option<String>,
         parent_id: Option<i64>,
     ) -> Result<Option<i64>, Error> {
-        db::create_message(&self.pool, sender_id, recipient_id, encrypted_content, signature.as_deref(), parent_id).await
+        self.create_attested_message(
+            sender_id,
+            recipient_id,
+            encrypted_content,
+            signature,
+            parent_id,
+            None,
+            None,
+        )
+        .await
+    }
+
+    async fn create_attested_message<'a>(
+        &self,
+        sender_id: Uuid,
+        recipient_id: Uuid,
+        encrypted_content: &str,
+        signature: Option<String>,
+        parent_id: Option<i64>,
+        commitment: Option<&'a str>,
+        ephemeral_key_proof: Option<&'a str>,
+    ) -> Result<Option<i64>, Error> {
+        let mut conn = self.connection().await?;
+        let Some(envelope) = self.envelope() else {
+            return T::Database::create_attested_message(
+                &mut conn,
+                sender_id,
+                recipient_id,
+                encrypted_content,
+                signature.as_deref(),
+                parent_id,
+                commitment,
+                ephemeral_key_proof,
+            )
+            .await;
+        };
+
+        // The data key is bound to the row's id, so the body is sealed once
+        // the row exists, in the same transaction
+        let mut tx = conn.begin().await?;
+        let Some(message_id) = T::Database::create_attested_message(
+            &mut tx,
+            sender_id,
+            recipient_id,
+            "",
+            None,
+            parent_id,
+            commitment,
+            ephemeral_key_proof,
+        )
+        .await?
+        else {
+            return Ok(None);
+        };
+        let row = SealedRow::Message {
+            id: message_id,
+            sender_id,
+            recipient_id,
+        };
+        let (encrypted_content, signature) =
+            envelope.seal(row, encrypted_content, signature.as_deref());
+        T::Database::replace_message_body(
+            &mut tx,
+            message_id,
+            "",
+            &encrypted_content,
+            signature.as_deref(),
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(Some(message_id))
     }
 
     async fn get_message(&self, message_id: i64) -> Result<Option<Message>, Error> {
-        db::get_message(&self.pool, message_id).await
+        T::Database::get_message(&mut *self.connection().await?, message_id)
+            .await?
+            .map(|message| envelope::open(self.envelope(), message))
+            .transpose()
+    }
+
+    async fn get_message_commitment(&self, message_id: i64) -> Result<Option<String>, Error> {
+        T::Database::get_message_commitment(&mut *self.connection().await?, message_id).await
+    }
+
+    async fn get_ephemeral_key_proof(&self, message_id: i64) -> Result<Option<String>, Error> {
+        T::Database::get_ephemeral_key_proof(&mut *self.connection().await?, message_id).await
+    }
+
+    async fn get_user_message_key(&self, user_id: Uuid) -> Result<Option<String>, Error> {
+        T::Database::fetch_message_key(&mut *self.connection().await?, user_id).await
+    }
+
+    async fn create_anonymous_message(
+        &self,
+        recipient_id: Uuid,
+        encrypted_content: &str,
+        membership_root: &str,
+        share: &RateLimitShare,
+    ) -> Result<i64, Error> {
+        let mut conn = self.connection().await?;
+        let Some(envelope) = self.envelope() else {
+            return T::Database::create_anonymous_message(
+                &mut conn,
+                recipient_id,
+                encrypted_content,
+                membership_root,
+                share,
+            )
+            .await;
+        };
+
+        // Sealed once the row exists, as in `create_attested_message`
+        let mut tx = conn.begin().await?;
+        let message_id = T::Database::create_anonymous_message(
+            &mut tx,
+            recipient_id,
+            "",
+            membership_root,
+            share,
+        )
+        .await?;
+        let row = SealedRow::AnonymousMessage {
+            id: message_id,
+            recipient_id,
+        };
+        let (encrypted_content, _) = envelope.seal(row, encrypted_content, None);
+        T::Database::replace_anonymous_message_content(&mut tx, message_id, "", &encrypted_content)
+            .await?;
+        tx.commit().await?;
+
+        Ok(message_id)
+    }
+
+    async fn get_rate_limit_share(&self, nullifier: &str) -> Result<Option<RateLimitShare>, Error> {
+        T::Database::get_rate_limit_share(&mut *self.connection().await?, nullifier).await
+    }
+
+    async fn block_identity(
+        &self,
+        identity_commitment: &str,
+        identity_secret: &str,
+    ) -> Result<(), Error> {
+        T::Database::block_identity(
+            &mut *self.connection().await?,
+            identity_commitment,
+            identity_secret,
+        )
+        .await
+    }
+
+    async fn get_blocked_identity_secrets(&self) -> Result<Vec<String>, Error> {
+        T::Database::get_blocked_identity_secrets(&mut *self.connection().await?).await
+    }
+
+    async fn get_anonymous_messages(
+        &self,
+        recipient_id: Uuid,
+        limit: i64,
+    ) -> Result<Vec<AnonymousMessage>, Error> {
+        Ok(envelope::open_all(
+            self.envelope(),
+            T::Database::get_anonymous_messages(&mut *self.connection().await?, recipient_id, limit)
+                .await?,
+        ))
     }
 
     async fn mark_message_read(&self, message_id: i64) -> Result<(), Error> {
-        db::mark_message_read(&self.pool, message_id).await
+        T::Database::mark_message_read(&mut *self.connection().await?, message_id).await
     }
 
     async fn get_conversation(
@@ -77,11 +405,18 @@ impl MessageDb for SqliteDb {
         user2_id: Uuid,
         limit: Option<i64>,
     ) -> Result<Vec<Message>, Error> {
-        db::get_conversation(&self.pool, user1_id, user2_id, limit).await
+        Ok(envelope::open_all(
+            self.envelope(),
+            T::Database::get_conversation(&mut *self.connection().await?, user1_id, user2_id, limit)
+                .await?,
+        ))
     }
 
     async fn get_unread_messages(&self, user_id: Uuid) -> Result<Vec<Message>, Error> {
-        db::get_unread_messages(&self.pool, user_id).await
+        Ok(envelope::open_all(
+            self.envelope(),
+            T::Database::get_unread_messages(&mut *self.connection().await?, user_id).await?,
+        ))
     }
 
     async fn get_thread_replies(
@@ -90,7 +425,16 @@ impl MessageDb for SqliteDb {
         limit: Option<i64>,
         offset: Option<i64>,
     ) -> Result<Vec<Message>, Error> {
-        db::get_thread_replies(&self.pool, parent_id, limit, offset).await
+        Ok(envelope::open_all(
+            self.envelope(),
+            T::Database::get_thread_replies(
+                &mut *self.connection().await?,
+                parent_id,
+                limit,
+                offset,
+            )
+            .await?,
+        ))
     }
 
     async fn get_complete_thread(
@@ -98,7 +442,11 @@ impl MessageDb for SqliteDb {
         thread_root_id: i64,
         limit: Option<i64>,
     ) -> Result<Vec<Message>, Error> {
-        db::get_complete_thread(&self.pool, thread_root_id, limit).await
+        Ok(envelope::open_all(
+            self.envelope(),
+            T::Database::get_complete_thread(&mut *self.connection().await?, thread_root_id, limit)
+                .await?,
+        ))
     }
 
     async fn get_user_threads(
@@ -106,6 +454,360 @@ impl MessageDb for SqliteDb {
         user_id: Uuid,
         limit: Option<i64>,
     ) -> Result<Vec<Message>, Error> {
-        db::get_user_threads(&self.pool, user_id, limit).await
+        Ok(envelope::open_all(
+            self.envelope(),
+            T::Database::get_user_threads(&mut *self.connection().await?, user_id, limit).await?,
+        ))
+    }
+}
+
+#[async_trait]
+impl MessageQueries for Sqlite {
+    async fn create_attested_message(
+        conn: &mut SqliteConnection,
+        sender_id: Uuid,
+        recipient_id: Uuid,
+        encrypted_content: &str,
+        signature: Option<&str>,
+        parent_id: Option<i64>,
+        commitment: Option<&str>,
+        ephemeral_key_proof: Option<&str>,
+    ) -> Result<Option<i64>, Error> {
+        db::create_attested_message(
+            conn,
+            sender_id,
+            recipient_id,
+            encrypted_content,
+            signature,
+            parent_id,
+            commitment,
+            ephemeral_key_proof,
+        )
+        .await
+    }
+
+    async fn replace_message_body(
+        conn: &mut SqliteConnection,
+        message_id: i64,
+        expected_content: &str,
+        encrypted_content: &str,
+        signature: Option<&str>,
+    ) -> Result<bool, Error> {
+        db::replace_message_body(
+            conn,
+            message_id,
+            expected_content,
+            encrypted_content,
+            signature,
+        )
+        .await
+    }
+
+    async fn get_message(
+        conn: &mut SqliteConnection,
+        message_id: i64,
+    ) -> Result<Option<Message>, Error> {
+        db::get_message(conn, message_id).await
+    }
+
+    async fn get_message_commitment(
+        conn: &mut SqliteConnection,
+        message_id: i64,
+    ) -> Result<Option<String>, Error> {
+        db::get_message_commitment(conn, message_id).await
+    }
+
+    async fn get_ephemeral_key_proof(
+        conn: &mut SqliteConnection,
+        message_id: i64,
+    ) -> Result<Option<String>, Error> {
+        db::get_ephemeral_key_proof(conn, message_id).await
+    }
+
+    async fn fetch_message_key(
+        conn: &mut SqliteConnection,
+        user_id: Uuid,
+    ) -> Result<Option<String>, Error> {
+        db::fetch_message_key(conn, user_id).await
+    }
+
+    async fn create_anonymous_message(
+        conn: &mut SqliteConnection,
+        recipient_id: Uuid,
+        encrypted_content: &str,
+        membership_root: &str,
+        share: &RateLimitShare,
+    ) -> Result<i64, Error> {
+        db::create_anonymous_message(
+            conn,
+            recipient_id,
+            encrypted_content,
+            membership_root,
+            share,
+        )
+        .await
+    }
+
+    async fn replace_anonymous_message_content(
+        conn: &mut SqliteConnection,
+        message_id: i64,
+        expected_content: &str,
+        encrypted_content: &str,
+    ) -> Result<bool, Error> {
+        db::replace_anonymous_message_content(
+            conn,
+            message_id,
+            expected_content,
+            encrypted_content,
+        )
+        .await
+    }
+
+    async fn get_rate_limit_share(
+        conn: &mut SqliteConnection,
+        nullifier: &str,
+    ) -> Result<Option<RateLimitShare>, Error> {
+        db::get_rate_limit_share(conn, nullifier).await
+    }
+
+    async fn block_identity(
+        conn: &mut SqliteConnection,
+        identity_commitment: &str,
+        identity_secret: &str,
+    ) -> Result<(), Error> {
+        db::block_identity(conn, identity_commitment, identity_secret).await
+    }
+
+    async fn get_blocked_identity_secrets(conn: &mut SqliteConnection) -> Result<Vec<String>, Error> {
+        db::get_blocked_identity_secrets(conn).await
+    }
+
+    async fn get_anonymous_messages(
+        conn: &mut SqliteConnection,
+        recipient_id: Uuid,
+        limit: i64,
+    ) -> Result<Vec<AnonymousMessage>, Error> {
+        db::get_anonymous_messages(conn, recipient_id, limit).await
+    }
+
+    async fn mark_message_read(conn: &mut SqliteConnection, message_id: i64) -> Result<(), Error> {
+        db::mark_message_read(conn, message_id).await
+    }
+
+    async fn get_conversation(
+        conn: &mut SqliteConnection,
+        user1_id: Uuid,
+        user2_id: Uuid,
+        limit: Option<i64>,
+    ) -> Result<Vec<Message>, Error> {
+        db::get_conversation(conn, user1_id, user2_id, limit).await
+    }
+
+    async fn get_unread_messages(
+        conn: &mut SqliteConnection,
+        user_id: Uuid,
+    ) -> Result<Vec<Message>, Error> {
+        db::get_unread_messages(conn, user_id).await
+    }
+
+    async fn get_thread_replies(
+        conn: &mut SqliteConnection,
+        parent_id: i64,
+        limit: Option<i64>,
+        offset: Option<i64>,
+    ) -> Result<Vec<Message>, Error> {
+        db::get_thread_replies(conn, parent_id, limit, offset).await
+    }
+
+    async fn get_complete_thread(
+        conn: &mut SqliteConnection,
+        thread_root_id: i64,
+        limit: Option<i64>,
+    ) -> Result<Vec<Message>, Error> {
+        db::get_complete_thread(conn, thread_root_id, limit).await
+    }
+
+    async fn get_user_threads(
+        conn: &mut SqliteConnection,
+        user_id: Uuid,
+        limit: Option<i64>,
+    ) -> Result<Vec<Message>, Error> {
+        db::get_user_threads(conn, user_id, limit).await
+    }
+}
+
+#[cfg(feature = "postgres")]
+#[async_trait]
+impl MessageQueries for Postgres {
+    async fn create_attested_message(
+        conn: &mut PgConnection,
+        sender_id: Uuid,
+        recipient_id: Uuid,
+        encrypted_content: &str,
+        signature: Option<&str>,
+        parent_id: Option<i64>,
+        commitment: Option<&str>,
+        ephemeral_key_proof: Option<&str>,
+    ) -> Result<Option<i64>, Error> {
+        pg::create_attested_message(
+            conn,
+            sender_id,
+            recipient_id,
+            encrypted_content,
+            signature,
+            parent_id,
+            commitment,
+            ephemeral_key_proof,
+        )
+        .await
+    }
+
+    async fn replace_message_body(
+        conn: &mut PgConnection,
+        message_id: i64,
+        expected_content: &str,
+        encrypted_content: &str,
+        signature: Option<&str>,
+    ) -> Result<bool, Error> {
+        pg::replace_message_body(
+            conn,
+            message_id,
+            expected_content,
+            encrypted_content,
+            signature,
+        )
+        .await
+    }
+
+    async fn get_message(
+        conn: &mut PgConnection,
+        message_id: i64,
+    ) -> Result<Option<Message>, Error> {
+        pg::get_message(conn, message_id).await
+    }
+
+    async fn get_message_commitment(
+        conn: &mut PgConnection,
+        message_id: i64,
+    ) -> Result<Option<String>, Error> {
+        pg::get_message_commitment(conn, message_id).await
+    }
+
+    async fn get_ephemeral_key_proof(
+        conn: &mut PgConnection,
+        message_id: i64,
+    ) -> Result<Option<String>, Error> {
+        pg::get_ephemeral_key_proof(conn, message_id).await
+    }
+
+    async fn fetch_message_key(
+        conn: &mut PgConnection,
+        user_id: Uuid,
+    ) -> Result<Option<String>, Error> {
+        pg::fetch_message_key(conn, user_id).await
+    }
+
+    async fn create_anonymous_message(
+        conn: &mut PgConnection,
+        recipient_id: Uuid,
+        encrypted_content: &str,
+        membership_root: &str,
+        share: &RateLimitShare,
+    ) -> Result<i64, Error> {
+        pg::create_anonymous_message(
+            conn,
+            recipient_id,
+            encrypted_content,
+            membership_root,
+            share,
+        )
+        .await
+    }
+
+    async fn replace_anonymous_message_content(
+        conn: &mut PgConnection,
+        message_id: i64,
+        expected_content: &str,
+        encrypted_content: &str,
+    ) -> Result<bool, Error> {
+        pg::replace_anonymous_message_content(
+            conn,
+            message_id,
+            expected_content,
+            encrypted_content,
+        )
+        .await
+    }
+
+    async fn get_rate_limit_share(
+        conn: &mut PgConnection,
+        nullifier: &str,
+    ) -> Result<Option<RateLimitShare>, Error> {
+        pg::get_rate_limit_share(conn, nullifier).await
+    }
+
+    async fn block_identity(
+        conn: &mut PgConnection,
+        identity_commitment: &str,
+        identity_secret: &str,
+    ) -> Result<(), Error> {
+        pg::block_identity(conn, identity_commitment, identity_secret).await
+    }
+
+    async fn get_blocked_identity_secrets(conn: &mut PgConnection) -> Result<Vec<String>, Error> {
+        pg::get_blocked_identity_secrets(conn).await
+    }
+
+    async fn get_anonymous_messages(
+        conn: &mut PgConnection,
+        recipient_id: Uuid,
+        limit: i64,
+    ) -> Result<Vec<AnonymousMessage>, Error> {
+        pg::get_anonymous_messages(conn, recipient_id, limit).await
+    }
+
+    async fn mark_message_read(conn: &mut PgConnection, message_id: i64) -> Result<(), Error> {
+        pg::mark_message_read(conn, message_id).await
+    }
+
+    async fn get_conversation(
+        conn: &mut PgConnection,
+        user1_id: Uuid,
+        user2_id: Uuid,
+        limit: Option<i64>,
+    ) -> Result<Vec<Message>, Error> {
+        pg::get_conversation(conn, user1_id, user2_id, limit).await
+    }
+
+    async fn get_unread_messages(
+        conn: &mut PgConnection,
+        user_id: Uuid,
+    ) -> Result<Vec<Message>, Error> {
+        pg::get_unread_messages(conn, user_id).await
+    }
+
+    async fn get_thread_replies(
+        conn: &mut PgConnection,
+        parent_id: i64,
+        limit: Option<i64>,
+        offset: Option<i64>,
+    ) -> Result<Vec<Message>, Error> {
+        pg::get_thread_replies(conn, parent_id, limit, offset).await
+    }
+
+    async fn get_complete_thread(
+        conn: &mut PgConnection,
+        thread_root_id: i64,
+        limit: Option<i64>,
+    ) -> Result<Vec<Message>, Error> {
+        pg::get_complete_thread(conn, thread_root_id, limit).await
+    }
+
+    async fn get_user_threads(
+        conn: &mut PgConnection,
+        user_id: Uuid,
+        limit: Option<i64>,
+    ) -> Result<Vec<Message>, Error> {
+        pg::get_user_threads(conn, user_id, limit).await
     }
 }
@@ -29,6 +29,99 @@ pub struct User {
     pub updated_at: NaiveDateTime,
 }
 
+/// A contact whose safety number a user has compared out-of-band, pinned to the
+/// contact's public key hash at the time of verification.
+#[serde_as]
+#[derive(Debug, FromRow, Serialize, Deserialize, Clone, ToSchema)]
+pub struct VerifiedContact {
+    #[serde(with = "hyphenated_uuid")]
+    pub user_id: Uuid,
+    #[serde(with = "hyphenated_uuid")]
+    pub contact_id: Uuid,
+    pub public_key_hash: PublicKeyHash,
+    #[serde_as(as = "TimestampSecondsWithFrac<String>")]
+    pub verified_at: NaiveDateTime,
+}
+
+/// A leaf of the anonymity set, at its position in the membership tree.
+#[derive(Debug, FromRow, Serialize, Deserialize, Clone, ToSchema)]
+pub struct MembershipLeaf {
+    pub leaf_index: i64,
+    #[serde(with = "hyphenated_uuid")]
+    pub user_id: Uuid,
+    pub leaf: String,
+}
+
+/// A message whose sender proved membership of the anonymity set instead of
+/// identifying themselves. The nullifier is unique per sender, recipient and
+/// epoch.
+#[serde_as]
+#[derive(Debug, FromRow, Serialize, Deserialize, Clone, ToSchema)]
+pub struct AnonymousMessage {
+    pub id: i64,
+    #[serde(with = "uuid::serde::simple")]
+    pub recipient_id: Uuid,
+    pub encrypted_content: String,
+    pub nullifier: String,
+    pub membership_root: String,
+    #[serde_as(as = "TimestampSecondsWithFrac<String>")]
+    pub created_at: NaiveDateTime,
+}
+
+/// The share an anonymous message revealed, filed under its per-epoch
+/// nullifier.
+#[derive(Debug, FromRow, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RateLimitShare {
+    pub nullifier: String,
+    pub epoch: i64,
+    pub share_x: String,
+    pub share_y: String,
+}
+
+/// A user's X3DH identity key and current signed prekey, with the signature
+/// their account key made over both.
+#[serde_as]
+#[derive(Debug, FromRow, Serialize, Deserialize, Clone, PartialEq, ToSchema)]
+pub struct SignedPrekey {
+    #[serde(with = "hyphenated_uuid")]
+    pub user_id: Uuid,
+    pub identity_key: String,
+    pub prekey_id: i64,
+    pub prekey: String,
+    pub signature: String,
+    #[serde_as(as = "TimestampSecondsWithFrac<String>")]
+    pub updated_at: NaiveDateTime,
+}
+
+/// A user's key backup as stored: the client's sealed key and recovery key,
+/// each encrypted again with the server's key.
+#[serde_as]
+#[derive(Debug, FromRow, Serialize, Deserialize, Clone, PartialEq, ToSchema)]
+pub struct KeyBackup {
+    #[serde(with = "hyphenated_uuid")]
+    pub user_id: Uuid,
+    pub version: i64,
+    pub encrypted_key: String,
+    pub salt: String,
+    pub kdf_iterations: i64,
+    pub recovery_key: String,
+    pub failed_attempts: i64,
+    /// Recovery-key signatures are refused until then, after failed attempts
+    #[serde_as(as = "Option<TimestampSecondsWithFrac<String>>")]
+    pub recovery_retry_at: Option<NaiveDateTime>,
+    #[serde_as(as = "TimestampSecondsWithFrac<String>")]
+    pub updated_at: NaiveDateTime,
+}
+
+/// One of a user's one-time prekeys, with the signature their account key
+/// made over it.
+#[derive(Debug, FromRow, Serialize, Deserialize, Clone, PartialEq, ToSchema)]
+pub struct OneTimePrekey {
+    pub prekey_id: i64,
+    pub prekey: String,
+    pub signature: String,
+}
+
 #[derive(Debug, FromRow, Serialize, Deserialize)]
 pub struct RawMessage {
     pub id: i64,
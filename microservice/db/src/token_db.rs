@@ -1,7 +1,9 @@
 use async_trait::async_trait;
 use sqlx::Error;
 use uuid::Uuid;
-use crate::db::{self, SqliteDb};
+use crate::db::{self, SqliteDb, SqliteTx};
+#[cfg(feature = "postgres")]
+use crate::pg::{self, PgDb, PgTx};
 use mockall::{automock};
 
 #[automock]
@@ -27,6 +29,12 @@ pub trait TokenDb {
         reason: Option<String>,
     ) -> Result<(), Error>;
 
+    async fn revoke_user_refresh_tokens(
+        &self,
+        user_id: Uuid,
+        reason: Option<String>,
+    ) -> Result<u64, Error>;
+
     async fn cleanup_expired_tokens(&self) -> Result<u64, Error>;
 }
 
@@ -55,10 +63,143 @@ impl TokenDb for SqliteDb {
         token_hash: &str,
         reason: Option<String>,
     ) -> Result<(), Error> {
-        db::revoke_refresh_token(&self.pool, token_hash, reason.as_deref()).await
+        db::revoke_refresh_token(&mut *self.pool.acquire().await?, token_hash, reason.as_deref()).await
+    }
+
+    async fn revoke_user_refresh_tokens(
+        &self,
+        user_id: Uuid,
+        reason: Option<String>,
+    ) -> Result<u64, Error> {
+        db::revoke_user_refresh_tokens(&mut *self.pool.acquire().await?, user_id, reason.as_deref()).await
     }
 
     async fn cleanup_expired_tokens(&self) -> Result<u64, Error> {
         db::cleanup_expired_tokens(&self.pool).await
     }
 }
+
+#[async_trait]
+impl TokenDb for SqliteTx {
+    async fn store_refresh_token(
+        &self,
+        user_id: Uuid,
+        token_hash: &str,
+        expires_at: i64,
+        device_info: Option<String>,
+    ) -> Result<(), Error> {
+        db::store_refresh_token(&mut **self.tx.lock().await, user_id, token_hash, expires_at, device_info.as_deref()).await
+    }
+
+    async fn validate_refresh_token(
+        &self,
+        user_id: Uuid,
+        token_hash: &str,
+    ) -> Result<bool, Error> {
+        db::validate_refresh_token(&mut **self.tx.lock().await, user_id, token_hash).await
+    }
+
+    async fn revoke_refresh_token(
+        &self,
+        token_hash: &str,
+        reason: Option<String>,
+    ) -> Result<(), Error> {
+        db::revoke_refresh_token(&mut **self.tx.lock().await, token_hash, reason.as_deref()).await
+    }
+
+    async fn revoke_user_refresh_tokens(
+        &self,
+        user_id: Uuid,
+        reason: Option<String>,
+    ) -> Result<u64, Error> {
+        db::revoke_user_refresh_tokens(&mut **self.tx.lock().await, user_id, reason.as_deref()).await
+    }
+
+    async fn cleanup_expired_tokens(&self) -> Result<u64, Error> {
+        db::cleanup_expired_tokens(&mut **self.tx.lock().await).await
+    }
+}
+
+#[cfg(feature = "postgres")]
+#[async_trait]
+impl TokenDb for PgDb {
+    async fn store_refresh_token(
+        &self,
+        user_id: Uuid,
+        token_hash: &str,
+        expires_at: i64,
+        device_info: Option<String>,
+    ) -> Result<(), Error> {
+        pg::store_refresh_token(&self.pool, user_id, token_hash, expires_at, device_info.as_deref()).await
+    }
+
+    async fn validate_refresh_token(
+        &self,
+        user_id: Uuid,
+        token_hash: &str,
+    ) -> Result<bool, Error> {
+        pg::validate_refresh_token(&self.pool, user_id, token_hash).await
+    }
+
+    async fn revoke_refresh_token(
+        &self,
+        token_hash: &str,
+        reason: Option<String>,
+    ) -> Result<(), Error> {
+        pg::revoke_refresh_token(&mut *self.pool.acquire().await?, token_hash, reason.as_deref()).await
+    }
+
+    async fn revoke_user_refresh_tokens(
+        &self,
+        user_id: Uuid,
+        reason: Option<String>,
+    ) -> Result<u64, Error> {
+        pg::revoke_user_refresh_tokens(&mut *self.pool.acquire().await?, user_id, reason.as_deref()).await
+    }
+
+    async fn cleanup_expired_tokens(&self) -> Result<u64, Error> {
+        pg::cleanup_expired_tokens(&self.pool).await
+    }
+}
+
+#[cfg(feature = "postgres")]
+#[async_trait]
+impl TokenDb for PgTx {
+    async fn store_refresh_token(
+        &self,
+        user_id: Uuid,
+        token_hash: &str,
+        expires_at: i64,
+        device_info: Option<String>,
+    ) -> Result<(), Error> {
+        pg::store_refresh_token(&mut **self.tx.lock().await, user_id, token_hash, expires_at, device_info.as_deref()).await
+    }
+
+    async fn validate_refresh_token(
+        &self,
+        user_id: Uuid,
+        token_hash: &str,
+    ) -> Result<bool, Error> {
+        pg::validate_refresh_token(&mut **self.tx.lock().await, user_id, token_hash).await
+    }
+
+    async fn revoke_refresh_token(
+        &self,
+        token_hash: &str,
+        reason: Option<String>,
+    ) -> Result<(), Error> {
+        pg::revoke_refresh_token(&mut **self.tx.lock().await, token_hash, reason.as_deref()).await
+    }
+
+    async fn revoke_user_refresh_tokens(
+        &self,
+        user_id: Uuid,
+        reason: Option<String>,
+    ) -> Result<u64, Error> {
+        pg::revoke_user_refresh_tokens(&mut **self.tx.lock().await, user_id, reason.as_deref()).await
+    }
+
+    async fn cleanup_expired_tokens(&self) -> Result<u64, Error> {
+        pg::cleanup_expired_tokens(&mut **self.tx.lock().await).await
+    }
+}
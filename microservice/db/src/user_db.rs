@@ -1,8 +1,11 @@
-use crate::{models::User, public_key::PublicKey, public_key_hash::PublicKeyHash};
+use crate::{models::{KeyBackup, MembershipLeaf, OneTimePrekey, SignedPrekey, User, VerifiedContact}, public_key::PublicKey, public_key_hash::PublicKeyHash};
 use async_trait::async_trait;
+use chrono::NaiveDateTime;
 use sqlx::Error;
 use uuid::Uuid;
-use crate::db::{self, SqliteDb};
+use crate::db::{self, SqliteDb, SqliteTx};
+#[cfg(feature = "postgres")]
+use crate::pg::{self, PgDb, PgTx};
 use mockall::{automock};
 
 #[automock]
@@ -30,6 +33,93 @@ pub trait UserDb {
     ) -> Result<(), Error>;
 
     async fn fetch_public_key_hash(&self, user_id: Uuid) -> Result<String, Error>;
+
+    async fn mark_contact_verified(
+        &self,
+        user_id: Uuid,
+        contact_id: Uuid,
+        public_key_hash: &PublicKeyHash,
+    ) -> Result<(), Error>;
+
+    async fn get_verified_contact(
+        &self,
+        user_id: Uuid,
+        contact_id: Uuid,
+    ) -> Result<Option<VerifiedContact>, Error>;
+
+    async fn insert_membership_leaf(&self, user_id: Uuid, leaf: &str) -> Result<i64, Error>;
+
+    /// Registers the Jubjub key messages to the user are sealed to, replacing
+    /// any earlier one.
+    async fn set_message_key(&self, user_id: Uuid, message_key: &str) -> Result<(), Error>;
+
+    async fn get_membership_leaves(&self, after_index: i64) -> Result<Vec<MembershipLeaf>, Error>;
+
+    async fn upsert_signed_prekey(
+        &self,
+        user_id: Uuid,
+        identity_key: &str,
+        prekey_id: i64,
+        prekey: &str,
+        signature: &str,
+    ) -> Result<bool, Error>;
+
+    async fn get_signed_prekey(&self, user_id: Uuid) -> Result<Option<SignedPrekey>, Error>;
+
+    async fn insert_one_time_prekeys(
+        &self,
+        user_id: Uuid,
+        prekeys: &[OneTimePrekey],
+    ) -> Result<u64, Error>;
+
+    async fn claim_one_time_prekey(&self, user_id: Uuid) -> Result<Option<OneTimePrekey>, Error>;
+
+    async fn count_one_time_prekeys(&self, user_id: Uuid) -> Result<i64, Error>;
+
+    async fn upsert_key_backup(
+        &self,
+        user_id: Uuid,
+        version: i64,
+        encrypted_key: &str,
+        salt: &str,
+        kdf_iterations: i64,
+        recovery_key: &str,
+    ) -> Result<bool, Error>;
+
+    async fn get_key_backup(&self, user_id: Uuid) -> Result<Option<KeyBackup>, Error>;
+
+    async fn set_key_backup_challenge(
+        &self,
+        user_id: Uuid,
+        challenge: &str,
+        now: NaiveDateTime,
+        expires_at: NaiveDateTime,
+        max_pending: i64,
+    ) -> Result<bool, Error>;
+
+    async fn take_key_backup_challenge(
+        &self,
+        user_id: Uuid,
+        challenge: &str,
+        now: NaiveDateTime,
+        ignore_backoff: bool,
+    ) -> Result<Option<KeyBackup>, Error>;
+
+    async fn rewrap_key_backup(
+        &self,
+        user_id: Uuid,
+        version: i64,
+        encrypted_key: &str,
+        recovery_key: &str,
+    ) -> Result<bool, Error>;
+
+    async fn record_failed_key_recovery(&self, user_id: Uuid) -> Result<Option<i64>, Error>;
+
+    async fn hold_back_key_recovery(
+        &self,
+        user_id: Uuid,
+        retry_at: NaiveDateTime,
+    ) -> Result<(), Error>;
 }
 
 #[async_trait]
@@ -68,4 +158,656 @@ impl UserDb for SqliteDb {
     async fn fetch_public_key_hash(&self, user_id: Uuid) -> Result<String, Error> {
         db::fetch_public_key_hash(&self.pool, user_id).await
     }
+
+    async fn mark_contact_verified(
+        &self,
+        user_id: Uuid,
+        contact_id: Uuid,
+        public_key_hash: &PublicKeyHash,
+    ) -> Result<(), Error> {
+        db::mark_contact_verified(&self.pool, user_id, contact_id, public_key_hash).await
+    }
+
+    async fn get_verified_contact(
+        &self,
+        user_id: Uuid,
+        contact_id: Uuid,
+    ) -> Result<Option<VerifiedContact>, Error> {
+        db::get_verified_contact(&self.pool, user_id, contact_id).await
+    }
+
+    async fn insert_membership_leaf(&self, user_id: Uuid, leaf: &str) -> Result<i64, Error> {
+        db::insert_membership_leaf(&self.pool, user_id, leaf).await
+    }
+
+    async fn set_message_key(&self, user_id: Uuid, message_key: &str) -> Result<(), Error> {
+        db::set_message_key(&self.pool, user_id, message_key).await
+    }
+
+    async fn get_membership_leaves(&self, after_index: i64) -> Result<Vec<MembershipLeaf>, Error> {
+        db::get_membership_leaves(&self.pool, after_index).await
+    }
+
+    async fn upsert_signed_prekey(
+        &self,
+        user_id: Uuid,
+        identity_key: &str,
+        prekey_id: i64,
+        prekey: &str,
+        signature: &str,
+    ) -> Result<bool, Error> {
+        db::upsert_signed_prekey(&self.pool, user_id, identity_key, prekey_id, prekey, signature).await
+    }
+
+    async fn get_signed_prekey(&self, user_id: Uuid) -> Result<Option<SignedPrekey>, Error> {
+        db::get_signed_prekey(&self.pool, user_id).await
+    }
+
+    async fn insert_one_time_prekeys(
+        &self,
+        user_id: Uuid,
+        prekeys: &[OneTimePrekey],
+    ) -> Result<u64, Error> {
+        db::insert_one_time_prekeys(&self.pool, user_id, prekeys).await
+    }
+
+    async fn claim_one_time_prekey(&self, user_id: Uuid) -> Result<Option<OneTimePrekey>, Error> {
+        db::claim_one_time_prekey(&self.pool, user_id).await
+    }
+
+    async fn count_one_time_prekeys(&self, user_id: Uuid) -> Result<i64, Error> {
+        db::count_one_time_prekeys(&self.pool, user_id).await
+    }
+
+    async fn upsert_key_backup(
+        &self,
+        user_id: Uuid,
+        version: i64,
+        encrypted_key: &str,
+        salt: &str,
+        kdf_iterations: i64,
+        recovery_key: &str,
+    ) -> Result<bool, Error> {
+        db::upsert_key_backup(&self.pool, user_id, version, encrypted_key, salt, kdf_iterations, recovery_key).await
+    }
+
+    async fn get_key_backup(&self, user_id: Uuid) -> Result<Option<KeyBackup>, Error> {
+        db::get_key_backup(&self.pool, user_id).await
+    }
+
+    async fn set_key_backup_challenge(
+        &self,
+        user_id: Uuid,
+        challenge: &str,
+        now: NaiveDateTime,
+        expires_at: NaiveDateTime,
+        max_pending: i64,
+    ) -> Result<bool, Error> {
+        db::set_key_backup_challenge(
+            &mut *self.pool.acquire().await?,
+            user_id,
+            challenge,
+            now,
+            expires_at,
+            max_pending,
+        )
+        .await
+    }
+
+    async fn take_key_backup_challenge(
+        &self,
+        user_id: Uuid,
+        challenge: &str,
+        now: NaiveDateTime,
+        ignore_backoff: bool,
+    ) -> Result<Option<KeyBackup>, Error> {
+        db::take_key_backup_challenge(
+            &mut *self.pool.acquire().await?,
+            user_id,
+            challenge,
+            now,
+            ignore_backoff,
+        )
+        .await
+    }
+
+    async fn rewrap_key_backup(
+        &self,
+        user_id: Uuid,
+        version: i64,
+        encrypted_key: &str,
+        recovery_key: &str,
+    ) -> Result<bool, Error> {
+        db::rewrap_key_backup(&self.pool, user_id, version, encrypted_key, recovery_key).await
+    }
+
+    async fn record_failed_key_recovery(&self, user_id: Uuid) -> Result<Option<i64>, Error> {
+        db::record_failed_key_recovery(&self.pool, user_id).await
+    }
+
+    async fn hold_back_key_recovery(
+        &self,
+        user_id: Uuid,
+        retry_at: NaiveDateTime,
+    ) -> Result<(), Error> {
+        db::hold_back_key_recovery(&self.pool, user_id, retry_at).await
+    }
+}
+
+#[async_trait]
+impl UserDb for SqliteTx {
+    async fn insert_user(
+        &self,
+        public_key_hash: &PublicKeyHash,
+        public_key: &PublicKey,
+        username: &str,
+    ) -> Result<Uuid, Error> {
+        db::insert_user(&mut **self.tx.lock().await, public_key_hash, public_key, username).await
+    }
+
+    async fn get_user_by_id(&self, user_id: Uuid) -> Result<User, Error> {
+        db::get_user_by_id(&mut **self.tx.lock().await, user_id).await
+    }
+
+    async fn get_user_by_pubkey(&self, pubkey_hash: &PublicKeyHash) -> Result<User, Error> {
+        db::get_user_by_pubkey(&mut **self.tx.lock().await, pubkey_hash).await
+    }
+
+    async fn get_users(&self, limit: Option<i64>) -> Result<Vec<User>, Error> {
+        db::get_users(&mut **self.tx.lock().await, limit).await
+    }
+
+    async fn update_user<'a>(
+        &self,
+        user_id: Uuid,
+        new_username: Option<&'a str>,
+        new_public_key: Option<&'a PublicKey>,
+        new_public_key_hash: Option<&'a PublicKeyHash>,
+    ) -> Result<(), Error> {
+        db::update_user(&mut **self.tx.lock().await, user_id, new_username, new_public_key, new_public_key_hash).await
+    }
+
+    async fn fetch_public_key_hash(&self, user_id: Uuid) -> Result<String, Error> {
+        db::fetch_public_key_hash(&mut **self.tx.lock().await, user_id).await
+    }
+
+    async fn mark_contact_verified(
+        &self,
+        user_id: Uuid,
+        contact_id: Uuid,
+        public_key_hash: &PublicKeyHash,
+    ) -> Result<(), Error> {
+        db::mark_contact_verified(&mut **self.tx.lock().await, user_id, contact_id, public_key_hash).await
+    }
+
+    async fn get_verified_contact(
+        &self,
+        user_id: Uuid,
+        contact_id: Uuid,
+    ) -> Result<Option<VerifiedContact>, Error> {
+        db::get_verified_contact(&mut **self.tx.lock().await, user_id, contact_id).await
+    }
+
+    async fn insert_membership_leaf(&self, user_id: Uuid, leaf: &str) -> Result<i64, Error> {
+        db::insert_membership_leaf(&mut **self.tx.lock().await, user_id, leaf).await
+    }
+
+    async fn set_message_key(&self, user_id: Uuid, message_key: &str) -> Result<(), Error> {
+        db::set_message_key(&mut **self.tx.lock().await, user_id, message_key).await
+    }
+
+    async fn get_membership_leaves(&self, after_index: i64) -> Result<Vec<MembershipLeaf>, Error> {
+        db::get_membership_leaves(&mut **self.tx.lock().await, after_index).await
+    }
+
+    async fn upsert_signed_prekey(
+        &self,
+        user_id: Uuid,
+        identity_key: &str,
+        prekey_id: i64,
+        prekey: &str,
+        signature: &str,
+    ) -> Result<bool, Error> {
+        db::upsert_signed_prekey(&mut **self.tx.lock().await, user_id, identity_key, prekey_id, prekey, signature).await
+    }
+
+    async fn get_signed_prekey(&self, user_id: Uuid) -> Result<Option<SignedPrekey>, Error> {
+        db::get_signed_prekey(&mut **self.tx.lock().await, user_id).await
+    }
+
+    async fn insert_one_time_prekeys(
+        &self,
+        user_id: Uuid,
+        prekeys: &[OneTimePrekey],
+    ) -> Result<u64, Error> {
+        db::insert_one_time_prekeys(&mut **self.tx.lock().await, user_id, prekeys).await
+    }
+
+    async fn claim_one_time_prekey(&self, user_id: Uuid) -> Result<Option<OneTimePrekey>, Error> {
+        db::claim_one_time_prekey(&mut **self.tx.lock().await, user_id).await
+    }
+
+    async fn count_one_time_prekeys(&self, user_id: Uuid) -> Result<i64, Error> {
+        db::count_one_time_prekeys(&mut **self.tx.lock().await, user_id).await
+    }
+
+    async fn upsert_key_backup(
+        &self,
+        user_id: Uuid,
+        version: i64,
+        encrypted_key: &str,
+        salt: &str,
+        kdf_iterations: i64,
+        recovery_key: &str,
+    ) -> Result<bool, Error> {
+        db::upsert_key_backup(&mut **self.tx.lock().await, user_id, version, encrypted_key, salt, kdf_iterations, recovery_key).await
+    }
+
+    async fn get_key_backup(&self, user_id: Uuid) -> Result<Option<KeyBackup>, Error> {
+        db::get_key_backup(&mut **self.tx.lock().await, user_id).await
+    }
+
+    async fn set_key_backup_challenge(
+        &self,
+        user_id: Uuid,
+        challenge: &str,
+        now: NaiveDateTime,
+        expires_at: NaiveDateTime,
+        max_pending: i64,
+    ) -> Result<bool, Error> {
+        db::set_key_backup_challenge(
+            &mut **self.tx.lock().await,
+            user_id,
+            challenge,
+            now,
+            expires_at,
+            max_pending,
+        )
+        .await
+    }
+
+    async fn take_key_backup_challenge(
+        &self,
+        user_id: Uuid,
+        challenge: &str,
+        now: NaiveDateTime,
+        ignore_backoff: bool,
+    ) -> Result<Option<KeyBackup>, Error> {
+        db::take_key_backup_challenge(
+            &mut **self.tx.lock().await,
+            user_id,
+            challenge,
+            now,
+            ignore_backoff,
+        )
+        .await
+    }
+
+    async fn rewrap_key_backup(
+        &self,
+        user_id: Uuid,
+        version: i64,
+        encrypted_key: &str,
+        recovery_key: &str,
+    ) -> Result<bool, Error> {
+        db::rewrap_key_backup(&mut **self.tx.lock().await, user_id, version, encrypted_key, recovery_key).await
+    }
+
+    async fn record_failed_key_recovery(&self, user_id: Uuid) -> Result<Option<i64>, Error> {
+        db::record_failed_key_recovery(&mut **self.tx.lock().await, user_id).await
+    }
+
+    async fn hold_back_key_recovery(
+        &self,
+        user_id: Uuid,
+        retry_at: NaiveDateTime,
+    ) -> Result<(), Error> {
+        db::hold_back_key_recovery(&mut **self.tx.lock().await, user_id, retry_at).await
+    }
+}
+
+#[cfg(feature = "postgres")]
+#[async_trait]
+impl UserDb for PgDb {
+    async fn insert_user(
+        &self,
+        public_key_hash: &PublicKeyHash,
+        public_key: &PublicKey,
+        username: &str,
+    ) -> Result<Uuid, Error> {
+        pg::insert_user(&self.pool, public_key_hash, public_key, username).await
+    }
+
+    async fn get_user_by_id(&self, user_id: Uuid) -> Result<User, Error> {
+        pg::get_user_by_id(&self.pool, user_id).await
+    }
+
+    async fn get_user_by_pubkey(&self, pubkey_hash: &PublicKeyHash) -> Result<User, Error> {
+        pg::get_user_by_pubkey(&self.pool, pubkey_hash).await
+    }
+
+    async fn get_users(&self, limit: Option<i64>) -> Result<Vec<User>, Error> {
+        pg::get_users(&self.pool, limit).await
+    }
+
+    async fn update_user<'a>(
+        &self,
+        user_id: Uuid,
+        new_username: Option<&'a str>,
+        new_public_key: Option<&'a PublicKey>,
+        new_public_key_hash: Option<&'a PublicKeyHash>,
+    ) -> Result<(), Error> {
+        pg::update_user(&self.pool, user_id, new_username, new_public_key, new_public_key_hash).await
+    }
+
+    async fn fetch_public_key_hash(&self, user_id: Uuid) -> Result<String, Error> {
+        pg::fetch_public_key_hash(&self.pool, user_id).await
+    }
+
+    async fn mark_contact_verified(
+        &self,
+        user_id: Uuid,
+        contact_id: Uuid,
+        public_key_hash: &PublicKeyHash,
+    ) -> Result<(), Error> {
+        pg::mark_contact_verified(&self.pool, user_id, contact_id, public_key_hash).await
+    }
+
+    async fn get_verified_contact(
+        &self,
+        user_id: Uuid,
+        contact_id: Uuid,
+    ) -> Result<Option<VerifiedContact>, Error> {
+        pg::get_verified_contact(&self.pool, user_id, contact_id).await
+    }
+
+    async fn insert_membership_leaf(&self, user_id: Uuid, leaf: &str) -> Result<i64, Error> {
+        pg::insert_membership_leaf(&self.pool, user_id, leaf).await
+    }
+
+    async fn set_message_key(&self, user_id: Uuid, message_key: &str) -> Result<(), Error> {
+        pg::set_message_key(&self.pool, user_id, message_key).await
+    }
+
+    async fn get_membership_leaves(&self, after_index: i64) -> Result<Vec<MembershipLeaf>, Error> {
+        pg::get_membership_leaves(&self.pool, after_index).await
+    }
+
+    async fn upsert_signed_prekey(
+        &self,
+        user_id: Uuid,
+        identity_key: &str,
+        prekey_id: i64,
+        prekey: &str,
+        signature: &str,
+    ) -> Result<bool, Error> {
+        pg::upsert_signed_prekey(&self.pool, user_id, identity_key, prekey_id, prekey, signature).await
+    }
+
+    async fn get_signed_prekey(&self, user_id: Uuid) -> Result<Option<SignedPrekey>, Error> {
+        pg::get_signed_prekey(&self.pool, user_id).await
+    }
+
+    async fn insert_one_time_prekeys(
+        &self,
+        user_id: Uuid,
+        prekeys: &[OneTimePrekey],
+    ) -> Result<u64, Error> {
+        pg::insert_one_time_prekeys(&self.pool, user_id, prekeys).await
+    }
+
+    async fn claim_one_time_prekey(&self, user_id: Uuid) -> Result<Option<OneTimePrekey>, Error> {
+        pg::claim_one_time_prekey(&self.pool, user_id).await
+    }
+
+    async fn count_one_time_prekeys(&self, user_id: Uuid) -> Result<i64, Error> {
+        pg::count_one_time_prekeys(&self.pool, user_id).await
+    }
+
+    async fn upsert_key_backup(
+        &self,
+        user_id: Uuid,
+        version: i64,
+        encrypted_key: &str,
+        salt: &str,
+        kdf_iterations: i64,
+        recovery_key: &str,
+    ) -> Result<bool, Error> {
+        pg::upsert_key_backup(&self.pool, user_id, version, encrypted_key, salt, kdf_iterations, recovery_key).await
+    }
+
+    async fn get_key_backup(&self, user_id: Uuid) -> Result<Option<KeyBackup>, Error> {
+        pg::get_key_backup(&self.pool, user_id).await
+    }
+
+    async fn set_key_backup_challenge(
+        &self,
+        user_id: Uuid,
+        challenge: &str,
+        now: NaiveDateTime,
+        expires_at: NaiveDateTime,
+        max_pending: i64,
+    ) -> Result<bool, Error> {
+        pg::set_key_backup_challenge(
+            &mut *self.pool.acquire().await?,
+            user_id,
+            challenge,
+            now,
+            expires_at,
+            max_pending,
+        )
+        .await
+    }
+
+    async fn take_key_backup_challenge(
+        &self,
+        user_id: Uuid,
+        challenge: &str,
+        now: NaiveDateTime,
+        ignore_backoff: bool,
+    ) -> Result<Option<KeyBackup>, Error> {
+        pg::take_key_backup_challenge(
+            &mut *self.pool.acquire().await?,
+            user_id,
+            challenge,
+            now,
+            ignore_backoff,
+        )
+        .await
+    }
+
+    async fn rewrap_key_backup(
+        &self,
+        user_id: Uuid,
+        version: i64,
+        encrypted_key: &str,
+        recovery_key: &str,
+    ) -> Result<bool, Error> {
+        pg::rewrap_key_backup(&self.pool, user_id, version, encrypted_key, recovery_key).await
+    }
+
+    async fn record_failed_key_recovery(&self, user_id: Uuid) -> Result<Option<i64>, Error> {
+        pg::record_failed_key_recovery(&self.pool, user_id).await
+    }
+
+    async fn hold_back_key_recovery(
+        &self,
+        user_id: Uuid,
+        retry_at: NaiveDateTime,
+    ) -> Result<(), Error> {
+        pg::hold_back_key_recovery(&self.pool, user_id, retry_at).await
+    }
+}
+
+#[cfg(feature = "postgres")]
+#[async_trait]
+impl UserDb for PgTx {
+    async fn insert_user(
+        &self,
+        public_key_hash: &PublicKeyHash,
+        public_key: &PublicKey,
+        username: &str,
+    ) -> Result<Uuid, Error> {
+        pg::insert_user(&mut **self.tx.lock().await, public_key_hash, public_key, username).await
+    }
+
+    async fn get_user_by_id(&self, user_id: Uuid) -> Result<User, Error> {
+        pg::get_user_by_id(&mut **self.tx.lock().await, user_id).await
+    }
+
+    async fn get_user_by_pubkey(&self, pubkey_hash: &PublicKeyHash) -> Result<User, Error> {
+        pg::get_user_by_pubkey(&mut **self.tx.lock().await, pubkey_hash).await
+    }
+
+    async fn get_users(&self, limit: Option<i64>) -> Result<Vec<User>, Error> {
+        pg::get_users(&mut **self.tx.lock().await, limit).await
+    }
+
+    async fn update_user<'a>(
+        &self,
+        user_id: Uuid,
+        new_username: Option<&'a str>,
+        new_public_key: Option<&'a PublicKey>,
+        new_public_key_hash: Option<&'a PublicKeyHash>,
+    ) -> Result<(), Error> {
+        pg::update_user(&mut **self.tx.lock().await, user_id, new_username, new_public_key, new_public_key_hash).await
+    }
+
+    async fn fetch_public_key_hash(&self, user_id: Uuid) -> Result<String, Error> {
+        pg::fetch_public_key_hash(&mut **self.tx.lock().await, user_id).await
+    }
+
+    async fn mark_contact_verified(
+        &self,
+        user_id: Uuid,
+        contact_id: Uuid,
+        public_key_hash: &PublicKeyHash,
+    ) -> Result<(), Error> {
+        pg::mark_contact_verified(&mut **self.tx.lock().await, user_id, contact_id, public_key_hash).await
+    }
+
+    async fn get_verified_contact(
+        &self,
+        user_id: Uuid,
+        contact_id: Uuid,
+    ) -> Result<Option<VerifiedContact>, Error> {
+        pg::get_verified_contact(&mut **self.tx.lock().await, user_id, contact_id).await
+    }
+
+    async fn insert_membership_leaf(&self, user_id: Uuid, leaf: &str) -> Result<i64, Error> {
+        pg::insert_membership_leaf(&mut **self.tx.lock().await, user_id, leaf).await
+    }
+
+    async fn set_message_key(&self, user_id: Uuid, message_key: &str) -> Result<(), Error> {
+        pg::set_message_key(&mut **self.tx.lock().await, user_id, message_key).await
+    }
+
+    async fn get_membership_leaves(&self, after_index: i64) -> Result<Vec<MembershipLeaf>, Error> {
+        pg::get_membership_leaves(&mut **self.tx.lock().await, after_index).await
+    }
+
+    async fn upsert_signed_prekey(
+        &self,
+        user_id: Uuid,
+        identity_key: &str,
+        prekey_id: i64,
+        prekey: &str,
+        signature: &str,
+    ) -> Result<bool, Error> {
+        pg::upsert_signed_prekey(&mut **self.tx.lock().await, user_id, identity_key, prekey_id, prekey, signature).await
+    }
+
+    async fn get_signed_prekey(&self, user_id: Uuid) -> Result<Option<SignedPrekey>, Error> {
+        pg::get_signed_prekey(&mut **self.tx.lock().await, user_id).await
+    }
+
+    async fn insert_one_time_prekeys(
+        &self,
+        user_id: Uuid,
+        prekeys: &[OneTimePrekey],
+    ) -> Result<u64, Error> {
+        pg::insert_one_time_prekeys(&mut **self.tx.lock().await, user_id, prekeys).await
+    }
+
+    async fn claim_one_time_prekey(&self, user_id: Uuid) -> Result<Option<OneTimePrekey>, Error> {
+        pg::claim_one_time_prekey(&mut **self.tx.lock().await, user_id).await
+    }
+
+    async fn count_one_time_prekeys(&self, user_id: Uuid) -> Result<i64, Error> {
+        pg::count_one_time_prekeys(&mut **self.tx.lock().await, user_id).await
+    }
+
+    async fn upsert_key_backup(
+        &self,
+        user_id: Uuid,
+        version: i64,
+        encrypted_key: &str,
+        salt: &str,
+        kdf_iterations: i64,
+        recovery_key: &str,
+    ) -> Result<bool, Error> {
+        pg::upsert_key_backup(&mut **self.tx.lock().await, user_id, version, encrypted_key, salt, kdf_iterations, recovery_key).await
+    }
+
+    async fn get_key_backup(&self, user_id: Uuid) -> Result<Option<KeyBackup>, Error> {
+        pg::get_key_backup(&mut **self.tx.lock().await, user_id).await
+    }
+
+    async fn set_key_backup_challenge(
+        &self,
+        user_id: Uuid,
+        challenge: &str,
+        now: NaiveDateTime,
+        expires_at: NaiveDateTime,
+        max_pending: i64,
+    ) -> Result<bool, Error> {
+        pg::set_key_backup_challenge(
+            &mut **self.tx.lock().await,
+            user_id,
+            challenge,
+            now,
+            expires_at,
+            max_pending,
+        )
+        .await
+    }
+
+    async fn take_key_backup_challenge(
+        &self,
+        user_id: Uuid,
+        challenge: &str,
+        now: NaiveDateTime,
+        ignore_backoff: bool,
+    ) -> Result<Option<KeyBackup>, Error> {
+        pg::take_key_backup_challenge(
+            &mut **self.tx.lock().await,
+            user_id,
+            challenge,
+            now,
+            ignore_backoff,
+        )
+        .await
+    }
+
+    async fn rewrap_key_backup(
+        &self,
+        user_id: Uuid,
+        version: i64,
+        encrypted_key: &str,
+        recovery_key: &str,
+    ) -> Result<bool, Error> {
+        pg::rewrap_key_backup(&mut **self.tx.lock().await, user_id, version, encrypted_key, recovery_key).await
+    }
+
+    async fn record_failed_key_recovery(&self, user_id: Uuid) -> Result<Option<i64>, Error> {
+        pg::record_failed_key_recovery(&mut **self.tx.lock().await, user_id).await
+    }
+
+    async fn hold_back_key_recovery(
+        &self,
+        user_id: Uuid,
+        retry_at: NaiveDateTime,
+    ) -> Result<(), Error> {
+        pg::hold_back_key_recovery(&mut **self.tx.lock().await, user_id, retry_at).await
+    }
 }
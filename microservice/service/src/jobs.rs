@@ -0,0 +1,117 @@
+//! Periodic background jobs. Each run's result is logged and counted in
+//! [`BACKGROUND_JOB_RUNS`], and the items it processed in
+//! [`BACKGROUND_JOB_ITEMS`].
+
+use shared::{
+    errors::AppError,
+    metrics::{BACKGROUND_JOB_ITEMS, BACKGROUND_JOB_RUNS},
+};
+use std::{future::Future, sync::Arc, time::Duration};
+use tokio::{
+    sync::Notify,
+    task::JoinHandle,
+    time::{self, MissedTickBehavior},
+};
+use tracing::{info_span, Instrument};
+
+/// A job started by [`spawn_periodic`].
+pub struct PeriodicJob {
+    stop: Arc<Notify>,
+    task: JoinHandle<()>,
+}
+
+impl PeriodicJob {
+    /// Stops the job, letting a run in progress finish first.
+    pub async fn stop(self) {
+        self.stop.notify_one();
+        if let Err(err) = self.task.await {
+            tracing::error!(error = %err, "background job panicked");
+        }
+    }
+}
+
+/// Runs `job` every `period`, the first time right away, until stopped. The
+/// job returns how many items it processed.
+pub fn spawn_periodic<F, Fut>(name: &'static str, period: Duration, mut job: F) -> PeriodicJob
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<u64, AppError>> + Send,
+{
+    let stop = Arc::new(Notify::new());
+    let task = tokio::spawn({
+        let stop = stop.clone();
+        async move {
+            let mut interval = time::interval(period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                // A stop requested during a run is remembered until here, and
+                // wins over a tick that is also due
+                tokio::select! {
+                    biased;
+                    _ = stop.notified() => return,
+                    _ = interval.tick() => run(name, job()).await,
+                }
+            }
+        }
+    });
+    PeriodicJob { stop, task }
+}
+
+/// Runs one job to completion and records its result.
+pub async fn run(name: &'static str, job: impl Future<Output = Result<u64, AppError>>) {
+    let result = job.instrument(info_span!("background_job", job = name)).await;
+    match result {
+        Ok(items) => {
+            tracing::info!(job = name, items, "background job finished");
+            BACKGROUND_JOB_RUNS.with_label_values(&[name, "ok"]).inc();
+            BACKGROUND_JOB_ITEMS.with_label_values(&[name]).inc_by(items);
+        }
+        Err(err) => {
+            tracing::error!(job = name, error = %err, "background job failed");
+            BACKGROUND_JOB_RUNS.with_label_values(&[name, "error"]).inc();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_runs_are_counted_by_result() {
+        run("test_ok", async { Ok(3) }).await;
+        run("test_error", async {
+            Err(AppError::InternalError(String::from("failed")))
+        })
+        .await;
+
+        assert_eq!(BACKGROUND_JOB_RUNS.with_label_values(&["test_ok", "ok"]).get(), 1);
+        assert_eq!(BACKGROUND_JOB_ITEMS.with_label_values(&["test_ok"]).get(), 3);
+        assert_eq!(
+            BACKGROUND_JOB_RUNS.with_label_values(&["test_error", "error"]).get(),
+            1
+        );
+        assert_eq!(BACKGROUND_JOB_ITEMS.with_label_values(&["test_error"]).get(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_stop_waits_for_the_run_in_progress() {
+        let (started_tx, started_rx) = tokio::sync::oneshot::channel();
+        let mut started_tx = Some(started_tx);
+        let job = spawn_periodic("test_stop", Duration::from_millis(1), move || {
+            let started = started_tx.take();
+            async move {
+                if let Some(started) = started {
+                    started.send(()).unwrap();
+                    time::sleep(Duration::from_millis(50)).await;
+                }
+                Ok(1)
+            }
+        });
+
+        started_rx.await.unwrap();
+        job.stop().await;
+        assert_eq!(BACKGROUND_JOB_ITEMS.with_label_values(&["test_stop"]).get(), 1);
+        assert_eq!(BACKGROUND_JOB_RUNS.with_label_values(&["test_stop", "ok"]).get(), 1);
+    }
+}
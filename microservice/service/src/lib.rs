@@ -1,5 +1,9 @@
+pub mod jobs;
+pub mod membership;
+pub mod memory;
 pub mod message;
 pub mod token;
+pub mod unit_of_work;
 pub mod user;
 
 pub use p256;
@@ -0,0 +1,339 @@
+//! The server's copy of the anonymity set: the Merkle tree over the leaves of
+//! every user who registered an identity commitment, and the roots it had
+//! recently.
+//!
+//! The leaves themselves live in the repository, which is the source of
+//! truth. The tree is rebuilt from it on startup and catches up with
+//! [`AnonymitySet::sync`] after each registration.
+//!
+//! Anonymous messages are rate-limited per epoch of [`EPOCH_SECONDS`]: each
+//! member may send one message per recipient per epoch, and a second one
+//! reveals their identity secret.
+
+use crate::{message::repository::MessageRepository, user::UserRepository};
+use base64::Engine;
+use chrono::Utc;
+use circuits::{
+    membership::{Fr, Identity, MembershipTree, epoch_scope, message_to_field},
+    prover,
+};
+use db::uuid::Uuid;
+use shared::{errors::AppError, models::CUSTOM_ENGINE};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+/// How many past roots proofs are still accepted against. A client's proof
+/// is built against the root it last fetched, so it must survive a few
+/// registrations landing before it is submitted.
+pub const ROOT_HISTORY: usize = 32;
+
+/// Length of a rate-limiting epoch.
+pub const EPOCH_SECONDS: u64 = 60;
+
+pub fn current_epoch() -> u64 {
+    Utc::now().timestamp().max(0) as u64 / EPOCH_SECONDS
+}
+
+/// The scope a message's nullifier and share are taken in: the epoch and the
+/// recipient, so the limit is one message per recipient per epoch.
+pub fn message_scope(epoch: u64, recipient_id: Uuid) -> Fr {
+    epoch_scope(epoch, recipient_id.as_bytes())
+}
+
+/// The `x` a message's share is evaluated at: the recipient id's bytes
+/// followed by the ciphertext as sent.
+pub fn message_x(recipient_id: Uuid, encrypted_content: &str) -> Fr {
+    message_to_field(&[recipient_id.as_bytes(), encrypted_content.as_bytes()].concat())
+}
+
+pub struct AnonymitySet {
+    state: RwLock<State>,
+}
+
+struct State {
+    tree: MembershipTree,
+    // Index of the last leaf inserted into `tree`
+    last_index: i64,
+    // Newest last
+    recent_roots: VecDeque<Fr>,
+}
+
+impl Default for AnonymitySet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AnonymitySet {
+    pub fn new() -> Self {
+        let tree = MembershipTree::new();
+        let recent_roots = VecDeque::from([tree.root()]);
+
+        Self {
+            state: RwLock::new(State {
+                tree,
+                last_index: 0,
+                recent_roots,
+            }),
+        }
+    }
+
+    /// Builds the set from every leaf stored in `repository`.
+    pub async fn load<R: UserRepository>(repository: &R) -> Result<Self, AppError> {
+        let set = Self::new();
+        set.sync(repository).await?;
+        Ok(set)
+    }
+
+    /// Appends the leaves stored since the last sync, in index order.
+    pub async fn sync<R: UserRepository>(&self, repository: &R) -> Result<(), AppError> {
+        let after_index = self.read().last_index;
+        let leaves = repository.get_membership_leaves(after_index).await?;
+
+        let mut state = self.write();
+        let mut changed = false;
+        for leaf in leaves {
+            // A concurrent sync may have inserted it while we were fetching
+            if leaf.leaf_index <= state.last_index {
+                continue;
+            }
+            state.tree.insert(decode_field(&leaf.leaf)?);
+            state.last_index = leaf.leaf_index;
+            changed = true;
+        }
+
+        if changed {
+            let root = state.tree.root();
+            state.recent_roots.push_back(root);
+            if state.recent_roots.len() > ROOT_HISTORY {
+                state.recent_roots.pop_front();
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn root(&self) -> Fr {
+        self.read().tree.root()
+    }
+
+    /// Whether `root` is the current root or one of the [`ROOT_HISTORY`]
+    /// before it.
+    pub fn is_recent_root(&self, root: &Fr) -> bool {
+        self.read().recent_roots.contains(root)
+    }
+
+    pub fn leaves(&self) -> Vec<Fr> {
+        self.read().tree.leaves().to_vec()
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, State> {
+        self.state
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, State> {
+        self.state
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// The identities blocked for exceeding the rate limit, and the nullifiers
+/// they would use in the scopes seen recently.
+///
+/// A blocked sender is recognised by their nullifier, which depends on the
+/// scope, so each blocked secret has to be hashed once per scope. The secrets
+/// are read from the repository on first use and kept, and the nullifiers of
+/// a scope are derived the first time it is seen and kept while its epoch is
+/// still accepted; checking a sender is then a set lookup.
+#[derive(Default)]
+pub struct BlockList {
+    state: tokio::sync::Mutex<Option<BlockedState>>,
+}
+
+struct BlockedState {
+    identities: Vec<Identity>,
+    // The epoch each scope belongs to, and the blocked nullifiers in it
+    scopes: HashMap<Fr, (u64, HashSet<Fr>)>,
+}
+
+impl BlockList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether `nullifier` belongs to a blocked identity in `scope`, which is
+    /// in `epoch`. Scopes from before `oldest_epoch` are forgotten.
+    pub async fn is_blocked<R: MessageRepository>(
+        &self,
+        repository: &R,
+        epoch: u64,
+        scope: Fr,
+        nullifier: &Fr,
+        oldest_epoch: u64,
+    ) -> Result<bool, AppError> {
+        let mut guard = self.state.lock().await;
+        let state = match &mut *guard {
+            Some(state) => state,
+            None => {
+                let identities = repository
+                    .get_blocked_identity_secrets()
+                    .await?
+                    .iter()
+                    .map(|secret| decode_field(secret).map(Identity::from_secret))
+                    .collect::<Result<_, _>>()?;
+                guard.insert(BlockedState {
+                    identities,
+                    scopes: HashMap::new(),
+                })
+            }
+        };
+        if state.identities.is_empty() {
+            return Ok(false);
+        }
+
+        state.scopes.retain(|_, (scope_epoch, _)| *scope_epoch >= oldest_epoch);
+        let identities = &state.identities;
+        let (_, nullifiers) = state.scopes.entry(scope).or_insert_with(|| {
+            let nullifiers = identities.iter().map(|identity| identity.nullifier(scope));
+            (epoch, nullifiers.collect())
+        });
+        Ok(nullifiers.contains(nullifier))
+    }
+
+    /// Adds `secret`, which has just been stored as blocked.
+    pub async fn insert(&self, secret: Fr) {
+        // Not loaded yet, so it will be read with the rest
+        let mut guard = self.state.lock().await;
+        let Some(state) = &mut *guard else {
+            return;
+        };
+
+        if state.identities.iter().any(|identity| identity.secret() == secret) {
+            return;
+        }
+        let identity = Identity::from_secret(secret);
+        for (scope, (_, nullifiers)) in &mut state.scopes {
+            nullifiers.insert(identity.nullifier(*scope));
+        }
+        state.identities.push(identity);
+    }
+}
+
+/// Encodes a field element the way clients send them.
+pub fn encode_field(value: &Fr) -> String {
+    CUSTOM_ENGINE.encode(prover::to_bytes(value).expect("serializing into a Vec cannot fail"))
+}
+
+/// Decodes a field element from its compressed [`CUSTOM_ENGINE`] encoding,
+/// rejecting non-canonical ones.
+pub fn decode_field(value: &str) -> Result<Fr, AppError> {
+    let bytes = CUSTOM_ENGINE
+        .decode(value)
+        .map_err(|_| AppError::InvalidInputSyntax)?;
+    prover::from_bytes(&bytes).map_err(|_| AppError::InvalidInputSyntax)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::InMemoryStore;
+    use circuits::membership::{leaf, public_key_hash_to_field};
+
+    async fn register(store: &InMemoryStore, n: u8) -> Fr {
+        let public_key = CUSTOM_ENGINE.encode([n; 33]);
+        let user_id = store
+            .insert_user(&public_key, &format!("member{n}"))
+            .await
+            .unwrap();
+        let leaf = leaf(public_key_hash_to_field(&[n]), Fr::from(u64::from(n)));
+        store
+            .insert_membership_leaf(user_id, &encode_field(&leaf))
+            .await
+            .unwrap();
+        leaf
+    }
+
+    #[tokio::test]
+    async fn sync_appends_new_leaves_and_remembers_roots() {
+        let store = InMemoryStore::new();
+        let first = register(&store, 1).await;
+
+        let set = AnonymitySet::load(&store).await.unwrap();
+        let first_root = set.root();
+        assert_eq!(set.leaves(), vec![first]);
+
+        let second = register(&store, 2).await;
+        set.sync(&store).await.unwrap();
+        set.sync(&store).await.unwrap();
+
+        assert_eq!(set.leaves(), vec![first, second]);
+        assert_eq!(set.root(), MembershipTree::from_leaves([first, second]).root());
+        assert!(set.is_recent_root(&first_root));
+        assert!(set.is_recent_root(&set.root()));
+        assert!(!set.is_recent_root(&Fr::from(5u64)));
+    }
+
+    #[tokio::test]
+    async fn old_roots_expire() {
+        let store = InMemoryStore::new();
+        let set = AnonymitySet::new();
+        let empty_root = set.root();
+
+        for n in 0..ROOT_HISTORY as u8 {
+            register(&store, n).await;
+            set.sync(&store).await.unwrap();
+        }
+
+        assert!(!set.is_recent_root(&empty_root));
+    }
+
+    #[tokio::test]
+    async fn block_list_matches_blocked_nullifiers_per_scope() {
+        let store = InMemoryStore::new();
+        let blocked = Identity::from_secret(Fr::from(1u64));
+        let later = Identity::from_secret(Fr::from(2u64));
+        store
+            .block_identity("blocked", &encode_field(&blocked.secret()))
+            .await
+            .unwrap();
+        let (scope, other_scope) = (message_scope(1, Uuid::nil()), message_scope(2, Uuid::nil()));
+
+        let list = BlockList::new();
+        let is_blocked = async |scope_epoch, scope, identity: &Identity| {
+            let nullifier = identity.nullifier(scope);
+            list.is_blocked(&store, scope_epoch, scope, &nullifier, 1).await.unwrap()
+        };
+        assert!(is_blocked(1, scope, &blocked).await);
+        assert!(!is_blocked(1, scope, &later).await);
+        // A nullifier from another scope doesn't match
+        assert!(!list
+            .is_blocked(&store, 1, scope, &blocked.nullifier(other_scope), 1)
+            .await
+            .unwrap());
+
+        // Identities blocked later count in scopes already seen, and new ones
+        list.insert(later.secret()).await;
+        assert!(is_blocked(1, scope, &later).await);
+        assert!(is_blocked(2, other_scope, &later).await);
+    }
+
+    #[test]
+    fn field_encoding_round_trips() {
+        let value = Fr::from(42u64);
+        assert_eq!(decode_field(&encode_field(&value)).unwrap(), value);
+        assert!(matches!(
+            decode_field("not base64!"),
+            Err(AppError::InvalidInputSyntax)
+        ));
+        // 32 bytes of 0xff is above the modulus
+        assert!(matches!(
+            decode_field(&CUSTOM_ENGINE.encode([0xff; 32])),
+            Err(AppError::InvalidInputSyntax)
+        ));
+    }
+}
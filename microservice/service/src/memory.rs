@@ -0,0 +1,1016 @@
+//! In-memory implementation of every repository trait, for tests and demos
+//! that shouldn't need a database file.
+//!
+//! The store mirrors the semantics of `db::db` rather than the mocks: the
+//! unique, foreign key and check constraints of the SQLite schema are enforced
+//! and surface as the same [`AppError`] variants the SQLite errors convert to.
+//!
+//! Transactions are serialized, as SQLite's are: a transaction holds the
+//! store's writer lock from `begin` until it commits or is dropped, and every
+//! other write waits for it. It works on a snapshot of the state that replaces
+//! the shared state on commit; the snapshot shares each table with the state
+//! until the transaction first writes to it.
+
+use crate::{
+    message::repository::MessageRepository,
+    token::repository::TokenRepository,
+    unit_of_work::{UnitOfWork, Work},
+    user::UserRepository,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use db::{
+    Error as SqlxError,
+    models::{
+        AnonymousMessage, KeyBackup, MembershipLeaf, Message, OneTimePrekey, RateLimitShare, SignedPrekey,
+        User, VerifiedContact,
+    },
+    public_key::PublicKey,
+    public_key_hash::PublicKeyHash,
+    uuid::Uuid,
+};
+use shared::errors::AppError;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    ops::{Deref, DerefMut},
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+use tokio::sync::{Mutex, MutexGuard, OwnedMutexGuard};
+
+#[derive(Clone, Default)]
+pub struct InMemoryStore {
+    state: Arc<RwLock<State>>,
+    /// Held by whoever is writing, for as long as a transaction is open.
+    writer: Arc<Mutex<()>>,
+    /// Set on the stores handed out by [`UnitOfWork::begin`].
+    transaction: Option<Arc<Transaction>>,
+}
+
+/// The store a transaction commits into, and its hold on that store's writer
+/// lock.
+struct Transaction {
+    origin: Arc<RwLock<State>>,
+    _writer: OwnedMutexGuard<()>,
+}
+
+#[derive(Clone, Default)]
+struct State {
+    // Keyed by v7 ids, so iteration follows insertion order like SQLite's rowid.
+    users: Table<BTreeMap<Uuid, User>>,
+    message_keys: Table<HashMap<Uuid, String>>,
+    verified_contacts: Table<HashMap<(Uuid, Uuid), VerifiedContact>>,
+    messages: Table<BTreeMap<i64, Message>>,
+    message_commitments: Table<HashMap<i64, String>>,
+    ephemeral_key_proofs: Table<HashMap<i64, String>>,
+    last_message_id: i64,
+    // In index order; a leaf's index is its position plus one
+    membership_leaves: Table<Vec<MembershipLeaf>>,
+    anonymous_messages: Table<BTreeMap<i64, AnonymousMessage>>,
+    rate_limit_shares: Table<HashMap<String, RateLimitShare>>,
+    // Identity commitment to recovered secret
+    blocked_identities: Table<HashMap<String, String>>,
+    signed_prekeys: Table<HashMap<Uuid, SignedPrekey>>,
+    // Each key with whether it has been claimed; claimed keys stay so their
+    // ids cannot be uploaded again.
+    one_time_prekeys: Table<BTreeMap<(Uuid, i64), (OneTimePrekey, bool)>>,
+    // Each backup with its pending challenges and when they expire. Replacing
+    // a backup drops them, as they were issued for the old one.
+    key_backups: Table<HashMap<Uuid, (KeyBackup, Challenges)>>,
+    refresh_tokens: Table<HashMap<String, RefreshToken>>,
+    revoked_tokens: Table<HashMap<String, Option<String>>>,
+}
+
+/// Key backup challenges, with when they expire.
+type Challenges = HashMap<String, NaiveDateTime>;
+
+/// A table of [`State`], shared by the state and its snapshots until one of
+/// them writes to it, which then gets a copy of its own.
+#[derive(Clone, Default)]
+struct Table<T>(Arc<T>);
+
+impl<T> Deref for Table<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Clone> DerefMut for Table<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        Arc::make_mut(&mut self.0)
+    }
+}
+
+/// The state, locked for writing, and the writer lock if the write is not
+/// part of a transaction.
+struct WriteGuard<'a> {
+    state: RwLockWriteGuard<'a, State>,
+    _writer: Option<MutexGuard<'a, ()>>,
+}
+
+impl Deref for WriteGuard<'_> {
+    type Target = State;
+
+    fn deref(&self) -> &State {
+        &self.state
+    }
+}
+
+impl DerefMut for WriteGuard<'_> {
+    fn deref_mut(&mut self) -> &mut State {
+        &mut self.state
+    }
+}
+
+#[derive(Clone)]
+struct RefreshToken {
+    user_id: Uuid,
+    expires_at: i64,
+}
+
+impl InMemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, State> {
+        read(&self.state)
+    }
+
+    /// Waits for any open transaction to finish, unless this is the store
+    /// of the transaction itself.
+    async fn write(&self) -> WriteGuard<'_> {
+        let writer = match self.transaction {
+            Some(_) => None,
+            None => Some(self.writer.lock().await),
+        };
+        WriteGuard {
+            state: write(&self.state),
+            _writer: writer,
+        }
+    }
+}
+
+fn read(state: &RwLock<State>) -> RwLockReadGuard<'_, State> {
+    state.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn write(state: &RwLock<State>) -> RwLockWriteGuard<'_, State> {
+    state.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl State {
+    fn user(&self, user_id: Uuid) -> Result<&User, AppError> {
+        self.users.get(&user_id).ok_or_else(not_found)
+    }
+
+    fn ensure_user_exists(&self, user_id: Uuid) -> Result<(), AppError> {
+        if self.users.contains_key(&user_id) {
+            Ok(())
+        } else {
+            Err(foreign_key_violation())
+        }
+    }
+
+    fn insert_message(
+        &mut self,
+        sender_id: Uuid,
+        recipient_id: Uuid,
+        encrypted_content: &str,
+        signature: Option<String>,
+        parent_id: Option<i64>,
+    ) -> Result<i64, AppError> {
+        self.ensure_user_exists(sender_id)?;
+        self.ensure_user_exists(recipient_id)?;
+
+        self.last_message_id += 1;
+        let id = self.last_message_id;
+        self.messages.insert(
+            id,
+            Message {
+                id,
+                sender_id,
+                recipient_id,
+                encrypted_content: encrypted_content.to_string(),
+                parent_id,
+                signature,
+                created_at: now_in_seconds(),
+                is_read: false,
+            },
+        );
+
+        Ok(id)
+    }
+
+    fn ensure_unique_user(
+        &self,
+        user_id: Uuid,
+        username: &str,
+        public_key_hash: &PublicKeyHash,
+    ) -> Result<(), AppError> {
+        let others = self.users.values().filter(|user| user.id != user_id);
+        for user in others {
+            if user.username == username {
+                return Err(unique_violation("users.username"));
+            }
+            if &user.public_key_hash == public_key_hash {
+                return Err(unique_violation("users.public_key_hash"));
+            }
+        }
+        Ok(())
+    }
+
+    fn replies(&self, parent_id: i64, limit: Option<i64>, offset: Option<i64>) -> Vec<Message> {
+        let mut replies: Vec<&Message> = self
+            .messages
+            .values()
+            .filter(|message| message.parent_id == Some(parent_id))
+            .collect();
+        replies.sort_by_key(|message| message.created_at);
+
+        replies
+            .into_iter()
+            .skip(offset.unwrap_or(0).max(0) as usize)
+            .take(limit.unwrap_or(100).max(0) as usize)
+            .cloned()
+            .collect()
+    }
+
+    fn collect_replies(&self, parent_id: i64, limit: Option<i64>, acc: &mut Vec<Message>) {
+        for reply in self.replies(parent_id, limit, None) {
+            let reply_id = reply.id;
+            acc.push(reply);
+            self.collect_replies(reply_id, limit, acc);
+        }
+    }
+}
+
+fn not_found() -> AppError {
+    SqlxError::RowNotFound.into()
+}
+
+fn unique_violation(column: &str) -> AppError {
+    AppError::UniqueViolation(format!("UNIQUE constraint failed: {column}"))
+}
+
+fn foreign_key_violation() -> AppError {
+    AppError::ForeignKeyViolation("FOREIGN KEY constraint failed".to_string())
+}
+
+/// Stands in for the `CHECK` constraints on `users.username`.
+fn check_username(username: &str) -> Result<(), AppError> {
+    if (3..=50).contains(&username.chars().count()) {
+        Ok(())
+    } else {
+        Err(AppError::DatabaseError(SqlxError::InvalidArgument(
+            "CHECK constraint failed: username".to_string(),
+        )))
+    }
+}
+
+/// `messages.created_at` is stored in whole seconds.
+fn now_in_seconds() -> DateTime<Utc> {
+    DateTime::from_timestamp(Utc::now().timestamp(), 0).unwrap_or_else(Utc::now)
+}
+
+fn limited(messages: Vec<&Message>, limit: i64) -> Vec<Message> {
+    messages
+        .into_iter()
+        .take(limit.max(0) as usize)
+        .cloned()
+        .collect()
+}
+
+#[async_trait]
+impl UserRepository for InMemoryStore {
+    async fn insert_user(&self, public_key: &str, username: &str) -> Result<Uuid, AppError> {
+        let pkey = PublicKey::new(public_key.to_string())?;
+        let pkey_hash = pkey.to_hash()?;
+        check_username(username)?;
+
+        let mut state = self.write().await;
+        let id = Uuid::now_v7();
+        state.ensure_unique_user(id, username, &pkey_hash)?;
+
+        let now = Utc::now().naive_utc();
+        state.users.insert(
+            id,
+            User {
+                id,
+                username: username.to_string(),
+                public_key: pkey,
+                public_key_hash: pkey_hash,
+                created_at: now,
+                last_login: None,
+                updated_at: now,
+            },
+        );
+
+        Ok(id)
+    }
+
+    async fn get_user_by_pubkey(&self, public_key_hash: &str) -> Result<User, AppError> {
+        let pkey_hash = PublicKeyHash::new(public_key_hash.to_string())?;
+
+        self.read()
+            .users
+            .values()
+            .find(|user| user.public_key_hash == pkey_hash)
+            .cloned()
+            .ok_or_else(not_found)
+    }
+
+    async fn get_user_by_id(&self, user_id: Uuid) -> Result<User, AppError> {
+        self.read().user(user_id).cloned()
+    }
+
+    async fn get_users(&self, limit: Option<i64>) -> Result<Vec<User>, AppError> {
+        let limit = limit.unwrap_or(1000).max(0) as usize;
+        Ok(self.read().users.values().take(limit).cloned().collect())
+    }
+
+    async fn update_user(
+        &self,
+        user_id: Uuid,
+        new_username: Option<String>,
+        new_public_key: Option<String>,
+        _new_public_key_hash: Option<String>,
+    ) -> Result<(), AppError> {
+        let (new_pubkey, new_pubkey_hash) = if let Some(pubkey_str) = new_public_key {
+            let pubkey = PublicKey::new(pubkey_str)?;
+            let pubkey_hash = pubkey.to_hash()?;
+            (Some(pubkey), Some(pubkey_hash))
+        } else {
+            (None, None)
+        };
+
+        if new_username.as_deref() == Some("") {
+            return Err(SqlxError::InvalidArgument("Username cannot be empty".to_string()).into());
+        }
+        if new_pubkey.as_ref().is_some_and(|pubkey| pubkey.as_str().is_empty()) {
+            return Err(SqlxError::InvalidArgument("Public Key cannot be empty".to_string()).into());
+        }
+
+        let mut state = self.write().await;
+        let current = state.user(user_id)?;
+
+        let username = new_username.unwrap_or_else(|| current.username.clone());
+        let public_key = new_pubkey.unwrap_or_else(|| current.public_key.clone());
+        let public_key_hash = new_pubkey_hash.unwrap_or_else(|| current.public_key_hash.clone());
+        check_username(&username)?;
+        state.ensure_unique_user(user_id, &username, &public_key_hash)?;
+
+        let user = state.users.get_mut(&user_id).ok_or_else(not_found)?;
+        user.username = username;
+        user.public_key = public_key;
+        user.public_key_hash = public_key_hash;
+        user.updated_at = Utc::now().naive_utc();
+
+        Ok(())
+    }
+
+    async fn fetch_public_key_hash(&self, user_id: Uuid) -> Result<String, AppError> {
+        Ok(self.read().user(user_id)?.public_key_hash.to_string())
+    }
+
+    async fn mark_contact_verified(
+        &self,
+        user_id: Uuid,
+        contact_id: Uuid,
+        public_key_hash: &str,
+    ) -> Result<(), AppError> {
+        let pkey_hash = PublicKeyHash::new(public_key_hash.to_string())?;
+
+        let mut state = self.write().await;
+        state.ensure_user_exists(user_id)?;
+        state.ensure_user_exists(contact_id)?;
+
+        state.verified_contacts.insert(
+            (user_id, contact_id),
+            VerifiedContact {
+                user_id,
+                contact_id,
+                public_key_hash: pkey_hash,
+                verified_at: Utc::now().naive_utc(),
+            },
+        );
+
+        Ok(())
+    }
+
+    async fn get_verified_contact(
+        &self,
+        user_id: Uuid,
+        contact_id: Uuid,
+    ) -> Result<Option<VerifiedContact>, AppError> {
+        Ok(self
+            .read()
+            .verified_contacts
+            .get(&(user_id, contact_id))
+            .cloned())
+    }
+
+    async fn set_message_key(&self, user_id: Uuid, message_key: &str) -> Result<(), AppError> {
+        let mut state = self.write().await;
+        let user = state.users.get_mut(&user_id).ok_or_else(not_found)?;
+        user.updated_at = Utc::now().naive_utc();
+        state.message_keys.insert(user_id, message_key.to_string());
+
+        Ok(())
+    }
+
+    async fn insert_membership_leaf(&self, user_id: Uuid, leaf: &str) -> Result<i64, AppError> {
+        let mut state = self.write().await;
+        state.ensure_user_exists(user_id)?;
+        if state
+            .membership_leaves
+            .iter()
+            .any(|existing| existing.user_id == user_id)
+        {
+            return Err(unique_violation("membership_leaves.user_id"));
+        }
+
+        let leaf_index = state.membership_leaves.len() as i64 + 1;
+        state.membership_leaves.push(MembershipLeaf {
+            leaf_index,
+            user_id,
+            leaf: leaf.to_string(),
+        });
+
+        Ok(leaf_index)
+    }
+
+    async fn get_membership_leaves(
+        &self,
+        after_index: i64,
+    ) -> Result<Vec<MembershipLeaf>, AppError> {
+        Ok(self
+            .read()
+            .membership_leaves
+            .iter()
+            .skip(after_index.max(0) as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn upsert_signed_prekey(
+        &self,
+        user_id: Uuid,
+        identity_key: &str,
+        prekey_id: i64,
+        prekey: &str,
+        signature: &str,
+    ) -> Result<bool, AppError> {
+        let mut state = self.write().await;
+        state.ensure_user_exists(user_id)?;
+        if state
+            .signed_prekeys
+            .get(&user_id)
+            .is_some_and(|current| prekey_id <= current.prekey_id)
+        {
+            return Ok(false);
+        }
+
+        state.signed_prekeys.insert(
+            user_id,
+            SignedPrekey {
+                user_id,
+                identity_key: identity_key.to_string(),
+                prekey_id,
+                prekey: prekey.to_string(),
+                signature: signature.to_string(),
+                updated_at: Utc::now().naive_utc(),
+            },
+        );
+        Ok(true)
+    }
+
+    async fn get_signed_prekey(&self, user_id: Uuid) -> Result<Option<SignedPrekey>, AppError> {
+        Ok(self.read().signed_prekeys.get(&user_id).cloned())
+    }
+
+    async fn insert_one_time_prekeys(
+        &self,
+        user_id: Uuid,
+        prekeys: &[OneTimePrekey],
+    ) -> Result<u64, AppError> {
+        if prekeys.is_empty() {
+            return Ok(0);
+        }
+
+        let mut state = self.write().await;
+        state.ensure_user_exists(user_id)?;
+
+        let mut inserted = 0;
+        for prekey in prekeys {
+            state
+                .one_time_prekeys
+                .entry((user_id, prekey.prekey_id))
+                .or_insert_with(|| {
+                    inserted += 1;
+                    (prekey.clone(), false)
+                });
+        }
+        Ok(inserted)
+    }
+
+    async fn claim_one_time_prekey(
+        &self,
+        user_id: Uuid,
+    ) -> Result<Option<OneTimePrekey>, AppError> {
+        let mut state = self.write().await;
+        let claimed = state
+            .one_time_prekeys
+            .range_mut((user_id, i64::MIN)..=(user_id, i64::MAX))
+            .map(|(_, entry)| entry)
+            .find(|(_, claimed)| !claimed)
+            .map(|(prekey, claimed)| {
+                *claimed = true;
+                prekey.clone()
+            });
+        Ok(claimed)
+    }
+
+    async fn upsert_key_backup(
+        &self,
+        user_id: Uuid,
+        version: i64,
+        encrypted_key: &str,
+        salt: &str,
+        kdf_iterations: i64,
+        recovery_key: &str,
+    ) -> Result<bool, AppError> {
+        let mut state = self.write().await;
+        state.ensure_user_exists(user_id)?;
+        if state
+            .key_backups
+            .get(&user_id)
+            .is_some_and(|(current, _)| version <= current.version)
+        {
+            return Ok(false);
+        }
+
+        let backup = KeyBackup {
+            user_id,
+            version,
+            encrypted_key: encrypted_key.to_string(),
+            salt: salt.to_string(),
+            kdf_iterations,
+            recovery_key: recovery_key.to_string(),
+            failed_attempts: 0,
+            recovery_retry_at: None,
+            updated_at: Utc::now().naive_utc(),
+        };
+        state.key_backups.insert(user_id, (backup, HashMap::new()));
+        Ok(true)
+    }
+
+    async fn get_key_backup(&self, user_id: Uuid) -> Result<Option<KeyBackup>, AppError> {
+        Ok(self
+            .read()
+            .key_backups
+            .get(&user_id)
+            .map(|(backup, _)| backup.clone()))
+    }
+
+    async fn set_key_backup_challenge(
+        &self,
+        user_id: Uuid,
+        challenge: &str,
+        now: NaiveDateTime,
+        expires_at: NaiveDateTime,
+        max_pending: i64,
+    ) -> Result<bool, AppError> {
+        let mut state = self.write().await;
+        let Some((_, pending)) = state.key_backups.get_mut(&user_id) else {
+            return Ok(false);
+        };
+        pending.retain(|_, pending_expires_at| *pending_expires_at > now);
+        if pending.len() as i64 >= max_pending {
+            return Ok(false);
+        }
+        pending.insert(challenge.to_string(), expires_at);
+        Ok(true)
+    }
+
+    async fn take_key_backup_challenge(
+        &self,
+        user_id: Uuid,
+        challenge: &str,
+        now: NaiveDateTime,
+        ignore_backoff: bool,
+    ) -> Result<Option<KeyBackup>, AppError> {
+        let mut state = self.write().await;
+        let Some((backup, pending)) = state.key_backups.get_mut(&user_id) else {
+            return Ok(None);
+        };
+        if !ignore_backoff && backup.recovery_retry_at.is_some_and(|retry_at| retry_at > now) {
+            return Ok(None);
+        }
+        match pending.get(challenge) {
+            Some(expires_at) if *expires_at > now => {
+                pending.remove(challenge);
+                Ok(Some(backup.clone()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn rewrap_key_backup(
+        &self,
+        user_id: Uuid,
+        version: i64,
+        encrypted_key: &str,
+        recovery_key: &str,
+    ) -> Result<bool, AppError> {
+        match self.write().await.key_backups.get_mut(&user_id) {
+            Some((backup, _)) if backup.version == version => {
+                backup.encrypted_key = encrypted_key.to_string();
+                backup.recovery_key = recovery_key.to_string();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn record_failed_key_recovery(&self, user_id: Uuid) -> Result<Option<i64>, AppError> {
+        Ok(self.write().await.key_backups.get_mut(&user_id).map(|(backup, _)| {
+            backup.failed_attempts += 1;
+            backup.failed_attempts
+        }))
+    }
+
+    async fn hold_back_key_recovery(
+        &self,
+        user_id: Uuid,
+        retry_at: NaiveDateTime,
+    ) -> Result<(), AppError> {
+        if let Some((backup, _)) = self.write().await.key_backups.get_mut(&user_id) {
+            backup.recovery_retry_at = backup.recovery_retry_at.max(Some(retry_at));
+        }
+        Ok(())
+    }
+
+    async fn count_one_time_prekeys(&self, user_id: Uuid) -> Result<i64, AppError> {
+        let count = self
+            .read()
+            .one_time_prekeys
+            .range((user_id, i64::MIN)..=(user_id, i64::MAX))
+            .filter(|(_, (_, claimed))| !claimed)
+            .count();
+        Ok(count as i64)
+    }
+}
+
+#[async_trait]
+impl MessageRepository for InMemoryStore {
+    async fn insert_message(
+        &self,
+        sender_id: Uuid,
+        recipient_id: Uuid,
+        encrypted_content: &str,
+        signature: Option<String>,
+        parent_id: Option<i64>,
+    ) -> Result<Option<i64>, AppError> {
+        let id = self.write().await.insert_message(
+            sender_id,
+            recipient_id,
+            encrypted_content,
+            signature,
+            parent_id,
+        )?;
+        Ok(Some(id))
+    }
+
+    async fn insert_attested_message<'a>(
+        &self,
+        sender_id: Uuid,
+        recipient_id: Uuid,
+        encrypted_content: &str,
+        signature: Option<String>,
+        parent_id: Option<i64>,
+        commitment: Option<&'a str>,
+        ephemeral_key_proof: Option<&'a str>,
+    ) -> Result<Option<i64>, AppError> {
+        let mut state = self.write().await;
+        let id = state.insert_message(
+            sender_id,
+            recipient_id,
+            encrypted_content,
+            signature,
+            parent_id,
+        )?;
+        if let Some(commitment) = commitment {
+            state.message_commitments.insert(id, commitment.to_string());
+        }
+        if let Some(proof) = ephemeral_key_proof {
+            state.ephemeral_key_proofs.insert(id, proof.to_string());
+        }
+        Ok(Some(id))
+    }
+
+    async fn get_message_by_id(&self, message_id: i64) -> Result<Option<Message>, AppError> {
+        Ok(self.read().messages.get(&message_id).cloned())
+    }
+
+    async fn get_message_commitment(&self, message_id: i64) -> Result<Option<String>, AppError> {
+        Ok(self.read().message_commitments.get(&message_id).cloned())
+    }
+
+    async fn get_ephemeral_key_proof(&self, message_id: i64) -> Result<Option<String>, AppError> {
+        Ok(self.read().ephemeral_key_proofs.get(&message_id).cloned())
+    }
+
+    async fn get_user_message_key(&self, user_id: Uuid) -> Result<Option<String>, AppError> {
+        Ok(self.read().message_keys.get(&user_id).cloned())
+    }
+
+    async fn insert_anonymous_message(
+        &self,
+        recipient_id: Uuid,
+        encrypted_content: &str,
+        membership_root: &str,
+        share: &RateLimitShare,
+    ) -> Result<i64, AppError> {
+        let mut state = self.write().await;
+        state.ensure_user_exists(recipient_id)?;
+        if state.rate_limit_shares.contains_key(&share.nullifier) {
+            return Err(unique_violation("anonymous_messages.nullifier"));
+        }
+
+        let id = state
+            .anonymous_messages
+            .last_key_value()
+            .map_or(1, |(id, _)| id + 1);
+        state.anonymous_messages.insert(
+            id,
+            AnonymousMessage {
+                id,
+                recipient_id,
+                encrypted_content: encrypted_content.to_string(),
+                nullifier: share.nullifier.clone(),
+                membership_root: membership_root.to_string(),
+                created_at: Utc::now().naive_utc(),
+            },
+        );
+        state
+            .rate_limit_shares
+            .insert(share.nullifier.clone(), share.clone());
+
+        Ok(id)
+    }
+
+    async fn get_rate_limit_share(
+        &self,
+        nullifier: &str,
+    ) -> Result<Option<RateLimitShare>, AppError> {
+        Ok(self.read().rate_limit_shares.get(nullifier).cloned())
+    }
+
+    async fn block_identity(
+        &self,
+        identity_commitment: &str,
+        identity_secret: &str,
+    ) -> Result<(), AppError> {
+        self.write().await
+            .blocked_identities
+            .entry(identity_commitment.to_string())
+            .or_insert_with(|| identity_secret.to_string());
+        Ok(())
+    }
+
+    async fn get_blocked_identity_secrets(&self) -> Result<Vec<String>, AppError> {
+        Ok(self.read().blocked_identities.values().cloned().collect())
+    }
+
+    async fn get_anonymous_messages(
+        &self,
+        recipient_id: Uuid,
+        limit: i64,
+    ) -> Result<Vec<AnonymousMessage>, AppError> {
+        Ok(self
+            .read()
+            .anonymous_messages
+            .values()
+            .rev()
+            .filter(|message| message.recipient_id == recipient_id)
+            .take(limit.max(0) as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn get_conversation(
+        &self,
+        user1_id: Uuid,
+        user2_id: Uuid,
+        limit: Option<i64>,
+    ) -> Result<Vec<Message>, AppError> {
+        let state = self.read();
+        let mut messages: Vec<&Message> = state
+            .messages
+            .values()
+            .rev()
+            .filter(|message| {
+                (message.sender_id == user1_id && message.recipient_id == user2_id)
+                    || (message.sender_id == user2_id && message.recipient_id == user1_id)
+            })
+            .collect();
+        messages.sort_by_key(|message| Reverse(message.created_at));
+
+        Ok(limited(messages, limit.unwrap_or(100)))
+    }
+
+    async fn get_thread_replies(
+        &self,
+        parent_id: i64,
+        limit: Option<i64>,
+        offset: Option<i64>,
+    ) -> Result<Vec<Message>, AppError> {
+        Ok(self.read().replies(parent_id, limit, offset))
+    }
+
+    async fn get_complete_thread(
+        &self,
+        thread_root_id: i64,
+        limit: Option<i64>,
+    ) -> Result<Vec<Message>, AppError> {
+        let state = self.read();
+        let root = state.messages.get(&thread_root_id).ok_or_else(not_found)?;
+
+        let mut thread = vec![root.clone()];
+        state.collect_replies(root.id, limit, &mut thread);
+        thread.sort_by_key(|message| message.created_at);
+
+        Ok(thread)
+    }
+
+    async fn get_user_threads(
+        &self,
+        user_id: Uuid,
+        limit: Option<i64>,
+    ) -> Result<Vec<Message>, AppError> {
+        let state = self.read();
+        let mut threads: Vec<&Message> = state
+            .messages
+            .values()
+            .rev()
+            .filter(|message| message.sender_id == user_id || message.recipient_id == user_id)
+            .filter(|message| {
+                state
+                    .messages
+                    .values()
+                    .any(|reply| reply.parent_id == Some(message.id))
+            })
+            .collect();
+        threads.sort_by_key(|message| Reverse(message.created_at));
+
+        Ok(limited(threads, limit.unwrap_or(20)))
+    }
+
+    async fn mark_message_read(&self, message_id: i64) -> Result<(), AppError> {
+        if let Some(message) = self.write().await.messages.get_mut(&message_id) {
+            message.is_read = true;
+        }
+        Ok(())
+    }
+
+    async fn get_unread_messages(&self, user_id: Uuid) -> Result<Vec<Message>, AppError> {
+        let state = self.read();
+        let mut messages: Vec<&Message> = state
+            .messages
+            .values()
+            .filter(|message| message.recipient_id == user_id && !message.is_read)
+            .collect();
+        messages.sort_by_key(|message| message.created_at);
+
+        Ok(messages.into_iter().cloned().collect())
+    }
+}
+
+#[async_trait]
+impl TokenRepository for InMemoryStore {
+    async fn store_refresh_token(
+        &self,
+        user_id: Uuid,
+        token_hash: &str,
+        expires_at: i64,
+        _device_info: Option<String>,
+    ) -> Result<(), AppError> {
+        let mut state = self.write().await;
+        state.ensure_user_exists(user_id)?;
+        if state.refresh_tokens.contains_key(token_hash) {
+            return Err(unique_violation("refresh_tokens.token_hash"));
+        }
+
+        state.refresh_tokens.insert(
+            token_hash.to_string(),
+            RefreshToken {
+                user_id,
+                expires_at,
+            },
+        );
+
+        Ok(())
+    }
+
+    async fn validate_refresh_token(
+        &self,
+        user_id: Uuid,
+        token_hash: &str,
+    ) -> Result<bool, AppError> {
+        let now = Utc::now().timestamp();
+        Ok(self
+            .read()
+            .refresh_tokens
+            .get(token_hash)
+            .is_some_and(|token| token.user_id == user_id && token.expires_at > now))
+    }
+
+    async fn revoke_refresh_token(
+        &self,
+        token_hash: &str,
+        reason: Option<String>,
+    ) -> Result<(), AppError> {
+        let mut state = self.write().await;
+        if state.refresh_tokens.contains_key(token_hash) {
+            if state.revoked_tokens.contains_key(token_hash) {
+                return Err(unique_violation("revoked_tokens.token_hash"));
+            }
+            state.revoked_tokens.insert(token_hash.to_string(), reason);
+            state.refresh_tokens.remove(token_hash);
+        }
+
+        Ok(())
+    }
+
+    async fn revoke_user_refresh_tokens(
+        &self,
+        user_id: Uuid,
+        reason: Option<String>,
+    ) -> Result<u64, AppError> {
+        let mut state = self.write().await;
+        let token_hashes: Vec<String> = state
+            .refresh_tokens
+            .iter()
+            .filter(|(_, token)| token.user_id == user_id)
+            .map(|(token_hash, _)| token_hash.clone())
+            .collect();
+
+        for token_hash in &token_hashes {
+            state.refresh_tokens.remove(token_hash);
+            state
+                .revoked_tokens
+                .entry(token_hash.clone())
+                .or_insert_with(|| reason.clone());
+        }
+
+        Ok(token_hashes.len() as u64)
+    }
+
+    async fn cleanup_expired_tokens(&self) -> Result<u64, AppError> {
+        let now = Utc::now().timestamp();
+        let mut state = self.write().await;
+        let before = state.refresh_tokens.len();
+        state.refresh_tokens.retain(|_, token| token.expires_at > now);
+        Ok((before - state.refresh_tokens.len()) as u64)
+    }
+}
+
+#[async_trait]
+impl UnitOfWork for InMemoryStore {
+    type Work = InMemoryStore;
+
+    async fn begin(&self) -> Result<InMemoryStore, AppError> {
+        let writer = self.writer.clone().lock_owned().await;
+        let snapshot = read(&self.state).clone();
+        Ok(InMemoryStore {
+            state: Arc::new(RwLock::new(snapshot)),
+            writer: self.writer.clone(),
+            transaction: Some(Arc::new(Transaction {
+                origin: self.state.clone(),
+                _writer: writer,
+            })),
+        })
+    }
+}
+
+#[async_trait]
+impl Work for InMemoryStore {
+    async fn commit(self) -> Result<(), AppError> {
+        // Outside a transaction every write has already been applied.
+        let Some(transaction) = self.transaction else {
+            return Ok(());
+        };
+
+        // Nothing else can have written since `begin`, so this loses nothing
+        *write(&transaction.origin) = std::mem::take(&mut *write(&self.state));
+
+        Ok(())
+    }
+
+    async fn rollback(self) -> Result<(), AppError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+//! Pedersen commitments to message ciphertexts. A sender may attach one when
+//! creating a message; the server checks it is the commitment carried by the
+//! sealed message and stores it, so the recipient, or an auditor the
+//! recipient hands the opening to, can later check that the delivered
+//! ciphertext is exactly the committed one.
+
+use base64::Engine;
+use circuits::message_preparation::{self, SealedMessage};
+use shared::{errors::AppError, models::CUSTOM_ENGINE};
+
+/// Checks that `encoded` is a valid compressed commitment point, and the
+/// very commitment carried by the [`SealedMessage`] in `encrypted_content`.
+pub fn check(encoded: &str, encrypted_content: &str) -> Result<(), AppError> {
+    let bytes = CUSTOM_ENGINE
+        .decode(encoded)
+        .map_err(|_| AppError::InvalidInputSyntax)?;
+    let commitment = message_preparation::decode_commitment(&bytes)
+        .map_err(|_| AppError::InvalidInputSyntax)?;
+    let sealed =
+        SealedMessage::from_base64(encrypted_content).map_err(|_| AppError::InvalidInputSyntax)?;
+
+    if sealed.commitment == commitment {
+        Ok(())
+    } else {
+        Err(AppError::InvalidInputSyntax)
+    }
+}
+
+/// Checks that `commitment`, as returned by the commitment endpoint, opens to
+/// the ciphertext in `encrypted_content` under `opening`, the compressed
+/// scalar from `SealedMessage::opening`. All three are [`CUSTOM_ENGINE`]
+/// base64.
+pub fn verify_delivery(
+    commitment: &str,
+    encrypted_content: &str,
+    opening: &str,
+) -> Result<bool, AppError> {
+    let decode = |field: &str| {
+        CUSTOM_ENGINE
+            .decode(field)
+            .map_err(|_| AppError::InvalidInputSyntax)
+    };
+
+    let sealed =
+        SealedMessage::from_base64(encrypted_content).map_err(|_| AppError::InvalidInputSyntax)?;
+
+    message_preparation::verify_delivery_encoded(
+        &decode(commitment)?,
+        &sealed,
+        &decode(opening)?,
+    )
+    .map_err(|_| AppError::InvalidInputSyntax)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use circuits::{message_preparation::MessageContext, prover};
+
+    fn context() -> MessageContext {
+        MessageContext {
+            sender_id: [1u8; 16],
+            recipient_id: [2u8; 16],
+            parent_id: None,
+        }
+    }
+
+    // What a sender posts and what the recipient derives after delivery
+    fn delivered(plaintext: &[u8]) -> (String, String, String) {
+        let (sk, pk) = message_preparation::generate_keypair();
+        let sealed = message_preparation::seal(&pk, plaintext, &context()).unwrap();
+        let opening = sealed.opening(&sk, &context()).unwrap();
+
+        (
+            CUSTOM_ENGINE.encode(prover::to_bytes(&sealed.commitment).unwrap()),
+            sealed.to_base64(),
+            CUSTOM_ENGINE.encode(prover::to_bytes(&opening).unwrap()),
+        )
+    }
+
+    #[test]
+    fn accepts_committed_ciphertext() {
+        let (commitment, encrypted_content, opening) = delivered(b"hello");
+
+        assert!(check(&commitment, &encrypted_content).is_ok());
+        assert!(verify_delivery(&commitment, &encrypted_content, &opening).unwrap());
+    }
+
+    #[test]
+    fn rejects_other_ciphertext() {
+        let (commitment, _, opening) = delivered(b"hello");
+        let (_, other_content, _) = delivered(b"hello");
+
+        assert!(!verify_delivery(&commitment, &other_content, &opening).unwrap());
+    }
+
+    #[test]
+    fn rejects_commitment_to_other_message() {
+        let (commitment, _, _) = delivered(b"hello");
+        let (_, other_content, _) = delivered(b"hello");
+
+        assert!(matches!(
+            check(&commitment, &other_content),
+            Err(AppError::InvalidInputSyntax)
+        ));
+        assert!(matches!(
+            check(&commitment, "bm90IHNlYWxlZA"),
+            Err(AppError::InvalidInputSyntax)
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_commitment() {
+        let (_, encrypted_content, _) = delivered(b"hello");
+
+        assert!(matches!(
+            check("not base64!", &encrypted_content),
+            Err(AppError::InvalidInputSyntax)
+        ));
+        assert!(matches!(
+            check(&CUSTOM_ENGINE.encode([0xffu8; 32]), &encrypted_content),
+            Err(AppError::InvalidInputSyntax)
+        ));
+    }
+}
@@ -1,2 +1,4 @@
+pub mod commitment;
+pub mod proof;
 pub mod repository;
 pub mod service;
@@ -0,0 +1,282 @@
+use crate::membership::{self, AnonymitySet};
+use base64::Engine;
+use circuits::membership::Fr;
+use circuits::prover::{self, VerifyingKey};
+use db::uuid::Uuid;
+use shared::{
+    errors::AppError,
+    models::{CUSTOM_ENGINE, EphemeralKeyProof, MembershipProof},
+};
+use std::sync::Arc;
+
+/// Checks [`EphemeralKeyProof`]s against the verifying key produced by the
+/// `circuits::prover` setup.
+pub struct EphemeralKeyVerifier {
+    vk: VerifyingKey,
+}
+
+impl EphemeralKeyVerifier {
+    pub fn new(vk: VerifyingKey) -> Self {
+        Self { vk }
+    }
+
+    /// Loads a verifying key serialized with `circuits::prover::to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, AppError> {
+        prover::from_bytes(bytes)
+            .map(Self::new)
+            .map_err(|e| AppError::InternalError(format!("Invalid verifying key: {e}")))
+    }
+
+    pub fn verify(&self, proof: &EphemeralKeyProof) -> Result<(), AppError> {
+        let decode = |field: &str| {
+            CUSTOM_ENGINE
+                .decode(field)
+                .map_err(|_| AppError::InvalidInputSyntax)
+        };
+
+        let verified = prover::verify_encoded(
+            &self.vk,
+            &decode(&proof.recipient_key)?,
+            &decode(&proof.epub)?,
+            &decode(&proof.proof)?,
+        )
+        .map_err(|_| AppError::InvalidInputSyntax)?;
+
+        if verified {
+            Ok(())
+        } else {
+            Err(AppError::Forbidden(String::from(
+                "Ephemeral key proof is invalid",
+            )))
+        }
+    }
+}
+
+/// Checks [`MembershipProof`]s against the verifying key produced by the
+/// `circuits::membership` setup, and against the roots `anonymity_set` had
+/// recently.
+pub struct MembershipVerifier {
+    vk: VerifyingKey,
+    anonymity_set: Arc<AnonymitySet>,
+}
+
+impl MembershipVerifier {
+    pub fn new(vk: VerifyingKey, anonymity_set: Arc<AnonymitySet>) -> Self {
+        Self { vk, anonymity_set }
+    }
+
+    /// Loads a verifying key serialized with `circuits::prover::to_bytes`.
+    pub fn from_bytes(bytes: &[u8], anonymity_set: Arc<AnonymitySet>) -> Result<Self, AppError> {
+        prover::from_bytes(bytes)
+            .map(|vk| Self::new(vk, anonymity_set))
+            .map_err(|e| AppError::InternalError(format!("Invalid verifying key: {e}")))
+    }
+
+    /// Succeeds if `proof` shows its sender is a member of a recent anonymity
+    /// set, with the nullifier for its epoch and `recipient_id` and their share
+    /// at `x`.
+    pub fn verify(
+        &self,
+        proof: &MembershipProof,
+        recipient_id: Uuid,
+        x: Fr,
+    ) -> Result<(), AppError> {
+        let root = membership::decode_field(&proof.root)?;
+        if !self.anonymity_set.is_recent_root(&root) {
+            return Err(AppError::Forbidden(String::from(
+                "Membership root is unknown or expired",
+            )));
+        }
+
+        let scope = membership::message_scope(proof.epoch, recipient_id);
+        let nullifier = membership::decode_field(&proof.nullifier)?;
+        let y = membership::decode_field(&proof.share)?;
+        let proof_bytes = CUSTOM_ENGINE
+            .decode(&proof.proof)
+            .map_err(|_| AppError::InvalidInputSyntax)?;
+        let proof = prover::from_bytes(&proof_bytes).map_err(|_| AppError::InvalidInputSyntax)?;
+
+        let verified = circuits::membership::verify(&self.vk, root, scope, nullifier, x, y, &proof)
+            .map_err(|_| AppError::InvalidInputSyntax)?;
+
+        if verified {
+            Ok(())
+        } else {
+            Err(AppError::Forbidden(String::from(
+                "Membership proof is invalid",
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{memory::InMemoryStore, user::UserRepository};
+    use circuits::membership::{Identity, leaf, public_key_hash_to_field};
+    use circuits::message_protocol::MessageProtocolCircuit;
+    use circuits::prover::ProvingKey;
+    use rand::{SeedableRng, rngs::StdRng};
+    use std::sync::OnceLock;
+
+    // Setup is slow in debug builds, so every test shares one key pair.
+    fn keys() -> &'static (ProvingKey, VerifyingKey) {
+        static KEYS: OnceLock<(ProvingKey, VerifyingKey)> = OnceLock::new();
+        KEYS.get_or_init(|| prover::setup(&mut StdRng::seed_from_u64(7)).unwrap())
+    }
+
+    fn valid_proof(rng: &mut StdRng) -> EphemeralKeyProof {
+        let (_, recipient_key) = MessageProtocolCircuit::generate_ephemeral_keys(rng);
+        let (esk, epub) = MessageProtocolCircuit::generate_ephemeral_keys(rng);
+        let proof = prover::prove(&keys().0, recipient_key, esk, rng).unwrap();
+
+        EphemeralKeyProof {
+            epub: CUSTOM_ENGINE.encode(prover::to_bytes(&epub).unwrap()),
+            recipient_key: CUSTOM_ENGINE.encode(prover::to_bytes(&recipient_key).unwrap()),
+            proof: CUSTOM_ENGINE.encode(prover::to_bytes(&proof).unwrap()),
+        }
+    }
+
+    fn verifier() -> EphemeralKeyVerifier {
+        let bytes = prover::to_bytes(&keys().1).unwrap();
+        EphemeralKeyVerifier::from_bytes(&bytes).unwrap()
+    }
+
+    #[test]
+    fn accepts_valid_proof() {
+        let proof = valid_proof(&mut StdRng::seed_from_u64(1));
+        assert!(verifier().verify(&proof).is_ok());
+    }
+
+    #[test]
+    fn rejects_proof_for_other_epub() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let mut proof = valid_proof(&mut rng);
+        let (_, other_epub) = MessageProtocolCircuit::generate_ephemeral_keys(&mut rng);
+        proof.epub = CUSTOM_ENGINE.encode(prover::to_bytes(&other_epub).unwrap());
+
+        assert!(matches!(
+            verifier().verify(&proof),
+            Err(AppError::Forbidden(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_encoding() {
+        let mut proof = valid_proof(&mut StdRng::seed_from_u64(3));
+        proof.proof = String::from("not base64!");
+
+        assert!(matches!(
+            verifier().verify(&proof),
+            Err(AppError::InvalidInputSyntax)
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_verifying_key() {
+        assert!(matches!(
+            EphemeralKeyVerifier::from_bytes(&[0u8; 8]),
+            Err(AppError::InternalError(_))
+        ));
+    }
+
+    fn membership_keys() -> &'static (ProvingKey, VerifyingKey) {
+        static KEYS: OnceLock<(ProvingKey, VerifyingKey)> = OnceLock::new();
+        KEYS.get_or_init(|| circuits::membership::setup(&mut StdRng::seed_from_u64(7)).unwrap())
+    }
+
+    const RECIPIENT: Uuid = Uuid::from_bytes([4; 16]);
+
+    /// A verifier over an anonymity set holding one member, and that
+    /// member's proof for sending the message at `x` to [`RECIPIENT`] in
+    /// epoch 1.
+    async fn member_proof(x: Fr) -> (MembershipVerifier, MembershipProof) {
+        let mut rng = StdRng::seed_from_u64(4);
+        let identity = Identity::generate(&mut rng);
+        let public_key_hash = public_key_hash_to_field(&[9; 32]);
+
+        let store = InMemoryStore::new();
+        let user_id = store
+            .insert_user(&CUSTOM_ENGINE.encode([9; 33]), "member")
+            .await
+            .unwrap();
+        let member_leaf = leaf(public_key_hash, identity.commitment());
+        store
+            .insert_membership_leaf(user_id, &membership::encode_field(&member_leaf))
+            .await
+            .unwrap();
+        let anonymity_set = Arc::new(AnonymitySet::load(&store).await.unwrap());
+
+        let tree = circuits::membership::MembershipTree::from_leaves([member_leaf]);
+        let scope = membership::message_scope(1, RECIPIENT);
+        let proof = circuits::membership::prove(
+            &membership_keys().0,
+            &identity,
+            public_key_hash,
+            &tree.path(0).unwrap(),
+            scope,
+            x,
+            &mut rng,
+        )
+        .unwrap();
+
+        let verifier = MembershipVerifier::new(membership_keys().1.clone(), anonymity_set);
+        let proof = MembershipProof {
+            root: membership::encode_field(&tree.root()),
+            epoch: 1,
+            nullifier: membership::encode_field(&identity.nullifier(scope)),
+            share: membership::encode_field(&identity.share(scope, x)),
+            proof: CUSTOM_ENGINE.encode(prover::to_bytes(&proof).unwrap()),
+        };
+        (verifier, proof)
+    }
+
+    #[tokio::test]
+    async fn accepts_valid_membership_proof() {
+        let (verifier, proof) = member_proof(Fr::from(3u64)).await;
+
+        assert!(verifier.verify(&proof, RECIPIENT, Fr::from(3u64)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn rejects_membership_proof_for_other_message() {
+        let (verifier, proof) = member_proof(Fr::from(3u64)).await;
+
+        assert!(matches!(
+            verifier.verify(&proof, RECIPIENT, Fr::from(4u64)),
+            Err(AppError::Forbidden(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_membership_proof_for_other_epoch() {
+        let (verifier, mut proof) = member_proof(Fr::from(3u64)).await;
+        proof.epoch = 2;
+
+        assert!(matches!(
+            verifier.verify(&proof, RECIPIENT, Fr::from(3u64)),
+            Err(AppError::Forbidden(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_membership_proof_for_other_recipient() {
+        let (verifier, proof) = member_proof(Fr::from(3u64)).await;
+
+        assert!(matches!(
+            verifier.verify(&proof, Uuid::from_bytes([5; 16]), Fr::from(3u64)),
+            Err(AppError::Forbidden(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_membership_proof_against_unknown_root() {
+        let (verifier, mut proof) = member_proof(Fr::from(3u64)).await;
+        proof.root = membership::encode_field(&Fr::from(1u64));
+
+        assert!(matches!(
+            verifier.verify(&proof, RECIPIENT, Fr::from(3u64)),
+            Err(AppError::Forbidden(_))
+        ));
+    }
+}
@@ -1,7 +1,13 @@
 use async_trait::async_trait;
-use db::{Error as SqlxError, SqlitePool, db::{self as database, SqliteDb}, message_db::MessageDb, models::Message, uuid::Uuid};
+use db::{
+    Error as SqlxError,
+    message_db::MessageDb,
+    models::{AnonymousMessage, Message, RateLimitShare},
+    uuid::Uuid,
+};
 use mockall::automock;
 use shared::errors::AppError;
+use tracing::instrument;
 
 #[automock]
 #[async_trait]
@@ -15,8 +21,60 @@ pub trait MessageRepository: Send + Sync {
         parent_id: Option<i64>,
     ) -> Result<Option<i64>, AppError>;
 
+    /// Inserts a message together with the sender's commitment to its
+    /// ciphertext and their verified ephemeral key proof, so none is stored
+    /// without the others.
+    async fn insert_attested_message<'a>(
+        &self,
+        sender_id: Uuid,
+        recipient_id: Uuid,
+        encrypted_content: &str,
+        signature: Option<String>,
+        parent_id: Option<i64>,
+        commitment: Option<&'a str>,
+        ephemeral_key_proof: Option<&'a str>,
+    ) -> Result<Option<i64>, AppError>;
+
     async fn get_message_by_id(&self, message_id: i64) -> Result<Option<Message>, AppError>;
 
+    async fn get_message_commitment(&self, message_id: i64) -> Result<Option<String>, AppError>;
+
+    async fn get_ephemeral_key_proof(&self, message_id: i64) -> Result<Option<String>, AppError>;
+
+    /// The Jubjub key messages to `user_id` are sealed to, or `None` if there
+    /// is no such user or they haven't registered one.
+    async fn get_user_message_key(&self, user_id: Uuid) -> Result<Option<String>, AppError>;
+
+    /// Inserts a message from an anonymous sender together with the share it
+    /// revealed. Fails with [`AppError::UniqueViolation`] if the share's
+    /// nullifier was used before.
+    async fn insert_anonymous_message(
+        &self,
+        recipient_id: Uuid,
+        encrypted_content: &str,
+        membership_root: &str,
+        share: &RateLimitShare,
+    ) -> Result<i64, AppError>;
+
+    async fn get_rate_limit_share(
+        &self,
+        nullifier: &str,
+    ) -> Result<Option<RateLimitShare>, AppError>;
+
+    async fn block_identity(
+        &self,
+        identity_commitment: &str,
+        identity_secret: &str,
+    ) -> Result<(), AppError>;
+
+    async fn get_blocked_identity_secrets(&self) -> Result<Vec<String>, AppError>;
+
+    async fn get_anonymous_messages(
+        &self,
+        recipient_id: Uuid,
+        limit: i64,
+    ) -> Result<Vec<AnonymousMessage>, AppError>;
+
     async fn get_conversation(
         &self,
         user1_id: Uuid,
@@ -60,9 +118,9 @@ impl Clone for MockMessageRepository {
     }
 }
 
-/*
 #[async_trait]
-impl MessageRepository for SqliteDb {
+impl<D: MessageDb + Send + Sync> MessageRepository for D {
+    #[instrument(level = "debug", skip_all)]
     async fn insert_message(
         &self,
         sender_id: Uuid,
@@ -71,130 +129,150 @@ impl MessageRepository for SqliteDb {
         signature: Option<String>,
         parent_id: Option<i64>,
     ) -> Result<Option<i64>, AppError> {
-        self.create_message(sender_id, recipient_id, encrypted_content, signature, parent_id)
-            .await
-            .map_err(AppError::from)
+        Ok(self
+            .create_message(sender_id, recipient_id, encrypted_content, signature, parent_id)
+            .await?)
     }
 
-    async fn get_message_by_id(&self, message_id: i64) -> Result<Option<Message>, AppError> {
-        MessageDb::get_message(self, message_id).await.map_err(AppError::from)
-    }
-
-    async fn get_conversation(
+    #[instrument(level = "debug", skip_all)]
+    async fn insert_attested_message<'a>(
         &self,
-        user1_id: Uuid,
-        user2_id: Uuid,
-        limit: Option<i64>,
-    ) -> Result<Vec<Message>, AppError> {
-        MessageDb::get_conversation(self, user1_id, user2_id, limit).await.map_err(AppError::from)
-    }
-
-    async fn get_thread_replies(
-        &self,
-        parent_id: i64,
-        limit: Option<i64>,
-        offset: Option<i64>,
-    ) -> Result<Vec<Message>, AppError> {
-        MessageDb::get_thread_replies(self, parent_id, limit, offset).await.map_err(AppError::from)
+        sender_id: Uuid,
+        recipient_id: Uuid,
+        encrypted_content: &str,
+        signature: Option<String>,
+        parent_id: Option<i64>,
+        commitment: Option<&'a str>,
+        ephemeral_key_proof: Option<&'a str>,
+    ) -> Result<Option<i64>, AppError> {
+        Ok(self
+            .create_attested_message(
+                sender_id,
+                recipient_id,
+                encrypted_content,
+                signature,
+                parent_id,
+                commitment,
+                ephemeral_key_proof,
+            )
+            .await?)
     }
 
-    async fn get_complete_thread(
-        &self,
-        thread_root_id: i64,
-        limit: Option<i64>,
-    ) -> Result<Vec<Message>, AppError> {
-        MessageDb::get_complete_thread(self, thread_root_id, limit).await.map_err(AppError::from)
+    #[instrument(level = "debug", skip_all)]
+    async fn get_message_by_id(&self, message_id: i64) -> Result<Option<Message>, AppError> {
+        Ok(self.get_message(message_id).await?)
     }
 
-    async fn get_user_threads(
-        &self,
-        user_id: Uuid,
-        limit: Option<i64>,
-    ) -> Result<Vec<Message>, AppError> {
-        MessageDb::get_user_threads(self, user_id, limit).await.map_err(AppError::from)
+    #[instrument(level = "debug", skip_all)]
+    async fn get_message_commitment(&self, message_id: i64) -> Result<Option<String>, AppError> {
+        Ok(MessageDb::get_message_commitment(self, message_id).await?)
     }
 
-    async fn mark_message_read(&self, message_id: i64) -> Result<(), AppError> {
-        MessageDb::mark_message_read(self, message_id).await.map_err(AppError::from)
+    #[instrument(level = "debug", skip_all)]
+    async fn get_ephemeral_key_proof(&self, message_id: i64) -> Result<Option<String>, AppError> {
+        Ok(MessageDb::get_ephemeral_key_proof(self, message_id).await?)
     }
 
-    async fn get_unread_messages(&self, user_id: Uuid) -> Result<Vec<Message>, AppError> {
-        MessageDb::get_unread_messages(self, user_id).await.map_err(AppError::from)
+    #[instrument(level = "debug", skip_all)]
+    async fn get_user_message_key(&self, user_id: Uuid) -> Result<Option<String>, AppError> {
+        Ok(MessageDb::get_user_message_key(self, user_id).await?)
     }
-}
-*/
 
-#[async_trait]
-impl MessageRepository for SqlitePool {
-    async fn insert_message(
+    #[instrument(level = "debug", skip_all)]
+    async fn insert_anonymous_message(
         &self,
-        sender_id: Uuid,
         recipient_id: Uuid,
         encrypted_content: &str,
-        signature: Option<String>,
-        parent_id: Option<i64>,
-    ) -> Result<Option<i64>, AppError> {
-        Ok(database::create_message(
+        membership_root: &str,
+        share: &RateLimitShare,
+    ) -> Result<i64, AppError> {
+        Ok(MessageDb::create_anonymous_message(
             self,
-            sender_id,
             recipient_id,
             encrypted_content,
-            signature.as_deref(),
-            parent_id,
+            membership_root,
+            share,
         )
         .await?)
     }
 
-    async fn get_message_by_id(&self, message_id: i64) -> Result<Option<Message>, AppError> {
-        Ok(database::get_message(self, message_id).await?)
+    #[instrument(level = "debug", skip_all)]
+    async fn get_rate_limit_share(
+        &self,
+        nullifier: &str,
+    ) -> Result<Option<RateLimitShare>, AppError> {
+        Ok(MessageDb::get_rate_limit_share(self, nullifier).await?)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn block_identity(
+        &self,
+        identity_commitment: &str,
+        identity_secret: &str,
+    ) -> Result<(), AppError> {
+        Ok(MessageDb::block_identity(self, identity_commitment, identity_secret).await?)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn get_blocked_identity_secrets(&self) -> Result<Vec<String>, AppError> {
+        Ok(MessageDb::get_blocked_identity_secrets(self).await?)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn get_anonymous_messages(
+        &self,
+        recipient_id: Uuid,
+        limit: i64,
+    ) -> Result<Vec<AnonymousMessage>, AppError> {
+        Ok(MessageDb::get_anonymous_messages(self, recipient_id, limit).await?)
     }
 
+    #[instrument(level = "debug", skip_all)]
     async fn get_conversation(
         &self,
         user1_id: Uuid,
         user2_id: Uuid,
         limit: Option<i64>,
     ) -> Result<Vec<Message>, AppError> {
-        Ok(database::get_conversation(self, user1_id, user2_id, limit).await?)
+        Ok(MessageDb::get_conversation(self, user1_id, user2_id, limit).await?)
     }
 
+    #[instrument(level = "debug", skip_all)]
     async fn get_thread_replies(
         &self,
         parent_id: i64,
         limit: Option<i64>,
         offset: Option<i64>,
     ) -> Result<Vec<Message>, AppError> {
-        Ok(database::get_thread_replies(self, parent_id, limit, offset).await?)
+        Ok(MessageDb::get_thread_replies(self, parent_id, limit, offset).await?)
     }
 
+    #[instrument(level = "debug", skip_all)]
     async fn get_complete_thread(
         &self,
         thread_root_id: i64,
         limit: Option<i64>,
     ) -> Result<Vec<Message>, AppError> {
-        Ok(database::get_complete_thread(self, thread_root_id, limit).await?)
+        Ok(MessageDb::get_complete_thread(self, thread_root_id, limit).await?)
     }
 
+    #[instrument(level = "debug", skip_all)]
     async fn get_user_threads(
         &self,
         user_id: Uuid,
         limit: Option<i64>,
     ) -> Result<Vec<Message>, AppError> {
-        Ok(database::get_user_threads(self, user_id, limit).await?)
+        Ok(MessageDb::get_user_threads(self, user_id, limit).await?)
     }
-    
-    async fn mark_message_read(
-        &self,
-        message_id: i64
-    ) -> Result<(), AppError> {
-        Ok(database::mark_message_read(self, message_id).await?)        
+
+    #[instrument(level = "debug", skip_all)]
+    async fn mark_message_read(&self, message_id: i64) -> Result<(), AppError> {
+        Ok(MessageDb::mark_message_read(self, message_id).await?)
     }
 
-    async fn get_unread_messages(
-        &self,
-        user_id: Uuid,
-    ) -> Result<Vec<Message>, AppError> {
-        Ok(database::get_unread_messages(self, user_id).await?)
+    #[instrument(level = "debug", skip_all)]
+    async fn get_unread_messages(&self, user_id: Uuid) -> Result<Vec<Message>, AppError> {
+        Ok(MessageDb::get_unread_messages(self, user_id).await?)
     }
 }
 
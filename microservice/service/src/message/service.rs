@@ -1,22 +1,125 @@
-use db::{models::Message, uuid::Uuid};
-use shared::errors::AppError;
-
-use super::repository::MessageRepository;
+use std::sync::Arc;
+
+use base64::Engine;
+use circuits::membership::{Fr, Identity, recover_secret};
+use circuits::{message_preparation::SealedMessage, prover};
+use db::{
+    models::{AnonymousMessage, Message, RateLimitShare},
+    uuid::Uuid,
+};
+use shared::{
+    errors::AppError,
+    metrics::MESSAGES_CREATED,
+    models::{CUSTOM_ENGINE, EphemeralKeyProof, MembershipProof},
+};
+use tracing::instrument;
+
+use super::{
+    commitment,
+    proof::{EphemeralKeyVerifier, MembershipVerifier},
+    repository::MessageRepository,
+};
+use crate::membership::{self, BlockList};
 
 #[derive(Clone)]
 pub struct MessageService<R: MessageRepository> {
     repository: R,
+    proof_verifier: Option<Arc<EphemeralKeyVerifier>>,
+    membership_verifier: Option<Arc<MembershipVerifier>>,
+    blocked: Arc<BlockList>,
+    current_epoch: Arc<dyn Fn() -> u64 + Send + Sync>,
 }
 
 impl<R: MessageRepository> MessageService<R> {
     pub fn new(repository: R) -> Self {
-        Self { repository }
+        Self {
+            repository,
+            proof_verifier: None,
+            membership_verifier: None,
+            blocked: Arc::new(BlockList::new()),
+            current_epoch: Arc::new(membership::current_epoch),
+        }
+    }
+
+    /// Reads the current rate-limiting epoch from `current_epoch` instead of
+    /// the system clock.
+    pub fn with_epoch_source(
+        mut self,
+        current_epoch: impl Fn() -> u64 + Send + Sync + 'static,
+    ) -> Self {
+        self.current_epoch = Arc::new(current_epoch);
+        self
     }
 
+    /// Accept anonymous messages whose [`MembershipProof`] checks out against `verifier`.
+    pub fn with_membership_verifier(mut self, verifier: MembershipVerifier) -> Self {
+        self.membership_verifier = Some(Arc::new(verifier));
+        self
+    }
+
+    /// Accept messages carrying an [`EphemeralKeyProof`], checked with `verifier`.
+    pub fn with_proof_verifier(mut self, verifier: EphemeralKeyVerifier) -> Self {
+        self.proof_verifier = Some(Arc::new(verifier));
+        self
+    }
+
+    /// Checks that `proof` is about the sealed message in `encrypted_content`
+    /// and the message key `recipient_id` registered, then that it verifies,
+    /// so that a proof made for one message cannot be replayed onto another.
+    /// Both keys are the Jubjub points the message's key agreement ran on.
+    async fn check_ephemeral_key_proof(
+        &self,
+        recipient_id: Uuid,
+        encrypted_content: &str,
+        proof: &EphemeralKeyProof,
+    ) -> Result<(), AppError> {
+        let Some(verifier) = &self.proof_verifier else {
+            return Err(AppError::Forbidden(String::from(
+                "Ephemeral key proofs are not accepted",
+            )));
+        };
+        let decode = |field: &str| {
+            CUSTOM_ENGINE
+                .decode(field)
+                .map_err(|_| AppError::InvalidInputSyntax)
+        };
+
+        let sealed = SealedMessage::from_base64(encrypted_content)
+            .map_err(|_| AppError::InvalidInputSyntax)?;
+        let epub = prover::to_bytes(&sealed.epub)
+            .map_err(|e| AppError::InternalError(format!("Could not encode epub: {e}")))?;
+        if decode(&proof.epub)? != epub {
+            return Err(AppError::Forbidden(String::from(
+                "Ephemeral key proof is not for this message's ephemeral key",
+            )));
+        }
+
+        let recipient_key = self
+            .repository
+            .get_user_message_key(recipient_id)
+            .await?
+            .ok_or_else(|| {
+                AppError::Forbidden(String::from("Recipient has not registered a message key"))
+            })?;
+        if decode(&proof.recipient_key)? != decode(&recipient_key)? {
+            return Err(AppError::Forbidden(String::from(
+                "Ephemeral key proof is not for the recipient's message key",
+            )));
+        }
+
+        verifier.verify(proof)
+    }
+
+    #[instrument(skip_all, fields(%message_id))]
     pub async fn get_message_by_id(&self, message_id: i64) -> Result<Option<Message>, AppError> {
         self.repository.get_message_by_id(message_id).await
     }
 
+    /// Stores a message. A `commitment` must be the one carried by the sealed
+    /// message in `encrypted_content`; an `ephemeral_key_proof` is checked
+    /// against that sealed message and the recipient before it is stored
+    /// with the message.
+    #[instrument(skip_all, fields(%sender_id, %recipient_id, ?parent_id))]
     pub async fn create_message(
         &self,
         sender_id: Uuid,
@@ -24,18 +127,179 @@ impl<R: MessageRepository> MessageService<R> {
         encrypted_content: &str,
         signature: Option<String>,
         parent_id: Option<i64>,
+        commitment: Option<String>,
+        ephemeral_key_proof: Option<&EphemeralKeyProof>,
     ) -> Result<Option<i64>, AppError> {
+        if let Some(commitment) = &commitment {
+            commitment::check(commitment, encrypted_content)?;
+        }
+        if let Some(proof) = ephemeral_key_proof {
+            self.check_ephemeral_key_proof(recipient_id, encrypted_content, proof)
+                .await?;
+        }
+
+        let message_id = if commitment.is_none() && ephemeral_key_proof.is_none() {
+            self.repository
+                .insert_message(
+                    sender_id,
+                    recipient_id,
+                    encrypted_content,
+                    signature,
+                    parent_id,
+                )
+                .await
+        } else {
+            self.repository
+                .insert_attested_message(
+                    sender_id,
+                    recipient_id,
+                    encrypted_content,
+                    signature,
+                    parent_id,
+                    commitment.as_deref(),
+                    ephemeral_key_proof.map(|proof| proof.proof.as_str()),
+                )
+                .await
+        }?;
+        MESSAGES_CREATED.with_label_values(&["direct"]).inc();
+        Ok(message_id)
+    }
+
+    /// The commitment stored with `message_id`, if the sender supplied one.
+    #[instrument(skip_all, fields(%message_id))]
+    pub async fn get_message_commitment(&self, message_id: i64) -> Result<Option<String>, AppError> {
+        self.repository.get_message_commitment(message_id).await
+    }
+
+    /// Stores a message from a sender who proved membership of the anonymity
+    /// set instead of identifying themselves.
+    ///
+    /// Each member may send one anonymous message per recipient per epoch.
+    /// The proof is checked before anything is stored, and carries the
+    /// sender's nullifier for the epoch and recipient along with a share of
+    /// their identity secret evaluated at the message. A second message to
+    /// the same recipient in the same epoch reuses the nullifier
+    /// with a second share; the two recover the secret, the sender is blocked
+    /// from then on, and the message fails with [`AppError::Forbidden`].
+    /// Resending the same message fails with [`AppError::UniqueViolation`].
+    #[instrument(skip_all, fields(%recipient_id))]
+    pub async fn create_anonymous_message(
+        &self,
+        recipient_id: Uuid,
+        encrypted_content: &str,
+        proof: &MembershipProof,
+    ) -> Result<i64, AppError> {
+        let Some(verifier) = &self.membership_verifier else {
+            return Err(AppError::Forbidden(String::from(
+                "Anonymous messages are not accepted",
+            )));
+        };
+
+        // The previous epoch is still accepted, for proofs made just before
+        // it ended
+        let epoch = (self.current_epoch)();
+        let oldest_epoch = epoch.saturating_sub(1);
+        if proof.epoch != epoch && proof.epoch != oldest_epoch {
+            return Err(AppError::Forbidden(String::from(
+                "Membership proof is for an expired epoch",
+            )));
+        }
+
+        let x = membership::message_x(recipient_id, encrypted_content);
+        verifier.verify(proof, recipient_id, x)?;
+
+        let scope = membership::message_scope(proof.epoch, recipient_id);
+        let nullifier = membership::decode_field(&proof.nullifier)?;
+        let y = membership::decode_field(&proof.share)?;
+        if self
+            .blocked
+            .is_blocked(&self.repository, proof.epoch, scope, &nullifier, oldest_epoch)
+            .await?
+        {
+            return Err(AppError::Forbidden(String::from("Sender is blocked")));
+        }
+
+        // Stored re-encoded, so one nullifier can't be replayed under a
+        // different spelling of the same field element
+        let root = membership::encode_field(&membership::decode_field(&proof.root)?);
+        let share = RateLimitShare {
+            nullifier: membership::encode_field(&nullifier),
+            epoch: proof.epoch as i64,
+            share_x: membership::encode_field(&x),
+            share_y: membership::encode_field(&y),
+        };
+
+        match self
+            .repository
+            .insert_anonymous_message(recipient_id, encrypted_content, &root, &share)
+            .await
+        {
+            Err(AppError::UniqueViolation(message)) => {
+                if self.block_if_rate_limited(&share, scope, (x, y)).await? {
+                    Err(AppError::Forbidden(String::from(
+                        "Rate limit exceeded; sender is blocked",
+                    )))
+                } else {
+                    Err(AppError::UniqueViolation(message))
+                }
+            }
+            result => {
+                if result.is_ok() {
+                    MESSAGES_CREATED.with_label_values(&["anonymous"]).inc();
+                }
+                result
+            }
+        }
+    }
+
+    /// Called when `share`'s nullifier was already used this epoch. If the
+    /// earlier share was for a different message, the two recover the
+    /// sender's secret and the sender is blocked.
+    async fn block_if_rate_limited(
+        &self,
+        share: &RateLimitShare,
+        scope: Fr,
+        point: (Fr, Fr),
+    ) -> Result<bool, AppError> {
+        let Some(earlier) = self.repository.get_rate_limit_share(&share.nullifier).await? else {
+            return Ok(false);
+        };
+        let earlier_point = (
+            membership::decode_field(&earlier.share_x)?,
+            membership::decode_field(&earlier.share_y)?,
+        );
+        let Some(secret) = recover_secret(earlier_point, point) else {
+            return Ok(false);
+        };
+
+        let identity = Identity::from_secret(secret);
+        if membership::encode_field(&identity.nullifier(scope)) != share.nullifier {
+            return Err(AppError::InternalError(String::from(
+                "Recovered secret does not match the nullifier",
+            )));
+        }
         self.repository
-            .insert_message(
-                sender_id,
-                recipient_id,
-                encrypted_content,
-                signature,
-                parent_id,
+            .block_identity(
+                &membership::encode_field(&identity.commitment()),
+                &membership::encode_field(&secret),
             )
+            .await?;
+        self.blocked.insert(secret).await;
+        Ok(true)
+    }
+
+    #[instrument(skip_all, fields(%recipient_id))]
+    pub async fn get_anonymous_messages(
+        &self,
+        recipient_id: Uuid,
+        limit: Option<i64>,
+    ) -> Result<Vec<AnonymousMessage>, AppError> {
+        self.repository
+            .get_anonymous_messages(recipient_id, limit.unwrap_or(100))
             .await
     }
 
+    #[instrument(skip_all, fields(%user1_id, %user2_id))]
     pub async fn get_conversation(
         &self,
         user1_id: Uuid,
@@ -47,6 +311,7 @@ impl<R: MessageRepository> MessageService<R> {
             .await
     }
 
+    #[instrument(skip_all, fields(%parent_id))]
     pub async fn get_thread_replies(
         &self,
         parent_id: i64,
@@ -58,6 +323,7 @@ impl<R: MessageRepository> MessageService<R> {
             .await
     }
 
+    #[instrument(skip_all, fields(%thread_root_id))]
     pub async fn get_complete_thread(
         &self,
         thread_root_id: i64,
@@ -68,6 +334,7 @@ impl<R: MessageRepository> MessageService<R> {
             .await
     }
 
+    #[instrument(skip_all, fields(%user_id))]
     pub async fn get_user_threads(
         &self,
         user_id: Uuid,
@@ -83,8 +350,9 @@ mod tests {
     use async_trait::async_trait;
     use db::uuid::Uuid;
     use mockall::mock;
+    use base64::Engine;
     use mockall::predicate::*;
-    use shared::errors::AppError;
+    use shared::{errors::AppError, models::CUSTOM_ENGINE};
     use std::sync::Arc;
 
     mock! {
@@ -101,6 +369,47 @@ mod tests {
                 signature: Option<String>,
                 parent_id: Option<i64>,
             ) -> Result<Option<i64>, AppError>;
+            async fn insert_attested_message<'a>(
+                &self,
+                sender_id: Uuid,
+                recipient_id: Uuid,
+                encrypted_content: &str,
+                signature: Option<String>,
+                parent_id: Option<i64>,
+                commitment: Option<&'a str>,
+                ephemeral_key_proof: Option<&'a str>,
+            ) -> Result<Option<i64>, AppError>;
+            async fn get_message_commitment(
+                &self,
+                message_id: i64,
+            ) -> Result<Option<String>, AppError>;
+            async fn get_ephemeral_key_proof(
+                &self,
+                message_id: i64,
+            ) -> Result<Option<String>, AppError>;
+            async fn get_user_message_key(&self, user_id: Uuid) -> Result<Option<String>, AppError>;
+            async fn insert_anonymous_message(
+                &self,
+                recipient_id: Uuid,
+                encrypted_content: &str,
+                membership_root: &str,
+                share: &RateLimitShare,
+            ) -> Result<i64, AppError>;
+            async fn get_rate_limit_share(
+                &self,
+                nullifier: &str,
+            ) -> Result<Option<RateLimitShare>, AppError>;
+            async fn block_identity(
+                &self,
+                identity_commitment: &str,
+                identity_secret: &str,
+            ) -> Result<(), AppError>;
+            async fn get_blocked_identity_secrets(&self) -> Result<Vec<String>, AppError>;
+            async fn get_anonymous_messages(
+                &self,
+                recipient_id: Uuid,
+                limit: i64,
+            ) -> Result<Vec<AnonymousMessage>, AppError>;
             async fn get_conversation(
                 &self,
                 user1_id: Uuid,
@@ -236,6 +545,8 @@ mod tests {
                 encrypted_content,
                 signature,
                 parent_id,
+                None,
+                None,
             )
             .await
             .unwrap();
@@ -276,6 +587,8 @@ mod tests {
                 encrypted_content,
                 signature,
                 parent_id,
+                None,
+                None,
             )
             .await;
 
@@ -311,6 +624,8 @@ mod tests {
                 encrypted_content,
                 signature,
                 parent_id,
+                None,
+                None,
             )
             .await;
 
@@ -493,6 +808,8 @@ mod tests {
                 encrypted_content.clone(),
                 signature,
                 parent_id,
+                None,
+                None,
             )
             .await
             .unwrap();
@@ -557,6 +874,8 @@ mod tests {
                 &encrypted_content,
                 signature,
                 parent_id,
+                None,
+                None,
             )
             .await;
 
@@ -604,4 +923,451 @@ mod tests {
 
         assert!(result.is_empty());
     }
+
+    fn sealed_with_commitment(sender_id: Uuid, recipient_id: Uuid) -> (String, String) {
+        use circuits::message_preparation::{self, MessageContext};
+
+        let context = MessageContext {
+            sender_id: *sender_id.as_bytes(),
+            recipient_id: *recipient_id.as_bytes(),
+            parent_id: None,
+        };
+        let (_, pk) = message_preparation::generate_keypair();
+        let sealed = message_preparation::seal(&pk, b"hello", &context).unwrap();
+        (
+            sealed.to_base64(),
+            CUSTOM_ENGINE.encode(circuits::prover::to_bytes(&sealed.commitment).unwrap()),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_create_message_with_commitment() {
+        let mut mock_repo = MockRepository::new();
+        let sender_id = Uuid::now_v7();
+        let recipient_id = Uuid::now_v7();
+        let (encrypted_content, commitment) = sealed_with_commitment(sender_id, recipient_id);
+
+        mock_repo
+            .expect_insert_attested_message()
+            .withf({
+                let (expected_content, expected_commitment) =
+                    (encrypted_content.clone(), commitment.clone());
+                move |sender, recipient, content, signature, parent_id, stored, proof| {
+                    (*sender, *recipient, signature, *parent_id)
+                        == (sender_id, recipient_id, &None, None)
+                        && *content == expected_content
+                        && *stored == Some(expected_commitment.as_str())
+                        && proof.is_none()
+                }
+            })
+            .times(1)
+            .returning(|_, _, _, _, _, _, _| Ok(Some(1)));
+
+        let service = MessageService::new(mock_repo);
+        let result = service
+            .create_message(
+                sender_id,
+                recipient_id,
+                &encrypted_content,
+                None,
+                None,
+                Some(commitment),
+                None,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(result, Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_create_message_with_malformed_commitment() {
+        let service = MessageService::new(MockRepository::new());
+        let result = service
+            .create_message(
+                Uuid::now_v7(),
+                Uuid::now_v7(),
+                "encrypted",
+                None,
+                None,
+                Some(String::from("AAAA")),
+                None,
+            )
+            .await;
+
+        assert!(matches!(result, Err(AppError::InvalidInputSyntax)));
+    }
+
+    #[tokio::test]
+    async fn test_create_message_with_commitment_to_other_message() {
+        let (sender_id, recipient_id) = (Uuid::now_v7(), Uuid::now_v7());
+        let (encrypted_content, _) = sealed_with_commitment(sender_id, recipient_id);
+        let (_, other_commitment) = sealed_with_commitment(sender_id, recipient_id);
+
+        // Nothing is stored
+        let service = MessageService::new(MockRepository::new());
+        let result = service
+            .create_message(
+                sender_id,
+                recipient_id,
+                &encrypted_content,
+                None,
+                None,
+                Some(other_commitment),
+                None,
+            )
+            .await;
+
+        assert!(matches!(result, Err(AppError::InvalidInputSyntax)));
+    }
+
+    #[tokio::test]
+    async fn test_create_anonymous_message_without_verifier() {
+        let service = MessageService::new(MockRepository::new());
+        let proof = MembershipProof {
+            root: String::from("AAAA"),
+            epoch: 0,
+            nullifier: String::from("AAAA"),
+            share: String::from("AAAA"),
+            proof: String::from("AAAA"),
+        };
+
+        let result = service
+            .create_anonymous_message(Uuid::now_v7(), "encrypted", &proof)
+            .await;
+
+        assert!(matches!(result, Err(AppError::Forbidden(_))));
+    }
+
+    mod rate_limit {
+        use super::*;
+        use crate::{
+            membership::{AnonymitySet, encode_field, message_scope, message_x},
+            memory::InMemoryStore,
+            user::UserRepository,
+        };
+        use circuits::{
+            membership::{MembershipTree, leaf, public_key_hash_to_field},
+            prover::{self, ProvingKey, VerifyingKey},
+        };
+        use rand::{SeedableRng, rngs::StdRng};
+        use std::sync::OnceLock;
+
+        /// The epoch the services in these tests run in, however long
+        /// proving takes.
+        const EPOCH: u64 = 1_000;
+
+        fn membership_keys() -> &'static (ProvingKey, VerifyingKey) {
+            static KEYS: OnceLock<(ProvingKey, VerifyingKey)> = OnceLock::new();
+            KEYS.get_or_init(|| circuits::membership::setup(&mut StdRng::seed_from_u64(7)).unwrap())
+        }
+
+        struct Member {
+            identity: Identity,
+            public_key_hash: Fr,
+            tree: MembershipTree,
+        }
+
+        impl Member {
+            fn prove(&self, epoch: u64, recipient_id: Uuid, encrypted_content: &str) -> MembershipProof {
+                let scope = message_scope(epoch, recipient_id);
+                let x = message_x(recipient_id, encrypted_content);
+                let proof = circuits::membership::prove(
+                    &membership_keys().0,
+                    &self.identity,
+                    self.public_key_hash,
+                    &self.tree.path(0).unwrap(),
+                    scope,
+                    x,
+                    &mut StdRng::seed_from_u64(epoch),
+                )
+                .unwrap();
+
+                MembershipProof {
+                    root: encode_field(&self.tree.root()),
+                    epoch,
+                    nullifier: encode_field(&self.identity.nullifier(scope)),
+                    share: encode_field(&self.identity.share(scope, x)),
+                    proof: CUSTOM_ENGINE.encode(prover::to_bytes(&proof).unwrap()),
+                }
+            }
+        }
+
+        /// A service in [`EPOCH`] over a store holding one member, who is also
+        /// the recipient.
+        async fn setup() -> (MessageService<InMemoryStore>, InMemoryStore, Member, Uuid) {
+            let store = InMemoryStore::new();
+            let user_id = store
+                .insert_user(&CUSTOM_ENGINE.encode([9; 33]), "member")
+                .await
+                .unwrap();
+            let identity = Identity::generate(&mut StdRng::seed_from_u64(5));
+            let public_key_hash = public_key_hash_to_field(&[9; 32]);
+            let member_leaf = leaf(public_key_hash, identity.commitment());
+            store
+                .insert_membership_leaf(user_id, &encode_field(&member_leaf))
+                .await
+                .unwrap();
+            let member = Member {
+                identity,
+                public_key_hash,
+                tree: MembershipTree::from_leaves([member_leaf]),
+            };
+
+            let anonymity_set = Arc::new(AnonymitySet::load(&store).await.unwrap());
+            let verifier = MembershipVerifier::new(membership_keys().1.clone(), anonymity_set);
+            let service = MessageService::new(store.clone())
+                .with_membership_verifier(verifier)
+                .with_epoch_source(|| EPOCH);
+            (service, store, member, user_id)
+        }
+
+        #[tokio::test]
+        async fn test_second_message_in_epoch_blocks_sender() {
+            let (service, store, member, recipient_id) = setup().await;
+            let epoch = EPOCH;
+
+            let first = member.prove(epoch, recipient_id, "first");
+            service
+                .create_anonymous_message(recipient_id, "first", &first)
+                .await
+                .unwrap();
+
+            // Resending the same message reveals nothing new
+            let result = service
+                .create_anonymous_message(recipient_id, "first", &first)
+                .await;
+            assert!(matches!(result, Err(AppError::UniqueViolation(_))));
+            assert!(store.get_blocked_identity_secrets().await.unwrap().is_empty());
+
+            let second = member.prove(epoch, recipient_id, "second");
+            let result = service
+                .create_anonymous_message(recipient_id, "second", &second)
+                .await;
+            assert!(matches!(result, Err(AppError::Forbidden(_))));
+            assert_eq!(
+                store.get_blocked_identity_secrets().await.unwrap(),
+                vec![encode_field(&member.identity.secret())]
+            );
+
+            // Blocked in later epochs too
+            let later = member.prove(epoch + 1, recipient_id, "third");
+            let result = service
+                .clone()
+                .with_epoch_source(|| EPOCH + 1)
+                .create_anonymous_message(recipient_id, "third", &later)
+                .await;
+            assert!(matches!(result, Err(AppError::Forbidden(_))));
+            assert_eq!(
+                store.get_anonymous_messages(recipient_id, 10).await.unwrap().len(),
+                1
+            );
+        }
+
+        #[tokio::test]
+        async fn test_one_message_per_recipient_per_epoch() {
+            let (service, store, member, recipient_id) = setup().await;
+            let other_id = store
+                .insert_user(&CUSTOM_ENGINE.encode([8; 33]), "other")
+                .await
+                .unwrap();
+
+            for (recipient_id, content) in [(recipient_id, "first"), (other_id, "second")] {
+                let proof = member.prove(EPOCH, recipient_id, content);
+                service
+                    .create_anonymous_message(recipient_id, content, &proof)
+                    .await
+                    .unwrap();
+            }
+            assert!(store.get_blocked_identity_secrets().await.unwrap().is_empty());
+        }
+
+        #[tokio::test]
+        async fn test_proof_for_expired_epoch_is_rejected() {
+            let (service, _, member, recipient_id) = setup().await;
+            let proof = member.prove(EPOCH - 2, recipient_id, "late");
+
+            let result = service
+                .create_anonymous_message(recipient_id, "late", &proof)
+                .await;
+
+            assert!(matches!(result, Err(AppError::Forbidden(_))));
+        }
+
+        #[tokio::test]
+        async fn test_proof_for_other_message_is_rejected() {
+            let (service, store, member, recipient_id) = setup().await;
+            let proof = member.prove(EPOCH, recipient_id, "original");
+
+            let result = service
+                .create_anonymous_message(recipient_id, "tampered", &proof)
+                .await;
+
+            assert!(matches!(result, Err(AppError::Forbidden(_))));
+            assert!(store.get_blocked_identity_secrets().await.unwrap().is_empty());
+        }
+    }
+
+    fn ephemeral_key_proof(epub: &str, recipient_key: &str) -> EphemeralKeyProof {
+        EphemeralKeyProof {
+            epub: epub.to_string(),
+            recipient_key: recipient_key.to_string(),
+            proof: String::from("AAAA"),
+        }
+    }
+
+    // The bindings are checked before the proof itself, so any verifying key
+    // will do
+    fn with_any_proof_verifier(repository: MockRepository) -> MessageService<MockRepository> {
+        let vk = circuits::prover::VerifyingKey::default();
+        MessageService::new(repository).with_proof_verifier(EphemeralKeyVerifier::new(vk))
+    }
+
+    async fn create_with_proof(
+        service: &MessageService<MockRepository>,
+        recipient_id: Uuid,
+        encrypted_content: &str,
+        proof: &EphemeralKeyProof,
+    ) -> Result<Option<i64>, AppError> {
+        service
+            .create_message(
+                Uuid::now_v7(),
+                recipient_id,
+                encrypted_content,
+                None,
+                None,
+                None,
+                Some(proof),
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_create_message_with_ephemeral_key_proof_without_verifier() {
+        let (encrypted_content, _) = sealed_with_commitment(Uuid::now_v7(), Uuid::now_v7());
+        let service = MessageService::new(MockRepository::new());
+        let proof = ephemeral_key_proof("AAAA", "AAAA");
+
+        let result = create_with_proof(&service, Uuid::now_v7(), &encrypted_content, &proof).await;
+
+        assert!(matches!(result, Err(AppError::Forbidden(_))));
+    }
+
+    #[tokio::test]
+    async fn test_ephemeral_key_proof_must_be_for_the_sealed_epub() {
+        let recipient_id = Uuid::now_v7();
+        let (encrypted_content, _) = sealed_with_commitment(Uuid::now_v7(), recipient_id);
+        let (other_content, _) = sealed_with_commitment(Uuid::now_v7(), recipient_id);
+        let other_epub = SealedMessage::from_base64(&other_content).unwrap().epub;
+        let other_epub = CUSTOM_ENGINE.encode(prover::to_bytes(&other_epub).unwrap());
+
+        // Nothing is looked up or stored
+        let service = with_any_proof_verifier(MockRepository::new());
+        let proof = ephemeral_key_proof(&other_epub, "AAAA");
+
+        let result = create_with_proof(&service, recipient_id, &encrypted_content, &proof).await;
+        assert!(matches!(result, Err(AppError::Forbidden(_))));
+
+        let result = create_with_proof(&service, recipient_id, "bm90IHNlYWxlZA", &proof).await;
+        assert!(matches!(result, Err(AppError::InvalidInputSyntax)));
+    }
+
+    #[tokio::test]
+    async fn test_ephemeral_key_proof_must_be_for_the_recipient_key() {
+        let recipient_id = Uuid::now_v7();
+        let (encrypted_content, _) = sealed_with_commitment(Uuid::now_v7(), recipient_id);
+        let epub = SealedMessage::from_base64(&encrypted_content).unwrap().epub;
+        let epub = CUSTOM_ENGINE.encode(prover::to_bytes(&epub).unwrap());
+        let recipient_key = CUSTOM_ENGINE.encode([7u8; 65]);
+
+        let mut mock_repo = MockRepository::new();
+        mock_repo
+            .expect_get_user_message_key()
+            .returning(move |user_id| Ok((user_id == recipient_id).then(|| recipient_key.clone())));
+        let service = with_any_proof_verifier(mock_repo);
+
+        let proof = ephemeral_key_proof(&epub, &CUSTOM_ENGINE.encode([8u8; 65]));
+        let result = create_with_proof(&service, recipient_id, &encrypted_content, &proof).await;
+        assert!(matches!(result, Err(AppError::Forbidden(_))));
+
+        // With both bound, the proof itself is checked
+        let proof = ephemeral_key_proof(&epub, &CUSTOM_ENGINE.encode([7u8; 65]));
+        let result = create_with_proof(&service, recipient_id, &encrypted_content, &proof).await;
+        assert!(matches!(result, Err(AppError::InvalidInputSyntax)));
+
+        let result = create_with_proof(&service, Uuid::now_v7(), &encrypted_content, &proof).await;
+        assert!(matches!(result, Err(AppError::Forbidden(_))));
+    }
+
+    #[tokio::test]
+    async fn test_create_message_with_valid_ephemeral_key_proof() {
+        use circuits::message_preparation::{self, MessageContext};
+        use circuits::message_protocol::MessageProtocolCircuit;
+        use rand::{SeedableRng, rngs::StdRng};
+
+        let mut rng = StdRng::seed_from_u64(11);
+        let (proving_key, verifying_key) = prover::setup(&mut rng).unwrap();
+        let (sender_id, recipient_id) = (Uuid::now_v7(), Uuid::now_v7());
+        let context = MessageContext {
+            sender_id: *sender_id.as_bytes(),
+            recipient_id: *recipient_id.as_bytes(),
+            parent_id: None,
+        };
+
+        // The sender seals under the ephemeral key they prove knowledge of
+        let (_, message_key) = message_preparation::generate_keypair();
+        let (esk, epub) = MessageProtocolCircuit::generate_ephemeral_keys(&mut rng);
+        let sealed =
+            message_preparation::seal_with_ephemeral_key(esk, &message_key, b"hello", &context)
+                .unwrap();
+        let encode = |bytes: Vec<u8>| CUSTOM_ENGINE.encode(bytes);
+        let proof = EphemeralKeyProof {
+            epub: encode(prover::to_bytes(&epub).unwrap()),
+            recipient_key: encode(prover::to_bytes(&message_key).unwrap()),
+            proof: encode(
+                prover::to_bytes(&prover::prove(&proving_key, message_key, esk, &mut rng).unwrap())
+                    .unwrap(),
+            ),
+        };
+        let encrypted_content = sealed.to_base64();
+
+        let mut mock_repo = MockRepository::new();
+        let registered = proof.recipient_key.clone();
+        mock_repo
+            .expect_get_user_message_key()
+            .with(eq(recipient_id))
+            .times(1)
+            .returning(move |_| Ok(Some(registered.clone())));
+        mock_repo
+            .expect_insert_attested_message()
+            .withf({
+                let (expected_content, expected_proof) =
+                    (encrypted_content.clone(), proof.proof.clone());
+                move |sender, recipient, content, _, _, commitment, stored| {
+                    (*sender, *recipient) == (sender_id, recipient_id)
+                        && *content == expected_content
+                        && commitment.is_none()
+                        && *stored == Some(expected_proof.as_str())
+                }
+            })
+            .times(1)
+            .returning(|_, _, _, _, _, _, _| Ok(Some(1)));
+
+        let service = MessageService::new(mock_repo)
+            .with_proof_verifier(EphemeralKeyVerifier::new(verifying_key));
+        let result = service
+            .create_message(
+                sender_id,
+                recipient_id,
+                &encrypted_content,
+                None,
+                None,
+                None,
+                Some(&proof),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(result, Some(1));
+    }
 }
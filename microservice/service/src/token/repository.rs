@@ -1,7 +1,8 @@
 use async_trait::async_trait;
-use db::{Error as SqlxError, SqlitePool, db as database, uuid::Uuid};
+use db::{token_db::TokenDb, uuid::Uuid};
 use mockall::automock;
 use shared::errors::AppError;
+use tracing::instrument;
 
 #[automock]
 #[async_trait]
@@ -25,10 +26,20 @@ pub trait TokenRepository: Send + Sync {
         token_hash: &str,
         reason: Option<String>,
     ) -> Result<(), AppError>;
+
+    async fn revoke_user_refresh_tokens(
+        &self,
+        user_id: Uuid,
+        reason: Option<String>,
+    ) -> Result<u64, AppError>;
+
+    /// Deletes refresh tokens past their expiry, returning how many.
+    async fn cleanup_expired_tokens(&self) -> Result<u64, AppError>;
 }
 
 #[async_trait]
-impl TokenRepository for SqlitePool {
+impl<D: TokenDb + Send + Sync> TokenRepository for D {
+    #[instrument(level = "debug", skip_all)]
     async fn store_refresh_token(
         &self,
         user_id: Uuid,
@@ -36,30 +47,39 @@ impl TokenRepository for SqlitePool {
         expires_at: i64,
         device_info: Option<String>,
     ) -> Result<(), AppError> {
-        Ok(database::store_refresh_token(
-            self,
-            user_id,
-            token_hash,
-            expires_at,
-            device_info.as_deref(),
-        )
-        .await?)
+        Ok(TokenDb::store_refresh_token(self, user_id, token_hash, expires_at, device_info).await?)
     }
 
+    #[instrument(level = "debug", skip_all)]
     async fn validate_refresh_token(
         &self,
         user_id: Uuid,
         token_hash: &str,
     ) -> Result<bool, AppError> {
-        Ok(database::validate_refresh_token(self, user_id, token_hash).await?)
+        Ok(TokenDb::validate_refresh_token(self, user_id, token_hash).await?)
     }
 
+    #[instrument(level = "debug", skip_all)]
     async fn revoke_refresh_token(
         &self,
         token_hash: &str,
         reason: Option<String>,
     ) -> Result<(), AppError> {
-        Ok(database::revoke_refresh_token(self, token_hash, reason.as_deref()).await?)
+        Ok(TokenDb::revoke_refresh_token(self, token_hash, reason).await?)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn revoke_user_refresh_tokens(
+        &self,
+        user_id: Uuid,
+        reason: Option<String>,
+    ) -> Result<u64, AppError> {
+        Ok(TokenDb::revoke_user_refresh_tokens(self, user_id, reason).await?)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn cleanup_expired_tokens(&self) -> Result<u64, AppError> {
+        Ok(TokenDb::cleanup_expired_tokens(self).await?)
     }
 }
 
@@ -1,5 +1,6 @@
 use db::uuid::Uuid;
 use shared::errors::AppError;
+use tracing::instrument;
 
 use super::repository::TokenRepository;
 
@@ -13,6 +14,7 @@ impl<R: TokenRepository> TokenService<R> {
         Self { repository }
     }
 
+    #[instrument(skip_all, fields(%user_id))]
     pub async fn store_refresh_token(
         &self,
         user_id: Uuid,
@@ -25,6 +27,7 @@ impl<R: TokenRepository> TokenService<R> {
             .await
     }
 
+    #[instrument(skip_all, fields(%user_id))]
     pub async fn validate_refresh_token(
         &self,
         user_id: Uuid,
@@ -35,6 +38,7 @@ impl<R: TokenRepository> TokenService<R> {
             .await
     }
 
+    #[instrument(skip_all)]
     pub async fn revoke_refresh_token(
         &self,
         token_hash: &str,
@@ -44,6 +48,11 @@ impl<R: TokenRepository> TokenService<R> {
             .revoke_refresh_token(token_hash, reason)
             .await
     }
+
+    #[instrument(skip_all)]
+    pub async fn cleanup_expired_tokens(&self) -> Result<u64, AppError> {
+        self.repository.cleanup_expired_tokens().await
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,48 @@
+//! Transactions that span every repository.
+//!
+//! A service that needs several repository calls to succeed or fail together
+//! asks its store for a [`Work`], makes the calls through it and commits.
+//! Dropping a `Work` without committing discards everything done through it.
+
+use crate::{
+    message::repository::MessageRepository, token::repository::TokenRepository,
+    user::UserRepository,
+};
+use async_trait::async_trait;
+use db::db_trait::{Db, DbTransaction};
+use shared::errors::AppError;
+
+#[async_trait]
+pub trait UnitOfWork: Send + Sync {
+    type Work: Work;
+
+    async fn begin(&self) -> Result<Self::Work, AppError>;
+}
+
+/// The repositories bound to one open transaction.
+#[async_trait]
+pub trait Work: UserRepository + MessageRepository + TokenRepository {
+    async fn commit(self) -> Result<(), AppError>;
+
+    async fn rollback(self) -> Result<(), AppError>;
+}
+
+#[async_trait]
+impl<D: Db> UnitOfWork for D {
+    type Work = D::Transaction;
+
+    async fn begin(&self) -> Result<Self::Work, AppError> {
+        Ok(Db::begin(self).await?)
+    }
+}
+
+#[async_trait]
+impl<T: DbTransaction> Work for T {
+    async fn commit(self) -> Result<(), AppError> {
+        Ok(DbTransaction::commit(self).await?)
+    }
+
+    async fn rollback(self) -> Result<(), AppError> {
+        Ok(DbTransaction::rollback(self).await?)
+    }
+}
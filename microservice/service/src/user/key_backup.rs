@@ -0,0 +1,573 @@
+//! Server side of [`shared::key_backup`]. The server stores what the client
+//! sealed under its passphrase, encrypted once more with the server's own
+//! key, and only hands it back to whoever answers a single-use challenge
+//! with a signature by the account key or by the passphrase's recovery key.
+//!
+//! Past [`FREE_FAILED_RECOVERIES`] failed recovery-key signatures, each
+//! further failure holds the next attempt back for twice as long, up to a
+//! day, so the passphrase cannot be guessed online at any useful rate and
+//! its owner is never locked out for good. The account key is never held
+//! back, and uploading a new backup, which needs it, starts over. A hold
+//! applies to challenges issued before it too, and a user can have at most
+//! [`MAX_PENDING_CHALLENGES`] pending, so no more guesses than that can be
+//! in flight at once to race a hold being recorded.
+//!
+//! The server's encryption is bound to the user and column each value is
+//! stored in. Values still encrypted under a retired key are re-encrypted
+//! under the current one whenever a backup is recovered.
+
+use base64::Engine as _;
+use chrono::Duration;
+use db::uuid::Uuid;
+use p256::ecdsa::VerifyingKey;
+use shared::{data_encryption::KeyRing, errors::AppError, models::CUSTOM_ENGINE};
+
+/// How long a recovery challenge can be answered for.
+pub const CHALLENGE_TTL_SECONDS: i64 = 300;
+
+/// How many recovery challenges a user can have pending at once.
+pub const MAX_PENDING_CHALLENGES: i64 = 3;
+
+/// Recovery-key signatures that can fail before further ones are held back.
+pub const FREE_FAILED_RECOVERIES: i64 = 5;
+
+/// The hold after the first failure past the free ones, doubling with each
+/// failure after it up to [`MAX_RECOVERY_BACKOFF_SECONDS`].
+const RECOVERY_BACKOFF_SECONDS: i64 = 60;
+
+const MAX_RECOVERY_BACKOFF_SECONDS: i64 = 24 * 60 * 60;
+
+/// How long recovery-key signatures are refused once `failed_attempts` have
+/// failed.
+pub fn recovery_backoff(failed_attempts: i64) -> Duration {
+    let doublings = failed_attempts - FREE_FAILED_RECOVERIES;
+    if doublings < 0 {
+        return Duration::zero();
+    }
+    let seconds = RECOVERY_BACKOFF_SECONDS.saturating_mul(1 << doublings.min(32));
+    Duration::seconds(seconds.min(MAX_RECOVERY_BACKOFF_SECONDS))
+}
+
+/// The column of `key_backups` a wrapped value is stored in.
+#[derive(Debug, Clone, Copy)]
+pub enum Column {
+    EncryptedKey,
+    RecoveryKey,
+}
+
+impl Column {
+    fn associated_data(self, user_id: Uuid) -> Vec<u8> {
+        let name: &[u8] = match self {
+            Column::EncryptedKey => b"key_backups.encrypted_key/",
+            Column::RecoveryKey => b"key_backups.recovery_key/",
+        };
+        [name, user_id.as_bytes()].concat()
+    }
+}
+
+/// Encrypts `bytes` for storage in `column` of `user_id`'s backup.
+pub fn wrap(key_ring: &KeyRing, user_id: Uuid, column: Column, bytes: &[u8]) -> String {
+    CUSTOM_ENGINE.encode(key_ring.encrypt(bytes, &column.associated_data(user_id)))
+}
+
+/// Reverses [`wrap`]. If `stored` was encrypted under a retired key, also
+/// returns it wrapped again under the current one.
+pub fn unwrap(
+    key_ring: &KeyRing,
+    user_id: Uuid,
+    column: Column,
+    stored: &str,
+) -> Result<(Vec<u8>, Option<String>), AppError> {
+    let bytes = CUSTOM_ENGINE
+        .decode(stored)
+        .map_err(|_| AppError::InternalError(String::from("Stored key backup is corrupt")))?;
+    let (plaintext, refreshed) =
+        key_ring.decrypt_and_refresh(&bytes, &column.associated_data(user_id))?;
+    Ok((
+        plaintext,
+        refreshed.map(|bytes| CUSTOM_ENGINE.encode(bytes)),
+    ))
+}
+
+pub fn decode(value: &str) -> Result<Vec<u8>, AppError> {
+    CUSTOM_ENGINE
+        .decode(value)
+        .map_err(|_| AppError::InvalidInputSyntax)
+}
+
+/// The recovery key a backup was uploaded with.
+pub fn decode_recovery_key(bytes: &[u8]) -> Result<VerifyingKey, AppError> {
+    VerifyingKey::from_sec1_bytes(bytes).map_err(|_| AppError::InvalidInputSyntax)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        memory::InMemoryStore,
+        user::{UserRepository, UserService},
+    };
+    use base64::Engine as _;
+    use db::{public_key::PublicKey, uuid::Uuid};
+    use p256::{
+        ecdsa::{Signature, SigningKey, VerifyingKey, signature::Signer},
+        elliptic_curve::rand_core::OsRng,
+    };
+    use shared::{
+        data_encryption::{self, KeyRing},
+        errors::AppError,
+        key_backup::{self, PassphraseKeys},
+        models::{CUSTOM_ENGINE, RecoverKeyBackupRequest, UploadKeyBackupRequest},
+    };
+    use std::sync::Arc;
+
+    const SERVER_KEY: [u8; 32] = [7; 32];
+    const ROTATED_SERVER_KEY: [u8; 32] = [8; 32];
+    // Few iterations keep the tests fast; the server never runs the KDF
+    const ITERATIONS: u32 = 1_000;
+
+    struct Account {
+        user_id: Uuid,
+        signing_key: SigningKey,
+    }
+
+    async fn setup() -> (UserService<InMemoryStore>, InMemoryStore, Account) {
+        let store = InMemoryStore::new();
+        let signing_key = SigningKey::random(&mut OsRng);
+        let encoded = VerifyingKey::from(&signing_key).to_encoded_point(true);
+        let public_key = PublicKey::new(CUSTOM_ENGINE.encode(encoded.as_bytes())).unwrap();
+        let user_id = store
+            .insert_user(public_key.as_str(), "alice")
+            .await
+            .unwrap();
+
+        let service = UserService::new(store.clone()).with_key_ring(key_ring(1, &SERVER_KEY));
+        (
+            service,
+            store,
+            Account {
+                user_id,
+                signing_key,
+            },
+        )
+    }
+
+    fn key_ring(key_id: u32, key: &[u8]) -> Arc<KeyRing> {
+        Arc::new(KeyRing::new(key_id, key).unwrap())
+    }
+
+    fn upload_request(
+        account: &Account,
+        version: u32,
+        passphrase: &str,
+    ) -> (UploadKeyBackupRequest, PassphraseKeys) {
+        let salt = key_backup::generate_salt();
+        let keys = PassphraseKeys::derive(passphrase, &salt, ITERATIONS);
+        let encrypted_key = keys.seal(&account.signing_key.to_bytes());
+        let recovery_key = keys.recovery_key().verifying_key().to_encoded_point(true);
+
+        let payload = key_backup::upload_payload(
+            account.user_id,
+            version,
+            &encrypted_key,
+            &salt,
+            ITERATIONS,
+            recovery_key.as_bytes(),
+        );
+        let signature: Signature = account.signing_key.sign(&payload);
+
+        let request = UploadKeyBackupRequest {
+            version,
+            encrypted_key: CUSTOM_ENGINE.encode(&encrypted_key),
+            salt: CUSTOM_ENGINE.encode(salt),
+            kdf_iterations: ITERATIONS,
+            recovery_key: CUSTOM_ENGINE.encode(recovery_key.as_bytes()),
+            signature: CUSTOM_ENGINE.encode(signature.to_bytes()),
+        };
+        (request, keys)
+    }
+
+    async fn answer(
+        service: &UserService<InMemoryStore>,
+        user_id: Uuid,
+        signer: &SigningKey,
+    ) -> RecoverKeyBackupRequest {
+        let challenge = service.create_key_backup_challenge(user_id).await.unwrap();
+        let payload = key_backup::recovery_payload(
+            user_id,
+            &CUSTOM_ENGINE.decode(&challenge.challenge).unwrap(),
+        );
+        let signature: Signature = signer.sign(&payload);
+
+        RecoverKeyBackupRequest {
+            challenge: challenge.challenge,
+            signature: CUSTOM_ENGINE.encode(signature.to_bytes()),
+        }
+    }
+
+    #[tokio::test]
+    async fn passphrase_alone_recovers_the_account_key() {
+        let (service, store, account) = setup().await;
+        let (request, _) = upload_request(&account, 1, "correct horse");
+        service
+            .upload_key_backup(account.user_id, request.clone())
+            .await
+            .unwrap();
+
+        // Stored wrapped, not as uploaded
+        let stored = store
+            .get_key_backup(account.user_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_ne!(stored.encrypted_key, request.encrypted_key);
+
+        // A new device knows only the passphrase, and learns the salt from
+        // the challenge
+        let challenge = service
+            .create_key_backup_challenge(account.user_id)
+            .await
+            .unwrap();
+        assert_eq!(challenge.salt, request.salt);
+        let salt = CUSTOM_ENGINE.decode(&challenge.salt).unwrap();
+        let keys = PassphraseKeys::derive("correct horse", &salt, challenge.kdf_iterations);
+
+        let recover = answer(&service, account.user_id, keys.recovery_key()).await;
+        let backup = service
+            .recover_key_backup(account.user_id, recover)
+            .await
+            .unwrap();
+
+        let sealed = CUSTOM_ENGINE.decode(&backup.encrypted_key).unwrap();
+        assert_eq!(
+            keys.open(&sealed).unwrap(),
+            account.signing_key.to_bytes().to_vec()
+        );
+    }
+
+    #[tokio::test]
+    async fn account_key_also_answers_the_challenge() {
+        let (service, _, account) = setup().await;
+        let (request, _) = upload_request(&account, 1, "correct horse");
+        service
+            .upload_key_backup(account.user_id, request.clone())
+            .await
+            .unwrap();
+
+        let recover = answer(&service, account.user_id, &account.signing_key).await;
+        let backup = service
+            .recover_key_backup(account.user_id, recover)
+            .await
+            .unwrap();
+        assert_eq!(backup.encrypted_key, request.encrypted_key);
+    }
+
+    #[tokio::test]
+    async fn challenges_are_single_use() {
+        let (service, _, account) = setup().await;
+        let (request, _) = upload_request(&account, 1, "correct horse");
+        service
+            .upload_key_backup(account.user_id, request)
+            .await
+            .unwrap();
+
+        let recover = answer(&service, account.user_id, &account.signing_key).await;
+        service
+            .recover_key_backup(account.user_id, recover.clone())
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            service.recover_key_backup(account.user_id, recover).await,
+            Err(AppError::Forbidden(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn failed_recoveries_hold_back_the_passphrase() {
+        let (service, store, account) = setup().await;
+        let (request, keys) = upload_request(&account, 1, "correct horse");
+        service
+            .upload_key_backup(account.user_id, request)
+            .await
+            .unwrap();
+
+        let wrong = PassphraseKeys::derive("wrong", &[0; 16], ITERATIONS);
+        for _ in 0..super::FREE_FAILED_RECOVERIES {
+            let recover = answer(&service, account.user_id, wrong.recovery_key()).await;
+            assert!(matches!(
+                service.recover_key_backup(account.user_id, recover).await,
+                Err(AppError::Forbidden(_))
+            ));
+        }
+
+        // Even the right passphrase has to wait now, but not the account key
+        let recover = answer(&service, account.user_id, keys.recovery_key()).await;
+        match service.recover_key_backup(account.user_id, recover).await {
+            Err(AppError::RateLimited(wait)) => {
+                assert!(wait > std::time::Duration::from_secs(55));
+                assert!(wait <= std::time::Duration::from_secs(60));
+            }
+            other => panic!("expected to be held back, got {other:?}"),
+        }
+        let recover = answer(&service, account.user_id, &account.signing_key).await;
+        assert!(
+            service
+                .recover_key_backup(account.user_id, recover)
+                .await
+                .is_ok()
+        );
+
+        // A newer backup, which takes the account key, starts over
+        let (request, keys) = upload_request(&account, 2, "correct horse");
+        service
+            .upload_key_backup(account.user_id, request)
+            .await
+            .unwrap();
+        let recover = answer(&service, account.user_id, keys.recovery_key()).await;
+        assert!(
+            service
+                .recover_key_backup(account.user_id, recover)
+                .await
+                .is_ok()
+        );
+        let stored = store.get_key_backup(account.user_id).await.unwrap().unwrap();
+        assert_eq!(stored.failed_attempts, 0);
+    }
+
+    #[tokio::test]
+    async fn holds_apply_to_challenges_issued_before_them() {
+        let (service, store, account) = setup().await;
+        let (request, _) = upload_request(&account, 1, "correct horse");
+        service
+            .upload_key_backup(account.user_id, request)
+            .await
+            .unwrap();
+
+        let wrong = PassphraseKeys::derive("wrong", &[0; 16], ITERATIONS);
+        for _ in 1..super::FREE_FAILED_RECOVERIES {
+            let recover = answer(&service, account.user_id, wrong.recovery_key()).await;
+            assert!(matches!(
+                service.recover_key_backup(account.user_id, recover).await,
+                Err(AppError::Forbidden(_))
+            ));
+        }
+
+        // Guesses lined up in advance stop being checked at the last free one
+        let mut guesses = Vec::new();
+        for _ in 0..super::MAX_PENDING_CHALLENGES {
+            guesses.push(answer(&service, account.user_id, wrong.recovery_key()).await);
+        }
+        let mut results = Vec::new();
+        for guess in guesses {
+            results.push(service.recover_key_backup(account.user_id, guess).await);
+        }
+        assert!(matches!(results[0], Err(AppError::Forbidden(_))));
+        assert!(
+            results[1..]
+                .iter()
+                .all(|result| matches!(result, Err(AppError::RateLimited(_))))
+        );
+        let stored = store.get_key_backup(account.user_id).await.unwrap().unwrap();
+        assert_eq!(stored.failed_attempts, super::FREE_FAILED_RECOVERIES);
+    }
+
+    #[tokio::test]
+    async fn pending_challenges_are_limited() {
+        let (service, _, account) = setup().await;
+        let (request, _) = upload_request(&account, 1, "correct horse");
+        service
+            .upload_key_backup(account.user_id, request)
+            .await
+            .unwrap();
+
+        let mut pending = Vec::new();
+        for _ in 0..super::MAX_PENDING_CHALLENGES {
+            pending.push(
+                service
+                    .create_key_backup_challenge(account.user_id)
+                    .await
+                    .unwrap(),
+            );
+        }
+        assert!(matches!(
+            service.create_key_backup_challenge(account.user_id).await,
+            Err(AppError::RateLimited(_))
+        ));
+
+        // Answering one makes room for another
+        let challenge = pending.swap_remove(0).challenge;
+        let payload = key_backup::recovery_payload(
+            account.user_id,
+            &CUSTOM_ENGINE.decode(&challenge).unwrap(),
+        );
+        let signature: Signature = account.signing_key.sign(&payload);
+        let recover = RecoverKeyBackupRequest {
+            challenge,
+            signature: CUSTOM_ENGINE.encode(signature.to_bytes()),
+        };
+        service
+            .recover_key_backup(account.user_id, recover)
+            .await
+            .unwrap();
+        assert!(
+            service
+                .create_key_backup_challenge(account.user_id)
+                .await
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn recovery_backoff_doubles_up_to_a_day() {
+        let backoff = |failed_attempts| super::recovery_backoff(failed_attempts).num_seconds();
+
+        assert_eq!(backoff(super::FREE_FAILED_RECOVERIES - 1), 0);
+        assert_eq!(backoff(super::FREE_FAILED_RECOVERIES), 60);
+        assert_eq!(backoff(super::FREE_FAILED_RECOVERIES + 1), 120);
+        assert_eq!(backoff(super::FREE_FAILED_RECOVERIES + 2), 240);
+        assert_eq!(backoff(super::FREE_FAILED_RECOVERIES + 20), 24 * 60 * 60);
+        assert_eq!(backoff(i64::MAX), 24 * 60 * 60);
+    }
+
+    #[tokio::test]
+    async fn new_challenges_leave_earlier_ones_answerable() {
+        let (service, _, account) = setup().await;
+        let (request, _) = upload_request(&account, 1, "correct horse");
+        service
+            .upload_key_backup(account.user_id, request)
+            .await
+            .unwrap();
+
+        // Someone else asking for a challenge doesn't cut the owner off
+        let recover = answer(&service, account.user_id, &account.signing_key).await;
+        service
+            .create_key_backup_challenge(account.user_id)
+            .await
+            .unwrap();
+        assert!(
+            service
+                .recover_key_backup(account.user_id, recover)
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn upload_needs_the_account_key_and_a_newer_version() {
+        let (service, _, account) = setup().await;
+        let mallory = Account {
+            user_id: account.user_id,
+            signing_key: SigningKey::random(&mut OsRng),
+        };
+
+        let (forged, _) = upload_request(&mallory, 1, "mallory");
+        assert!(matches!(
+            service.upload_key_backup(account.user_id, forged).await,
+            Err(AppError::Forbidden(_))
+        ));
+
+        let (first, _) = upload_request(&account, 2, "correct horse");
+        service
+            .upload_key_backup(account.user_id, first)
+            .await
+            .unwrap();
+        let (replayed, _) = upload_request(&account, 2, "other");
+        assert!(matches!(
+            service.upload_key_backup(account.user_id, replayed).await,
+            Err(AppError::UniqueViolation(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn recovery_moves_the_backup_onto_the_current_key() {
+        let (service, store, account) = setup().await;
+        let (request, _) = upload_request(&account, 1, "correct horse");
+        service
+            .upload_key_backup(account.user_id, request.clone())
+            .await
+            .unwrap();
+
+        let mut rotated = KeyRing::new(1, &SERVER_KEY).unwrap();
+        rotated.rotate(2, &ROTATED_SERVER_KEY).unwrap();
+        let service = UserService::new(store.clone()).with_key_ring(Arc::new(rotated));
+        let key_id =
+            |stored: &str| data_encryption::key_id(&CUSTOM_ENGINE.decode(stored).unwrap()).unwrap();
+
+        let recover = answer(&service, account.user_id, &account.signing_key).await;
+        let backup = service
+            .recover_key_backup(account.user_id, recover)
+            .await
+            .unwrap();
+        assert_eq!(backup.encrypted_key, request.encrypted_key);
+
+        let stored = store
+            .get_key_backup(account.user_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(key_id(&stored.encrypted_key), 2);
+        assert_eq!(key_id(&stored.recovery_key), 2);
+
+        // The retired key is no longer needed for this backup
+        let service = UserService::new(store).with_key_ring(key_ring(2, &ROTATED_SERVER_KEY));
+        let recover = answer(&service, account.user_id, &account.signing_key).await;
+        assert!(
+            service
+                .recover_key_backup(account.user_id, recover)
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn wrapped_values_cannot_be_swapped_between_users() {
+        let (service, store, account) = setup().await;
+        let (other_service, other_store, other) = setup().await;
+        let (request, _) = upload_request(&account, 1, "correct horse");
+        service
+            .upload_key_backup(account.user_id, request)
+            .await
+            .unwrap();
+        let (other_request, _) = upload_request(&other, 1, "other");
+        other_service
+            .upload_key_backup(other.user_id, other_request)
+            .await
+            .unwrap();
+
+        // Copy the first user's stored backup over the second's
+        let stored = store
+            .get_key_backup(account.user_id)
+            .await
+            .unwrap()
+            .unwrap();
+        other_store
+            .rewrap_key_backup(
+                other.user_id,
+                1,
+                &stored.encrypted_key,
+                &stored.recovery_key,
+            )
+            .await
+            .unwrap();
+
+        let recover = answer(&other_service, other.user_id, &other.signing_key).await;
+        assert!(matches!(
+            other_service
+                .recover_key_backup(other.user_id, recover)
+                .await,
+            Err(AppError::InternalError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn backups_are_refused_without_a_server_key() {
+        let (_, store, account) = setup().await;
+        let service = UserService::new(store);
+        let (request, _) = upload_request(&account, 1, "correct horse");
+
+        assert!(matches!(
+            service.upload_key_backup(account.user_id, request).await,
+            Err(AppError::Forbidden(_))
+        ));
+    }
+}
@@ -1,17 +1,42 @@
+pub mod key_backup;
+pub mod prekeys;
+pub mod safety_number;
+
 use async_trait::async_trait;
+use chrono::{Duration, NaiveDateTime, Utc};
+use base64::Engine as _;
+use circuits::message_preparation;
 use db::{
-    Error as SqlxError, SqlitePool, db as database,
+    Error as SqlxError,
     faker_rand::en_us::names::FullName,
-    models::User,
+    models::{KeyBackup, MembershipLeaf, OneTimePrekey, SignedPrekey, User, VerifiedContact},
     public_key::PublicKey,
     public_key_hash::PublicKeyHash,
+    user_db::UserDb,
     uuid::{self, Uuid},
 };
+use crate::{
+    membership::{self, AnonymitySet},
+    token::repository::TokenRepository,
+    unit_of_work::{UnitOfWork, Work},
+};
+use key_backup::Column;
 use mockall::automock;
+use p256::ecdsa::{Signature, VerifyingKey, signature::Verifier};
+use safety_number::SafetyNumber;
 use shared::{
+    data_encryption::KeyRing,
     errors::AppError,
-    models::{RegisterRequest, RegisterResponse, UpdateUserRequest},
+    models::{
+        CUSTOM_ENGINE, ContactVerificationResponse, KeyBackupChallengeResponse,
+        KeyBackupResponse, MembershipTreeResponse, PrekeyBundleResponse, PrekeysResponse,
+        RecoverKeyBackupRequest, RegisterRequest, RegisterResponse, SignedOneTimePrekey,
+        UpdateUserRequest, UploadKeyBackupRequest, UploadPrekeysRequest, VerifyContactRequest,
+    },
 };
+use std::sync::Arc;
+use tracing::instrument;
+use validator::{ValidationError, ValidationErrors};
 
 #[automock]
 #[async_trait]
@@ -33,6 +58,119 @@ pub trait UserRepository: Send + Sync {
     ) -> Result<(), AppError>;
 
     async fn fetch_public_key_hash(&self, user_id: Uuid) -> Result<String, AppError>;
+
+    async fn mark_contact_verified(
+        &self,
+        user_id: Uuid,
+        contact_id: Uuid,
+        public_key_hash: &str,
+    ) -> Result<(), AppError>;
+
+    async fn get_verified_contact(
+        &self,
+        user_id: Uuid,
+        contact_id: Uuid,
+    ) -> Result<Option<VerifiedContact>, AppError>;
+
+    /// Registers the Jubjub key messages to the user are sealed to, replacing
+    /// any earlier one.
+    async fn set_message_key(&self, user_id: Uuid, message_key: &str) -> Result<(), AppError>;
+
+    /// Appends `leaf` to the anonymity set. A user contributes at most one leaf.
+    async fn insert_membership_leaf(&self, user_id: Uuid, leaf: &str) -> Result<i64, AppError>;
+
+    /// The leaves with an index above `after_index`, in index order.
+    async fn get_membership_leaves(&self, after_index: i64)
+    -> Result<Vec<MembershipLeaf>, AppError>;
+
+    /// Stores `user_id`'s signed prekey unless the stored one has the same or
+    /// a newer `prekey_id`. Returns whether it was stored.
+    async fn upsert_signed_prekey(
+        &self,
+        user_id: Uuid,
+        identity_key: &str,
+        prekey_id: i64,
+        prekey: &str,
+        signature: &str,
+    ) -> Result<bool, AppError>;
+
+    async fn get_signed_prekey(&self, user_id: Uuid) -> Result<Option<SignedPrekey>, AppError>;
+
+    /// Adds `prekeys` to `user_id`'s one-time prekeys, skipping ids the user
+    /// has uploaded before. Returns how many were added.
+    async fn insert_one_time_prekeys(
+        &self,
+        user_id: Uuid,
+        prekeys: &[OneTimePrekey],
+    ) -> Result<u64, AppError>;
+
+    /// Takes `user_id`'s unclaimed one-time prekey with the lowest id, if any.
+    /// No key is returned twice.
+    async fn claim_one_time_prekey(&self, user_id: Uuid)
+    -> Result<Option<OneTimePrekey>, AppError>;
+
+    /// How many of `user_id`'s one-time prekeys are unclaimed.
+    async fn count_one_time_prekeys(&self, user_id: Uuid) -> Result<i64, AppError>;
+
+    /// Stores `user_id`'s key backup unless the stored one has the same or a
+    /// newer `version`, forgetting failed attempts. Challenges issued for the
+    /// old backup no longer answer. Returns whether it was stored.
+    async fn upsert_key_backup(
+        &self,
+        user_id: Uuid,
+        version: i64,
+        encrypted_key: &str,
+        salt: &str,
+        kdf_iterations: i64,
+        recovery_key: &str,
+    ) -> Result<bool, AppError>;
+
+    async fn get_key_backup(&self, user_id: Uuid) -> Result<Option<KeyBackup>, AppError>;
+
+    /// Adds a recovery challenge for `user_id`'s current key backup. Other
+    /// pending challenges stay valid; those expired by `now` are dropped.
+    /// Returns whether it was added, which it isn't if the user has no backup
+    /// or already has `max_pending` challenges pending.
+    async fn set_key_backup_challenge(
+        &self,
+        user_id: Uuid,
+        challenge: &str,
+        now: NaiveDateTime,
+        expires_at: NaiveDateTime,
+        max_pending: i64,
+    ) -> Result<bool, AppError>;
+
+    /// Removes the pending challenge `challenge` if it expires after `now`,
+    /// returning the backup, unless a newer backup was uploaded since it was
+    /// issued. A challenge can be taken once. Unless `ignore_backoff`,
+    /// nothing is taken while failed recoveries hold the backup back.
+    async fn take_key_backup_challenge(
+        &self,
+        user_id: Uuid,
+        challenge: &str,
+        now: NaiveDateTime,
+        ignore_backoff: bool,
+    ) -> Result<Option<KeyBackup>, AppError>;
+
+    async fn rewrap_key_backup(
+        &self,
+        user_id: Uuid,
+        version: i64,
+        encrypted_key: &str,
+        recovery_key: &str,
+    ) -> Result<bool, AppError>;
+
+    /// Counts a failed recovery-key attempt, returning how many there have
+    /// been, or `None` if the user has no backup.
+    async fn record_failed_key_recovery(&self, user_id: Uuid) -> Result<Option<i64>, AppError>;
+
+    /// Refuses recovery-key attempts until `retry_at`, unless they are
+    /// already refused for longer.
+    async fn hold_back_key_recovery(
+        &self,
+        user_id: Uuid,
+        retry_at: NaiveDateTime,
+    ) -> Result<(), AppError>;
 }
 
 impl Clone for MockUserRepository {
@@ -42,34 +180,39 @@ impl Clone for MockUserRepository {
 }
 
 #[async_trait]
-impl UserRepository for SqlitePool {
+impl<D: UserDb + Send + Sync> UserRepository for D {
+    #[instrument(level = "debug", skip_all)]
     async fn insert_user(&self, public_key: &str, username: &str) -> Result<Uuid, AppError> {
         let pkey = PublicKey::new(public_key.to_string())?;
 
         let pkey_hash = pkey.to_hash()?;
 
-        let uid = database::insert_user(self, &pkey_hash, &pkey, username).await?;
+        let uid = UserDb::insert_user(self, &pkey_hash, &pkey, username).await?;
 
         Ok(uid)
     }
 
+    #[instrument(level = "debug", skip_all)]
     async fn get_user_by_pubkey(&self, public_key_hash: &str) -> Result<User, AppError> {
         let pkey_hash = PublicKeyHash::new(public_key_hash.to_string())?;
 
-        let user = database::get_user_by_pubkey(self, &pkey_hash).await?;
+        let user = UserDb::get_user_by_pubkey(self, &pkey_hash).await?;
         Ok(user)
     }
 
+    #[instrument(level = "debug", skip_all)]
     async fn get_user_by_id(&self, user_id: Uuid) -> Result<User, AppError> {
-        let user = database::get_user_by_id(self, user_id).await?;
+        let user = UserDb::get_user_by_id(self, user_id).await?;
         Ok(user)
     }
 
+    #[instrument(level = "debug", skip_all)]
     async fn get_users(&self, limit: Option<i64>) -> Result<Vec<User>, AppError> {
-        let users = database::get_users(self, limit).await?;
+        let users = UserDb::get_users(self, limit).await?;
         Ok(users)
     }
 
+    #[instrument(level = "debug", skip_all)]
     async fn update_user(
         &self,
         user_id: Uuid,
@@ -85,7 +228,7 @@ impl UserRepository for SqlitePool {
             (None, None)
         };
 
-        database::update_user(
+        UserDb::update_user(
             self,
             user_id,
             new_username.as_deref(),
@@ -97,98 +240,806 @@ impl UserRepository for SqlitePool {
         Ok(())
     }
 
+    #[instrument(level = "debug", skip_all)]
     async fn fetch_public_key_hash(&self, user_id: Uuid) -> Result<String, AppError> {
-        let pk_hash = database::fetch_public_key_hash(self, user_id).await?;
+        let pk_hash = UserDb::fetch_public_key_hash(self, user_id).await?;
 
         Ok(pk_hash)
     }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn mark_contact_verified(
+        &self,
+        user_id: Uuid,
+        contact_id: Uuid,
+        public_key_hash: &str,
+    ) -> Result<(), AppError> {
+        let pkey_hash = PublicKeyHash::new(public_key_hash.to_string())?;
+
+        UserDb::mark_contact_verified(self, user_id, contact_id, &pkey_hash).await?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn get_verified_contact(
+        &self,
+        user_id: Uuid,
+        contact_id: Uuid,
+    ) -> Result<Option<VerifiedContact>, AppError> {
+        Ok(UserDb::get_verified_contact(self, user_id, contact_id).await?)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn set_message_key(&self, user_id: Uuid, message_key: &str) -> Result<(), AppError> {
+        Ok(UserDb::set_message_key(self, user_id, message_key).await?)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn insert_membership_leaf(&self, user_id: Uuid, leaf: &str) -> Result<i64, AppError> {
+        Ok(UserDb::insert_membership_leaf(self, user_id, leaf).await?)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn get_membership_leaves(
+        &self,
+        after_index: i64,
+    ) -> Result<Vec<MembershipLeaf>, AppError> {
+        Ok(UserDb::get_membership_leaves(self, after_index).await?)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn upsert_signed_prekey(
+        &self,
+        user_id: Uuid,
+        identity_key: &str,
+        prekey_id: i64,
+        prekey: &str,
+        signature: &str,
+    ) -> Result<bool, AppError> {
+        Ok(UserDb::upsert_signed_prekey(self, user_id, identity_key, prekey_id, prekey, signature).await?)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn get_signed_prekey(&self, user_id: Uuid) -> Result<Option<SignedPrekey>, AppError> {
+        Ok(UserDb::get_signed_prekey(self, user_id).await?)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn insert_one_time_prekeys(
+        &self,
+        user_id: Uuid,
+        prekeys: &[OneTimePrekey],
+    ) -> Result<u64, AppError> {
+        Ok(UserDb::insert_one_time_prekeys(self, user_id, prekeys).await?)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn claim_one_time_prekey(
+        &self,
+        user_id: Uuid,
+    ) -> Result<Option<OneTimePrekey>, AppError> {
+        Ok(UserDb::claim_one_time_prekey(self, user_id).await?)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn count_one_time_prekeys(&self, user_id: Uuid) -> Result<i64, AppError> {
+        Ok(UserDb::count_one_time_prekeys(self, user_id).await?)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn upsert_key_backup(
+        &self,
+        user_id: Uuid,
+        version: i64,
+        encrypted_key: &str,
+        salt: &str,
+        kdf_iterations: i64,
+        recovery_key: &str,
+    ) -> Result<bool, AppError> {
+        Ok(UserDb::upsert_key_backup(
+            self,
+            user_id,
+            version,
+            encrypted_key,
+            salt,
+            kdf_iterations,
+            recovery_key,
+        )
+        .await?)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn get_key_backup(&self, user_id: Uuid) -> Result<Option<KeyBackup>, AppError> {
+        Ok(UserDb::get_key_backup(self, user_id).await?)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn set_key_backup_challenge(
+        &self,
+        user_id: Uuid,
+        challenge: &str,
+        now: NaiveDateTime,
+        expires_at: NaiveDateTime,
+        max_pending: i64,
+    ) -> Result<bool, AppError> {
+        Ok(
+            UserDb::set_key_backup_challenge(self, user_id, challenge, now, expires_at, max_pending)
+                .await?,
+        )
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn take_key_backup_challenge(
+        &self,
+        user_id: Uuid,
+        challenge: &str,
+        now: NaiveDateTime,
+        ignore_backoff: bool,
+    ) -> Result<Option<KeyBackup>, AppError> {
+        Ok(UserDb::take_key_backup_challenge(self, user_id, challenge, now, ignore_backoff).await?)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn rewrap_key_backup(
+        &self,
+        user_id: Uuid,
+        version: i64,
+        encrypted_key: &str,
+        recovery_key: &str,
+    ) -> Result<bool, AppError> {
+        Ok(UserDb::rewrap_key_backup(self, user_id, version, encrypted_key, recovery_key).await?)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn record_failed_key_recovery(&self, user_id: Uuid) -> Result<Option<i64>, AppError> {
+        Ok(UserDb::record_failed_key_recovery(self, user_id).await?)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn hold_back_key_recovery(
+        &self,
+        user_id: Uuid,
+        retry_at: NaiveDateTime,
+    ) -> Result<(), AppError> {
+        Ok(UserDb::hold_back_key_recovery(self, user_id, retry_at).await?)
+    }
 }
 
 #[derive(Clone)]
 pub struct UserService<R: UserRepository> {
     repository: R,
+    anonymity_set: Arc<AnonymitySet>,
+    key_ring: Option<Arc<KeyRing>>,
 }
 
 impl<R: UserRepository> UserService<R> {
     pub fn new(repository: R) -> Self {
-        Self { repository }
+        Self {
+            repository,
+            anonymity_set: Arc::new(AnonymitySet::new()),
+            key_ring: None,
+        }
     }
 
-    pub async fn register_user(
-        &self,
-        request: RegisterRequest,
-    ) -> Result<RegisterResponse, AppError> {
-        let user_id = uuid::Uuid::now_v7();
-
-        let username = match request.username {
-            Some(name) => name,
-            None => generate_random_username(),
-        };
-
-        let pkeyclone = request.public_key.as_str();
+    /// Accept key backups, storing them encrypted under `key_ring`.
+    pub fn with_key_ring(mut self, key_ring: Arc<KeyRing>) -> Self {
+        self.key_ring = Some(key_ring);
+        self
+    }
 
-        let user_id = self
-            .repository
-            .insert_user(&request.public_key, &username)
-            .await?;
+    /// Keep `anonymity_set` up to date as users register, so anonymous
+    /// messages can be checked against it.
+    pub fn with_anonymity_set(mut self, anonymity_set: Arc<AnonymitySet>) -> Self {
+        self.anonymity_set = anonymity_set;
+        self
+    }
 
-        Ok(RegisterResponse {
-            user_id: user_id,
-            username: username.to_string(),
+    /// The current root and leaves of the anonymity set, including any
+    /// registered through other instances sharing the repository.
+    #[instrument(skip_all)]
+    pub async fn get_membership_tree(&self) -> Result<MembershipTreeResponse, AppError> {
+        self.anonymity_set.sync(&self.repository).await?;
+
+        Ok(MembershipTreeResponse {
+            root: membership::encode_field(&self.anonymity_set.root()),
+            leaves: self
+                .anonymity_set
+                .leaves()
+                .iter()
+                .map(membership::encode_field)
+                .collect(),
         })
     }
 
+    #[instrument(skip_all)]
     pub async fn get_user_by_public_key(&self, public_key: &str) -> Result<User, AppError> {
         let pkey = PublicKey::new(public_key.to_string())?;
         let public_key_hash = pkey.to_hash()?;
         self.repository.get_user_by_pubkey(public_key_hash.as_str()).await
     }
 
+    #[instrument(skip_all, fields(%user_id))]
     pub async fn get_user_by_id(&self, user_id: Uuid) -> Result<User, AppError> {
         self.repository.get_user_by_id(user_id).await
     }
 
+    #[instrument(skip_all)]
     pub async fn get_users(&self, limit: Option<i64>) -> Result<Vec<User>, AppError> {
         self.repository.get_users(limit).await
     }
 
+    #[instrument(skip_all, fields(%user_id))]
     pub async fn fetch_public_key_hash(&self, user_id: Uuid) -> Result<String, AppError> {
         self.repository.fetch_public_key_hash(user_id).await
     }
 
-    pub async fn update_user(
+    /// Computes the safety number two users compare out-of-band to verify each other's keys.
+    #[instrument(skip_all, fields(%user_id, %contact_id))]
+    pub async fn get_safety_number(
         &self,
         user_id: Uuid,
-        request: UpdateUserRequest,
+        contact_id: Uuid,
+    ) -> Result<SafetyNumber, AppError> {
+        let user = self.repository.get_user_by_id(user_id).await?;
+        let contact = self.repository.get_user_by_id(contact_id).await?;
+
+        SafetyNumber::derive(user.id, &user.public_key, contact.id, &contact.public_key)
+    }
+
+    /// Records that `user_id` verified `contact_id`, pinning the contact's current key.
+    /// The submitted safety number must match the one derived from the current keys.
+    #[instrument(skip_all, fields(%user_id, %contact_id))]
+    pub async fn verify_contact(
+        &self,
+        user_id: Uuid,
+        contact_id: Uuid,
+        request: VerifyContactRequest,
     ) -> Result<(), AppError> {
-        let mut public_key_hash: Option<String> = None;
-        if let Some(ref public_key) = request.new_public_key {
-            public_key_hash = Some(sha256_hash(&public_key)?);
+        if user_id == contact_id {
+            return Err(safety_number_error(
+                "self_verification",
+                "Users cannot verify themselves",
+            ));
+        }
+
+        let user = self.repository.get_user_by_id(user_id).await?;
+        let contact = self.repository.get_user_by_id(contact_id).await?;
+
+        let current =
+            SafetyNumber::derive(user.id, &user.public_key, contact.id, &contact.public_key)?;
+        if !current.matches(&request.safety_number) {
+            return Err(safety_number_error(
+                "safety_number_mismatch",
+                "Safety number does not match the current keys",
+            ));
         }
 
         self.repository
-            .update_user(
+            .mark_contact_verified(user_id, contact_id, contact.public_key_hash.as_str())
+            .await
+    }
+
+    /// Reports whether `contact_id` is still verified by `user_id`, flagging a key change
+    /// since the verification so clients can warn.
+    #[instrument(skip_all, fields(%user_id, %contact_id))]
+    pub async fn get_contact_verification(
+        &self,
+        user_id: Uuid,
+        contact_id: Uuid,
+    ) -> Result<ContactVerificationResponse, AppError> {
+        let contact = self.repository.get_user_by_id(contact_id).await?;
+        let verified = self
+            .repository
+            .get_verified_contact(user_id, contact_id)
+            .await?;
+
+        Ok(match verified {
+            Some(record) => {
+                let key_changed = record.public_key_hash != contact.public_key_hash;
+                ContactVerificationResponse {
+                    contact_id,
+                    verified: !key_changed,
+                    key_changed,
+                    verified_at: Some(record.verified_at.and_utc().timestamp()),
+                }
+            }
+            None => ContactVerificationResponse {
+                contact_id,
+                verified: false,
+                key_changed: false,
+                verified_at: None,
+            },
+        })
+    }
+
+    /// Publishes `user_id`'s identity key, signed prekey and any one-time
+    /// prekeys once the user's account key is shown to have signed them all.
+    /// The signed prekey id must be above the stored one, so an earlier
+    /// upload cannot be replayed over a newer one; otherwise this fails with
+    /// [`AppError::UniqueViolation`]. Re-sending the stored signed prekey
+    /// unchanged is allowed, so that one-time prekeys can be topped up on
+    /// their own.
+    #[instrument(skip_all, fields(%user_id))]
+    pub async fn upload_prekeys(
+        &self,
+        user_id: Uuid,
+        request: UploadPrekeysRequest,
+    ) -> Result<PrekeysResponse, AppError> {
+        let user = self.repository.get_user_by_id(user_id).await?;
+        prekeys::verify_upload(&user.public_key, &request)?;
+
+        let signed_prekey_id = i64::from(request.signed_prekey_id);
+        let stored = self
+            .repository
+            .upsert_signed_prekey(
                 user_id,
-                request.new_username,
-                request.new_public_key,
-                public_key_hash,
+                &request.identity_key,
+                signed_prekey_id,
+                &request.signed_prekey,
+                &request.signature,
             )
             .await?;
+        if !stored {
+            let unchanged = self
+                .repository
+                .get_signed_prekey(user_id)
+                .await?
+                .is_some_and(|current| {
+                    current.prekey_id == signed_prekey_id
+                        && current.identity_key == request.identity_key
+                        && current.prekey == request.signed_prekey
+                        && current.signature == request.signature
+                });
+            if !unchanged {
+                return Err(AppError::UniqueViolation(String::from(
+                    "A signed prekey with the same or a newer id is already published",
+                )));
+            }
+        }
+
+        let one_time_prekeys: Vec<OneTimePrekey> = request
+            .one_time_prekeys
+            .into_iter()
+            .map(|prekey| OneTimePrekey {
+                prekey_id: i64::from(prekey.prekey_id),
+                prekey: prekey.prekey,
+                signature: prekey.signature,
+            })
+            .collect();
+        self.repository
+            .insert_one_time_prekeys(user_id, &one_time_prekeys)
+            .await?;
+
+        self.get_prekeys(user_id).await
+    }
+
+    #[instrument(skip_all, fields(%user_id))]
+    pub async fn get_prekeys(&self, user_id: Uuid) -> Result<PrekeysResponse, AppError> {
+        let prekey = self.signed_prekey(user_id).await?;
+        let remaining = self.repository.count_one_time_prekeys(user_id).await?;
+        let remaining = u32::try_from(remaining).unwrap_or(u32::MAX);
+
+        Ok(PrekeysResponse {
+            user_id,
+            identity_key: prekey.identity_key,
+            signed_prekey_id: stored_prekey_id(prekey.prekey_id)?,
+            signed_prekey: prekey.prekey,
+            signature: prekey.signature,
+            one_time_prekeys_remaining: remaining,
+            replenish: remaining <= prekeys::ONE_TIME_PREKEY_LOW_WATERMARK,
+        })
+    }
+
+    /// What a sender needs to start a session with `user_id`, claiming one of
+    /// the user's one-time prekeys for them if any are left.
+    #[instrument(skip_all, fields(%user_id))]
+    pub async fn get_prekey_bundle(&self, user_id: Uuid) -> Result<PrekeyBundleResponse, AppError> {
+        // Checked first, so a user without a signed prekey keeps their
+        // one-time prekeys
+        let prekey = self.signed_prekey(user_id).await?;
+        let one_time_prekey = match self.repository.claim_one_time_prekey(user_id).await? {
+            Some(one_time_prekey) => Some(SignedOneTimePrekey {
+                prekey_id: stored_prekey_id(one_time_prekey.prekey_id)?,
+                prekey: one_time_prekey.prekey,
+                signature: one_time_prekey.signature,
+            }),
+            None => None,
+        };
+
+        Ok(PrekeyBundleResponse {
+            user_id,
+            identity_key: prekey.identity_key,
+            signed_prekey_id: stored_prekey_id(prekey.prekey_id)?,
+            signed_prekey: prekey.prekey,
+            signature: prekey.signature,
+            one_time_prekey,
+        })
+    }
+
+    /// Stores `user_id`'s key backup once the user's account key is shown to
+    /// have signed it. The version must be above the stored one, so an
+    /// earlier backup cannot be replayed over a newer one; otherwise this
+    /// fails with [`AppError::UniqueViolation`].
+    #[instrument(skip_all, fields(%user_id))]
+    pub async fn upload_key_backup(
+        &self,
+        user_id: Uuid,
+        request: UploadKeyBackupRequest,
+    ) -> Result<(), AppError> {
+        let key_ring = self.key_ring()?;
+        let user = self.repository.get_user_by_id(user_id).await?;
+
+        let encrypted_key = key_backup::decode(&request.encrypted_key)?;
+        let salt = key_backup::decode(&request.salt)?;
+        let recovery_key = key_backup::decode(&request.recovery_key)?;
+        key_backup::decode_recovery_key(&recovery_key)?;
+
+        let payload = shared::key_backup::upload_payload(
+            user_id,
+            request.version,
+            &encrypted_key,
+            &salt,
+            request.kdf_iterations,
+            &recovery_key,
+        );
+        verify_signature(
+            &account_verifying_key(&user.public_key)?,
+            &payload,
+            &request.signature,
+            "Key backup",
+        )?;
+
+        let stored = self
+            .repository
+            .upsert_key_backup(
+                user_id,
+                i64::from(request.version),
+                &key_backup::wrap(key_ring, user_id, Column::EncryptedKey, &encrypted_key),
+                &request.salt,
+                i64::from(request.kdf_iterations),
+                &key_backup::wrap(key_ring, user_id, Column::RecoveryKey, &recovery_key),
+            )
+            .await?;
+        if !stored {
+            return Err(AppError::UniqueViolation(String::from(
+                "A key backup with the same or a newer version is already stored",
+            )));
+        }
 
         Ok(())
     }
+
+    /// Issues a challenge for recovering `user_id`'s key backup. Challenges
+    /// issued earlier can still be answered until they expire.
+    #[instrument(skip_all, fields(%user_id))]
+    pub async fn create_key_backup_challenge(
+        &self,
+        user_id: Uuid,
+    ) -> Result<KeyBackupChallengeResponse, AppError> {
+        self.key_ring()?;
+        let backup = self.key_backup(user_id).await?;
+
+        let challenge = CUSTOM_ENGINE.encode(rand::random::<[u8; 32]>());
+        let now = Utc::now();
+        let expires_at = now + Duration::seconds(key_backup::CHALLENGE_TTL_SECONDS);
+        let added = self
+            .repository
+            .set_key_backup_challenge(
+                user_id,
+                &challenge,
+                now.naive_utc(),
+                expires_at.naive_utc(),
+                key_backup::MAX_PENDING_CHALLENGES,
+            )
+            .await?;
+        if !added {
+            // One of the pending challenges expires by then
+            let ttl = key_backup::CHALLENGE_TTL_SECONDS.unsigned_abs();
+            return Err(AppError::RateLimited(std::time::Duration::from_secs(ttl)));
+        }
+
+        Ok(KeyBackupChallengeResponse {
+            challenge,
+            salt: backup.salt,
+            kdf_iterations: stored_kdf_iterations(backup.kdf_iterations)?,
+            expires_at: expires_at.timestamp(),
+        })
+    }
+
+    fn key_ring(&self) -> Result<&KeyRing, AppError> {
+        self.key_ring
+            .as_deref()
+            .ok_or_else(|| AppError::Forbidden(String::from("Key backups are not accepted")))
+    }
+
+    async fn key_backup(&self, user_id: Uuid) -> Result<KeyBackup, AppError> {
+        self.repository
+            .get_key_backup(user_id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(String::from("User has no key backup")))
+    }
+
+    async fn signed_prekey(&self, user_id: Uuid) -> Result<SignedPrekey, AppError> {
+        self.repository
+            .get_signed_prekey(user_id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(String::from("User has not published prekeys")))
+    }
 }
 
-fn generate_random_username() -> String {
-    rand::random::<FullName>().to_string().replace(" ", "")
+impl<R: UserRepository + UnitOfWork> UserService<R> {
+    /// Stores the user and, when they send an identity commitment, their
+    /// anonymity set leaf in a single unit of work.
+    #[instrument(skip_all)]
+    pub async fn register_user(
+        &self,
+        request: RegisterRequest,
+    ) -> Result<RegisterResponse, AppError> {
+        let user_id = uuid::Uuid::now_v7();
+
+        let username = match request.username {
+            Some(name) => name,
+            None => generate_random_username(),
+        };
+
+        let pkeyclone = request.public_key.as_str();
+
+        // Checked before the user is stored, so a malformed commitment
+        // doesn't leave a registered user outside the anonymity set
+        let leaf = match &request.identity_commitment {
+            Some(commitment) => Some(membership_leaf(&request.public_key, commitment)?),
+            None => None,
+        };
+        if let Some(message_key) = &request.message_key {
+            check_message_key(message_key)?;
+        }
+
+        // A failed leaf insert rolls the user back with it
+        let work = self.repository.begin().await?;
+        let user_id = work.insert_user(&request.public_key, &username).await?;
+        if let Some(leaf) = &leaf {
+            work.insert_membership_leaf(user_id, &membership::encode_field(leaf))
+                .await?;
+        }
+        if let Some(message_key) = &request.message_key {
+            work.set_message_key(user_id, message_key).await?;
+        }
+        work.commit().await?;
+
+        if leaf.is_some() {
+            self.anonymity_set.sync(&self.repository).await?;
+        }
+
+        Ok(RegisterResponse {
+            user_id: user_id,
+            username: username.to_string(),
+        })
+    }
+
+    /// Replaces the user's public key and revokes every refresh token issued under the old
+    /// one. Both happen in a single unit of work, so on failure the old key and sessions stay
+    /// valid. Returns the number of refresh tokens revoked.
+    #[instrument(skip_all, fields(%user_id))]
+    pub async fn rotate_key(&self, user_id: Uuid, new_public_key: &str) -> Result<u64, AppError> {
+        self.replace_key(user_id, None, new_public_key).await
+    }
+
+    /// Renames the user and/or replaces their public key or message key. A
+    /// new public key goes through [`Self::rotate_key`]'s unit of work, so it
+    /// revokes the user's refresh tokens as well.
+    #[instrument(skip_all, fields(%user_id))]
+    pub async fn update_user(
+        &self,
+        user_id: Uuid,
+        request: UpdateUserRequest,
+    ) -> Result<(), AppError> {
+        if let Some(message_key) = &request.new_message_key {
+            check_message_key(message_key)?;
+        }
+
+        match request.new_public_key {
+            Some(new_public_key) => {
+                self.replace_key(user_id, request.new_username, &new_public_key)
+                    .await?;
+            }
+            None => {
+                self.repository
+                    .update_user(user_id, request.new_username, None, None)
+                    .await?
+            }
+        }
+        if let Some(message_key) = &request.new_message_key {
+            self.repository.set_message_key(user_id, message_key).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Hands back `user_id`'s key backup, as the client sealed it, if
+    /// `request` answers a pending challenge with a signature by the
+    /// account key or by the backup's recovery key.
+    #[instrument(skip_all, fields(%user_id))]
+    pub async fn recover_key_backup(
+        &self,
+        user_id: Uuid,
+        request: RecoverKeyBackupRequest,
+    ) -> Result<KeyBackupResponse, AppError> {
+        let key_ring = self.key_ring()?;
+        let user = self.repository.get_user_by_id(user_id).await?;
+        let challenge = key_backup::decode(&request.challenge)?;
+        let payload = shared::key_backup::recovery_payload(user_id, &challenge);
+
+        let signed_by = |key: &VerifyingKey| {
+            verify_signature(key, &payload, &request.signature, "Recovery").is_ok()
+        };
+        let by_account = account_verifying_key(&user.public_key)
+            .map(|key| signed_by(&key))
+            .unwrap_or(false);
+
+        // Held-back attempts are refused as the challenge is taken, so
+        // concurrent ones can't all be checked against the same count
+        let now = Utc::now();
+        let taken = self
+            .repository
+            .take_key_backup_challenge(user_id, &request.challenge, now.naive_utc(), by_account)
+            .await?;
+        let Some(backup) = taken else {
+            let retry_at = self
+                .repository
+                .get_key_backup(user_id)
+                .await?
+                .and_then(|backup| backup.recovery_retry_at);
+            if let Some(retry_at) = retry_at {
+                let wait = retry_at.and_utc() - now;
+                if wait > Duration::zero() {
+                    return Err(AppError::RateLimited(wait.to_std().unwrap_or_default()));
+                }
+            }
+            return Err(AppError::Forbidden(String::from(
+                "Recovery challenge is unknown or expired",
+            )));
+        };
+        let (encrypted_key, refreshed_key) =
+            key_backup::unwrap(key_ring, user_id, Column::EncryptedKey, &backup.encrypted_key)?;
+        let (recovery_key, refreshed_recovery_key) =
+            key_backup::unwrap(key_ring, user_id, Column::RecoveryKey, &backup.recovery_key)?;
+
+        if !by_account && !signed_by(&key_backup::decode_recovery_key(&recovery_key)?) {
+            // The hold follows from the count this failure brought it to,
+            // not the one read with the backup
+            let work = self.repository.begin().await?;
+            if let Some(failed_attempts) = work.record_failed_key_recovery(user_id).await? {
+                let retry_at = now + key_backup::recovery_backoff(failed_attempts);
+                work.hold_back_key_recovery(user_id, retry_at.naive_utc())
+                    .await?;
+            }
+            work.commit().await?;
+            return Err(AppError::Forbidden(String::from(
+                "Recovery signature is invalid",
+            )));
+        }
+
+        // Lazily move the backup onto the current server key
+        if refreshed_key.is_some() || refreshed_recovery_key.is_some() {
+            self.repository
+                .rewrap_key_backup(
+                    user_id,
+                    backup.version,
+                    refreshed_key.as_ref().unwrap_or(&backup.encrypted_key),
+                    refreshed_recovery_key.as_ref().unwrap_or(&backup.recovery_key),
+                )
+                .await?;
+        }
+
+        Ok(KeyBackupResponse {
+            version: u32::try_from(backup.version).map_err(|_| {
+                AppError::InternalError(String::from("Stored backup version out of range"))
+            })?,
+            encrypted_key: CUSTOM_ENGINE.encode(encrypted_key),
+            salt: backup.salt,
+            kdf_iterations: stored_kdf_iterations(backup.kdf_iterations)?,
+        })
+    }
+
+    async fn replace_key(
+        &self,
+        user_id: Uuid,
+        new_username: Option<String>,
+        new_public_key: &str,
+    ) -> Result<u64, AppError> {
+        let work = self.repository.begin().await?;
+
+        let revoked = work
+            .revoke_user_refresh_tokens(user_id, Some("key_rotated".to_string()))
+            .await?;
+        work.update_user(user_id, new_username, Some(new_public_key.to_string()), None)
+            .await?;
+
+        work.commit().await?;
+
+        Ok(revoked)
+    }
+}
+
+/// The P-256 key `public_key` encodes, for checking what its owner signed.
+fn account_verifying_key(public_key: &PublicKey) -> Result<VerifyingKey, AppError> {
+    CUSTOM_ENGINE
+        .decode(public_key.as_str())
+        .ok()
+        .and_then(|bytes| VerifyingKey::from_sec1_bytes(&bytes).ok())
+        .ok_or_else(|| AppError::Forbidden(String::from("Account key cannot sign")))
 }
 
-fn sha256_hash(input: &str) -> Result<String, AppError> {
-    use sha2::{Digest, Sha256};
-    let mut hasher = Sha256::new();
-    hasher.update(input.as_bytes());
-    let result = hasher.finalize();
-    Ok(base64::encode(result))
+/// Checks a 64-byte ECDSA `signature` over `payload`; `what` names the signed
+/// thing in the error.
+fn verify_signature(
+    key: &VerifyingKey,
+    payload: &[u8],
+    signature: &str,
+    what: &str,
+) -> Result<(), AppError> {
+    let signature = CUSTOM_ENGINE
+        .decode(signature)
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        .ok_or(AppError::InvalidInputSyntax)?;
+
+    key.verify(payload, &signature)
+        .map_err(|_| AppError::Forbidden(format!("{what} signature is invalid")))
+}
+
+// Prekey ids are uploaded as u32, so a stored one outside that range means
+// the row was written some other way.
+fn stored_prekey_id(prekey_id: i64) -> Result<u32, AppError> {
+    u32::try_from(prekey_id)
+        .map_err(|_| AppError::InternalError(String::from("Stored prekey id out of range")))
+}
+
+fn stored_kdf_iterations(kdf_iterations: i64) -> Result<u32, AppError> {
+    u32::try_from(kdf_iterations)
+        .map_err(|_| AppError::InternalError(String::from("Stored KDF iterations out of range")))
+}
+
+fn safety_number_error(code: &'static str, message: &'static str) -> AppError {
+    let mut errors = ValidationErrors::new();
+
+    let mut error = ValidationError::new(code);
+    error.message = Some(message.into());
+
+    errors.add("safety_number", error);
+
+    AppError::ValidationError(errors)
+}
+
+/// The leaf binding `identity_commitment` to the user's public key hash.
+fn membership_leaf(
+    public_key: &str,
+    identity_commitment: &str,
+) -> Result<circuits::membership::Fr, AppError> {
+    let public_key_hash = PublicKey::new(public_key.to_string())?.to_hash()?;
+    let public_key_hash = CUSTOM_ENGINE
+        .decode(public_key_hash.as_str())
+        .map_err(|_| AppError::InvalidInputSyntax)?;
+
+    Ok(circuits::membership::leaf(
+        circuits::membership::public_key_hash_to_field(&public_key_hash),
+        membership::decode_field(identity_commitment)?,
+    ))
+}
+
+/// Rejects a message key that [`message_preparation::seal`] could not seal
+/// to.
+fn check_message_key(message_key: &str) -> Result<(), AppError> {
+    let bytes = CUSTOM_ENGINE
+        .decode(message_key)
+        .map_err(|_| AppError::InvalidInputSyntax)?;
+    message_preparation::decode_public_key(&bytes)
+        .map(|_| ())
+        .map_err(|_| AppError::InvalidInputSyntax)
+}
+
+fn generate_random_username() -> String {
+    rand::random::<FullName>().to_string().replace(" ", "")
 }
 
 #[cfg(test)]
@@ -199,6 +1050,7 @@ mod tests {
         engine::{self, general_purpose},
     };
     use chrono::{NaiveDateTime, Utc};
+    use db::{db::SqliteDb, token_db::TokenDb};
     use mockall::{mock, predicate::*};
     use p256::{
         ecdsa::{SigningKey, VerifyingKey},
@@ -237,85 +1089,185 @@ mod tests {
 
     #[tokio::test]
     async fn test_register_user_with_username() {
-        let mut mock_repo = MockUserRepository::new();
-
-        let test_public_key = "test_public_key";
-        let public_key_hash = sha256_hash(test_public_key).unwrap();
-        let expected_user_id = Uuid::now_v7();
-
-        mock_repo
-            .expect_insert_user()
-            .with(eq(test_public_key), eq("testuser"))
-            .times(1)
-            .returning(move |_, _| Ok(expected_user_id));
+        let db = sqlite_db().await;
+        let (public_key, _) = generate_key().await;
 
-        let service = UserService::new(mock_repo);
+        let service = UserService::new(db.clone());
         let request = RegisterRequest {
             username: Some("testuser".to_string()),
-            public_key: test_public_key.to_string(),
+            public_key: public_key.as_str().to_string(),
+            identity_commitment: None,
+            message_key: None,
         };
 
         let result = service.register_user(request).await.unwrap();
 
-        assert_eq!(result.user_id, expected_user_id);
         assert_eq!(result.username, "testuser");
+        let user = UserDb::get_user_by_id(&db, result.user_id).await.unwrap();
+        assert_eq!(user.username, "testuser");
+        assert_eq!(user.public_key, public_key);
     }
 
     #[tokio::test]
     async fn test_register_user_without_username() {
-        let mut mock_repo = MockUserRepository::new();
-        let test_public_key = "test_public_key";
-        let public_key_hash = sha256_hash(test_public_key).unwrap();
-        let expected_user_id = Uuid::now_v7();
+        let db = sqlite_db().await;
+        let (public_key, _) = generate_key().await;
 
-        // Setup mock to accept any username
-        mock_repo
-            .expect_insert_user()
-            .with(eq(test_public_key), always())
-            .times(1)
-            .returning(move |_, username| {
-                assert!(!username.is_empty());
-                Ok(expected_user_id)
-            });
-
-        let service = UserService::new(mock_repo);
+        let service = UserService::new(db.clone());
         let request = RegisterRequest {
             username: None,
-            public_key: test_public_key.to_string(),
+            public_key: public_key.as_str().to_string(),
+            identity_commitment: None,
+            message_key: None,
         };
 
         let result = service.register_user(request).await.unwrap();
 
-        assert_eq!(result.user_id, expected_user_id);
         assert!(!result.username.is_empty());
+        let user = UserDb::get_user_by_id(&db, result.user_id).await.unwrap();
+        assert_eq!(user.username, result.username);
     }
 
     #[tokio::test]
     async fn test_register_user_db_error() {
-        let mut mock_repo = MockUserRepository::new();
-        let test_public_key = "test_public_key";
-        let public_key_hash = sha256_hash(test_public_key).unwrap();
-
-        mock_repo
-            .expect_insert_user()
-            .with(eq(test_public_key), eq("testuser"))
-            .times(1)
-            .returning(|_, _| {
-                Err(AppError::DatabaseError(SqlxError::InvalidArgument(
-                    "DB error".to_string(),
-                )))
-            });
+        let pool = sqlx::SqlitePool::connect("sqlite::memory:").await.unwrap();
+        pool.close().await;
+        let (public_key, _) = generate_key().await;
 
-        let service = UserService::new(mock_repo);
+        let service = UserService::new(SqliteDb::new(pool));
         let request = RegisterRequest {
             username: Some("testuser".to_string()),
-            public_key: test_public_key.to_string(),
+            public_key: public_key.as_str().to_string(),
+            identity_commitment: None,
+            message_key: None,
         };
 
         let result = service.register_user(request).await;
         assert!(matches!(result, Err(AppError::DatabaseError(_))));
     }
 
+    #[tokio::test]
+    async fn test_register_user_with_identity_commitment() {
+        let db = sqlite_db().await;
+        let (public_key, _) = generate_key().await;
+        let commitment = membership::encode_field(&circuits::membership::Fr::from(7u64));
+        let expected_leaf =
+            membership::encode_field(&membership_leaf(public_key.as_str(), &commitment).unwrap());
+
+        let service = UserService::new(db);
+        service
+            .register_user(RegisterRequest {
+                username: Some("member".to_string()),
+                public_key: public_key.as_str().to_string(),
+                identity_commitment: Some(commitment),
+                message_key: None,
+            })
+            .await
+            .unwrap();
+
+        let tree = service.get_membership_tree().await.unwrap();
+        assert_eq!(tree.leaves, vec![expected_leaf]);
+        assert_ne!(
+            tree.root,
+            membership::encode_field(&AnonymitySet::new().root())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_register_user_with_malformed_identity_commitment() {
+        let db = sqlite_db().await;
+        let (public_key, _) = generate_key().await;
+
+        // Rejected before the user is stored
+        let service = UserService::new(db.clone());
+        let result = service
+            .register_user(RegisterRequest {
+                username: Some("member".to_string()),
+                public_key: public_key.as_str().to_string(),
+                identity_commitment: Some(CUSTOM_ENGINE.encode([0xff; 32])),
+                message_key: None,
+            })
+            .await;
+
+        assert!(matches!(result, Err(AppError::InvalidInputSyntax)));
+        assert!(UserDb::get_users(&db, None).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_register_user_with_message_key() {
+        use db::message_db::MessageDb;
+
+        let db = sqlite_db().await;
+        let (public_key, _) = generate_key().await;
+        let (_, message_key) = message_preparation::generate_keypair();
+        let message_key = CUSTOM_ENGINE.encode(circuits::prover::to_bytes(&message_key).unwrap());
+
+        let service = UserService::new(db.clone());
+        let response = service
+            .register_user(RegisterRequest {
+                username: Some("member".to_string()),
+                public_key: public_key.as_str().to_string(),
+                identity_commitment: None,
+                message_key: Some(message_key.clone()),
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(
+            MessageDb::get_user_message_key(&db, response.user_id).await.unwrap(),
+            Some(message_key)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_register_user_with_malformed_message_key() {
+        let db = sqlite_db().await;
+        let (public_key, _) = generate_key().await;
+
+        let service = UserService::new(db.clone());
+        let result = service
+            .register_user(RegisterRequest {
+                username: Some("member".to_string()),
+                public_key: public_key.as_str().to_string(),
+                identity_commitment: None,
+                message_key: Some(CUSTOM_ENGINE.encode([0xff; 32])),
+            })
+            .await;
+
+        assert!(matches!(result, Err(AppError::InvalidInputSyntax)));
+        assert!(UserDb::get_users(&db, None).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_register_user_rolls_back_when_leaf_insert_fails() {
+        let pool = sqlx::SqlitePool::connect("sqlite::memory:").await.unwrap();
+        sqlx::migrate!("../db/migrations").run(&pool).await.unwrap();
+        sqlx::query(
+            "CREATE TRIGGER fail_insert_membership_leaves BEFORE INSERT ON membership_leaves
+             BEGIN SELECT RAISE(ABORT, 'injected failure'); END",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let db = SqliteDb::new(pool);
+        let (public_key, _) = generate_key().await;
+        let commitment = membership::encode_field(&circuits::membership::Fr::from(7u64));
+
+        let service = UserService::new(db.clone());
+        let result = service
+            .register_user(RegisterRequest {
+                username: Some("member".to_string()),
+                public_key: public_key.as_str().to_string(),
+                identity_commitment: Some(commitment),
+                message_key: None,
+            })
+            .await;
+
+        assert!(matches!(result, Err(AppError::DatabaseError(_))));
+        assert!(UserDb::get_users(&db, None).await.unwrap().is_empty());
+        assert!(service.get_membership_tree().await.unwrap().leaves.is_empty());
+    }
+
     #[tokio::test]
     async fn test_get_user_by_public_key_success() {
         let mut mock_repo = MockUserRepository::new();
@@ -367,18 +1319,6 @@ mod tests {
         assert_ne!(username1, username2);
     }
 
-    #[test]
-    fn test_sha256_hash() {
-        let input = "test_input";
-        let hash1 = sha256_hash(input).unwrap();
-        let hash2 = sha256_hash(input).unwrap();
-        let different_hash = sha256_hash("different_input").unwrap();
-
-        assert_eq!(hash1, hash2);
-        assert_ne!(hash1, different_hash);
-        assert!(base64::decode(&hash1).is_ok());
-    }
-
     #[tokio::test]
     async fn test_get_user_by_id_success() {
         let mut mock_repo = MockUserRepository::new();
@@ -503,126 +1443,93 @@ mod tests {
 
     #[tokio::test]
     async fn test_update_user_success() {
-        let mut mock_repo = MockUserRepository::new();
-        let user_id = Uuid::now_v7();
-        let new_public_key = "new_public_key";
-        let public_key_hash = sha256_hash(new_public_key).unwrap();
+        let db = sqlite_db().await;
+        let user_id = insert_user_with_tokens(&db, "alice", &["laptop"]).await;
+        let (new_public_key, new_public_key_hash) = generate_key().await;
         let request = UpdateUserRequest {
             new_username: Some("new_username".to_string()),
-            new_public_key: Some(new_public_key.to_string()),
+            new_public_key: Some(new_public_key.as_str().to_string()),
+            new_message_key: None,
         };
 
-        mock_repo
-            .expect_update_user()
-            .with(
-                eq(user_id),
-                eq(Some("new_username".to_string())),
-                eq(Some(new_public_key.to_string())),
-                eq(Some(public_key_hash)),
-            )
-            .times(1)
-            .returning(|_, _, _, _| Ok(()));
+        let service = UserService::new(db.clone());
+        service.update_user(user_id, request).await.unwrap();
 
-        let service = UserService::new(mock_repo);
-        let result = service.update_user(user_id, request).await;
-
-        assert!(result.is_ok());
+        let user = UserDb::get_user_by_id(&db, user_id).await.unwrap();
+        assert_eq!(user.username, "new_username");
+        assert_eq!(user.public_key, new_public_key);
+        assert_eq!(user.public_key_hash, new_public_key_hash);
+        assert!(!TokenDb::validate_refresh_token(&db, user_id, "laptop").await.unwrap());
     }
 
     #[tokio::test]
     async fn test_update_user_partial_fields() {
-        let mut mock_repo = MockUserRepository::new();
-        let user_id = Uuid::now_v7();
-        let new_username = "new_username";
+        let db = sqlite_db().await;
+        let user_id = insert_user_with_tokens(&db, "alice", &["laptop"]).await;
+        let original = UserDb::get_user_by_id(&db, user_id).await.unwrap();
+        let service = UserService::new(db.clone());
 
         // Test updating just username
         let username_request = UpdateUserRequest {
-            new_username: Some(new_username.to_string()),
+            new_username: Some("new_username".to_string()),
             new_public_key: None,
+            new_message_key: None,
         };
-        let mut mock_repo2 = mock_repo.clone();
+        service.update_user(user_id, username_request).await.unwrap();
 
-        mock_repo
-            .expect_update_user()
-            .with(
-                eq(user_id),
-                eq(Some(new_username.to_string())),
-                eq(None),
-                eq(None),
-            )
-            .times(1)
-            .returning(|_, _, _, _| Ok(()));
-
-        let service = UserService::new(mock_repo);
-        let result = service.update_user(user_id, username_request).await;
-        assert!(result.is_ok());
+        let user = UserDb::get_user_by_id(&db, user_id).await.unwrap();
+        assert_eq!(user.username, "new_username");
+        assert_eq!(user.public_key, original.public_key);
+        assert!(TokenDb::validate_refresh_token(&db, user_id, "laptop").await.unwrap());
 
         // Test updating just public key
-        let new_public_key = "new_public_key";
-        let public_key_hash = sha256_hash(new_public_key).unwrap();
+        let (new_public_key, _) = generate_key().await;
         let public_key_request = UpdateUserRequest {
             new_username: None,
-            new_public_key: Some(new_public_key.to_string()),
+            new_public_key: Some(new_public_key.as_str().to_string()),
+            new_message_key: None,
         };
+        service.update_user(user_id, public_key_request).await.unwrap();
 
-        mock_repo2
-            .expect_update_user()
-            .with(
-                eq(user_id),
-                eq(None),
-                eq(Some(new_public_key.to_string())),
-                eq(Some(public_key_hash)),
-            )
-            .times(1)
-            .returning(|_, _, _, _| Ok(()));
-
-        let service = UserService::new(mock_repo2);
-        let result = service.update_user(user_id, public_key_request).await;
-        assert!(result.is_ok());
+        let user = UserDb::get_user_by_id(&db, user_id).await.unwrap();
+        assert_eq!(user.username, "new_username");
+        assert_eq!(user.public_key, new_public_key);
+        assert!(!TokenDb::validate_refresh_token(&db, user_id, "laptop").await.unwrap());
     }
 
     #[tokio::test]
     async fn test_update_user_no_changes() {
-        let mut mock_repo = MockUserRepository::new();
-        let user_id = Uuid::now_v7();
+        let db = sqlite_db().await;
+        let user_id = insert_user_with_tokens(&db, "alice", &["laptop"]).await;
+        let original = UserDb::get_user_by_id(&db, user_id).await.unwrap();
         let request = UpdateUserRequest {
             new_username: None,
             new_public_key: None,
+            new_message_key: None,
         };
 
-        mock_repo
-            .expect_update_user()
-            .with(eq(user_id), eq(None), eq(None), eq(None))
-            .times(1)
-            .returning(|_, _, _, _| Ok(()));
-
-        let service = UserService::new(mock_repo);
-        let result = service.update_user(user_id, request).await;
+        let service = UserService::new(db.clone());
+        service.update_user(user_id, request).await.unwrap();
 
-        assert!(result.is_ok());
+        let user = UserDb::get_user_by_id(&db, user_id).await.unwrap();
+        assert_eq!(user.username, original.username);
+        assert_eq!(user.public_key, original.public_key);
+        assert!(TokenDb::validate_refresh_token(&db, user_id, "laptop").await.unwrap());
     }
 
     #[tokio::test]
     async fn test_update_user_db_error() {
-        let mut mock_repo = MockUserRepository::new();
-        let user_id = Uuid::now_v7();
+        let pool = sqlx::SqlitePool::connect("sqlite::memory:").await.unwrap();
+        pool.close().await;
+        let (new_public_key, _) = generate_key().await;
         let request = UpdateUserRequest {
             new_username: Some("new_username".to_string()),
-            new_public_key: Some("new_public_key".to_string()),
+            new_public_key: Some(new_public_key.as_str().to_string()),
+            new_message_key: None,
         };
 
-        mock_repo
-            .expect_update_user()
-            .with(always(), always(), always(), always())
-            .times(1)
-            .returning(|_, _, _, _| {
-                Err(AppError::DatabaseError(SqlxError::InvalidArgument(
-                    "DB error".to_string(),
-                )))
-            });
-
-        let service = UserService::new(mock_repo);
-        let result = service.update_user(user_id, request).await;
+        let service = UserService::new(SqliteDb::new(pool));
+        let result = service.update_user(Uuid::now_v7(), request).await;
 
         assert!(matches!(result, Err(AppError::DatabaseError(_))));
     }
@@ -682,4 +1589,284 @@ mod tests {
 
         assert!(matches!(result, Err(AppError::DatabaseError(_))));
     }
+
+    fn setup_two_users(
+        mock_repo: &mut MockUserRepository,
+        user: User,
+        contact: User,
+    ) {
+        let (user_id, contact_id) = (user.id, contact.id);
+
+        mock_repo
+            .expect_get_user_by_id()
+            .with(eq(user_id))
+            .returning(move |_| Ok(user.clone()));
+        mock_repo
+            .expect_get_user_by_id()
+            .with(eq(contact_id))
+            .returning(move |_| Ok(contact.clone()));
+    }
+
+    #[tokio::test]
+    async fn test_get_safety_number_is_symmetric() {
+        let mut mock_repo = MockUserRepository::new();
+        let user = create_test_user(Uuid::now_v7(), "alice").await;
+        let contact = create_test_user(Uuid::now_v7(), "bob").await;
+        let (user_id, contact_id) = (user.id, contact.id);
+        setup_two_users(&mut mock_repo, user, contact);
+
+        let service = UserService::new(mock_repo);
+        let ab = service.get_safety_number(user_id, contact_id).await.unwrap();
+        let ba = service.get_safety_number(contact_id, user_id).await.unwrap();
+
+        assert_eq!(ab, ba);
+        assert_eq!(ab.digits.len(), 60);
+    }
+
+    #[tokio::test]
+    async fn test_get_safety_number_unknown_contact() {
+        let mut mock_repo = MockUserRepository::new();
+        let user = create_test_user(Uuid::now_v7(), "alice").await;
+        let user_id = user.id;
+        let contact_id = Uuid::now_v7();
+
+        mock_repo
+            .expect_get_user_by_id()
+            .with(eq(user_id))
+            .returning(move |_| Ok(user.clone()));
+        mock_repo
+            .expect_get_user_by_id()
+            .with(eq(contact_id))
+            .returning(|_| Err(AppError::NotFound("User not found".to_string())));
+
+        let service = UserService::new(mock_repo);
+        let result = service.get_safety_number(user_id, contact_id).await;
+
+        assert!(matches!(result, Err(AppError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_verify_contact_pins_contact_key() {
+        let mut mock_repo = MockUserRepository::new();
+        let user = create_test_user(Uuid::now_v7(), "alice").await;
+        let contact = create_test_user(Uuid::now_v7(), "bob").await;
+        let (user_id, contact_id) = (user.id, contact.id);
+        let contact_hash = contact.public_key_hash.to_string();
+        let expected =
+            SafetyNumber::derive(user.id, &user.public_key, contact.id, &contact.public_key)
+                .unwrap();
+        setup_two_users(&mut mock_repo, user, contact);
+
+        mock_repo
+            .expect_mark_contact_verified()
+            .with(eq(user_id), eq(contact_id), eq(contact_hash))
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
+        let service = UserService::new(mock_repo);
+        let request = VerifyContactRequest {
+            safety_number: expected.digits,
+        };
+
+        assert!(service.verify_contact(user_id, contact_id, request).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_verify_contact_rejects_wrong_number() {
+        let mut mock_repo = MockUserRepository::new();
+        let user = create_test_user(Uuid::now_v7(), "alice").await;
+        let contact = create_test_user(Uuid::now_v7(), "bob").await;
+        let (user_id, contact_id) = (user.id, contact.id);
+        setup_two_users(&mut mock_repo, user, contact);
+
+        mock_repo.expect_mark_contact_verified().times(0);
+
+        let service = UserService::new(mock_repo);
+        let request = VerifyContactRequest {
+            safety_number: "1".repeat(60),
+        };
+        let result = service.verify_contact(user_id, contact_id, request).await;
+
+        match result {
+            Err(AppError::ValidationError(errors)) => {
+                assert!(errors.field_errors().contains_key("safety_number"));
+            }
+            other => panic!("Expected validation error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_contact_rejects_self() {
+        let mock_repo = MockUserRepository::new();
+        let user_id = Uuid::now_v7();
+
+        let service = UserService::new(mock_repo);
+        let request = VerifyContactRequest {
+            safety_number: "1".repeat(60),
+        };
+        let result = service.verify_contact(user_id, user_id, request).await;
+
+        assert!(matches!(result, Err(AppError::ValidationError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_get_contact_verification_unverified() {
+        let mut mock_repo = MockUserRepository::new();
+        let contact = create_test_user(Uuid::now_v7(), "bob").await;
+        let contact_id = contact.id;
+        let user_id = Uuid::now_v7();
+
+        mock_repo
+            .expect_get_user_by_id()
+            .with(eq(contact_id))
+            .returning(move |_| Ok(contact.clone()));
+        mock_repo
+            .expect_get_verified_contact()
+            .with(eq(user_id), eq(contact_id))
+            .returning(|_, _| Ok(None));
+
+        let service = UserService::new(mock_repo);
+        let status = service
+            .get_contact_verification(user_id, contact_id)
+            .await
+            .unwrap();
+
+        assert!(!status.verified);
+        assert!(!status.key_changed);
+        assert_eq!(status.verified_at, None);
+    }
+
+    #[tokio::test]
+    async fn test_get_contact_verification_detects_key_change() {
+        let mut mock_repo = MockUserRepository::new();
+        let contact = create_test_user(Uuid::now_v7(), "bob").await;
+        let contact_id = contact.id;
+        let user_id = Uuid::now_v7();
+        let (_, stale_hash) = generate_key().await;
+
+        mock_repo
+            .expect_get_user_by_id()
+            .with(eq(contact_id))
+            .returning(move |_| Ok(contact.clone()));
+        mock_repo
+            .expect_get_verified_contact()
+            .with(eq(user_id), eq(contact_id))
+            .returning(move |_, _| {
+                Ok(Some(VerifiedContact {
+                    user_id,
+                    contact_id,
+                    public_key_hash: stale_hash.clone(),
+                    verified_at: Utc::now().naive_utc(),
+                }))
+            });
+
+        let service = UserService::new(mock_repo);
+        let status = service
+            .get_contact_verification(user_id, contact_id)
+            .await
+            .unwrap();
+
+        assert!(!status.verified);
+        assert!(status.key_changed);
+        assert!(status.verified_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_get_contact_verification_still_verified() {
+        let mut mock_repo = MockUserRepository::new();
+        let contact = create_test_user(Uuid::now_v7(), "bob").await;
+        let contact_id = contact.id;
+        let pinned_hash = contact.public_key_hash.clone();
+        let user_id = Uuid::now_v7();
+
+        mock_repo
+            .expect_get_user_by_id()
+            .with(eq(contact_id))
+            .returning(move |_| Ok(contact.clone()));
+        mock_repo
+            .expect_get_verified_contact()
+            .with(eq(user_id), eq(contact_id))
+            .returning(move |_, _| {
+                Ok(Some(VerifiedContact {
+                    user_id,
+                    contact_id,
+                    public_key_hash: pinned_hash.clone(),
+                    verified_at: Utc::now().naive_utc(),
+                }))
+            });
+
+        let service = UserService::new(mock_repo);
+        let status = service
+            .get_contact_verification(user_id, contact_id)
+            .await
+            .unwrap();
+
+        assert!(status.verified);
+        assert!(!status.key_changed);
+    }
+
+    async fn sqlite_db() -> SqliteDb {
+        let pool = sqlx::SqlitePool::connect("sqlite::memory:").await.unwrap();
+        sqlx::migrate!("../db/migrations").run(&pool).await.unwrap();
+        SqliteDb::new(pool)
+    }
+
+    async fn insert_user_with_tokens(db: &SqliteDb, username: &str, tokens: &[&str]) -> Uuid {
+        let (public_key, public_key_hash) = generate_key().await;
+        let user_id = UserDb::insert_user(db, &public_key_hash, &public_key, username)
+            .await
+            .unwrap();
+        let expires_at = Utc::now().timestamp() + 3600;
+        for token in tokens {
+            TokenDb::store_refresh_token(db, user_id, token, expires_at, None)
+                .await
+                .unwrap();
+        }
+        user_id
+    }
+
+    #[tokio::test]
+    async fn test_rotate_key_replaces_key_and_revokes_tokens() {
+        let db = sqlite_db().await;
+        let user_id = insert_user_with_tokens(&db, "alice", &["laptop", "phone"]).await;
+        let (new_key, new_key_hash) = generate_key().await;
+
+        let service = UserService::new(db.clone());
+        let revoked = service.rotate_key(user_id, new_key.as_str()).await.unwrap();
+
+        assert_eq!(revoked, 2);
+        let user = UserDb::get_user_by_id(&db, user_id).await.unwrap();
+        assert_eq!(user.public_key, new_key);
+        assert_eq!(user.public_key_hash, new_key_hash);
+        assert!(!TokenDb::validate_refresh_token(&db, user_id, "laptop").await.unwrap());
+        assert!(!TokenDb::validate_refresh_token(&db, user_id, "phone").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_rotate_key_rolls_back_when_update_fails() {
+        let db = sqlite_db().await;
+        let user_id = insert_user_with_tokens(&db, "alice", &["laptop"]).await;
+        let other_id = insert_user_with_tokens(&db, "bob", &[]).await;
+        let original = UserDb::get_user_by_id(&db, user_id).await.unwrap();
+        let taken_key = UserDb::get_user_by_id(&db, other_id).await.unwrap().public_key;
+
+        let service = UserService::new(db.clone());
+        let result = service.rotate_key(user_id, taken_key.as_str()).await;
+
+        assert!(matches!(result, Err(AppError::UniqueViolation(_))));
+        let user = UserDb::get_user_by_id(&db, user_id).await.unwrap();
+        assert_eq!(user.public_key, original.public_key);
+        assert!(TokenDb::validate_refresh_token(&db, user_id, "laptop").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_rotate_key_unknown_user() {
+        let db = sqlite_db().await;
+        let (new_key, _) = generate_key().await;
+
+        let service = UserService::new(db);
+        let result = service.rotate_key(Uuid::now_v7(), new_key.as_str()).await;
+
+        assert!(matches!(result, Err(AppError::NotFound(_))));
+    }
 }
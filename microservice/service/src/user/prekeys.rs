@@ -0,0 +1,307 @@
+//! Checks on the prekeys users publish so others can start sessions with
+//! them while they are offline. A prekey is only worth distributing if the
+//! user's account key signed it; otherwise whoever uploaded it could read the
+//! sessions started against it.
+//!
+//! One-time prekeys are handed out one per sender. Once a user has
+//! [`ONE_TIME_PREKEY_LOW_WATERMARK`] or fewer left, responses tell them to
+//! upload more; senders who find none left fall back to the signed prekey.
+
+use super::{account_verifying_key, verify_signature};
+use base64::Engine as _;
+use circuits::ratchet::{self, Ed25519};
+use db::public_key::PublicKey;
+use p256::ecdsa::VerifyingKey;
+use shared::{
+    errors::AppError,
+    models::{CUSTOM_ENGINE, SignedOneTimePrekey, UploadPrekeysRequest},
+};
+
+/// Users with this many unclaimed one-time prekeys or fewer are asked to
+/// upload more.
+pub const ONE_TIME_PREKEY_LOW_WATERMARK: u32 = 10;
+
+/// Fails unless `request`'s keys decode to usable points and every signature
+/// in it, over the signed prekey and over each one-time prekey, was made
+/// with `account_key`.
+pub fn verify_upload(
+    account_key: &PublicKey,
+    request: &UploadPrekeysRequest,
+) -> Result<(), AppError> {
+    let account_key = account_verifying_key(account_key)?;
+
+    let identity_key = decode_key(&request.identity_key)?;
+    let signed_prekey = decode_key(&request.signed_prekey)?;
+    let payload =
+        ratchet::signed_prekey_payload(&identity_key, request.signed_prekey_id, &signed_prekey);
+    verify_signature(&account_key, &payload, &request.signature, "Signed prekey")?;
+
+    for one_time_prekey in &request.one_time_prekeys {
+        verify_one_time_prekey(&account_key, one_time_prekey)?;
+    }
+
+    Ok(())
+}
+
+fn verify_one_time_prekey(
+    account_key: &VerifyingKey,
+    one_time_prekey: &SignedOneTimePrekey,
+) -> Result<(), AppError> {
+    let prekey = decode_key(&one_time_prekey.prekey)?;
+    let payload = ratchet::one_time_prekey_payload(one_time_prekey.prekey_id, &prekey);
+    verify_signature(
+        account_key,
+        &payload,
+        &one_time_prekey.signature,
+        "One-time prekey",
+    )
+}
+
+fn decode_key(value: &str) -> Result<Ed25519, AppError> {
+    let bytes = CUSTOM_ENGINE
+        .decode(value)
+        .map_err(|_| AppError::InvalidInputSyntax)?;
+    ratchet::decode_public_key(&bytes).map_err(|_| AppError::InvalidInputSyntax)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        memory::InMemoryStore,
+        user::{UserRepository, UserService},
+    };
+    use circuits::ratchet::{KeyPair, encode_public_key};
+    use p256::{
+        ecdsa::{Signature, SigningKey, signature::Signer},
+        elliptic_curve::rand_core::OsRng,
+    };
+
+    fn account() -> (SigningKey, PublicKey) {
+        let signing_key = SigningKey::random(&mut OsRng);
+        let encoded = VerifyingKey::from(&signing_key).to_encoded_point(true);
+        let public_key = PublicKey::new(CUSTOM_ENGINE.encode(encoded.as_bytes())).unwrap();
+        (signing_key, public_key)
+    }
+
+    fn signed_request(signing_key: &SigningKey, signed_prekey_id: u32) -> UploadPrekeysRequest {
+        let identity_key = KeyPair::generate().public;
+        let signed_prekey = KeyPair::generate().public;
+        let payload =
+            ratchet::signed_prekey_payload(&identity_key, signed_prekey_id, &signed_prekey);
+        let signature: Signature = signing_key.sign(&payload);
+
+        UploadPrekeysRequest {
+            identity_key: CUSTOM_ENGINE.encode(encode_public_key(&identity_key)),
+            signed_prekey_id,
+            signed_prekey: CUSTOM_ENGINE.encode(encode_public_key(&signed_prekey)),
+            signature: CUSTOM_ENGINE.encode(signature.to_bytes()),
+            one_time_prekeys: Vec::new(),
+        }
+    }
+
+    fn one_time_prekeys(
+        signing_key: &SigningKey,
+        ids: std::ops::Range<u32>,
+    ) -> Vec<SignedOneTimePrekey> {
+        ids.map(|prekey_id| {
+            let prekey = KeyPair::generate().public;
+            let signature: Signature =
+                signing_key.sign(&ratchet::one_time_prekey_payload(prekey_id, &prekey));
+            SignedOneTimePrekey {
+                prekey_id,
+                prekey: CUSTOM_ENGINE.encode(encode_public_key(&prekey)),
+                signature: CUSTOM_ENGINE.encode(signature.to_bytes()),
+            }
+        })
+        .collect()
+    }
+
+    #[test]
+    fn accepts_prekey_signed_by_account_key() {
+        let (signing_key, public_key) = account();
+        let request = signed_request(&signing_key, 1);
+
+        assert!(verify_upload(&public_key, &request).is_ok());
+    }
+
+    #[test]
+    fn rejects_prekey_signed_by_other_key() {
+        let (_, public_key) = account();
+        let (other_key, _) = account();
+        let request = signed_request(&other_key, 1);
+
+        assert!(matches!(
+            verify_upload(&public_key, &request),
+            Err(AppError::Forbidden(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_prekey_with_altered_id() {
+        let (signing_key, public_key) = account();
+        let mut request = signed_request(&signing_key, 1);
+        request.signed_prekey_id = 2;
+
+        assert!(matches!(
+            verify_upload(&public_key, &request),
+            Err(AppError::Forbidden(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_identity_point() {
+        let (signing_key, public_key) = account();
+        let mut request = signed_request(&signing_key, 1);
+        request.signed_prekey = CUSTOM_ENGINE.encode(encode_public_key(&Ed25519::default()));
+
+        assert!(matches!(
+            verify_upload(&public_key, &request),
+            Err(AppError::InvalidInputSyntax)
+        ));
+    }
+
+    #[test]
+    fn rejects_one_time_prekey_signed_by_other_key() {
+        let (signing_key, public_key) = account();
+        let (other_key, _) = account();
+        let mut request = signed_request(&signing_key, 1);
+        request.one_time_prekeys = one_time_prekeys(&signing_key, 1..3);
+        request
+            .one_time_prekeys
+            .extend(one_time_prekeys(&other_key, 3..4));
+
+        assert!(matches!(
+            verify_upload(&public_key, &request),
+            Err(AppError::Forbidden(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn upload_replaces_prekeys_with_newer_ones_only() {
+        let store = InMemoryStore::new();
+        let (signing_key, public_key) = account();
+        let user_id = store
+            .insert_user(public_key.as_str(), "alice")
+            .await
+            .unwrap();
+        let service = UserService::new(store);
+
+        let first = signed_request(&signing_key, 1);
+        service
+            .upload_prekeys(user_id, first.clone())
+            .await
+            .unwrap();
+        let published = service.get_prekeys(user_id).await.unwrap();
+        assert_eq!(
+            (
+                published.user_id,
+                published.signed_prekey_id,
+                published.signed_prekey
+            ),
+            (user_id, 1, first.signed_prekey.clone())
+        );
+
+        let result = service
+            .upload_prekeys(user_id, signed_request(&signing_key, 1))
+            .await;
+        assert!(matches!(result, Err(AppError::UniqueViolation(_))));
+
+        let second = signed_request(&signing_key, 2);
+        service
+            .upload_prekeys(user_id, second.clone())
+            .await
+            .unwrap();
+        let published = service.get_prekeys(user_id).await.unwrap();
+        assert_eq!(published.identity_key, second.identity_key);
+        assert_eq!(published.signature, second.signature);
+    }
+
+    #[tokio::test]
+    async fn upload_for_other_user_is_forbidden() {
+        let store = InMemoryStore::new();
+        let (_, alice_key) = account();
+        let (mallory, _) = account();
+        let alice = store
+            .insert_user(alice_key.as_str(), "alice")
+            .await
+            .unwrap();
+        let service = UserService::new(store);
+
+        let result = service
+            .upload_prekeys(alice, signed_request(&mallory, 1))
+            .await;
+        assert!(matches!(result, Err(AppError::Forbidden(_))));
+        assert!(matches!(
+            service.get_prekeys(alice).await,
+            Err(AppError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn bundles_hand_out_each_one_time_prekey_once() {
+        let store = InMemoryStore::new();
+        let (signing_key, public_key) = account();
+        let user_id = store
+            .insert_user(public_key.as_str(), "alice")
+            .await
+            .unwrap();
+        let service = UserService::new(store);
+
+        let mut request = signed_request(&signing_key, 1);
+        request.one_time_prekeys = one_time_prekeys(&signing_key, 1..3);
+        let published = service
+            .upload_prekeys(user_id, request.clone())
+            .await
+            .unwrap();
+        assert_eq!(published.one_time_prekeys_remaining, 2);
+        assert!(published.replenish);
+
+        let first = service.get_prekey_bundle(user_id).await.unwrap();
+        let second = service.get_prekey_bundle(user_id).await.unwrap();
+        assert_eq!(first.signed_prekey, request.signed_prekey);
+        assert_eq!(
+            first.one_time_prekey.as_ref(),
+            request.one_time_prekeys.first()
+        );
+        assert_eq!(
+            second.one_time_prekey.as_ref(),
+            request.one_time_prekeys.get(1)
+        );
+
+        // Out of one-time prekeys, senders still get the signed prekey
+        let third = service.get_prekey_bundle(user_id).await.unwrap();
+        assert_eq!((third.signed_prekey_id, third.one_time_prekey), (1, None));
+
+        // Topping up re-sends the published signed prekey unchanged, and
+        // replaying the claimed keys does not bring them back
+        request.one_time_prekeys.extend(one_time_prekeys(
+            &signing_key,
+            3..(4 + ONE_TIME_PREKEY_LOW_WATERMARK),
+        ));
+        let published = service.upload_prekeys(user_id, request).await.unwrap();
+        assert_eq!(
+            published.one_time_prekeys_remaining,
+            ONE_TIME_PREKEY_LOW_WATERMARK + 1
+        );
+        assert!(!published.replenish);
+        let bundle = service.get_prekey_bundle(user_id).await.unwrap();
+        assert_eq!(bundle.one_time_prekey.unwrap().prekey_id, 3);
+    }
+
+    #[tokio::test]
+    async fn bundle_without_signed_prekey_is_not_found() {
+        let store = InMemoryStore::new();
+        let (_, public_key) = account();
+        let user_id = store
+            .insert_user(public_key.as_str(), "alice")
+            .await
+            .unwrap();
+        let service = UserService::new(store);
+
+        assert!(matches!(
+            service.get_prekey_bundle(user_id).await,
+            Err(AppError::NotFound(_))
+        ));
+    }
+}
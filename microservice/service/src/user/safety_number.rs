@@ -0,0 +1,189 @@
+use base64::Engine as _;
+use db::{public_key::PublicKey, uuid::Uuid};
+use sha2::{Digest, Sha512};
+use shared::{errors::AppError, models::CUSTOM_ENGINE};
+
+/// Bumped whenever the derivation below changes, so old and new numbers never match.
+pub const SAFETY_NUMBER_VERSION: u16 = 0;
+
+/// Number of SHA-512 rounds applied per fingerprint (same work factor as Signal).
+const ITERATIONS: usize = 5200;
+
+const FINGERPRINT_LEN: usize = 30;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SafetyNumber {
+    pub version: u16,
+    /// 60 decimal digits: the two per-user halves, lowest first
+    pub digits: String,
+    /// `version || fingerprint_low || fingerprint_high`
+    pub qr_payload: Vec<u8>,
+}
+
+impl SafetyNumber {
+    /// Derives the safety number shared by two users. Swapping the arguments
+    /// yields the same number.
+    pub fn derive(
+        user_a: Uuid,
+        key_a: &PublicKey,
+        user_b: Uuid,
+        key_b: &PublicKey,
+    ) -> Result<Self, AppError> {
+        let mut fingerprints = [fingerprint(user_a, key_a)?, fingerprint(user_b, key_b)?];
+        fingerprints.sort();
+
+        let digits = fingerprints
+            .iter()
+            .map(displayable)
+            .collect::<String>();
+
+        let mut qr_payload = Vec::with_capacity(2 + 2 * FINGERPRINT_LEN);
+        qr_payload.extend_from_slice(&SAFETY_NUMBER_VERSION.to_be_bytes());
+        for fp in &fingerprints {
+            qr_payload.extend_from_slice(fp);
+        }
+
+        Ok(Self {
+            version: SAFETY_NUMBER_VERSION,
+            digits,
+            qr_payload,
+        })
+    }
+
+    /// Compares a user-supplied number, ignoring the spacing clients use for display.
+    pub fn matches(&self, candidate: &str) -> bool {
+        let normalized: String = candidate.chars().filter(|c| !c.is_whitespace()).collect();
+        normalized == self.digits
+    }
+
+    pub fn qr_payload_base64(&self) -> String {
+        CUSTOM_ENGINE.encode(&self.qr_payload)
+    }
+}
+
+/// Iterated hash over the user's identity key, bound to the user id so that a
+/// key re-used by two accounts produces two different fingerprints.
+fn fingerprint(user_id: Uuid, public_key: &PublicKey) -> Result<[u8; FINGERPRINT_LEN], AppError> {
+    let key_bytes = CUSTOM_ENGINE
+        .decode(public_key.as_str())
+        .map_err(|_| AppError::PublicKeyError(db::public_key::PublicKeyError::InvalidBase64))?;
+
+    let mut hasher = Sha512::new();
+    hasher.update(SAFETY_NUMBER_VERSION.to_be_bytes());
+    hasher.update(&key_bytes);
+    hasher.update(user_id.as_bytes());
+    let mut digest = hasher.finalize();
+
+    for _ in 1..ITERATIONS {
+        let mut hasher = Sha512::new();
+        hasher.update(digest);
+        hasher.update(&key_bytes);
+        digest = hasher.finalize();
+    }
+
+    let mut fingerprint = [0u8; FINGERPRINT_LEN];
+    fingerprint.copy_from_slice(&digest[..FINGERPRINT_LEN]);
+    Ok(fingerprint)
+}
+
+/// Encodes a fingerprint as six 5-digit groups, each taken from 5 bytes mod 100000.
+fn displayable(fingerprint: &[u8; FINGERPRINT_LEN]) -> String {
+    fingerprint
+        .chunks(5)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |acc, byte| (acc << 8) | u64::from(*byte));
+            format!("{:05}", value % 100_000)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::{
+        ecdsa::{SigningKey, VerifyingKey},
+        elliptic_curve::rand_core::OsRng,
+    };
+
+    fn generate_key() -> PublicKey {
+        let signing_key = SigningKey::random(&mut OsRng);
+        let verifying_key = VerifyingKey::from(&signing_key);
+        PublicKey::new(CUSTOM_ENGINE.encode(verifying_key.to_encoded_point(true).as_bytes()))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_safety_number_is_order_independent() {
+        let (user_a, key_a) = (Uuid::now_v7(), generate_key());
+        let (user_b, key_b) = (Uuid::now_v7(), generate_key());
+
+        let ab = SafetyNumber::derive(user_a, &key_a, user_b, &key_b).unwrap();
+        let ba = SafetyNumber::derive(user_b, &key_b, user_a, &key_a).unwrap();
+
+        assert_eq!(ab, ba);
+    }
+
+    #[test]
+    fn test_safety_number_format() {
+        let number = SafetyNumber::derive(
+            Uuid::now_v7(),
+            &generate_key(),
+            Uuid::now_v7(),
+            &generate_key(),
+        )
+        .unwrap();
+
+        assert_eq!(number.digits.len(), 60);
+        assert!(number.digits.chars().all(|c| c.is_ascii_digit()));
+        assert_eq!(number.qr_payload.len(), 2 + 2 * FINGERPRINT_LEN);
+        assert_eq!(
+            CUSTOM_ENGINE.decode(number.qr_payload_base64()).unwrap(),
+            number.qr_payload
+        );
+    }
+
+    #[test]
+    fn test_safety_number_changes_with_key() {
+        let (user_a, key_a) = (Uuid::now_v7(), generate_key());
+        let user_b = Uuid::now_v7();
+
+        let before = SafetyNumber::derive(user_a, &key_a, user_b, &generate_key()).unwrap();
+        let after = SafetyNumber::derive(user_a, &key_a, user_b, &generate_key()).unwrap();
+
+        assert_ne!(before.digits, after.digits);
+    }
+
+    #[test]
+    fn test_safety_number_bound_to_user_id() {
+        let key = generate_key();
+        let other = (Uuid::now_v7(), generate_key());
+
+        let first = SafetyNumber::derive(Uuid::now_v7(), &key, other.0, &other.1).unwrap();
+        let second = SafetyNumber::derive(Uuid::now_v7(), &key, other.0, &other.1).unwrap();
+
+        assert_ne!(first.digits, second.digits);
+    }
+
+    #[test]
+    fn test_matches_ignores_whitespace() {
+        let number = SafetyNumber::derive(
+            Uuid::now_v7(),
+            &generate_key(),
+            Uuid::now_v7(),
+            &generate_key(),
+        )
+        .unwrap();
+
+        let spaced = number
+            .digits
+            .as_bytes()
+            .chunks(5)
+            .map(|chunk| std::str::from_utf8(chunk).unwrap())
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        assert!(number.matches(&number.digits));
+        assert!(number.matches(&spaced));
+        assert!(!number.matches(&"0".repeat(60)));
+    }
+}
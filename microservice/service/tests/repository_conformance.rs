@@ -0,0 +1,878 @@
+//! Shared behaviour every repository backend must provide. Each case is a
+//! generic function over the three repository traits; `conformance_tests!`
+//! instantiates it once per backend.
+
+use base64::Engine as _;
+use chrono::Utc;
+use db::{
+    SqlitePool,
+    db::SqliteDb,
+    models::{OneTimePrekey, RateLimitShare},
+    uuid::Uuid,
+};
+use p256::{
+    ecdsa::{SigningKey, VerifyingKey},
+    elliptic_curve::rand_core::OsRng,
+};
+use service::{
+    memory::InMemoryStore,
+    message::repository::MessageRepository,
+    token::repository::TokenRepository,
+    unit_of_work::{UnitOfWork, Work},
+    user::{UserRepository, UserService},
+};
+use shared::{errors::AppError, models::CUSTOM_ENGINE};
+
+trait Store: UserRepository + MessageRepository + TokenRepository + UnitOfWork + Clone {}
+
+impl<T> Store for T where
+    T: UserRepository + MessageRepository + TokenRepository + UnitOfWork + Clone
+{
+}
+
+async fn sqlite_store() -> SqliteDb {
+    let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+    sqlx::migrate!("../db/migrations").run(&pool).await.unwrap();
+    SqliteDb::new(pool)
+}
+
+macro_rules! conformance_tests {
+    ($($name:ident),* $(,)?) => {
+        mod sqlite {
+            $(
+                #[tokio::test]
+                async fn $name() {
+                    super::$name(&super::sqlite_store().await).await;
+                }
+            )*
+        }
+
+        mod memory {
+            $(
+                #[tokio::test]
+                async fn $name() {
+                    super::$name(&service::memory::InMemoryStore::new()).await;
+                }
+            )*
+        }
+    };
+}
+
+conformance_tests!(
+    user_roundtrip,
+    duplicate_username_is_rejected,
+    duplicate_public_key_is_rejected,
+    missing_user_is_not_found,
+    update_user_replaces_key,
+    update_user_to_taken_username_is_rejected,
+    verified_contact_is_upserted,
+    message_requires_existing_users,
+    message_commitment_is_stored,
+    ephemeral_key_proof_is_stored,
+    membership_leaves_are_appended_once_per_user,
+    anonymous_nullifier_is_single_use,
+    blocked_identities_are_recorded_once,
+    signed_prekey_only_moves_forward,
+    one_time_prekeys_are_claimed_once,
+    key_backup_challenge_is_taken_once,
+    key_backup_challenges_are_independent,
+    key_backup_challenges_are_limited_and_held_back,
+    conversation_is_bidirectional_and_limited,
+    unread_messages_until_marked_read,
+    thread_replies_paginate,
+    complete_thread_recurses,
+    complete_thread_of_missing_root_is_not_found,
+    user_threads_only_include_answered_messages,
+    refresh_token_lifecycle,
+    expired_refresh_token_is_invalid,
+    duplicate_refresh_token_is_rejected,
+    revoking_user_refresh_tokens_spares_other_users,
+    cleanup_removes_only_expired_tokens,
+    committed_work_is_applied,
+    rolled_back_work_is_discarded,
+    dropped_work_is_discarded,
+    rotate_key_revokes_sessions,
+    failed_rotate_key_leaves_key_and_sessions,
+);
+
+fn generate_public_key() -> String {
+    let signing_key = SigningKey::random(&mut OsRng);
+    let verifying_key = VerifyingKey::from(&signing_key);
+    CUSTOM_ENGINE.encode(verifying_key.to_encoded_point(true).as_bytes())
+}
+
+async fn create_user(store: &impl Store, username: &str) -> Uuid {
+    store
+        .insert_user(&generate_public_key(), username)
+        .await
+        .unwrap()
+}
+
+async fn send(store: &impl Store, from: Uuid, to: Uuid, parent_id: Option<i64>) -> i64 {
+    store
+        .insert_message(from, to, "ciphertext", None, parent_id)
+        .await
+        .unwrap()
+        .unwrap()
+}
+
+async fn user_roundtrip(store: &impl Store) {
+    let public_key = generate_public_key();
+    let user_id = store.insert_user(&public_key, "roundtrip").await.unwrap();
+
+    let user = store.get_user_by_id(user_id).await.unwrap();
+    assert_eq!(user.id, user_id);
+    assert_eq!(user.username, "roundtrip");
+    assert_eq!(user.public_key.as_str(), public_key);
+    assert_eq!(user.last_login, None);
+
+    let by_key = store
+        .get_user_by_pubkey(user.public_key_hash.as_str())
+        .await
+        .unwrap();
+    assert_eq!(by_key.id, user_id);
+
+    let hash = store.fetch_public_key_hash(user_id).await.unwrap();
+    assert_eq!(hash, user.public_key_hash.as_str());
+
+    let users = store.get_users(None).await.unwrap();
+    assert_eq!(users.len(), 1);
+}
+
+async fn duplicate_username_is_rejected(store: &impl Store) {
+    create_user(store, "taken").await;
+
+    let result = store.insert_user(&generate_public_key(), "taken").await;
+    assert!(matches!(result, Err(AppError::UniqueViolation(_))));
+}
+
+async fn duplicate_public_key_is_rejected(store: &impl Store) {
+    let public_key = generate_public_key();
+    store.insert_user(&public_key, "first").await.unwrap();
+
+    let result = store.insert_user(&public_key, "second").await;
+    assert!(matches!(result, Err(AppError::UniqueViolation(_))));
+}
+
+async fn missing_user_is_not_found(store: &impl Store) {
+    let result = store.get_user_by_id(Uuid::now_v7()).await;
+    assert!(matches!(result, Err(AppError::NotFound(_))));
+
+    let result = store.fetch_public_key_hash(Uuid::now_v7()).await;
+    assert!(matches!(result, Err(AppError::NotFound(_))));
+
+    let result = store
+        .update_user(Uuid::now_v7(), Some("nobody".to_string()), None, None)
+        .await;
+    assert!(matches!(result, Err(AppError::NotFound(_))));
+}
+
+async fn update_user_replaces_key(store: &impl Store) {
+    let user_id = create_user(store, "rotating").await;
+    let before = store.get_user_by_id(user_id).await.unwrap();
+
+    let new_key = generate_public_key();
+    store
+        .update_user(user_id, Some("rotated".to_string()), Some(new_key.clone()), None)
+        .await
+        .unwrap();
+
+    let after = store.get_user_by_id(user_id).await.unwrap();
+    assert_eq!(after.username, "rotated");
+    assert_eq!(after.public_key.as_str(), new_key);
+    assert_ne!(after.public_key_hash, before.public_key_hash);
+    assert!(store.get_user_by_pubkey(before.public_key_hash.as_str()).await.is_err());
+}
+
+async fn update_user_to_taken_username_is_rejected(store: &impl Store) {
+    create_user(store, "alice").await;
+    let bob = create_user(store, "bob").await;
+
+    let result = store
+        .update_user(bob, Some("alice".to_string()), None, None)
+        .await;
+    assert!(matches!(result, Err(AppError::UniqueViolation(_))));
+    assert_eq!(store.get_user_by_id(bob).await.unwrap().username, "bob");
+}
+
+async fn verified_contact_is_upserted(store: &impl Store) {
+    let alice = create_user(store, "alice").await;
+    let bob = create_user(store, "bob").await;
+    let old_hash = store.fetch_public_key_hash(alice).await.unwrap();
+    let new_hash = store.fetch_public_key_hash(bob).await.unwrap();
+
+    assert!(store.get_verified_contact(alice, bob).await.unwrap().is_none());
+
+    store.mark_contact_verified(alice, bob, &old_hash).await.unwrap();
+    store.mark_contact_verified(alice, bob, &new_hash).await.unwrap();
+
+    let verified = store.get_verified_contact(alice, bob).await.unwrap().unwrap();
+    assert_eq!(verified.public_key_hash.as_str(), new_hash);
+    assert!(store.get_verified_contact(bob, alice).await.unwrap().is_none());
+
+    let result = store.mark_contact_verified(alice, Uuid::now_v7(), &new_hash).await;
+    assert!(matches!(result, Err(AppError::ForeignKeyViolation(_))));
+}
+
+async fn message_requires_existing_users(store: &impl Store) {
+    let alice = create_user(store, "alice").await;
+
+    let result = store
+        .insert_message(alice, Uuid::now_v7(), "ciphertext", None, None)
+        .await;
+    assert!(matches!(result, Err(AppError::ForeignKeyViolation(_))));
+}
+
+async fn message_commitment_is_stored(store: &impl Store) {
+    let alice = create_user(store, "alice").await;
+    let bob = create_user(store, "bob").await;
+
+    let committed = store
+        .insert_attested_message(alice, bob, "ciphertext", None, None, Some("commitment"), None)
+        .await
+        .unwrap()
+        .unwrap();
+    let plain = send(store, alice, bob, None).await;
+
+    let commitment = store.get_message_commitment(committed).await.unwrap();
+    assert_eq!(commitment.as_deref(), Some("commitment"));
+    assert!(store.get_message_commitment(plain).await.unwrap().is_none());
+    assert!(store.get_message_commitment(i64::MAX).await.unwrap().is_none());
+
+    let message = store.get_message_by_id(committed).await.unwrap().unwrap();
+    assert_eq!(message.encrypted_content, "ciphertext");
+
+    let result = store
+        .insert_attested_message(
+            alice,
+            Uuid::now_v7(),
+            "ciphertext",
+            None,
+            None,
+            Some("commitment"),
+            Some("proof"),
+        )
+        .await;
+    assert!(matches!(result, Err(AppError::ForeignKeyViolation(_))));
+}
+
+async fn ephemeral_key_proof_is_stored(store: &impl Store) {
+    let alice = create_user(store, "alice").await;
+    let bob = create_user(store, "bob").await;
+
+    let proven = store
+        .insert_attested_message(alice, bob, "ciphertext", None, None, None, Some("proof"))
+        .await
+        .unwrap()
+        .unwrap();
+    let plain = send(store, alice, bob, None).await;
+
+    let proof = store.get_ephemeral_key_proof(proven).await.unwrap();
+    assert_eq!(proof.as_deref(), Some("proof"));
+    assert!(store.get_message_commitment(proven).await.unwrap().is_none());
+    assert!(store.get_ephemeral_key_proof(plain).await.unwrap().is_none());
+    assert!(store.get_ephemeral_key_proof(i64::MAX).await.unwrap().is_none());
+
+    assert!(store.get_user_message_key(bob).await.unwrap().is_none());
+    store.set_message_key(bob, "message-key").await.unwrap();
+    assert_eq!(
+        store.get_user_message_key(bob).await.unwrap().as_deref(),
+        Some("message-key")
+    );
+    assert!(store.get_user_message_key(Uuid::now_v7()).await.unwrap().is_none());
+    assert!(store.set_message_key(Uuid::now_v7(), "message-key").await.is_err());
+}
+
+async fn membership_leaves_are_appended_once_per_user(store: &impl Store) {
+    let alice = create_user(store, "alice").await;
+    let bob = create_user(store, "bob").await;
+
+    let first = store.insert_membership_leaf(alice, "leaf-a").await.unwrap();
+    let second = store.insert_membership_leaf(bob, "leaf-b").await.unwrap();
+    assert!(second > first);
+
+    let leaves = store.get_membership_leaves(0).await.unwrap();
+    let leaves: Vec<_> = leaves.iter().map(|leaf| (leaf.user_id, leaf.leaf.as_str())).collect();
+    assert_eq!(leaves, vec![(alice, "leaf-a"), (bob, "leaf-b")]);
+
+    let newer = store.get_membership_leaves(first).await.unwrap();
+    assert_eq!(newer.len(), 1);
+    assert_eq!(newer[0].leaf_index, second);
+
+    let result = store.insert_membership_leaf(alice, "leaf-c").await;
+    assert!(matches!(result, Err(AppError::UniqueViolation(_))));
+
+    let result = store.insert_membership_leaf(Uuid::now_v7(), "leaf-d").await;
+    assert!(matches!(result, Err(AppError::ForeignKeyViolation(_))));
+}
+
+fn share(nullifier: &str, share_x: &str) -> RateLimitShare {
+    RateLimitShare {
+        nullifier: nullifier.to_string(),
+        epoch: 1,
+        share_x: share_x.to_string(),
+        share_y: format!("y-{share_x}"),
+    }
+}
+
+async fn anonymous_nullifier_is_single_use(store: &impl Store) {
+    let alice = create_user(store, "alice").await;
+    let bob = create_user(store, "bob").await;
+
+    let first = store
+        .insert_anonymous_message(alice, "first", "root", &share("nullifier", "x1"))
+        .await
+        .unwrap();
+    let second = store
+        .insert_anonymous_message(alice, "second", "root", &share("other", "x2"))
+        .await
+        .unwrap();
+
+    let result = store
+        .insert_anonymous_message(bob, "third", "root", &share("nullifier", "x3"))
+        .await;
+    assert!(matches!(result, Err(AppError::UniqueViolation(_))));
+    // The earlier share is kept, for recovering the sender's secret
+    assert_eq!(
+        store.get_rate_limit_share("nullifier").await.unwrap(),
+        Some(share("nullifier", "x1"))
+    );
+
+    let result = store
+        .insert_anonymous_message(Uuid::now_v7(), "fourth", "root", &share("fresh", "x4"))
+        .await;
+    assert!(matches!(result, Err(AppError::ForeignKeyViolation(_))));
+    assert_eq!(store.get_rate_limit_share("fresh").await.unwrap(), None);
+
+    let messages = store.get_anonymous_messages(alice, 10).await.unwrap();
+    let ids: Vec<i64> = messages.iter().map(|message| message.id).collect();
+    assert_eq!(ids, vec![second, first]);
+    assert_eq!(messages[1].encrypted_content, "first");
+    assert_eq!(messages[1].nullifier, "nullifier");
+
+    assert_eq!(store.get_anonymous_messages(alice, 1).await.unwrap().len(), 1);
+    assert!(store.get_anonymous_messages(bob, 10).await.unwrap().is_empty());
+}
+
+async fn blocked_identities_are_recorded_once(store: &impl Store) {
+    assert!(store.get_blocked_identity_secrets().await.unwrap().is_empty());
+
+    store.block_identity("commitment-a", "secret-a").await.unwrap();
+    store.block_identity("commitment-b", "secret-b").await.unwrap();
+    store.block_identity("commitment-a", "secret-a").await.unwrap();
+
+    let mut secrets = store.get_blocked_identity_secrets().await.unwrap();
+    secrets.sort();
+    assert_eq!(secrets, vec!["secret-a", "secret-b"]);
+}
+
+async fn signed_prekey_only_moves_forward(store: &impl Store) {
+    let alice = create_user(store, "alice").await;
+    assert!(store.get_signed_prekey(alice).await.unwrap().is_none());
+
+    assert!(
+        store
+            .upsert_signed_prekey(alice, "identity", 2, "prekey-2", "signature-2")
+            .await
+            .unwrap()
+    );
+    assert!(
+        !store
+            .upsert_signed_prekey(alice, "identity", 1, "prekey-1", "signature-1")
+            .await
+            .unwrap()
+    );
+    assert!(
+        !store
+            .upsert_signed_prekey(alice, "identity", 2, "prekey-x", "signature-x")
+            .await
+            .unwrap()
+    );
+
+    let stored = store.get_signed_prekey(alice).await.unwrap().unwrap();
+    assert_eq!(stored.prekey_id, 2);
+    assert_eq!(stored.prekey, "prekey-2");
+
+    assert!(
+        store
+            .upsert_signed_prekey(alice, "identity", 3, "prekey-3", "signature-3")
+            .await
+            .unwrap()
+    );
+    let stored = store.get_signed_prekey(alice).await.unwrap().unwrap();
+    assert_eq!((stored.prekey_id, stored.signature.as_str()), (3, "signature-3"));
+
+    assert!(matches!(
+        store
+            .upsert_signed_prekey(Uuid::now_v7(), "identity", 1, "prekey", "signature")
+            .await,
+        Err(AppError::ForeignKeyViolation(_))
+    ));
+}
+
+async fn one_time_prekeys_are_claimed_once(store: &impl Store) {
+    let alice = create_user(store, "alice").await;
+    let bob = create_user(store, "bob").await;
+    let prekey = |prekey_id: i64| OneTimePrekey {
+        prekey_id,
+        prekey: format!("prekey-{prekey_id}"),
+        signature: format!("signature-{prekey_id}"),
+    };
+
+    let inserted = store
+        .insert_one_time_prekeys(alice, &[prekey(2), prekey(1)])
+        .await
+        .unwrap();
+    assert_eq!(inserted, 2);
+    store.insert_one_time_prekeys(bob, &[prekey(1)]).await.unwrap();
+
+    assert_eq!(store.claim_one_time_prekey(alice).await.unwrap(), Some(prekey(1)));
+    assert_eq!(store.count_one_time_prekeys(alice).await.unwrap(), 1);
+
+    // A claimed id cannot be uploaded again
+    let inserted = store
+        .insert_one_time_prekeys(alice, &[prekey(1), prekey(3)])
+        .await
+        .unwrap();
+    assert_eq!(inserted, 1);
+
+    assert_eq!(store.claim_one_time_prekey(alice).await.unwrap(), Some(prekey(2)));
+    assert_eq!(store.claim_one_time_prekey(alice).await.unwrap(), Some(prekey(3)));
+    assert_eq!(store.claim_one_time_prekey(alice).await.unwrap(), None);
+    assert_eq!(store.count_one_time_prekeys(bob).await.unwrap(), 1);
+
+    assert!(matches!(
+        store.insert_one_time_prekeys(Uuid::now_v7(), &[prekey(1)]).await,
+        Err(AppError::ForeignKeyViolation(_))
+    ));
+}
+
+async fn key_backup_challenge_is_taken_once(store: &impl Store) {
+    let alice = create_user(store, "alice").await;
+    let now = Utc::now().naive_utc();
+    let later = now + chrono::Duration::minutes(5);
+
+    assert!(!store.set_key_backup_challenge(alice, "challenge", now, later, 3).await.unwrap());
+    assert!(
+        store
+            .upsert_key_backup(alice, 2, "key", "salt", 100_000, "recovery")
+            .await
+            .unwrap()
+    );
+    assert!(
+        !store
+            .upsert_key_backup(alice, 1, "old", "salt", 100_000, "recovery")
+            .await
+            .unwrap()
+    );
+    assert!(store.set_key_backup_challenge(alice, "challenge", now, later, 3).await.unwrap());
+
+    assert!(
+        store
+            .take_key_backup_challenge(alice, "challenge", later, false)
+            .await
+            .unwrap()
+            .is_none()
+    );
+    let backup = store
+        .take_key_backup_challenge(alice, "challenge", now, false)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!((backup.version, backup.encrypted_key.as_str()), (2, "key"));
+    assert!(
+        store
+            .take_key_backup_challenge(alice, "challenge", now, false)
+            .await
+            .unwrap()
+            .is_none()
+    );
+
+    assert_eq!(store.record_failed_key_recovery(alice).await.unwrap(), Some(1));
+    assert_eq!(store.record_failed_key_recovery(alice).await.unwrap(), Some(2));
+    let bob = create_user(store, "bob").await;
+    assert_eq!(store.record_failed_key_recovery(bob).await.unwrap(), None);
+}
+
+async fn key_backup_challenges_are_independent(store: &impl Store) {
+    let alice = create_user(store, "alice").await;
+    let now = Utc::now().naive_utc();
+    let later = now + chrono::Duration::minutes(5);
+    store
+        .upsert_key_backup(alice, 1, "key", "salt", 100_000, "recovery")
+        .await
+        .unwrap();
+
+    // Issuing a challenge leaves the ones already handed out pending
+    store.set_key_backup_challenge(alice, "first", now, later, 3).await.unwrap();
+    store.set_key_backup_challenge(alice, "second", now, later, 3).await.unwrap();
+    assert!(store.take_key_backup_challenge(alice, "first", now, false).await.unwrap().is_some());
+    assert!(store.take_key_backup_challenge(alice, "second", now, false).await.unwrap().is_some());
+
+    // but not ones for a backup that has since been replaced
+    store.set_key_backup_challenge(alice, "stale", now, later, 3).await.unwrap();
+    store
+        .upsert_key_backup(alice, 2, "key-2", "salt", 100_000, "recovery")
+        .await
+        .unwrap();
+    assert!(store.take_key_backup_challenge(alice, "stale", now, false).await.unwrap().is_none());
+}
+
+async fn key_backup_challenges_are_limited_and_held_back(store: &impl Store) {
+    let alice = create_user(store, "alice").await;
+    let now = Utc::now().naive_utc();
+    let later = now + chrono::Duration::minutes(5);
+    let expires_at = now + chrono::Duration::minutes(10);
+    store
+        .upsert_key_backup(alice, 1, "key", "salt", 100_000, "recovery")
+        .await
+        .unwrap();
+
+    for challenge in ["first", "second"] {
+        assert!(store.set_key_backup_challenge(alice, challenge, now, expires_at, 2).await.unwrap());
+    }
+    assert!(!store.set_key_backup_challenge(alice, "third", now, expires_at, 2).await.unwrap());
+
+    // Holds are only extended, and keep challenges from being taken unless
+    // ignored
+    store.hold_back_key_recovery(alice, later).await.unwrap();
+    store.hold_back_key_recovery(alice, now).await.unwrap();
+    let backup = store.get_key_backup(alice).await.unwrap().unwrap();
+    assert_eq!(
+        backup.recovery_retry_at.map(|at| at.and_utc().timestamp_micros()),
+        Some(later.and_utc().timestamp_micros())
+    );
+    assert!(store.take_key_backup_challenge(alice, "first", now, false).await.unwrap().is_none());
+    assert!(store.take_key_backup_challenge(alice, "first", now, true).await.unwrap().is_some());
+    assert!(store.take_key_backup_challenge(alice, "second", later, false).await.unwrap().is_some());
+}
+
+async fn conversation_is_bidirectional_and_limited(store: &impl Store) {
+    let alice = create_user(store, "alice").await;
+    let bob = create_user(store, "bob").await;
+    let carol = create_user(store, "carol").await;
+
+    send(store, alice, bob, None).await;
+    send(store, bob, alice, None).await;
+    send(store, alice, carol, None).await;
+
+    let conversation = store.get_conversation(alice, bob, None).await.unwrap();
+    assert_eq!(conversation.len(), 2);
+    assert!(conversation.iter().all(|message| message.sender_id != carol
+        && message.recipient_id != carol));
+
+    let limited = store.get_conversation(bob, alice, Some(1)).await.unwrap();
+    assert_eq!(limited.len(), 1);
+}
+
+async fn unread_messages_until_marked_read(store: &impl Store) {
+    let alice = create_user(store, "alice").await;
+    let bob = create_user(store, "bob").await;
+
+    let first = send(store, alice, bob, None).await;
+    let second = send(store, alice, bob, None).await;
+    send(store, bob, alice, None).await;
+
+    let unread = store.get_unread_messages(bob).await.unwrap();
+    assert_eq!(
+        unread.iter().map(|message| message.id).collect::<Vec<_>>(),
+        vec![first, second]
+    );
+
+    store.mark_message_read(first).await.unwrap();
+    store.mark_message_read(i64::MAX).await.unwrap();
+
+    assert!(store.get_message_by_id(first).await.unwrap().unwrap().is_read);
+    let unread = store.get_unread_messages(bob).await.unwrap();
+    assert_eq!(unread.len(), 1);
+    assert_eq!(unread[0].id, second);
+}
+
+async fn thread_replies_paginate(store: &impl Store) {
+    let alice = create_user(store, "alice").await;
+    let bob = create_user(store, "bob").await;
+
+    let root = send(store, alice, bob, None).await;
+    for _ in 0..5 {
+        send(store, bob, alice, Some(root)).await;
+    }
+
+    let replies = store.get_thread_replies(root, None, None).await.unwrap();
+    assert_eq!(replies.len(), 5);
+    assert!(replies.iter().all(|reply| reply.parent_id == Some(root)));
+
+    let page = store.get_thread_replies(root, Some(2), Some(4)).await.unwrap();
+    assert_eq!(page.len(), 1);
+}
+
+async fn complete_thread_recurses(store: &impl Store) {
+    let alice = create_user(store, "alice").await;
+    let bob = create_user(store, "bob").await;
+
+    let root = send(store, alice, bob, None).await;
+    let reply = send(store, bob, alice, Some(root)).await;
+    let nested = send(store, alice, bob, Some(reply)).await;
+    let unrelated = send(store, alice, bob, None).await;
+
+    let thread = store.get_complete_thread(root, None).await.unwrap();
+    let mut ids: Vec<i64> = thread.iter().map(|message| message.id).collect();
+    assert_eq!(ids[0], root);
+
+    ids.sort();
+    assert_eq!(ids, vec![root, reply, nested]);
+    assert!(!ids.contains(&unrelated));
+}
+
+async fn complete_thread_of_missing_root_is_not_found(store: &impl Store) {
+    let result = store.get_complete_thread(i64::MAX, None).await;
+    assert!(matches!(result, Err(AppError::NotFound(_))));
+}
+
+async fn user_threads_only_include_answered_messages(store: &impl Store) {
+    let alice = create_user(store, "alice").await;
+    let bob = create_user(store, "bob").await;
+
+    let answered = send(store, alice, bob, None).await;
+    send(store, bob, alice, Some(answered)).await;
+    send(store, alice, bob, None).await;
+
+    let threads = store.get_user_threads(alice, None).await.unwrap();
+    assert_eq!(threads.len(), 1);
+    assert_eq!(threads[0].id, answered);
+
+    let threads = store.get_user_threads(bob, Some(0)).await.unwrap();
+    assert!(threads.is_empty());
+}
+
+async fn refresh_token_lifecycle(store: &impl Store) {
+    let alice = create_user(store, "alice").await;
+    let expires_at = Utc::now().timestamp() + 3600;
+
+    store
+        .store_refresh_token(alice, "token", expires_at, Some("laptop".to_string()))
+        .await
+        .unwrap();
+    assert!(store.validate_refresh_token(alice, "token").await.unwrap());
+    assert!(!store.validate_refresh_token(Uuid::now_v7(), "token").await.unwrap());
+    assert!(!store.validate_refresh_token(alice, "other").await.unwrap());
+
+    store
+        .revoke_refresh_token("token", Some("logout".to_string()))
+        .await
+        .unwrap();
+    assert!(!store.validate_refresh_token(alice, "token").await.unwrap());
+
+    store.revoke_refresh_token("unknown", None).await.unwrap();
+}
+
+async fn expired_refresh_token_is_invalid(store: &impl Store) {
+    let alice = create_user(store, "alice").await;
+
+    store
+        .store_refresh_token(alice, "expired", Utc::now().timestamp() - 3600, None)
+        .await
+        .unwrap();
+    assert!(!store.validate_refresh_token(alice, "expired").await.unwrap());
+}
+
+async fn duplicate_refresh_token_is_rejected(store: &impl Store) {
+    let alice = create_user(store, "alice").await;
+    let expires_at = Utc::now().timestamp() + 3600;
+
+    store
+        .store_refresh_token(alice, "token", expires_at, None)
+        .await
+        .unwrap();
+    let result = store.store_refresh_token(alice, "token", expires_at, None).await;
+    assert!(matches!(result, Err(AppError::UniqueViolation(_))));
+
+    let result = store
+        .store_refresh_token(Uuid::now_v7(), "orphan", expires_at, None)
+        .await;
+    assert!(matches!(result, Err(AppError::ForeignKeyViolation(_))));
+}
+
+async fn revoking_user_refresh_tokens_spares_other_users(store: &impl Store) {
+    let alice = create_user(store, "alice").await;
+    let bob = create_user(store, "bob").await;
+    let expires_at = Utc::now().timestamp() + 3600;
+
+    for (user, token) in [(alice, "alice_laptop"), (alice, "alice_phone"), (bob, "bob_laptop")] {
+        store
+            .store_refresh_token(user, token, expires_at, None)
+            .await
+            .unwrap();
+    }
+
+    let revoked = store
+        .revoke_user_refresh_tokens(alice, Some("logout_everywhere".to_string()))
+        .await
+        .unwrap();
+    assert_eq!(revoked, 2);
+    assert!(!store.validate_refresh_token(alice, "alice_laptop").await.unwrap());
+    assert!(!store.validate_refresh_token(alice, "alice_phone").await.unwrap());
+    assert!(store.validate_refresh_token(bob, "bob_laptop").await.unwrap());
+
+    let revoked = store.revoke_user_refresh_tokens(alice, None).await.unwrap();
+    assert_eq!(revoked, 0);
+}
+
+async fn cleanup_removes_only_expired_tokens(store: &impl Store) {
+    let alice = create_user(store, "alice").await;
+    let now = Utc::now().timestamp();
+    store
+        .store_refresh_token(alice, "expired", now - 3600, None)
+        .await
+        .unwrap();
+    store
+        .store_refresh_token(alice, "live", now + 3600, None)
+        .await
+        .unwrap();
+
+    assert_eq!(store.cleanup_expired_tokens().await.unwrap(), 1);
+    assert_eq!(store.cleanup_expired_tokens().await.unwrap(), 0);
+    assert!(store.validate_refresh_token(alice, "live").await.unwrap());
+}
+
+async fn committed_work_is_applied(store: &impl Store) {
+    let alice = create_user(store, "alice").await;
+    store
+        .store_refresh_token(alice, "token", Utc::now().timestamp() + 3600, None)
+        .await
+        .unwrap();
+
+    let work = store.begin().await.unwrap();
+    work.update_user(alice, Some("alicia".to_string()), None, None)
+        .await
+        .unwrap();
+    work.revoke_refresh_token("token", None).await.unwrap();
+    work.commit().await.unwrap();
+
+    assert_eq!(store.get_user_by_id(alice).await.unwrap().username, "alicia");
+    assert!(!store.validate_refresh_token(alice, "token").await.unwrap());
+}
+
+async fn rolled_back_work_is_discarded(store: &impl Store) {
+    let alice = create_user(store, "alice").await;
+
+    let work = store.begin().await.unwrap();
+    work.update_user(alice, Some("alicia".to_string()), None, None)
+        .await
+        .unwrap();
+    let bob = work.insert_user(&generate_public_key(), "bob").await.unwrap();
+    work.rollback().await.unwrap();
+
+    assert_eq!(store.get_user_by_id(alice).await.unwrap().username, "alice");
+    assert!(matches!(store.get_user_by_id(bob).await, Err(AppError::NotFound(_))));
+}
+
+async fn dropped_work_is_discarded(store: &impl Store) {
+    let alice = create_user(store, "alice").await;
+
+    {
+        let work = store.begin().await.unwrap();
+        work.update_user(alice, Some("alicia".to_string()), None, None)
+            .await
+            .unwrap();
+    }
+
+    assert_eq!(store.get_user_by_id(alice).await.unwrap().username, "alice");
+}
+
+async fn rotate_key_revokes_sessions(store: &impl Store) {
+    let alice = create_user(store, "alice").await;
+    let expires_at = Utc::now().timestamp() + 3600;
+    for token in ["laptop", "phone"] {
+        store
+            .store_refresh_token(alice, token, expires_at, None)
+            .await
+            .unwrap();
+    }
+    let new_key = generate_public_key();
+
+    let revoked = UserService::new(store.clone())
+        .rotate_key(alice, &new_key)
+        .await
+        .unwrap();
+
+    assert_eq!(revoked, 2);
+    assert_eq!(store.get_user_by_id(alice).await.unwrap().public_key.as_str(), new_key);
+    assert!(!store.validate_refresh_token(alice, "laptop").await.unwrap());
+    assert!(!store.validate_refresh_token(alice, "phone").await.unwrap());
+}
+
+async fn failed_rotate_key_leaves_key_and_sessions(store: &impl Store) {
+    let alice = create_user(store, "alice").await;
+    let bob = create_user(store, "bob").await;
+    store
+        .store_refresh_token(alice, "laptop", Utc::now().timestamp() + 3600, None)
+        .await
+        .unwrap();
+    let original_key = store.get_user_by_id(alice).await.unwrap().public_key;
+    let taken_key = store.get_user_by_id(bob).await.unwrap().public_key;
+
+    // The tokens are revoked first; the key update then fails on the unique
+    // constraint, which must undo the revocation.
+    let result = UserService::new(store.clone())
+        .rotate_key(alice, taken_key.as_str())
+        .await;
+
+    assert!(matches!(result, Err(AppError::UniqueViolation(_))));
+    assert_eq!(store.get_user_by_id(alice).await.unwrap().public_key, original_key);
+    assert!(store.validate_refresh_token(alice, "laptop").await.unwrap());
+}
+
+#[tokio::test]
+async fn in_memory_store_clones_share_state() {
+    let store = InMemoryStore::new();
+    let clone = store.clone();
+
+    let user_id = create_user(&store, "shared").await;
+    assert!(clone.get_user_by_id(user_id).await.is_ok());
+}
+
+#[tokio::test]
+async fn in_memory_writes_wait_for_open_transactions() {
+    let store = InMemoryStore::new();
+    let alice = create_user(&store, "alice").await;
+
+    let work = store.begin().await.unwrap();
+    work.update_user(alice, Some("alicia".to_string()), None, None)
+        .await
+        .unwrap();
+    let bob = tokio::spawn({
+        let store = store.clone();
+        async move { create_user(&store, "bob").await }
+    });
+    tokio::task::yield_now().await;
+    assert!(!bob.is_finished());
+
+    work.commit().await.unwrap();
+    let bob = bob.await.unwrap();
+    assert_eq!(store.get_user_by_id(alice).await.unwrap().username, "alicia");
+    assert!(store.get_user_by_id(bob).await.is_ok());
+}
+
+#[tokio::test]
+async fn in_memory_overlapping_transactions_both_commit() {
+    let store = InMemoryStore::new();
+
+    let first = store.begin().await.unwrap();
+    let second = tokio::spawn({
+        let store = store.clone();
+        async move {
+            let work = store.begin().await.unwrap();
+            let bob = work.insert_user(&generate_public_key(), "bob").await.unwrap();
+            work.commit().await.unwrap();
+            bob
+        }
+    });
+    tokio::task::yield_now().await;
+    assert!(!second.is_finished());
+
+    let alice = first.insert_user(&generate_public_key(), "alice").await.unwrap();
+    first.commit().await.unwrap();
+    let bob = second.await.unwrap();
+    assert!(store.get_user_by_id(alice).await.is_ok());
+    assert!(store.get_user_by_id(bob).await.is_ok());
+}
@@ -1,39 +1,290 @@
+//! Server-side encryption of data at rest with AES-256-GCM.
+//!
+//! Data is encrypted under a [`KeyRing`]: one current key that new data is
+//! encrypted with, and any number of retired keys that are only used to
+//! decrypt what was encrypted before a rotation. Each ciphertext starts with
+//! a header naming the format and the id of the key it was encrypted with:
+//!
+//! ```text
+//! format (1 byte) | key id (4 bytes, big-endian) | nonce (12 bytes) | ciphertext and tag
+//! ```
+//!
+//! The header is authenticated along with any associated data the caller
+//! binds the ciphertext to, such as the row and column it is stored in, so a
+//! ciphertext cannot be moved elsewhere or relabelled with another key id.
+//!
+//! Rotation is lazy: after [`KeyRing::rotate`], old ciphertexts still
+//! decrypt, and [`KeyRing::decrypt_and_refresh`] hands back a re-encryption
+//! under the current key for the caller to store in place of the old one.
+
 use aes_gcm::{
-    aead::{Aead, KeyInit, OsRng},
+    aead::{Aead, KeyInit, OsRng, Payload},
     AeadCore, Aes256Gcm, Nonce,
 };
-use base64;
+use base64::Engine as _;
+use db::envelope::MasterKey;
+use sqlx::error::BoxDynError;
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+use crate::{errors::AppError, models::CUSTOM_ENGINE};
+
+pub const KEY_LEN: usize = 32;
+
+const FORMAT_VERSION: u8 = 1;
+const HEADER_LEN: usize = 1 + 4;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum DataEncryptionError {
+    #[error("Keys must be {KEY_LEN} bytes")]
+    InvalidKeyLength,
+
+    #[error("Key id {0} is already in the key ring")]
+    DuplicateKey(u32),
+
+    #[error("Unknown key id {0}")]
+    UnknownKey(u32),
+
+    #[error("Ciphertext is too short")]
+    Truncated,
+
+    #[error("Unsupported ciphertext format {0}")]
+    UnsupportedFormat(u8),
+
+    #[error("Decryption failed")]
+    DecryptionFailed,
+
+    #[error("Invalid key ring configuration: {0}")]
+    InvalidConfig(String),
+}
+
+impl From<DataEncryptionError> for AppError {
+    fn from(err: DataEncryptionError) -> Self {
+        AppError::InternalError(format!("Data encryption: {err}"))
+    }
+}
+
+/// The keys data at rest is encrypted with, by id.
+#[derive(Clone)]
+pub struct KeyRing {
+    keys: BTreeMap<u32, Aes256Gcm>,
+    current: u32,
+}
+
+impl std::fmt::Debug for KeyRing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never print key material
+        f.debug_struct("KeyRing")
+            .field("key_ids", &self.keys.keys().collect::<Vec<_>>())
+            .field("current", &self.current)
+            .finish()
+    }
+}
+
+impl KeyRing {
+    /// A key ring holding only `key`, which becomes the current key.
+    pub fn new(key_id: u32, key: &[u8]) -> Result<Self, DataEncryptionError> {
+        let mut keys = BTreeMap::new();
+        keys.insert(key_id, cipher(key)?);
+        Ok(Self {
+            keys,
+            current: key_id,
+        })
+    }
+
+    /// Adds a retired key, kept only to decrypt data encrypted under it.
+    pub fn with_retired_key(
+        mut self,
+        key_id: u32,
+        key: &[u8],
+    ) -> Result<Self, DataEncryptionError> {
+        self.insert(key_id, key)?;
+        Ok(self)
+    }
+
+    /// Adds `key` and makes it current. Earlier keys stay in the ring until
+    /// everything encrypted under them has been refreshed.
+    pub fn rotate(&mut self, key_id: u32, key: &[u8]) -> Result<(), DataEncryptionError> {
+        self.insert(key_id, key)?;
+        self.current = key_id;
+        Ok(())
+    }
+
+    /// Reads a key ring from configuration: entries of the form
+    /// `<key id>:<key>`, separated by commas or newlines, each key being 32
+    /// bytes in the [`CUSTOM_ENGINE`] alphabet. Blank lines and lines starting
+    /// with `#` are ignored. The entry with the highest id is the current
+    /// key, so rotating means appending an entry with a higher id.
+    pub fn from_config(config: &str) -> Result<Self, DataEncryptionError> {
+        let mut entries = Vec::new();
+        for entry in config
+            .lines()
+            .filter(|line| !line.trim_start().starts_with('#'))
+            .flat_map(|line| line.split(','))
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let (key_id, key) = entry.split_once(':').ok_or_else(|| {
+                DataEncryptionError::InvalidConfig(String::from("expected `<key id>:<key>`"))
+            })?;
+            let key_id = key_id.trim().parse::<u32>().map_err(|_| {
+                DataEncryptionError::InvalidConfig(format!("invalid key id `{}`", key_id.trim()))
+            })?;
+            let key = CUSTOM_ENGINE.decode(key.trim()).map_err(|_| {
+                DataEncryptionError::InvalidConfig(format!("key {key_id} is not valid base64"))
+            })?;
+            entries.push((key_id, key));
+        }
+
+        let current = entries
+            .iter()
+            .map(|(key_id, _)| *key_id)
+            .max()
+            .ok_or_else(|| DataEncryptionError::InvalidConfig(String::from("no keys")))?;
+        let mut keys = BTreeMap::new();
+        for (key_id, key) in entries {
+            if keys.insert(key_id, cipher(&key)?).is_some() {
+                return Err(DataEncryptionError::DuplicateKey(key_id));
+            }
+        }
+
+        Ok(Self { keys, current })
+    }
+
+    pub fn current_key_id(&self) -> u32 {
+        self.current
+    }
+
+    /// Encrypts `plaintext` under the current key, bound to
+    /// `associated_data`, which must be given again to decrypt.
+    pub fn encrypt(&self, plaintext: &[u8], associated_data: &[u8]) -> Vec<u8> {
+        let header = header(self.current);
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self.keys[&self.current]
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: &aad(&header, associated_data),
+                },
+            )
+            .expect("encrypting into a Vec cannot fail");
+
+        let mut encrypted = header.to_vec();
+        encrypted.extend_from_slice(&nonce);
+        encrypted.extend(ciphertext);
+        encrypted
+    }
+
+    pub fn decrypt(
+        &self,
+        encrypted: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, DataEncryptionError> {
+        let key_id = key_id(encrypted)?;
+        if encrypted.len() < HEADER_LEN + NONCE_LEN + TAG_LEN {
+            return Err(DataEncryptionError::Truncated);
+        }
+        let cipher = self
+            .keys
+            .get(&key_id)
+            .ok_or(DataEncryptionError::UnknownKey(key_id))?;
+
+        let (header, rest) = encrypted.split_at(HEADER_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad(header, associated_data),
+                },
+            )
+            .map_err(|_| DataEncryptionError::DecryptionFailed)
+    }
+
+    /// Whether `encrypted` was encrypted under a key other than the current
+    /// one, and should be refreshed.
+    pub fn is_stale(&self, encrypted: &[u8]) -> Result<bool, DataEncryptionError> {
+        Ok(key_id(encrypted)? != self.current)
+    }
+
+    /// Decrypts `encrypted` and, if it was encrypted under a retired key,
+    /// also returns it re-encrypted under the current key, for the caller to
+    /// store in its place.
+    pub fn decrypt_and_refresh(
+        &self,
+        encrypted: &[u8],
+        associated_data: &[u8],
+    ) -> Result<(Vec<u8>, Option<Vec<u8>>), DataEncryptionError> {
+        let plaintext = self.decrypt(encrypted, associated_data)?;
+        let refreshed = self
+            .is_stale(encrypted)?
+            .then(|| self.encrypt(&plaintext, associated_data));
+        Ok((plaintext, refreshed))
+    }
 
-/// Encrypts data at rest using AES-256-GCM.
-pub fn encrypt_at_rest(data: &[u8], server_key: &[u8]) -> Vec<u8> {
-    // Create an AES-256-GCM cipher instance
-    let cipher = Aes256Gcm::new_from_slice(server_key).expect("Invalid key length");
+    fn insert(&mut self, key_id: u32, key: &[u8]) -> Result<(), DataEncryptionError> {
+        if self.keys.contains_key(&key_id) {
+            return Err(DataEncryptionError::DuplicateKey(key_id));
+        }
+        self.keys.insert(key_id, cipher(key)?);
+        Ok(())
+    }
+}
 
-    // Generate a random nonce (12 bytes for AES-GCM)
-    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+/// Lets the ring wrap the per-row data keys of [`db::envelope`].
+impl MasterKey for KeyRing {
+    fn wrap(&self, data_key: &[u8], associated_data: &[u8]) -> Vec<u8> {
+        self.encrypt(data_key, associated_data)
+    }
+
+    fn unwrap(&self, wrapped: &[u8], associated_data: &[u8]) -> Result<Vec<u8>, BoxDynError> {
+        Ok(self.decrypt(wrapped, associated_data)?)
+    }
 
-    // Encrypt the data
-    let encrypted_data = cipher.encrypt(&nonce, data).expect("Encryption failed");
+    fn needs_rewrap(&self, wrapped: &[u8]) -> bool {
+        // Malformed keys fail to unwrap either way
+        self.is_stale(wrapped).unwrap_or(false)
+    }
+}
 
-    // Combine the nonce and encrypted data into a single vector
-    let mut result = nonce.to_vec();
-    result.extend(encrypted_data);
-    result
+/// The id of the key `encrypted` was encrypted under, read from its header.
+pub fn key_id(encrypted: &[u8]) -> Result<u32, DataEncryptionError> {
+    let format = *encrypted.first().ok_or(DataEncryptionError::Truncated)?;
+    if format != FORMAT_VERSION {
+        return Err(DataEncryptionError::UnsupportedFormat(format));
+    }
+    let key_id = encrypted
+        .get(1..HEADER_LEN)
+        .ok_or(DataEncryptionError::Truncated)?;
+    Ok(u32::from_be_bytes(key_id.try_into().expect("4 bytes")))
 }
 
-/// Decrypts data at rest using AES-256-GCM.
-pub fn decrypt_at_rest(encrypted_data: &[u8], server_key: &[u8]) -> Vec<u8> {
-    // Create an AES-256-GCM cipher instance
-    let cipher = Aes256Gcm::new_from_slice(server_key).expect("Invalid key length");
+/// A fresh random key, for provisioning.
+pub fn generate_key() -> [u8; KEY_LEN] {
+    Aes256Gcm::generate_key(&mut OsRng).into()
+}
 
-    // Split the nonce and encrypted data
-    let nonce = Nonce::from_slice(&encrypted_data[..12]);
-    let encrypted_data = &encrypted_data[12..];
+fn cipher(key: &[u8]) -> Result<Aes256Gcm, DataEncryptionError> {
+    if key.len() != KEY_LEN {
+        return Err(DataEncryptionError::InvalidKeyLength);
+    }
+    Aes256Gcm::new_from_slice(key).map_err(|_| DataEncryptionError::InvalidKeyLength)
+}
 
-    // Decrypt the data
-    cipher
-        .decrypt(nonce, encrypted_data)
-        .expect("Decryption failed")
+fn header(key_id: u32) -> [u8; HEADER_LEN] {
+    let mut header = [FORMAT_VERSION; HEADER_LEN];
+    header[1..].copy_from_slice(&key_id.to_be_bytes());
+    header
+}
+
+fn aad(header: &[u8], associated_data: &[u8]) -> Vec<u8> {
+    let mut aad = header.to_vec();
+    aad.extend_from_slice(associated_data);
+    aad
 }
 
 #[cfg(test)]
@@ -41,54 +292,202 @@ mod tests {
     use super::*;
     use rand::Rng;
 
+    const AD: &[u8] = b"users/1/secret";
+
     #[test]
     fn test_encrypt_decrypt() {
-        let server_key = [0u8; 32]; // 256-bit key
+        let ring = KeyRing::new(1, &[0u8; 32]).unwrap();
         let data = b"Hello, World!";
-        let encrypted_data = encrypt_at_rest(data, &server_key);
-        let decrypted_data = decrypt_at_rest(&encrypted_data, &server_key);
-        assert_eq!(data, &decrypted_data[..]);
+        let encrypted_data = ring.encrypt(data, AD);
+        assert_eq!(key_id(&encrypted_data).unwrap(), 1);
+        assert_eq!(ring.decrypt(&encrypted_data, AD).unwrap(), data);
     }
 
     #[test]
     fn test_encrypt_decrypt_empty_data() {
-        let server_key = [0u8; 32]; // 256-bit key
-        let data = b"";
-        let encrypted_data = encrypt_at_rest(data, &server_key);
-        let decrypted_data = decrypt_at_rest(&encrypted_data, &server_key);
-        assert_eq!(data, &decrypted_data[..]);
+        let ring = KeyRing::new(1, &[0u8; 32]).unwrap();
+        let encrypted_data = ring.encrypt(b"", b"");
+        assert!(ring.decrypt(&encrypted_data, b"").unwrap().is_empty());
     }
 
     #[test]
     fn test_encrypt_decrypt_large_data() {
-        let server_key = [0u8; 32]; // 256-bit key
-        let mut data = Vec::new();
-        let mut rng = rand::thread_rng();
-        for _ in 0..1024 {
-            data.push(rng.gen::<u8>());
-        }
-        let encrypted_data = encrypt_at_rest(&data, &server_key);
-        let decrypted_data = decrypt_at_rest(&encrypted_data, &server_key);
-        assert_eq!(data, decrypted_data);
+        let ring = KeyRing::new(1, &generate_key()).unwrap();
+        let mut rng = rand::rng();
+        let data: Vec<u8> = (0..1024).map(|_| rng.random::<u8>()).collect();
+        let encrypted_data = ring.encrypt(&data, AD);
+        assert_eq!(ring.decrypt(&encrypted_data, AD).unwrap(), data);
     }
 
     #[test]
-    #[should_panic]
-    fn test_encrypt_invalid_key() {
-        let server_key = [0u8; 31]; // invalid key length
-        let data = b"Hello, World!";
-        encrypt_at_rest(data, &server_key);
+    fn test_invalid_key_length() {
+        assert_eq!(
+            KeyRing::new(1, &[0u8; 31]).unwrap_err(),
+            DataEncryptionError::InvalidKeyLength
+        );
     }
 
     #[test]
-    #[should_panic]
-    fn test_decrypt_invalid_nonce() {
-        let server_key = [0u8; 32]; // 256-bit key
-        let data = b"Hello, World!";
-        let encrypted_data = encrypt_at_rest(data, &server_key);
-        let mut invalid_nonce = encrypted_data[..12].to_vec();
-        invalid_nonce[0] ^= 1; // corrupt the nonce
-        let invalid_encrypted_data = [invalid_nonce, encrypted_data[12..].to_vec()].concat();
-        decrypt_at_rest(&invalid_encrypted_data, &server_key);
+    fn test_tampering_fails_without_panicking() {
+        let ring = KeyRing::new(1, &[0u8; 32]).unwrap();
+        let encrypted_data = ring.encrypt(b"Hello, World!", AD);
+
+        let mut invalid_nonce = encrypted_data.clone();
+        invalid_nonce[HEADER_LEN] ^= 1;
+        assert_eq!(
+            ring.decrypt(&invalid_nonce, AD).unwrap_err(),
+            DataEncryptionError::DecryptionFailed
+        );
+        assert_eq!(
+            ring.decrypt(&encrypted_data, b"users/2/secret")
+                .unwrap_err(),
+            DataEncryptionError::DecryptionFailed
+        );
+        assert_eq!(
+            ring.decrypt(&encrypted_data[..HEADER_LEN + 4], AD)
+                .unwrap_err(),
+            DataEncryptionError::Truncated
+        );
+        assert_eq!(
+            ring.decrypt(&[], AD).unwrap_err(),
+            DataEncryptionError::Truncated
+        );
+
+        let mut unknown_format = encrypted_data.clone();
+        unknown_format[0] = 9;
+        assert_eq!(
+            ring.decrypt(&unknown_format, AD).unwrap_err(),
+            DataEncryptionError::UnsupportedFormat(9)
+        );
+    }
+
+    #[test]
+    fn test_key_id_is_authenticated() {
+        let key = generate_key();
+        let ring = KeyRing::new(1, &key)
+            .unwrap()
+            .with_retired_key(2, &key)
+            .unwrap();
+        let mut relabelled = ring.encrypt(b"secret", AD);
+        relabelled[1..HEADER_LEN].copy_from_slice(&2u32.to_be_bytes());
+
+        assert_eq!(
+            ring.decrypt(&relabelled, AD).unwrap_err(),
+            DataEncryptionError::DecryptionFailed
+        );
+    }
+
+    #[test]
+    fn test_rotation_refreshes_lazily() {
+        let mut ring = KeyRing::new(1, &generate_key()).unwrap();
+        let old = ring.encrypt(b"secret", AD);
+        ring.rotate(2, &generate_key()).unwrap();
+
+        assert!(ring.is_stale(&old).unwrap());
+        let (plaintext, refreshed) = ring.decrypt_and_refresh(&old, AD).unwrap();
+        assert_eq!(plaintext, b"secret");
+        let refreshed = refreshed.unwrap();
+        assert_eq!(key_id(&refreshed).unwrap(), 2);
+
+        let (plaintext, again) = ring.decrypt_and_refresh(&refreshed, AD).unwrap();
+        assert_eq!(plaintext, b"secret");
+        assert!(again.is_none());
+
+        assert_eq!(
+            ring.rotate(2, &generate_key()).unwrap_err(),
+            DataEncryptionError::DuplicateKey(2)
+        );
+    }
+
+    #[test]
+    fn test_unknown_key_id() {
+        let old = KeyRing::new(1, &generate_key())
+            .unwrap()
+            .encrypt(b"secret", AD);
+        let ring = KeyRing::new(2, &generate_key()).unwrap();
+        assert_eq!(
+            ring.decrypt(&old, AD).unwrap_err(),
+            DataEncryptionError::UnknownKey(1)
+        );
+    }
+
+    #[test]
+    fn test_from_config() {
+        let first = generate_key();
+        let second = generate_key();
+        let config = format!(
+            "# retired\n1:{}\n\n2:{}\n",
+            CUSTOM_ENGINE.encode(first),
+            CUSTOM_ENGINE.encode(second)
+        );
+        let ring = KeyRing::from_config(&config).unwrap();
+        assert_eq!(ring.current_key_id(), 2);
+        let old = KeyRing::new(1, &first).unwrap().encrypt(b"secret", AD);
+        assert_eq!(ring.decrypt(&old, AD).unwrap(), b"secret");
+
+        // The same keys on one line, as an environment variable would hold them
+        let inline = config
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .collect::<Vec<_>>()
+            .join(",");
+        assert_eq!(KeyRing::from_config(&inline).unwrap().current_key_id(), 2);
+
+        for invalid in [
+            String::new(),
+            String::from("1"),
+            String::from("one:AAAA"),
+            format!("1:{}", CUSTOM_ENGINE.encode([0u8; 16])),
+            format!("1:{0},1:{0}", CUSTOM_ENGINE.encode(first)),
+        ] {
+            assert!(KeyRing::from_config(&invalid).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn test_rewrapped_messages_open_without_the_retired_key() {
+        use db::{
+            envelope::{MessageEnvelope, SealedRow},
+            models::Message,
+        };
+        use db::uuid::Uuid;
+        use std::sync::Arc;
+        use sqlx::types::chrono::Utc;
+
+        let (old_key, new_key) = (generate_key(), generate_key());
+        let before = MessageEnvelope::new(Arc::new(KeyRing::new(1, &old_key).unwrap()));
+        let (sender_id, recipient_id) = (Uuid::now_v7(), Uuid::now_v7());
+        let row = SealedRow::Message {
+            id: 1,
+            sender_id,
+            recipient_id,
+        };
+        let (encrypted_content, signature) = before.seal(row, "Y29udGVudA", Some("c2lnbmF0dXJl"));
+
+        let rotated = MessageEnvelope::new(Arc::new(
+            KeyRing::new(2, &new_key)
+                .unwrap()
+                .with_retired_key(1, &old_key)
+                .unwrap(),
+        ));
+        let encrypted_content = rotated
+            .rewrap(row, &encrypted_content)
+            .unwrap()
+            .expect("wrapped under a retired key");
+
+        let after = MessageEnvelope::new(Arc::new(KeyRing::new(2, &new_key).unwrap()));
+        let mut message = Message {
+            id: 1,
+            sender_id,
+            recipient_id,
+            encrypted_content,
+            parent_id: None,
+            signature,
+            created_at: Utc::now(),
+            is_read: false,
+        };
+        after.open(&mut message).unwrap();
+        assert_eq!(message.encrypted_content, "Y29udGVudA");
+        assert_eq!(message.signature.as_deref(), Some("c2lnbmF0dXJl"));
     }
 }
@@ -1,7 +1,12 @@
-use actix_web::{HttpResponse, ResponseError};
-use sqlx::error::DatabaseError;
-use thiserror::Error;
+use actix_web::{
+    http::{header, StatusCode},
+    HttpResponse, ResponseError,
+};
 use jsonwebtoken::errors::Error as JwtError;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use thiserror::Error;
+use utoipa::ToSchema;
 
 #[derive(Debug, Error)]
 pub enum AppError {
@@ -40,63 +45,191 @@ pub enum AppError {
 
     #[error("Internal server error: {0}")]
     InternalError(String),
+
+    /// Sent with a `Retry-After` header saying how long to wait.
+    #[error("Rate limited, retry after {0:?}")]
+    RateLimited(Duration),
+}
+
+tokio::task_local! {
+    /// The id of the request being handled. Errors raised while it is set
+    /// report it back in [`ErrorResponse::request_id`].
+    pub static REQUEST_ID: String;
+}
+
+/// The id of the request being handled, if any.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// Stable error codes. Clients should match on these rather than on the
+/// message, which is for humans and may change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    /// The request failed validation; `details` lists the failing fields.
+    ValidationFailed,
+    /// The request is malformed, e.g. a key is not validly encoded.
+    InvalidInput,
+    /// A value is longer than allowed.
+    DataTooLong,
+    /// The request refers to a user or message that does not exist.
+    InvalidReference,
+    /// A public key could not be parsed.
+    InvalidPublicKey,
+    /// A public key hash could not be parsed.
+    InvalidPublicKeyHash,
+    /// The request's credentials are missing, invalid or expired.
+    Unauthenticated,
+    /// The caller may not do this.
+    Forbidden,
+    /// The requested resource does not exist.
+    NotFound,
+    /// The resource already exists, or the request conflicts with its state.
+    Conflict,
+    /// Too many requests; retry after the number of seconds in `Retry-After`.
+    RateLimited,
+    /// Something went wrong on the server. Details are only logged.
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::ValidationFailed
+            | ErrorCode::InvalidInput
+            | ErrorCode::DataTooLong
+            | ErrorCode::InvalidReference
+            | ErrorCode::InvalidPublicKey
+            | ErrorCode::InvalidPublicKeyHash => StatusCode::BAD_REQUEST,
+            ErrorCode::Unauthenticated => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// The body of every error response.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ErrorResponse {
+    pub code: ErrorCode,
+    pub message: String,
+    /// Per-field errors for `VALIDATION_FAILED`, keyed by field name.
+    #[schema(value_type = Option<Object>)]
+    pub details: Option<serde_json::Value>,
+    /// The id of the failed request, for matching it up with server logs.
+    pub request_id: Option<String>,
+}
+
+const INTERNAL_MESSAGE: &str = "Internal server error";
+const UNIQUE_VIOLATION_MESSAGE: &str = "Resource already exists";
+const FOREIGN_KEY_VIOLATION_MESSAGE: &str = "Referenced resource does not exist";
+const ROW_NOT_FOUND_MESSAGE: &str = "Record not found";
+
+impl AppError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            AppError::DatabaseError(e) => match e.as_database_error().map(|e| e.kind()) {
+                Some(sqlx::error::ErrorKind::UniqueViolation) => ErrorCode::Conflict,
+                Some(sqlx::error::ErrorKind::ForeignKeyViolation) => ErrorCode::InvalidReference,
+                _ if matches!(e, sqlx::Error::RowNotFound) => ErrorCode::NotFound,
+                _ => ErrorCode::Internal,
+            },
+            AppError::UniqueViolation(_) => ErrorCode::Conflict,
+            AppError::ForeignKeyViolation(_) => ErrorCode::InvalidReference,
+            AppError::DataTooLong => ErrorCode::DataTooLong,
+            AppError::InvalidInputSyntax => ErrorCode::InvalidInput,
+            AppError::ValidationError(_) => ErrorCode::ValidationFailed,
+            AppError::NotFound(_) => ErrorCode::NotFound,
+            AppError::AuthenticationError(_) => ErrorCode::Unauthenticated,
+            AppError::Forbidden(_) => ErrorCode::Forbidden,
+            AppError::PublicKeyHashError(_) => ErrorCode::InvalidPublicKeyHash,
+            AppError::PublicKeyError(_) => ErrorCode::InvalidPublicKey,
+            AppError::InternalError(_) => ErrorCode::Internal,
+            AppError::RateLimited(_) => ErrorCode::RateLimited,
+        }
+    }
+
+    /// What the client is told. Database and internal errors get a fixed
+    /// message, so nothing about the schema or server state leaks.
+    fn public_message(&self) -> String {
+        match self {
+            AppError::DatabaseError(_) => match self.code() {
+                ErrorCode::Conflict => UNIQUE_VIOLATION_MESSAGE.to_string(),
+                ErrorCode::InvalidReference => FOREIGN_KEY_VIOLATION_MESSAGE.to_string(),
+                ErrorCode::NotFound => ROW_NOT_FOUND_MESSAGE.to_string(),
+                _ => INTERNAL_MESSAGE.to_string(),
+            },
+            AppError::InternalError(_) => INTERNAL_MESSAGE.to_string(),
+            AppError::ValidationError(_) => "Request validation failed".to_string(),
+            AppError::RateLimited(_) => "Too many requests".to_string(),
+            AppError::UniqueViolation(msg)
+            | AppError::ForeignKeyViolation(msg)
+            | AppError::NotFound(msg)
+            | AppError::AuthenticationError(msg)
+            | AppError::Forbidden(msg) => msg.clone(),
+            AppError::DataTooLong
+            | AppError::InvalidInputSyntax
+            | AppError::PublicKeyHashError(_)
+            | AppError::PublicKeyError(_) => self.to_string(),
+        }
+    }
+
+    pub fn to_response(&self) -> ErrorResponse {
+        ErrorResponse {
+            code: self.code(),
+            message: self.public_message(),
+            details: match self {
+                AppError::ValidationError(e) => serde_json::to_value(e).ok(),
+                _ => None,
+            },
+            request_id: current_request_id(),
+        }
+    }
 }
 
 impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        self.code().status()
+    }
+
     fn error_response(&self) -> HttpResponse {
-        match self {
-            AppError::DatabaseError(e) => {
-                if let Some(db_err) = e.as_database_error() {
-                    match db_err.kind() {
-                        sqlx::error::ErrorKind::UniqueViolation => {
-                            HttpResponse::Conflict().json(db_err.message())
-                        }
-                        sqlx::error::ErrorKind::ForeignKeyViolation => {
-                            HttpResponse::BadRequest().json(db_err.message())
-                        }
-                        _ => HttpResponse::InternalServerError().json("Database error"),
-                    }
-                } else {
-                    match e {
-                        sqlx::Error::RowNotFound => {
-                            HttpResponse::NotFound().json("Record not found")
-                        }
-                        _ => HttpResponse::InternalServerError().json("Database error"),
-                    }
-                }
-            }
-            AppError::UniqueViolation(msg) => HttpResponse::Conflict().json(msg),
-            AppError::ForeignKeyViolation(msg) => HttpResponse::BadRequest().json(msg),
-            AppError::DataTooLong => HttpResponse::BadRequest().json("Data too long for column"),
-            AppError::InvalidInputSyntax => HttpResponse::BadRequest().json("Invalid input syntax"),
-            AppError::ValidationError(e) => HttpResponse::BadRequest().json(e),
-            AppError::NotFound(msg) => HttpResponse::NotFound().json(msg),
-            AppError::AuthenticationError(msg) => {
-                HttpResponse::Unauthorized().json(msg)
-            }
-            AppError::Forbidden(msg) => HttpResponse::Forbidden().json(msg),
-            AppError::PublicKeyHashError(e) => HttpResponse::BadRequest().json(e.to_string()),
-            AppError::PublicKeyError(e) => HttpResponse::BadRequest().json(e.to_string()),
-            AppError::InternalError(msg) => HttpResponse::InternalServerError().json(msg),
+        let response = self.to_response();
+        if response.code == ErrorCode::Internal {
+            tracing::error!(error = %self, "request failed");
+        }
+        let mut builder = HttpResponse::build(self.status_code());
+        if let AppError::RateLimited(retry_after) = self {
+            // Whole seconds, rounded up so that retrying on time succeeds
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            builder.insert_header((header::RETRY_AFTER, seconds.max(1)));
         }
+        builder.json(response)
     }
 }
 
 impl From<sqlx::Error> for AppError {
     fn from(err: sqlx::Error) -> Self {
+        // The database's own message names tables and columns; it is logged
+        // rather than sent back.
         if let Some(db_err) = err.as_database_error() {
             match db_err.kind() {
                 sqlx::error::ErrorKind::UniqueViolation => {
-                    AppError::UniqueViolation(db_err.message().to_string())
+                    tracing::debug!(error = db_err.message(), "unique violation");
+                    AppError::UniqueViolation(UNIQUE_VIOLATION_MESSAGE.to_string())
                 }
                 sqlx::error::ErrorKind::ForeignKeyViolation => {
-                    AppError::ForeignKeyViolation(db_err.message().to_string())
+                    tracing::debug!(error = db_err.message(), "foreign key violation");
+                    AppError::ForeignKeyViolation(FOREIGN_KEY_VIOLATION_MESSAGE.to_string())
                 }
                 _ => AppError::DatabaseError(err),
             }
         } else {
             match err {
-                sqlx::Error::RowNotFound => AppError::NotFound("Record not found".to_string()),
+                sqlx::Error::RowNotFound => AppError::NotFound(ROW_NOT_FOUND_MESSAGE.to_string()),
                 _ => AppError::DatabaseError(err),
             }
         }
@@ -108,3 +241,82 @@ impl From<JwtError> for AppError {
         AppError::AuthenticationError(err.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::to_bytes;
+    use validator::Validate;
+
+    async fn body(err: AppError) -> (StatusCode, ErrorResponse) {
+        let response = err.error_response();
+        let status = response.status();
+        let body = to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn test_internal_details_are_not_returned() {
+        for err in [
+            AppError::InternalError(String::from("key file /etc/keys unreadable")),
+            AppError::DatabaseError(sqlx::Error::PoolTimedOut),
+        ] {
+            let (status, body) = body(err).await;
+            assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+            assert_eq!(body.code, ErrorCode::Internal);
+            assert_eq!(body.message, INTERNAL_MESSAGE);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_validation_errors_are_details() {
+        #[derive(Validate)]
+        struct Request {
+            #[validate(length(min = 3))]
+            username: String,
+        }
+
+        let errors = Request { username: String::from("ab") }.validate().unwrap_err();
+        let (status, body) = body(errors.into()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body.code, ErrorCode::ValidationFailed);
+        assert!(body.details.unwrap().get("username").is_some());
+    }
+
+    #[actix_web::test]
+    async fn test_codes_serialize_screaming_snake_case() {
+        let (status, body) = body(AppError::from(sqlx::Error::RowNotFound)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            serde_json::to_value(&body).unwrap(),
+            serde_json::json!({
+                "code": "NOT_FOUND",
+                "message": "Record not found",
+                "details": null,
+                "request_id": null,
+            })
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_request_id_is_reported() {
+        let (_, body) = REQUEST_ID
+            .scope(String::from("req-1"), body(AppError::Forbidden(String::from("No"))))
+            .await;
+        assert_eq!(body.request_id.as_deref(), Some("req-1"));
+        assert_eq!((body.code, body.message.as_str()), (ErrorCode::Forbidden, "No"));
+    }
+
+    #[test]
+    fn test_rate_limited_responses_say_when_to_retry() {
+        for (retry_after, header) in [
+            (Duration::from_millis(1500), "2"),
+            (Duration::from_secs(3), "3"),
+            (Duration::ZERO, "1"),
+        ] {
+            let response = AppError::RateLimited(retry_after).error_response();
+            assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), header);
+        }
+    }
+}
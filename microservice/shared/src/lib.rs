@@ -1,4 +1,6 @@
 pub mod crypto;
 pub mod data_encryption;
 pub mod errors;
+pub mod key_backup;
+pub mod metrics;
 pub mod models;
@@ -0,0 +1,190 @@
+//! Prometheus metrics, served in the text exposition format by `GET /metrics`.
+//!
+//! Everything is registered in one registry owned by this module. HTTP
+//! metrics are labelled with the matched route pattern, such as
+//! `/api/users/{user_id}`, never the raw path, so user ids and message ids
+//! stay out of label values.
+
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::sync::LazyLock;
+
+static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+fn register<C: Collector + Clone + 'static>(collector: C) -> C {
+    REGISTRY
+        .register(Box::new(collector.clone()))
+        .expect("metric names are unique");
+    collector
+}
+
+/// Requests handled, by method, route and status.
+pub static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled"),
+            &["method", "route", "status"],
+        )
+        .unwrap(),
+    )
+});
+
+/// Time taken to handle requests, by method and route.
+pub static HTTP_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time taken to handle HTTP requests",
+            ),
+            &["method", "route"],
+        )
+        .unwrap(),
+    )
+});
+
+/// Messages stored, by kind: `direct` or `anonymous`.
+pub static MESSAGES_CREATED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("messages_created_total", "Messages stored"),
+            &["kind"],
+        )
+        .unwrap(),
+    )
+});
+
+/// Refresh token validations, by outcome: `valid`, `invalid_jwt` (bad
+/// signature, expired or malformed), `unknown` (not stored, or revoked) or
+/// `error`.
+pub static TOKEN_VALIDATIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("token_validations_total", "Refresh token validations"),
+            &["outcome"],
+        )
+        .unwrap(),
+    )
+});
+
+/// Requests refused by the rate limiter, by policy.
+pub static RATE_LIMITED_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "rate_limited_requests_total",
+                "Requests refused by the rate limiter",
+            ),
+            &["policy"],
+        )
+        .unwrap(),
+    )
+});
+
+/// Background job runs, by job and result: `ok` or `error`.
+pub static BACKGROUND_JOB_RUNS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("background_job_runs_total", "Background job runs"),
+            &["job", "result"],
+        )
+        .unwrap(),
+    )
+});
+
+/// Items processed by background jobs, by job.
+pub static BACKGROUND_JOB_ITEMS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "background_job_items_total",
+                "Items processed by background jobs",
+            ),
+            &["job"],
+        )
+        .unwrap(),
+    )
+});
+
+/// Connections in a database pool at one moment.
+#[derive(Debug, Clone, Copy)]
+pub struct PoolStats {
+    pub max: u32,
+    pub open: u32,
+    pub idle: usize,
+}
+
+/// Reports a connection pool's size as the `db_pool_connections` gauge,
+/// labelled `max`, `in_use` and `idle`, reading it afresh at every scrape.
+pub fn register_pool(stats: impl Fn() -> PoolStats + Send + Sync + 'static) {
+    let connections = IntGaugeVec::new(
+        Opts::new("db_pool_connections", "Database pool connections"),
+        &["state"],
+    )
+    .unwrap();
+    REGISTRY
+        .register(Box::new(PoolCollector {
+            connections,
+            stats: Box::new(stats),
+        }))
+        .expect("only one pool is registered");
+}
+
+struct PoolCollector {
+    connections: IntGaugeVec,
+    stats: Box<dyn Fn() -> PoolStats + Send + Sync>,
+}
+
+impl Collector for PoolCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.connections.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let stats = (self.stats)();
+        let idle = i64::try_from(stats.idle).unwrap_or(i64::MAX);
+        self.connections
+            .with_label_values(&["max"])
+            .set(stats.max.into());
+        self.connections.with_label_values(&["idle"]).set(idle);
+        self.connections
+            .with_label_values(&["in_use"])
+            .set(i64::from(stats.open) - idle);
+        self.connections.collect()
+    }
+}
+
+/// Every metric, in the Prometheus text format.
+pub fn encode() -> String {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&REGISTRY.gather(), &mut buffer)
+        .expect("encoding into a Vec cannot fail");
+    String::from_utf8(buffer).expect("the text format is UTF-8")
+}
+
+/// The content type of [`encode`]'s output.
+pub const CONTENT_TYPE: &str = prometheus::TEXT_FORMAT;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_are_encoded() {
+        MESSAGES_CREATED.with_label_values(&["direct"]).inc();
+        register_pool(|| PoolStats {
+            max: 10,
+            open: 3,
+            idle: 1,
+        });
+
+        let text = encode();
+        assert!(text.contains("messages_created_total{kind=\"direct\"}"));
+        assert!(text.contains("db_pool_connections{state=\"in_use\"} 2"));
+        assert!(text.contains("db_pool_connections{state=\"max\"} 10"));
+    }
+}
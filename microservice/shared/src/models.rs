@@ -15,6 +15,20 @@ pub struct RegisterRequest {
 
     #[validate(length(min = 50, message = "Invalid public key format"))]
     pub public_key: String, // Base64-encoded SPKI format
+
+    /// Optional commitment to the user's anonymous identity, a compressed
+    /// BLS12-381 scalar in the [`CUSTOM_ENGINE`] alphabet. Registering one adds
+    /// the user to the anonymity set.
+    #[serde(default)]
+    #[validate(custom(function = "validate_field_element"))]
+    pub identity_commitment: Option<String>,
+
+    /// Optional Jubjub key messages to the user are sealed to, a compressed
+    /// point in the [`CUSTOM_ENGINE`] alphabet. Messages to the user can only
+    /// carry an [`EphemeralKeyProof`] once they registered one.
+    #[serde(default)]
+    #[validate(custom(function = "validate_curve_point"))]
+    pub message_key: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
@@ -31,6 +45,39 @@ pub struct UpdateUserRequest {
 
     #[validate(length(min = 50, message = "Invalid public key format"))]
     pub new_public_key: Option<String>, // Base64-encoded SPKI formaat
+
+    /// Replaces the key registered as [`RegisterRequest::message_key`]
+    #[serde(default)]
+    #[validate(custom(function = "validate_curve_point"))]
+    pub new_message_key: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct SafetyNumberResponse {
+    /// Version of the fingerprint derivation
+    pub version: u16,
+    /// 60 decimal digits, identical for both users regardless of order
+    pub safety_number: String,
+    /// URL-safe base64 payload meant to be rendered as a QR code
+    pub qr_payload: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate, ToSchema, Clone, PartialEq)]
+pub struct VerifyContactRequest {
+    /// The safety number the user compared out-of-band
+    #[validate(length(min = 60, max = 80, message = "Invalid safety number format"))]
+    pub safety_number: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct ContactVerificationResponse {
+    #[serde(with = "uuid::serde::simple")]
+    pub contact_id: Uuid,
+    pub verified: bool,
+    /// True when the contact's key changed after it was verified
+    pub key_changed: bool,
+    /// Unix timestamp of the last verification
+    pub verified_at: Option<i64>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Validate, ToSchema)]
@@ -74,6 +121,246 @@ pub struct CreateMessageRequest {
     pub signature: Option<String>,
 
     pub parent_id: Option<i64>,
+
+    /// Optional proof that the sender knows the ephemeral secret behind the
+    /// `epub` used to encrypt `encrypted_content`. Its `epub` must be the
+    /// sealed message's and its `recipient_key` the recipient's registered
+    /// public key; it is stored with the message once verified.
+    #[serde(default)]
+    pub ephemeral_key_proof: Option<EphemeralKeyProof>,
+
+    /// Optional Pedersen commitment to the ciphertext in `encrypted_content`,
+    /// a compressed edwards25519 point in the [`CUSTOM_ENGINE`] alphabet. It
+    /// must be the commitment carried by the sealed message.
+    #[serde(default)]
+    #[validate(custom(function = "validate_commitment"))]
+    pub commitment: Option<String>,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MessageCommitmentResponse {
+    pub message_id: i64,
+    pub commitment: String,
+}
+
+/// A Groth16 proof of knowledge of an ephemeral secret key. Every field is a
+/// compressed arkworks encoding in the [`CUSTOM_ENGINE`] base64 alphabet.
+#[derive(ToSchema, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EphemeralKeyProof {
+    /// The sender's ephemeral public key
+    pub epub: String,
+
+    /// The recipient key the shared secret was derived with, which must be
+    /// the message key the recipient registered
+    pub recipient_key: String,
+
+    pub proof: String,
+}
+
+/// A Groth16 proof that the sender owns a leaf of the membership tree with
+/// the given root, as produced by `circuits::membership::prove`. Every field
+/// but `epoch` is a compressed arkworks encoding in the [`CUSTOM_ENGINE`]
+/// base64 alphabet.
+#[derive(ToSchema, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MembershipProof {
+    pub root: String,
+
+    /// The rate-limiting epoch the proof was made for
+    pub epoch: u64,
+
+    /// Identifies the sender within the epoch and recipient, and nothing else
+    pub nullifier: String,
+
+    /// The sender's share for this message, evaluated at
+    /// `message_to_field(recipient_id || encrypted_content)`
+    pub share: String,
+
+    pub proof: String,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Debug, Validate, Clone)]
+pub struct CreateAnonymousMessageRequest {
+    #[serde(with = "uuid::serde::simple")]
+    pub recipient_id: Uuid,
+
+    #[validate(custom(function = "validate_base64_min_len_4"))]
+    pub encrypted_content: String,
+
+    pub membership_proof: MembershipProof,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CreateAnonymousMessageResponse {
+    pub message_id: i64,
+}
+
+/// The current anonymity set, from which clients build their Merkle paths.
+#[derive(ToSchema, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MembershipTreeResponse {
+    pub root: String,
+
+    /// Every leaf, in tree order
+    pub leaves: Vec<String>,
+}
+
+/// Whether the process is up. Returned by `GET /healthz`, which checks
+/// nothing else.
+#[derive(ToSchema, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LivenessResponse {
+    /// Always `ok`
+    pub status: String,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Ok,
+    Failed,
+}
+
+/// The outcome of one readiness check.
+#[derive(ToSchema, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReadinessCheckResult {
+    /// Such as `database` or `migrations`
+    pub name: String,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+/// Whether the instance can take traffic: only if every check passed.
+#[derive(ToSchema, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReadinessResponse {
+    pub ready: bool,
+    pub checks: Vec<ReadinessCheckResult>,
+}
+
+/// A user's X3DH identity key and signed prekey, signed with their account
+/// key. Keys are compressed Ed25519 points and the signature is a 64-byte
+/// P-256 ECDSA signature over `circuits::ratchet::signed_prekey_payload`,
+/// all in the [`CUSTOM_ENGINE`] base64 alphabet.
+#[derive(ToSchema, Serialize, Deserialize, Debug, Validate, Clone, PartialEq)]
+pub struct UploadPrekeysRequest {
+    #[validate(custom(function = "validate_curve_point"))]
+    pub identity_key: String,
+
+    /// Must be above the id of the signed prekey it replaces
+    pub signed_prekey_id: u32,
+
+    #[validate(custom(function = "validate_curve_point"))]
+    pub signed_prekey: String,
+
+    #[validate(custom(function = "validate_signature"))]
+    pub signature: String,
+
+    /// Up to 100 more one-time prekeys. Ids uploaded before are skipped, so
+    /// a client may re-send the signed prekey it already published along
+    /// with a fresh batch.
+    #[serde(default)]
+    #[validate(length(max = 100), nested)]
+    pub one_time_prekeys: Vec<SignedOneTimePrekey>,
+}
+
+/// A one-time prekey and the signature the owner's account key made over
+/// `circuits::ratchet::one_time_prekey_payload`, encoded like the keys of an
+/// [`UploadPrekeysRequest`].
+#[derive(ToSchema, Serialize, Deserialize, Debug, Validate, Clone, PartialEq)]
+pub struct SignedOneTimePrekey {
+    pub prekey_id: u32,
+
+    #[validate(custom(function = "validate_curve_point"))]
+    pub prekey: String,
+
+    #[validate(custom(function = "validate_signature"))]
+    pub signature: String,
+}
+
+/// The prekeys `user_id` has published, as uploaded in an
+/// [`UploadPrekeysRequest`], and whether they are running low on one-time
+/// prekeys.
+#[derive(ToSchema, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PrekeysResponse {
+    #[serde(with = "uuid::serde::simple")]
+    pub user_id: Uuid,
+    pub identity_key: String,
+    pub signed_prekey_id: u32,
+    pub signed_prekey: String,
+    pub signature: String,
+    pub one_time_prekeys_remaining: u32,
+
+    /// Set once `one_time_prekeys_remaining` is at or below the server's low
+    /// watermark; the owner should upload a new batch
+    pub replenish: bool,
+}
+
+/// What a sender needs to start a session with `user_id`. The one-time
+/// prekey is handed to this sender only, and is absent once the user has run
+/// out. Check both signatures against the user's public key before use.
+#[derive(ToSchema, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PrekeyBundleResponse {
+    #[serde(with = "uuid::serde::simple")]
+    pub user_id: Uuid,
+    pub identity_key: String,
+    pub signed_prekey_id: u32,
+    pub signed_prekey: String,
+    pub signature: String,
+    pub one_time_prekey: Option<SignedOneTimePrekey>,
+}
+
+/// A backup of the user's account key, sealed on the client with
+/// [`crate::key_backup::PassphraseKeys`]. Byte fields are in the
+/// [`CUSTOM_ENGINE`] base64 alphabet; `signature` is a 64-byte P-256 ECDSA
+/// signature by the account key over [`crate::key_backup::upload_payload`].
+#[derive(ToSchema, Serialize, Deserialize, Debug, Validate, Clone, PartialEq)]
+pub struct UploadKeyBackupRequest {
+    /// Must be above the version of the backup it replaces
+    pub version: u32,
+
+    #[validate(custom(function = "validate_sealed_key"))]
+    pub encrypted_key: String,
+
+    #[validate(custom(function = "validate_salt"))]
+    pub salt: String,
+
+    #[validate(range(min = crate::key_backup::MIN_KDF_ITERATIONS))]
+    pub kdf_iterations: u32,
+
+    /// The compressed public half of the passphrase's recovery key
+    #[validate(custom(function = "validate_compressed_p256_point"))]
+    pub recovery_key: String,
+
+    #[validate(custom(function = "validate_signature"))]
+    pub signature: String,
+}
+
+/// A single-use challenge for recovering a key backup, and what the client
+/// needs to derive the passphrase's keys to answer it.
+#[derive(ToSchema, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KeyBackupChallengeResponse {
+    pub challenge: String,
+    pub salt: String,
+    pub kdf_iterations: u32,
+    /// Unix timestamp after which the challenge is refused
+    pub expires_at: i64,
+}
+
+/// An answer to a [`KeyBackupChallengeResponse`]: a signature over
+/// [`crate::key_backup::recovery_payload`] by the account key or the
+/// passphrase's recovery key.
+#[derive(ToSchema, Serialize, Deserialize, Debug, Validate, Clone, PartialEq)]
+pub struct RecoverKeyBackupRequest {
+    #[validate(length(min = 1, max = 64))]
+    pub challenge: String,
+
+    #[validate(custom(function = "validate_signature"))]
+    pub signature: String,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KeyBackupResponse {
+    pub version: u32,
+    pub encrypted_key: String,
+    pub salt: String,
+    pub kdf_iterations: u32,
 }
 
 fn validate_base64_min_len_4(val: &str) -> Result<(), ValidationError> {
@@ -84,6 +371,63 @@ fn validate_base64_min_len_4(val: &str) -> Result<(), ValidationError> {
     }
 }
 
+fn validate_commitment(val: &str) -> Result<(), ValidationError> {
+    match CUSTOM_ENGINE.decode(val) {
+        Ok(decoded) if decoded.len() == 32 => Ok(()),
+        Ok(_) => Err(ValidationError::new("invalid_commitment_length")),
+        Err(_) => Err(ValidationError::new("invalid_base64")),
+    }
+}
+
+fn validate_field_element(val: &str) -> Result<(), ValidationError> {
+    match CUSTOM_ENGINE.decode(val) {
+        Ok(decoded) if decoded.len() == 32 => Ok(()),
+        Ok(_) => Err(ValidationError::new("invalid_field_element_length")),
+        Err(_) => Err(ValidationError::new("invalid_base64")),
+    }
+}
+
+fn validate_curve_point(val: &str) -> Result<(), ValidationError> {
+    match CUSTOM_ENGINE.decode(val) {
+        Ok(decoded) if decoded.len() == 32 => Ok(()),
+        Ok(_) => Err(ValidationError::new("invalid_point_length")),
+        Err(_) => Err(ValidationError::new("invalid_base64")),
+    }
+}
+
+fn validate_signature(val: &str) -> Result<(), ValidationError> {
+    match CUSTOM_ENGINE.decode(val) {
+        Ok(decoded) if decoded.len() == 64 => Ok(()),
+        Ok(_) => Err(ValidationError::new("invalid_signature_length")),
+        Err(_) => Err(ValidationError::new("invalid_base64")),
+    }
+}
+
+fn validate_sealed_key(val: &str) -> Result<(), ValidationError> {
+    // A nonce and tag around at least one byte, and room for any key encoding
+    match CUSTOM_ENGINE.decode(val) {
+        Ok(decoded) if (29..=1024).contains(&decoded.len()) => Ok(()),
+        Ok(_) => Err(ValidationError::new("invalid_sealed_key_length")),
+        Err(_) => Err(ValidationError::new("invalid_base64")),
+    }
+}
+
+fn validate_salt(val: &str) -> Result<(), ValidationError> {
+    match CUSTOM_ENGINE.decode(val) {
+        Ok(decoded) if (16..=64).contains(&decoded.len()) => Ok(()),
+        Ok(_) => Err(ValidationError::new("invalid_salt_length")),
+        Err(_) => Err(ValidationError::new("invalid_base64")),
+    }
+}
+
+fn validate_compressed_p256_point(val: &str) -> Result<(), ValidationError> {
+    match CUSTOM_ENGINE.decode(val) {
+        Ok(decoded) if decoded.len() == 33 => Ok(()),
+        Ok(_) => Err(ValidationError::new("invalid_point_length")),
+        Err(_) => Err(ValidationError::new("invalid_base64")),
+    }
+}
+
 fn validate_optional_base64_max_512(val: &str) -> Result<(), ValidationError> {
     match CUSTOM_ENGINE.decode(val) {
         Ok(decoded) if decoded.len() <= 512 => Ok(()),